@@ -65,6 +65,14 @@ impl crate::sparse_chain::ChainPosition for TxHeight {
 }
 
 impl TxHeight {
+    /// The number of confirmations at `tip_height` (`0` if unconfirmed).
+    pub fn confirmations(&self, tip_height: u32) -> u32 {
+        match self {
+            TxHeight::Confirmed(height) => tip_height.saturating_add(1).saturating_sub(*height),
+            TxHeight::Unconfirmed => 0,
+        }
+    }
+
     pub fn is_confirmed(&self) -> bool {
         matches!(self, Self::Confirmed(_))
     }
@@ -117,8 +125,181 @@ impl ConfirmationTime {
     }
 }
 
+/// Block height and timestamp in which a transaction is confirmed in, or the times an unconfirmed
+/// transaction was first and last seen (in the mempool).
+///
+/// Unconfirmed transactions are ordered by when they were first seen. When an unconfirmed
+/// transaction is updated with another unconfirmed position, [`SparseChain`] keeps the earliest
+/// `first_seen` and latest `last_seen` of the two.
+///
+/// [`SparseChain`]: crate::sparse_chain::SparseChain
+#[derive(Debug, Clone, PartialEq, Eq, Copy, PartialOrd, Ord, core::hash::Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(crate = "serde_crate")
+)]
+pub enum ObservedPosition {
+    Confirmed { height: u32, time: u64 },
+    Unconfirmed { first_seen: u64, last_seen: u64 },
+}
+
+impl sparse_chain::ChainPosition for ObservedPosition {
+    fn height(&self) -> TxHeight {
+        match self {
+            ObservedPosition::Confirmed { height, .. } => TxHeight::Confirmed(*height),
+            ObservedPosition::Unconfirmed { .. } => TxHeight::Unconfirmed,
+        }
+    }
+
+    fn max_ord_of_height(height: TxHeight) -> Self {
+        match height {
+            TxHeight::Confirmed(height) => Self::Confirmed {
+                height,
+                time: u64::MAX,
+            },
+            TxHeight::Unconfirmed => Self::Unconfirmed {
+                first_seen: u64::MAX,
+                last_seen: u64::MAX,
+            },
+        }
+    }
+
+    fn min_ord_of_height(height: TxHeight) -> Self {
+        match height {
+            TxHeight::Confirmed(height) => Self::Confirmed {
+                height,
+                time: u64::MIN,
+            },
+            TxHeight::Unconfirmed => Self::Unconfirmed {
+                first_seen: u64::MIN,
+                last_seen: u64::MIN,
+            },
+        }
+    }
+
+    /// An unconfirmed position that has not been seen yet. Merging this with an observed position
+    /// results in the observed position.
+    fn unconfirmed() -> Self {
+        Self::Unconfirmed {
+            first_seen: u64::MAX,
+            last_seen: u64::MIN,
+        }
+    }
+
+    fn last_seen(&self) -> Option<u64> {
+        match self {
+            ObservedPosition::Unconfirmed {
+                first_seen,
+                last_seen,
+            } if *first_seen <= *last_seen => Some(*last_seen),
+            _ => None,
+        }
+    }
+
+    fn merge_unconfirmed(&self, update: &Self) -> Self {
+        match (self, update) {
+            (
+                ObservedPosition::Unconfirmed {
+                    first_seen,
+                    last_seen,
+                },
+                ObservedPosition::Unconfirmed {
+                    first_seen: update_first_seen,
+                    last_seen: update_last_seen,
+                },
+            ) => ObservedPosition::Unconfirmed {
+                first_seen: *first_seen.min(update_first_seen),
+                last_seen: *last_seen.max(update_last_seen),
+            },
+            _ => *update,
+        }
+    }
+}
+
+impl ObservedPosition {
+    /// An unconfirmed position which has been seen once, at `seen_at`.
+    pub fn seen_at(seen_at: u64) -> Self {
+        Self::Unconfirmed {
+            first_seen: seen_at,
+            last_seen: seen_at,
+        }
+    }
+
+    pub fn is_confirmed(&self) -> bool {
+        matches!(self, Self::Confirmed { .. })
+    }
+}
+
+/// A transaction position which is anchored to the block that confirms it.
+///
+/// Unlike [`TxHeight`], the hash of the confirming block is recorded (as well as the transaction's
+/// index in the block, if known). [`SparseChain`] rejects anchored positions which do not match
+/// its checkpoints, so a transaction can never be positioned in a block that is not part of the
+/// chain.
+///
+/// [`SparseChain`]: crate::sparse_chain::SparseChain
+#[derive(Debug, Clone, PartialEq, Eq, Copy, PartialOrd, Ord, core::hash::Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(crate = "serde_crate")
+)]
+pub enum AnchoredPosition {
+    Confirmed { block: BlockId, index: Option<u32> },
+    Unconfirmed,
+}
+
+impl sparse_chain::ChainPosition for AnchoredPosition {
+    fn height(&self) -> TxHeight {
+        match self {
+            AnchoredPosition::Confirmed { block, .. } => TxHeight::Confirmed(block.height),
+            AnchoredPosition::Unconfirmed => TxHeight::Unconfirmed,
+        }
+    }
+
+    fn max_ord_of_height(height: TxHeight) -> Self {
+        match height {
+            TxHeight::Confirmed(height) => Self::Confirmed {
+                block: BlockId {
+                    height,
+                    hash: BlockHash::from_inner([0xff; 32]),
+                },
+                index: Some(u32::MAX),
+            },
+            TxHeight::Unconfirmed => Self::Unconfirmed,
+        }
+    }
+
+    fn min_ord_of_height(height: TxHeight) -> Self {
+        match height {
+            TxHeight::Confirmed(height) => Self::Confirmed {
+                block: BlockId {
+                    height,
+                    hash: BlockHash::from_inner([0x00; 32]),
+                },
+                index: None,
+            },
+            TxHeight::Unconfirmed => Self::Unconfirmed,
+        }
+    }
+
+    fn anchor_block(&self) -> Option<BlockId> {
+        match self {
+            AnchoredPosition::Confirmed { block, .. } => Some(*block),
+            AnchoredPosition::Unconfirmed => None,
+        }
+    }
+}
+
+impl AnchoredPosition {
+    pub fn is_confirmed(&self) -> bool {
+        matches!(self, Self::Confirmed { .. })
+    }
+}
+
 /// A reference to a block in the cannonical chain.
-#[derive(Debug, Clone, PartialEq, Eq, Copy, PartialOrd, Ord)]
+#[derive(Debug, Clone, PartialEq, Eq, Copy, PartialOrd, Ord, core::hash::Hash)]
 #[cfg_attr(
     feature = "serde",
     derive(serde::Deserialize, serde::Serialize),
@@ -161,6 +342,91 @@ impl From<(&u32, &BlockHash)> for BlockId {
     }
 }
 
+/// When a wallet was created. Blocks before the birthday cannot contain transactions of the wallet
+/// so chain sources can skip them.
+///
+/// When parsed from a string, numbers below `500_000_000` are heights and the rest are UNIX
+/// timestamps (the same convention as `nLockTime`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, core::hash::Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(crate = "serde_crate")
+)]
+pub enum Birthday {
+    /// The height of the first block that may contain transactions of the wallet.
+    Height(u32),
+    /// The UNIX timestamp (in seconds) that the wallet was created at.
+    Time(u64),
+}
+
+impl Birthday {
+    /// Block timestamps may be off by up to two hours, so blocks with a timestamp less than this
+    /// many seconds before a [`Birthday::Time`] are not considered to predate it.
+    pub const TIMESTAMP_WINDOW: u64 = 2 * 60 * 60;
+
+    const HEIGHT_THRESHOLD: u64 = 500_000_000;
+
+    /// Whether the block at `height` with timestamp `time` predates the birthday.
+    pub fn predates(&self, height: u32, time: u64) -> bool {
+        match *self {
+            Birthday::Height(birthday_height) => height < birthday_height,
+            Birthday::Time(birthday_time) => {
+                time.saturating_add(Self::TIMESTAMP_WINDOW) < birthday_time
+            }
+        }
+    }
+
+    /// Find the height to start scanning blocks from, which is the lowest height (up to
+    /// `tip_height`) of a block that does not predate the birthday.
+    ///
+    /// For a [`Birthday::Time`], this binary searches the chain with `block_time`, which returns the
+    /// timestamp of the block at a height. If every block up to `tip_height` predates the birthday,
+    /// `tip_height + 1` is returned.
+    pub fn start_height<E>(
+        &self,
+        tip_height: u32,
+        mut block_time: impl FnMut(u32) -> Result<u64, E>,
+    ) -> Result<u32, E> {
+        match *self {
+            Birthday::Height(height) => Ok(height.min(tip_height + 1)),
+            Birthday::Time(_) => {
+                let (mut low, mut high) = (0_u32, tip_height + 1);
+                while low < high {
+                    let mid = low + (high - low) / 2;
+                    if self.predates(mid, block_time(mid)?) {
+                        low = mid + 1;
+                    } else {
+                        high = mid;
+                    }
+                }
+                Ok(low)
+            }
+        }
+    }
+}
+
+impl core::fmt::Display for Birthday {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Height(height) => core::write!(f, "{}", height),
+            Self::Time(time) => core::write!(f, "{}", time),
+        }
+    }
+}
+
+impl core::str::FromStr for Birthday {
+    type Err = core::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let value = s.parse::<u64>()?;
+        Ok(match value < Self::HEIGHT_THRESHOLD {
+            true => Self::Height(value as u32),
+            false => Self::Time(value),
+        })
+    }
+}
+
 /// A `TxOut` with as much data as we can retreive about it
 #[derive(Debug, Clone, PartialEq)]
 pub struct FullTxOut<I> {
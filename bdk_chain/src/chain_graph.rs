@@ -1,7 +1,7 @@
 //! Module for structures that combine the features of [`sparse_chain`] and [`tx_graph`].
 use crate::{
-    collections::HashSet,
-    sparse_chain::{self, ChainPosition, SparseChain},
+    collections::{BTreeMap, BTreeSet, HashSet},
+    sparse_chain::{self, ChainPosition, CheckpointRetention, SparseChain},
     tx_graph::{self, TxGraph},
     AsTransaction, BlockId, ForEachTxOut, FullTxOut, IntoOwned, TxHeight,
 };
@@ -24,10 +24,14 @@ use core::fmt::Debug;
 /// Note that the `ChainGraph` guarantees a 1:1 mapping between transactions in the `chain` and
 /// `graph` but not the other way around. Transactions may fall out of the *chain* (via re-org or
 /// mempool eviction) but will remain in the *graph*.
+///
+/// Which of two conflicting unconfirmed transactions is kept in the chain is decided by the
+/// [`ConflictPolicy`]. Refer to [`ChainGraph::set_conflict_policy`].
 #[derive(Clone, Debug, PartialEq)]
 pub struct ChainGraph<P = TxHeight, T = Transaction> {
     chain: SparseChain<P>,
     graph: TxGraph<T>,
+    conflict_policy: ConflictPolicy,
 }
 
 impl<P, T> Default for ChainGraph<P, T> {
@@ -35,6 +39,7 @@ impl<P, T> Default for ChainGraph<P, T> {
         Self {
             chain: Default::default(),
             graph: Default::default(),
+            conflict_policy: Default::default(),
         }
     }
 }
@@ -106,7 +111,11 @@ where
             return Err(NewError::Missing(missing));
         }
 
-        Ok(Self { chain, graph })
+        Ok(Self {
+            chain,
+            graph,
+            conflict_policy: Default::default(),
+        })
     }
 
     /// Take an update in the form of a [`SparseChain<P>`][`SparseChain`] and attempt to turn it
@@ -158,6 +167,77 @@ where
         self.chain.set_checkpoint_limit(limit)
     }
 
+    /// Returns the checkpoint retention policy.
+    ///
+    /// Refer to [`SparseChain::checkpoint_retention`] for more.
+    pub fn checkpoint_retention(&self) -> Option<CheckpointRetention> {
+        self.chain.checkpoint_retention()
+    }
+
+    /// Sets the checkpoint retention policy.
+    ///
+    /// Refer to [`SparseChain::set_checkpoint_retention`] for more.
+    pub fn set_checkpoint_retention(&mut self, retention: Option<CheckpointRetention>) {
+        self.chain.set_checkpoint_retention(retention)
+    }
+
+    /// Determines the changes required to prune checkpoints according to the checkpoint retention
+    /// policy.
+    ///
+    /// Refer to [`SparseChain::prune_checkpoints_preview`] for more.
+    pub fn prune_checkpoints_preview(&self) -> ChangeSet<P, T> {
+        ChangeSet {
+            chain: self.chain.prune_checkpoints_preview(),
+            ..Default::default()
+        }
+    }
+
+    /// Prunes checkpoints according to the checkpoint retention policy.
+    ///
+    /// This is equivalent to calling [`Self::prune_checkpoints_preview`] and
+    /// [`Self::apply_changeset`] in sequence.
+    pub fn prune_checkpoints(&mut self) -> ChangeSet<P, T>
+    where
+        ChangeSet<P, T>: Clone,
+    {
+        let changeset = self.prune_checkpoints_preview();
+        self.apply_changeset(changeset.clone());
+        changeset
+    }
+
+    /// Returns the unconfirmed transaction expiry.
+    ///
+    /// Refer to [`SparseChain::unconfirmed_expiry`] for more.
+    pub fn unconfirmed_expiry(&self) -> u64 {
+        self.chain.unconfirmed_expiry()
+    }
+
+    /// Sets the unconfirmed transaction expiry.
+    ///
+    /// Refer to [`SparseChain::set_unconfirmed_expiry`] for more.
+    pub fn set_unconfirmed_expiry(&mut self, expiry: u64) {
+        self.chain.set_unconfirmed_expiry(expiry)
+    }
+
+    /// Returns the policy used to resolve conflicts between unconfirmed transactions.
+    ///
+    /// Refer to [`set_conflict_policy`] for more.
+    ///
+    /// [`set_conflict_policy`]: Self::set_conflict_policy
+    pub fn conflict_policy(&self) -> &ConflictPolicy {
+        &self.conflict_policy
+    }
+
+    /// Set the policy used to resolve conflicts between unconfirmed transactions.
+    ///
+    /// When a transaction introduced by an update or insertion conflicts with an unconfirmed
+    /// transaction in the chain, the policy decides which one is kept. A confirmed transaction is
+    /// always kept regardless of the policy. The transactions that lose are reported in
+    /// [`ChangeSet::evicted`].
+    pub fn set_conflict_policy(&mut self, policy: ConflictPolicy) {
+        self.conflict_policy = policy;
+    }
+
     /// Determines the changes required to invalidate checkpoints `from_height` (inclusive) and
     /// above. Displaced transactions will have their positions moved to [`TxHeight::Unconfirmed`].
     pub fn invalidate_checkpoints_preview(&self, from_height: u32) -> ChangeSet<P, T> {
@@ -181,6 +261,41 @@ where
         changeset
     }
 
+    /// Determines the changes required to remove unconfirmed transactions that have expired at
+    /// `now` (a UNIX timestamp in seconds). Descendants of expired transactions are also removed.
+    ///
+    /// Refer to [`SparseChain::expire_unconfirmed_preview`] for more.
+    pub fn expire_unconfirmed_preview(&self, now: u64) -> ChangeSet<P, T> {
+        let mut chain = self.chain.expire_unconfirmed_preview(now);
+        let expired = chain.txids.keys().cloned().collect::<Vec<_>>();
+        for txid in expired {
+            chain
+                .txids
+                .extend(self.graph.walk_descendants(txid, |_, descendant| {
+                    self.chain
+                        .tx_position(descendant)
+                        .map(|_| (descendant, None))
+                }));
+        }
+        ChangeSet {
+            chain,
+            ..Default::default()
+        }
+    }
+
+    /// Removes unconfirmed transactions (and their descendants) that have expired at `now`.
+    ///
+    /// This is equivalent to calling [`Self::expire_unconfirmed_preview`] and
+    /// [`Self::apply_changeset`] in sequence.
+    pub fn expire_unconfirmed(&mut self, now: u64) -> ChangeSet<P, T>
+    where
+        ChangeSet<P, T>: Clone,
+    {
+        let changeset = self.expire_unconfirmed_preview(now);
+        self.apply_changeset(changeset.clone());
+        changeset
+    }
+
     /// Get a transaction that is currently in the underlying [`SparseChain`].
     ///
     /// This does not necessarily mean that it is *confirmed* in the blockchain, it might just be in
@@ -191,6 +306,13 @@ where
         Some((position, full_tx))
     }
 
+    /// Returns the number of confirmations of a transaction in the chain.
+    ///
+    /// Refer to [`SparseChain::confirmations`] for more.
+    pub fn confirmations(&self, txid: Txid) -> Option<u32> {
+        self.chain.confirmations(txid)
+    }
+
     /// Determines the changes required to insert a transaction into the inner [`ChainGraph`] and
     /// [`SparseChain`] at the given `position`.
     ///
@@ -200,6 +322,7 @@ where
         let mut changeset = ChangeSet {
             chain: self.chain.insert_tx_preview(tx.as_tx().txid(), pos)?,
             graph: self.graph.insert_tx_preview(tx),
+            ..Default::default()
         };
         self.fix_conflicts(&mut changeset)?;
         Ok(changeset)
@@ -218,8 +341,8 @@ where
     /// Determines the changes required to insert a [`TxOut`] into the internal [`TxGraph`].
     pub fn insert_txout_preview(&self, outpoint: OutPoint, txout: TxOut) -> ChangeSet<P, T> {
         ChangeSet {
-            chain: Default::default(),
             graph: self.graph.insert_txout_preview(outpoint, txout),
+            ..Default::default()
         }
     }
 
@@ -279,6 +402,7 @@ where
         let mut changeset = ChangeSet {
             chain: chain_changeset,
             graph: self.graph.determine_additions(&update.graph),
+            ..Default::default()
         };
 
         self.fix_conflicts(&mut changeset)?;
@@ -303,13 +427,18 @@ where
 
     /// Fix changeset conflicts.
     ///
+    /// Conflicts between a transaction newly added by the `changeset` and an unconfirmed
+    /// transaction in the chain are resolved with the [`ConflictPolicy`]. The transaction that
+    /// loses (alongside all of its unconfirmed descendants) is kept out of the chain and recorded
+    /// in [`ChangeSet::evicted`].
+    ///
     /// **WARNING:** If there are any missing full txs, conflict resolution will not be complete. In
     /// debug mode, this will result in panic.
     fn fix_conflicts(
         &self,
         changeset: &mut ChangeSet<P, T>,
     ) -> Result<(), UnresolvableConflict<P>> {
-        let chain_conflicts = changeset
+        let chain_additions = changeset
             .chain
             .txids
             .iter()
@@ -330,57 +459,257 @@ where
                             .iter()
                             .find(|tx| tx.as_tx().txid() == txid)
                     })
-                    .map(|tx| (txid, tx, pos));
+                    .map(|tx| (txid, (pos.clone(), tx.as_tx().clone())));
                 debug_assert!(full_tx.is_some(), "should have full tx at this point");
                 full_tx
             })
-            .flat_map(|(new_txid, new_tx, new_pos)| {
-                self.tx_conflicts_in_chain(new_tx.as_tx()).map(
-                    move |(conflict_pos, conflict_txid)| {
-                        (new_pos.clone(), new_txid, conflict_pos, conflict_txid)
-                    },
-                )
-            })
-            .collect::<Vec<_>>();
+            .collect::<BTreeMap<_, _>>();
 
-        for (update_pos, update_txid, conflicting_pos, conflicting_txid) in chain_conflicts {
-            // We have found a tx that conflicts with our update txid. Only allow this when the
-            // conflicting tx will be positioned as "unconfirmed" after the update is applied.
-            // If so, we will modify the changeset to evict the conflicting txid.
+        // process ancestors before descendants so that a rejected tx takes its descendants with it
+        // before they get the chance to evict anything
+        for (update_txid, update_pos, update_tx) in ancestors_first(chain_additions) {
+            if !matches!(changeset.chain.txids.get(&update_txid), Some(Some(_))) {
+                // already evicted as the descendant of a rejected tx
+                continue;
+            }
 
-            // determine the position of the conflicting txid after current changeset is applied
-            let conflicting_new_pos = changeset
-                .chain
-                .txids
-                .get(&conflicting_txid)
-                .map(Option::as_ref)
-                .unwrap_or(Some(conflicting_pos));
+            let direct_conflicts = self
+                .graph
+                .direct_conflicts_of_tx(&update_tx)
+                .map(|(_, txid)| txid)
+                .collect::<BTreeSet<_>>();
+
+            let mut to_evict = Vec::new();
+            let mut rejection = None;
 
-            match conflicting_new_pos {
-                None => {
+            for conflicting_txid in direct_conflicts {
+                // conflicts which are not in the chain are already considered as evicted
+                let conflicting_pos = match self.chain.tx_position(conflicting_txid) {
+                    Some(pos) => pos,
+                    None => continue,
+                };
+
+                // determine the position of the conflicting txid after current changeset is applied
+                let conflicting_new_pos = match changeset
+                    .chain
+                    .txids
+                    .get(&conflicting_txid)
+                    .map(Option::as_ref)
+                    .unwrap_or(Some(conflicting_pos))
+                {
+                    Some(pos) => pos,
                     // conflicting txid will be deleted, can ignore
+                    None => continue,
+                };
+
+                if conflicting_new_pos.height().is_confirmed() {
+                    // the new postion of the conflicting tx is "confirmed", therefore cannot be
+                    // evicted, return error
+                    return Err(UnresolvableConflict {
+                        already_confirmed_tx: (conflicting_pos.clone(), conflicting_txid),
+                        update_tx: (update_pos, update_txid),
+                    });
                 }
-                Some(existing_new_pos) => match existing_new_pos.height() {
-                    TxHeight::Confirmed(_) => {
-                        // the new postion of the conflicting tx is "confirmed", therefore cannot be
-                        // evicted, return error
-                        return Err(UnresolvableConflict {
-                            already_confirmed_tx: (conflicting_pos.clone(), conflicting_txid),
-                            update_tx: (update_pos.clone(), update_txid),
-                        });
-                    }
-                    TxHeight::Unconfirmed => {
-                        // the new position of the conflicting tx is "unconfirmed", therefore it can
-                        // be evicted
-                        changeset.chain.txids.insert(conflicting_txid, None);
+
+                let resolution = if update_pos.height().is_confirmed() {
+                    Resolution::EvictExisting(EvictionReason::ConflictConfirmed)
+                } else {
+                    self.resolve_conflict(
+                        changeset,
+                        (&update_pos, &update_tx),
+                        (conflicting_new_pos, conflicting_txid),
+                    )
+                };
+
+                match resolution {
+                    Resolution::EvictExisting(reason) => to_evict.push((conflicting_txid, reason)),
+                    Resolution::RejectUpdate(reason) => {
+                        let eviction = Eviction {
+                            by: conflicting_txid,
+                            reason,
+                        };
+                        rejection = Some((eviction, conflicting_new_pos.clone()));
+                        break;
                     }
-                },
+                }
+            }
+
+            if let Some((eviction, winner_pos)) = rejection {
+                // the update tx loses, so it is never added to the chain
+                self.evict_with_descendants(
+                    changeset,
+                    update_txid,
+                    eviction,
+                    (winner_pos, eviction.by),
+                )?;
+                continue;
+            }
+
+            for (conflicting_txid, reason) in to_evict {
+                let eviction = Eviction {
+                    by: update_txid,
+                    reason,
+                };
+                self.evict_with_descendants(
+                    changeset,
+                    conflicting_txid,
+                    eviction,
+                    (update_pos.clone(), update_txid),
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Modify the `changeset` so that `txid` and all of its descendants are not in the chain after
+    /// the changeset is applied.
+    ///
+    /// Descendants are looked up in both the graph and the `changeset`. They are recorded in
+    /// [`ChangeSet::evicted`] with [`EvictionReason::AncestorEvicted`]. This fails if any of them is
+    /// confirmed (as it would conflict with `winner`).
+    fn evict_with_descendants(
+        &self,
+        changeset: &mut ChangeSet<P, T>,
+        txid: Txid,
+        eviction: Eviction,
+        winner: (P, Txid),
+    ) -> Result<(), UnresolvableConflict<P>> {
+        let descendant_eviction = Eviction {
+            by: eviction.by,
+            reason: EvictionReason::AncestorEvicted { ancestor: txid },
+        };
+        let mut stack = vec![(txid, eviction)];
+
+        while let Some((txid, eviction)) = stack.pop() {
+            let original_pos = self.chain.tx_position(txid);
+            let new_pos = match changeset.chain.txids.get(&txid) {
+                Some(pos_change) => pos_change.as_ref(),
+                None => original_pos,
             };
+            match new_pos {
+                // not in the chain after the changeset is applied
+                None => continue,
+                Some(new_pos) if new_pos.height().is_confirmed() => {
+                    return Err(UnresolvableConflict {
+                        already_confirmed_tx: (original_pos.unwrap_or(new_pos).clone(), txid),
+                        update_tx: winner,
+                    });
+                }
+                Some(_) => {}
+            }
+
+            if original_pos.is_some() {
+                changeset.chain.txids.insert(txid, None);
+            } else {
+                changeset.chain.txids.remove(&txid);
+            }
+            changeset.evicted.insert(txid, eviction);
+
+            let graph_children = self
+                .graph
+                .tx_outspends(txid)
+                .flat_map(|(_, spends)| spends.iter().cloned());
+            let changeset_children = changeset
+                .graph
+                .tx
+                .iter()
+                .map(AsTransaction::as_tx)
+                .filter(|tx| {
+                    tx.input
+                        .iter()
+                        .any(|txin| txin.previous_output.txid == txid)
+                })
+                .map(Transaction::txid);
+            stack.extend(
+                graph_children
+                    .chain(changeset_children)
+                    .map(|child| (child, descendant_eviction)),
+            );
         }
 
         Ok(())
     }
 
+    /// Decide which of two conflicting unconfirmed transactions to keep according to the
+    /// [`ConflictPolicy`].
+    fn resolve_conflict(
+        &self,
+        changeset: &ChangeSet<P, T>,
+        (update_pos, update_tx): (&P, &Transaction),
+        (existing_pos, existing_txid): (&P, Txid),
+    ) -> Resolution {
+        match &self.conflict_policy {
+            ConflictPolicy::PreferUpdate => {
+                Resolution::EvictExisting(EvictionReason::ReplacedByUpdate)
+            }
+            ConflictPolicy::PreferLastSeen => {
+                if existing_pos.last_seen() > update_pos.last_seen() {
+                    Resolution::RejectUpdate(EvictionReason::SeenEarlier)
+                } else {
+                    Resolution::EvictExisting(EvictionReason::SeenEarlier)
+                }
+            }
+            ConflictPolicy::PreferHigherFeerate => {
+                let existing_feerate = self
+                    .graph
+                    .get_tx(existing_txid)
+                    .and_then(|tx| self.fee_and_weight(changeset, tx.as_tx()));
+                let update_feerate = self.fee_and_weight(changeset, update_tx);
+                match (existing_feerate, update_feerate) {
+                    // compare `fee / weight` without dividing
+                    (Some((existing_fee, existing_weight)), Some((update_fee, update_weight)))
+                        if existing_fee as i128 * update_weight as i128
+                            > update_fee as i128 * existing_weight as i128 =>
+                    {
+                        Resolution::RejectUpdate(EvictionReason::LowerFeerate)
+                    }
+                    _ => Resolution::EvictExisting(EvictionReason::LowerFeerate),
+                }
+            }
+            ConflictPolicy::Keep(txids) => {
+                if txids.contains(&existing_txid) {
+                    Resolution::RejectUpdate(EvictionReason::ConflictKept)
+                } else if txids.contains(&update_tx.txid()) {
+                    Resolution::EvictExisting(EvictionReason::ConflictKept)
+                } else {
+                    Resolution::EvictExisting(EvictionReason::ReplacedByUpdate)
+                }
+            }
+        }
+    }
+
+    /// The fee and weight of `tx`. Prevouts are looked up in the graph and in the `changeset`.
+    fn fee_and_weight(
+        &self,
+        changeset: &ChangeSet<P, T>,
+        tx: &Transaction,
+    ) -> Option<(i64, usize)> {
+        let inputs_sum = tx
+            .input
+            .iter()
+            .map(|txin| {
+                let outpoint = txin.previous_output;
+                self.graph
+                    .get_txout(outpoint)
+                    .or_else(|| {
+                        changeset
+                            .graph
+                            .txouts()
+                            .find(|(op, _)| *op == outpoint)
+                            .map(|(_, txout)| txout)
+                    })
+                    .map(|txout| txout.value as i64)
+            })
+            .sum::<Option<i64>>()?;
+        let outputs_sum = tx
+            .output
+            .iter()
+            .map(|txout| txout.value as i64)
+            .sum::<i64>();
+        Some((inputs_sum - outputs_sum, tx.weight()))
+    }
+
     /// Applies `changeset` to `self`.
     ///
     /// **Warning** this method assumes the changeset is assumed to be correctly formed. If it isn't
@@ -446,10 +775,22 @@ where
 pub struct ChangeSet<P, T> {
     pub chain: sparse_chain::ChangeSet<P>,
     pub graph: tx_graph::Additions<T>,
+    /// Transactions that lost a conflict and are kept out of the chain, and why.
+    ///
+    /// This is a report and has no effect when the changeset is applied. It is not serialized (so
+    /// it is not persisted) and does not count towards [`is_empty`].
+    ///
+    /// [`is_empty`]: Self::is_empty
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub evicted: BTreeMap<Txid, Eviction>,
 }
 
 impl<P, T> ChangeSet<P, T> {
     /// Returns `true` if this [`ChangeSet`] records no changes.
+    ///
+    /// The [`evicted`] report is ignored as it is not a change.
+    ///
+    /// [`evicted`]: Self::evicted
     pub fn is_empty(&self) -> bool {
         self.chain.is_empty() && self.graph.is_empty()
     }
@@ -469,6 +810,10 @@ impl<P, T> ChangeSet<P, T> {
         P: ChainPosition,
         T: Ord,
     {
+        // a txid which is added back to the chain is no longer evicted
+        self.evicted
+            .retain(|txid, _| !matches!(other.chain.txids.get(txid), Some(Some(_))));
+        self.evicted.extend(other.evicted);
         self.chain.append(other.chain);
         self.graph.append(other.graph);
     }
@@ -479,10 +824,104 @@ impl<P, T> Default for ChangeSet<P, T> {
         Self {
             chain: Default::default(),
             graph: Default::default(),
+            evicted: Default::default(),
         }
     }
 }
 
+/// Policy that decides which of two conflicting unconfirmed transactions is kept in a
+/// [`ChainGraph`].
+///
+/// A confirmed transaction always wins against an unconfirmed one. Two conflicting confirmed
+/// transactions result in an [`UnresolvableConflict`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(crate = "serde_crate")
+)]
+pub enum ConflictPolicy {
+    /// Always keep the transaction introduced by the update (or insertion).
+    #[default]
+    PreferUpdate,
+    /// Keep the transaction that was seen last, as reported by [`ChainPosition::last_seen`]. Ties
+    /// are won by the update's transaction.
+    PreferLastSeen,
+    /// Keep the transaction that pays the higher feerate (fee per weight unit). If the fee of
+    /// either transaction cannot be calculated (or the feerates are equal), the update's
+    /// transaction is kept.
+    PreferHigherFeerate,
+    /// Keep the given transactions (such as the ones we have broadcast ourselves). Conflicts that
+    /// do not involve any of these are won by the update's transaction.
+    Keep(BTreeSet<Txid>),
+}
+
+/// Records why a transaction was evicted from (or kept out of) the chain of a [`ChainGraph`].
+///
+/// Refer to [`ChangeSet::evicted`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(crate = "serde_crate")
+)]
+pub struct Eviction {
+    /// The transaction that won the conflict.
+    pub by: Txid,
+    /// Why the evicted transaction lost.
+    pub reason: EvictionReason,
+}
+
+/// The reason of an [`Eviction`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(crate = "serde_crate")
+)]
+pub enum EvictionReason {
+    /// The conflicting transaction is confirmed.
+    ConflictConfirmed,
+    /// The conflicting transaction was introduced by an update ([`ConflictPolicy::PreferUpdate`]).
+    ReplacedByUpdate,
+    /// The conflicting transaction was seen later ([`ConflictPolicy::PreferLastSeen`]).
+    SeenEarlier,
+    /// The conflicting transaction pays a higher feerate ([`ConflictPolicy::PreferHigherFeerate`]).
+    LowerFeerate,
+    /// The conflicting transaction is one that we want to keep ([`ConflictPolicy::Keep`]).
+    ConflictKept,
+    /// The transaction spends (directly or indirectly) from the evicted `ancestor`.
+    AncestorEvicted { ancestor: Txid },
+}
+
+/// Order `txs` so that ancestors come before their descendants.
+fn ancestors_first<P>(mut txs: BTreeMap<Txid, (P, Transaction)>) -> Vec<(Txid, P, Transaction)> {
+    fn visit<P>(
+        txid: Txid,
+        txs: &mut BTreeMap<Txid, (P, Transaction)>,
+        ordered: &mut Vec<(Txid, P, Transaction)>,
+    ) {
+        if let Some((pos, tx)) = txs.remove(&txid) {
+            for txin in &tx.input {
+                visit(txin.previous_output.txid, txs, ordered);
+            }
+            ordered.push((txid, pos, tx));
+        }
+    }
+
+    let mut ordered = Vec::with_capacity(txs.len());
+    while let Some(&txid) = txs.keys().next() {
+        visit(txid, &mut txs, &mut ordered);
+    }
+    ordered
+}
+
+/// Outcome of resolving a conflict with the [`ConflictPolicy`].
+enum Resolution {
+    EvictExisting(EvictionReason),
+    RejectUpdate(EvictionReason),
+}
+
 impl<P, T: AsTransaction> ForEachTxOut for ChainGraph<P, T> {
     fn for_each_txout(&self, f: impl FnMut((OutPoint, &TxOut))) {
         self.graph.for_each_txout(f)
@@ -0,0 +1,179 @@
+//! Module for answering questions about the best chain.
+//!
+//! A [`ChainOracle`] is anything that can tell us which blocks are in the best chain. This can be a
+//! chain source (such as an electrum or esplora server), a store of block headers, or simply a
+//! [`SparseChain`] that we trust.
+//!
+//! [`checkpoint_update`] uses a [`ChainOracle`] to determine the checkpoints of an update
+//! [`SparseChain`] that connects to our local chain.
+use crate::{
+    collections::BTreeMap,
+    sparse_chain::{ChainPosition, SparseChain},
+    BlockId,
+};
+use bitcoin::{BlockHash, BlockHeader};
+use core::convert::Infallible;
+
+/// Represents a service that tracks the best chain.
+pub trait ChainOracle {
+    /// Error type.
+    type Error: core::fmt::Debug;
+
+    /// Get the height and hash of the best chain's tip.
+    ///
+    /// This returns [`None`] if the oracle does not know of any blocks.
+    fn get_tip(&self) -> Result<Option<BlockId>, Self::Error>;
+
+    /// Get the hash of the best-chain block at `height`.
+    ///
+    /// This returns [`None`] if the oracle does not know of a block at `height`.
+    fn get_block_hash(&self, height: u32) -> Result<Option<BlockHash>, Self::Error>;
+
+    /// Determines whether `block` is in the best chain.
+    ///
+    /// This returns [`None`] if the oracle cannot tell.
+    fn is_block_in_best_chain(&self, block: BlockId) -> Result<Option<bool>, Self::Error> {
+        Ok(self
+            .get_block_hash(block.height)?
+            .map(|hash| hash == block.hash))
+    }
+}
+
+impl<O: ChainOracle + ?Sized> ChainOracle for &O {
+    type Error = O::Error;
+
+    fn get_tip(&self) -> Result<Option<BlockId>, Self::Error> {
+        O::get_tip(self)
+    }
+
+    fn get_block_hash(&self, height: u32) -> Result<Option<BlockHash>, Self::Error> {
+        O::get_block_hash(self, height)
+    }
+}
+
+impl<P: ChainPosition> ChainOracle for SparseChain<P> {
+    type Error = Infallible;
+
+    fn get_tip(&self) -> Result<Option<BlockId>, Self::Error> {
+        Ok(self.latest_checkpoint())
+    }
+
+    fn get_block_hash(&self, height: u32) -> Result<Option<BlockHash>, Self::Error> {
+        Ok(self.checkpoint_at(height).map(|cp| cp.hash))
+    }
+}
+
+/// Block hashes by height.
+impl ChainOracle for BTreeMap<u32, BlockHash> {
+    type Error = Infallible;
+
+    fn get_tip(&self) -> Result<Option<BlockId>, Self::Error> {
+        Ok(self
+            .iter()
+            .last()
+            .map(|(&height, &hash)| BlockId { height, hash }))
+    }
+
+    fn get_block_hash(&self, height: u32) -> Result<Option<BlockHash>, Self::Error> {
+        Ok(self.get(&height).cloned())
+    }
+}
+
+/// A header store of block headers by height.
+impl ChainOracle for BTreeMap<u32, BlockHeader> {
+    type Error = Infallible;
+
+    fn get_tip(&self) -> Result<Option<BlockId>, Self::Error> {
+        Ok(self.iter().last().map(|(&height, header)| BlockId {
+            height,
+            hash: header.block_hash(),
+        }))
+    }
+
+    fn get_block_hash(&self, height: u32) -> Result<Option<BlockHash>, Self::Error> {
+        Ok(self.get(&height).map(BlockHeader::block_hash))
+    }
+}
+
+/// The number of times [`checkpoint_update`] starts again (because the oracle's tip conflicts
+/// with the checkpoints found) before it gives up.
+pub const CHECKPOINT_UPDATE_ATTEMPTS: usize = 10;
+
+/// Error of [`checkpoint_update`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheckpointUpdateError<E> {
+    /// The oracle returned an error.
+    Oracle(E),
+    /// The oracle's tip kept conflicting with the checkpoints found (after
+    /// [`CHECKPOINT_UPDATE_ATTEMPTS`] attempts).
+    UnstableTip,
+}
+
+impl<E> From<E> for CheckpointUpdateError<E> {
+    fn from(err: E) -> Self {
+        Self::Oracle(err)
+    }
+}
+
+impl<E: core::fmt::Display> core::fmt::Display for CheckpointUpdateError<E> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Oracle(err) => core::fmt::Display::fmt(err, f),
+            Self::UnstableTip => write!(
+                f,
+                "the tip kept changing after {} attempts to connect to it",
+                CHECKPOINT_UPDATE_ATTEMPTS
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<E: core::fmt::Debug + core::fmt::Display> std::error::Error for CheckpointUpdateError<E> {}
+
+/// Determine the checkpoints of an update [`SparseChain`] that connects to `local_chain`, according
+/// to the `oracle`.
+///
+/// Checkpoints of `local_chain` are checked from the tip backwards until one is found that is still
+/// in the best chain (the "agreement point"). The update includes every checkpoint checked (so
+/// that stale checkpoints are invalidated) and the `oracle`'s tip.
+///
+/// If the tip changes in a way that conflicts with the checkpoints found (a reorg happened while we
+/// were asking), the process starts again (up to [`CHECKPOINT_UPDATE_ATTEMPTS`] times).
+pub fn checkpoint_update<P, O>(
+    oracle: &O,
+    local_chain: &BTreeMap<u32, BlockHash>,
+) -> Result<SparseChain<P>, CheckpointUpdateError<O::Error>>
+where
+    P: ChainPosition,
+    O: ChainOracle + ?Sized,
+{
+    for _ in 0..CHECKPOINT_UPDATE_ATTEMPTS {
+        let mut update = SparseChain::default();
+
+        for (&height, &local_hash) in local_chain.iter().rev() {
+            let hash = match oracle.get_block_hash(height)? {
+                Some(hash) => hash,
+                None => continue,
+            };
+            let _ = update
+                .insert_checkpoint(BlockId { height, hash })
+                .expect("cannot repeat height here");
+            if hash == local_hash {
+                break;
+            }
+        }
+
+        // Insert the tip so new transactions will be accepted into the update.
+        if let Some(tip) = oracle.get_tip()? {
+            if update.insert_checkpoint(tip).is_err() {
+                // there was a reorg while we were obtaining checkpoints
+                continue;
+            }
+        }
+
+        return Ok(update);
+    }
+
+    Err(CheckpointUpdateError::UnstableTip)
+}
@@ -13,16 +13,31 @@
 //! [`KeychainTracker`] and changes made on a [`KeychainTracker`] are reported by
 //! [`KeychainChangeSet`]s.
 //!
+//! [`FullScanRequest`] and [`SyncRequest`] describe the data that a chain source should fetch to
+//! create a [`KeychainScan`] for a [`KeychainTracker`].
+//!
 //! [`SpkTxOutIndex`]: crate::SpkTxOutIndex
 use crate::{
     chain_graph::{self, ChainGraph},
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
     sparse_chain::ChainPosition,
     tx_graph::TxGraph,
     AsTransaction, ForEachTxOut,
 };
-use bitcoin::Transaction;
+use bitcoin::{OutPoint, Script, Transaction, Txid};
 
+mod labels;
+pub use labels::*;
+mod request;
+pub use request::*;
+#[cfg(feature = "miniscript")]
+mod events;
+#[cfg(feature = "miniscript")]
+pub use events::*;
+#[cfg(feature = "miniscript")]
+mod multi_tracker;
+#[cfg(feature = "miniscript")]
+pub use multi_tracker::*;
 #[cfg(feature = "miniscript")]
 pub mod persist;
 #[cfg(feature = "miniscript")]
@@ -35,6 +50,10 @@ pub use tracker::*;
 mod txout_index;
 #[cfg(feature = "miniscript")]
 pub use txout_index::*;
+#[cfg(feature = "miniscript")]
+mod view;
+#[cfg(feature = "miniscript")]
+pub use view::*;
 
 /// Represents updates to the derivation index of a [`KeychainTxOutIndex`].
 ///
@@ -105,6 +124,11 @@ pub struct KeychainScan<K, P, T = Transaction> {
     pub update: ChainGraph<P, T>,
     /// The last active indexes of each keychain
     pub last_active_indices: BTreeMap<K, u32>,
+    /// The progress of the full scan that this update is from
+    ///
+    /// This is `None` if the update is not from a full scan. A full scan that finished has an
+    /// empty [`ScanProgress`] (which clears the progress of the tracker it is applied to).
+    pub progress: Option<ScanProgress<K>>,
 }
 
 impl<K, P, T> Default for KeychainScan<K, P, T> {
@@ -112,6 +136,7 @@ impl<K, P, T> Default for KeychainScan<K, P, T> {
         Self {
             update: Default::default(),
             last_active_indices: Default::default(),
+            progress: None,
         }
     }
 }
@@ -120,7 +145,7 @@ impl<K, P, T> From<ChainGraph<P, T>> for KeychainScan<K, P, T> {
     fn from(update: ChainGraph<P, T>) -> Self {
         KeychainScan {
             update,
-            last_active_indices: Default::default(),
+            ..Default::default()
         }
     }
 }
@@ -146,6 +171,16 @@ pub struct KeychainChangeSet<K, P, T = Transaction> {
     pub derivation_indices: DerivationAdditions<K>,
     /// The changes that have occurred in the blockchain
     pub chain_graph: chain_graph::ChangeSet<P, T>,
+    /// The changes in frozen outpoints (`true` if the outpoint is frozen, `false` if unfrozen)
+    pub frozen: BTreeMap<OutPoint, bool>,
+    /// The changes in labels
+    pub labels: LabelChangeSet<K>,
+    /// The changes in watched script pubkeys (`true` if watched, `false` if no longer watched)
+    pub watched_spks: BTreeMap<Script, bool>,
+    /// The changes in watched outpoints (`true` if watched, `false` if no longer watched)
+    pub watched_outpoints: BTreeMap<OutPoint, bool>,
+    /// The new progress of an unfinished full scan (if changed)
+    pub scan_progress: Option<ScanProgress<K>>,
 }
 
 impl<K, P, T> Default for KeychainChangeSet<K, P, T> {
@@ -153,6 +188,11 @@ impl<K, P, T> Default for KeychainChangeSet<K, P, T> {
         Self {
             chain_graph: Default::default(),
             derivation_indices: Default::default(),
+            frozen: Default::default(),
+            labels: Default::default(),
+            watched_spks: Default::default(),
+            watched_outpoints: Default::default(),
+            scan_progress: None,
         }
     }
 }
@@ -160,7 +200,13 @@ impl<K, P, T> Default for KeychainChangeSet<K, P, T> {
 impl<K, P, T> KeychainChangeSet<K, P, T> {
     /// Returns whether the [`KeychainChangeSet`] is empty (no changes recorded).
     pub fn is_empty(&self) -> bool {
-        self.chain_graph.is_empty() && self.derivation_indices.is_empty()
+        self.chain_graph.is_empty()
+            && self.derivation_indices.is_empty()
+            && self.frozen.is_empty()
+            && self.labels.is_empty()
+            && self.watched_spks.is_empty()
+            && self.watched_outpoints.is_empty()
+            && self.scan_progress.is_none()
     }
 
     /// Appends the changes in `other` into `self` such that applying `self` afterwards has the same
@@ -176,6 +222,13 @@ impl<K, P, T> KeychainChangeSet<K, P, T> {
     {
         self.derivation_indices.append(other.derivation_indices);
         self.chain_graph.append(other.chain_graph);
+        self.frozen.extend(other.frozen);
+        self.labels.append(other.labels);
+        self.watched_spks.extend(other.watched_spks);
+        self.watched_outpoints.extend(other.watched_outpoints);
+        if other.scan_progress.is_some() {
+            self.scan_progress = other.scan_progress;
+        }
     }
 }
 
@@ -224,7 +277,16 @@ pub struct Balance {
     /// Unconfirmed UTXOs received from an external wallet
     pub untrusted_pending: u64,
     /// Confirmed and immediately spendable balance
+    ///
+    /// If a finality depth is set (refer to [`KeychainTracker::set_finality_depth`]), this only
+    /// includes outputs that have reached the finality depth.
     pub confirmed: u64,
+    /// Confirmed and immediately spendable balance that has not yet reached the finality depth
+    /// (this is always `0` if no finality depth is set)
+    pub shallow: u64,
+    /// Unspent outputs that are locked or frozen and cannot be spent (refer to
+    /// [`KeychainTracker::lock_utxo`] and [`KeychainTracker::freeze_utxo`])
+    pub locked: u64,
 }
 
 impl Balance {
@@ -233,12 +295,17 @@ impl Balance {
     /// This is the balance you can spend right now that shouldn't get cancelled via another party
     /// double spending it.
     pub fn trusted_spendable(&self) -> u64 {
-        self.confirmed + self.trusted_pending
+        self.confirmed + self.shallow + self.trusted_pending
     }
 
     /// Get the whole balance visible to the wallet.
     pub fn total(&self) -> u64 {
-        self.confirmed + self.trusted_pending + self.untrusted_pending + self.immature
+        self.confirmed
+            + self.shallow
+            + self.trusted_pending
+            + self.untrusted_pending
+            + self.immature
+            + self.locked
     }
 }
 
@@ -246,8 +313,8 @@ impl core::fmt::Display for Balance {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(
             f,
-            "{{ immature: {}, trusted_pending: {}, untrusted_pending: {}, confirmed: {} }}",
-            self.immature, self.trusted_pending, self.untrusted_pending, self.confirmed
+            "{{ immature: {}, trusted_pending: {}, untrusted_pending: {}, confirmed: {}, shallow: {}, locked: {} }}",
+            self.immature, self.trusted_pending, self.untrusted_pending, self.confirmed, self.shallow, self.locked
         )
     }
 }
@@ -261,10 +328,46 @@ impl core::ops::Add for Balance {
             trusted_pending: self.trusted_pending + other.trusted_pending,
             untrusted_pending: self.untrusted_pending + other.untrusted_pending,
             confirmed: self.confirmed + other.confirmed,
+            shallow: self.shallow + other.shallow,
+            locked: self.locked + other.locked,
         }
     }
 }
 
+/// A transaction relevant to a keychain tracker and how it affects the tracked keychains.
+///
+/// Returned by [`KeychainTracker::transactions`] and friends.
+#[derive(Debug, PartialEq, Clone)]
+pub struct WalletTx<'a, K, P, T = Transaction> {
+    /// The txid of the transaction
+    pub txid: Txid,
+    /// The position of the transaction in the chain
+    pub chain_position: P,
+    /// The transaction itself
+    pub tx: &'a T,
+    /// Total value of the tracked outputs spent by the transaction
+    pub sent: u64,
+    /// Total value of the transaction outputs that go to tracked script pubkeys
+    pub received: u64,
+    /// The fee paid by the transaction (`None` if we are missing any of the outputs it spends)
+    pub fee: Option<u64>,
+    /// The feerate of the transaction in sats per virtual byte (`None` if the fee is unknown)
+    pub feerate: Option<f32>,
+    /// The keychains of the spent outputs and the outputs of the transaction
+    pub keychains: BTreeSet<K>,
+    /// Whether all inputs and outputs of the transaction are tracked by the keychains
+    pub is_self_transfer: bool,
+    /// The label of the transaction (refer to [`KeychainTracker::set_label`])
+    pub label: Option<&'a str>,
+}
+
+impl<'a, K, P, T> WalletTx<'a, K, P, T> {
+    /// Get the net value the transaction gives to the keychains (received - sent).
+    pub fn net_value(&self) -> i64 {
+        self.received as i64 - self.sent as i64
+    }
+}
+
 #[cfg(test)]
 mod test {
     use bitcoin::Transaction;
@@ -292,11 +395,21 @@ mod test {
         let mut lhs = KeychainChangeSet {
             derivation_indices: DerivationAdditions(lhs_di),
             chain_graph: chain_graph::ChangeSet::<TxHeight, Transaction>::default(),
+            frozen: Default::default(),
+            labels: Default::default(),
+            watched_spks: Default::default(),
+            watched_outpoints: Default::default(),
+            scan_progress: Default::default(),
         };
 
         let rhs = KeychainChangeSet {
             derivation_indices: DerivationAdditions(rhs_di),
             chain_graph: chain_graph::ChangeSet::<TxHeight, Transaction>::default(),
+            frozen: Default::default(),
+            labels: Default::default(),
+            watched_spks: Default::default(),
+            watched_outpoints: Default::default(),
+            scan_progress: Default::default(),
         };
 
         lhs.append(rhs);
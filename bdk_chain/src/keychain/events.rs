@@ -0,0 +1,230 @@
+use alloc::vec::Vec;
+use bitcoin::{OutPoint, Txid};
+
+use crate::{
+    chain_graph::Eviction,
+    collections::*,
+    keychain::{KeychainChangeSet, KeychainTracker},
+    sparse_chain::ChainPosition,
+    AsTransaction, BlockId, ForEachTxOut,
+};
+
+/// A semantic event derived from a [`KeychainChangeSet`] and the [`KeychainTracker`] it is about to
+/// be applied to.
+///
+/// Refer to [`wallet_events`] for how events are derived.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WalletEvent<K, P> {
+    /// The tip of the chain changed.
+    TipChanged {
+        /// The tip before the changeset is applied.
+        from: Option<BlockId>,
+        /// The tip after the changeset is applied.
+        to: Option<BlockId>,
+    },
+    /// A script pubkey of a keychain is revealed.
+    AddressRevealed {
+        /// The keychain of the revealed script pubkey.
+        keychain: K,
+        /// The derivation index of the revealed script pubkey.
+        index: u32,
+    },
+    /// A transaction that was not in the chain is added to it.
+    TxReceived {
+        /// The txid of the transaction.
+        txid: Txid,
+        /// The position the transaction is added at.
+        position: P,
+    },
+    /// A transaction becomes confirmed.
+    ///
+    /// This is emitted for transactions that move from unconfirmed to confirmed, transactions
+    /// that are added straight into a block (after [`TxReceived`]) and transactions that get
+    /// confirmed in a different block after a reorg (after [`TxReorged`]).
+    ///
+    /// [`TxReceived`]: Self::TxReceived
+    /// [`TxReorged`]: Self::TxReorged
+    TxConfirmed {
+        /// The txid of the transaction.
+        txid: Txid,
+        /// The confirmed position of the transaction.
+        position: P,
+    },
+    /// A confirmed transaction is moved out of its block.
+    TxReorged {
+        /// The txid of the transaction.
+        txid: Txid,
+        /// The confirmed position the transaction was moved out of.
+        from: P,
+    },
+    /// A transaction is removed from the chain.
+    TxEvicted {
+        /// The txid of the transaction.
+        txid: Txid,
+        /// The position of the transaction before it was removed.
+        position: P,
+        /// Why the transaction was evicted, if it lost a conflict.
+        ///
+        /// This is `None` when the transaction was removed for other reasons (i.e. it expired or
+        /// the mempool was cleared).
+        eviction: Option<Eviction>,
+    },
+    /// An output of ours is spent by a transaction that is added to the chain.
+    UtxoSpent {
+        /// The outpoint that is spent.
+        outpoint: OutPoint,
+        /// The keychain of the spent output's script pubkey.
+        keychain: K,
+        /// The derivation index of the spent output's script pubkey.
+        index: u32,
+        /// The txid of the spending transaction.
+        spent_by: Txid,
+    },
+}
+
+/// Derives the [`WalletEvent`]s that applying `changeset` to `tracker` results in.
+///
+/// This must be called **before** the `changeset` is applied as events are derived by comparing the
+/// `changeset` against the current state of the `tracker`.
+///
+/// Events are ordered as follows: [`TipChanged`], [`AddressRevealed`] (per keychain in ascending
+/// index), then transaction events in txid order, and lastly [`UtxoSpent`].
+///
+/// [`TipChanged`]: WalletEvent::TipChanged
+/// [`AddressRevealed`]: WalletEvent::AddressRevealed
+/// [`UtxoSpent`]: WalletEvent::UtxoSpent
+pub fn wallet_events<K, P, T>(
+    tracker: &KeychainTracker<K, P, T>,
+    changeset: &KeychainChangeSet<K, P, T>,
+) -> Vec<WalletEvent<K, P>>
+where
+    K: Ord + Clone + core::fmt::Debug,
+    P: ChainPosition,
+    T: AsTransaction + Clone + Ord,
+{
+    let chain = tracker.chain();
+    let graph = tracker.graph();
+    let spk_index = tracker.txout_index.inner();
+    let chain_changeset = &changeset.chain_graph.chain;
+    let graph_additions = &changeset.chain_graph.graph;
+    let mut events = Vec::new();
+
+    // the resultant tip is the highest of the remaining original checkpoints and the checkpoints
+    // introduced by the changeset
+    let tip_before = chain.latest_checkpoint();
+    let tip_after = chain
+        .checkpoints()
+        .iter()
+        .rev()
+        .find(|(height, _)| !chain_changeset.checkpoints.contains_key(height))
+        .map(|(&height, &hash)| BlockId { height, hash })
+        .into_iter()
+        .chain(
+            chain_changeset
+                .checkpoints
+                .iter()
+                .rev()
+                .find_map(|(&height, hash)| {
+                    Some(BlockId {
+                        height,
+                        hash: (*hash)?,
+                    })
+                }),
+        )
+        .max_by_key(|block_id| block_id.height);
+    if tip_before != tip_after {
+        events.push(WalletEvent::TipChanged {
+            from: tip_before,
+            to: tip_after,
+        });
+    }
+
+    // script pubkeys are revealed by the derivation additions and by scanning the changeset
+    let mut reveal_targets = changeset.derivation_indices.as_inner().clone();
+    changeset.for_each_txout(|(_, txout)| {
+        if let Some((keychain, index)) = spk_index.index_of_spk(&txout.script_pubkey) {
+            let target = reveal_targets.entry(keychain.clone()).or_insert(*index);
+            *target = (*target).max(*index);
+        }
+    });
+    for (keychain, target) in reveal_targets {
+        let from = tracker
+            .txout_index
+            .last_revealed_index(&keychain)
+            .map_or(0, |index| index + 1);
+        events.extend((from..=target).map(|index| WalletEvent::AddressRevealed {
+            keychain: keychain.clone(),
+            index,
+        }));
+    }
+
+    let mut received = Vec::new();
+    for (&txid, new_pos) in &chain_changeset.txids {
+        match (chain.tx_position(txid), new_pos) {
+            (None, Some(new_pos)) => {
+                events.push(WalletEvent::TxReceived {
+                    txid,
+                    position: new_pos.clone(),
+                });
+                if new_pos.height().is_confirmed() {
+                    events.push(WalletEvent::TxConfirmed {
+                        txid,
+                        position: new_pos.clone(),
+                    });
+                }
+                received.push(txid);
+            }
+            (Some(old_pos), None) => events.push(WalletEvent::TxEvicted {
+                txid,
+                position: old_pos.clone(),
+                eviction: changeset.chain_graph.evicted.get(&txid).copied(),
+            }),
+            (Some(old_pos), Some(new_pos)) if old_pos != new_pos => {
+                if old_pos.height().is_confirmed() {
+                    events.push(WalletEvent::TxReorged {
+                        txid,
+                        from: old_pos.clone(),
+                    });
+                }
+                if new_pos.height().is_confirmed() {
+                    events.push(WalletEvent::TxConfirmed {
+                        txid,
+                        position: new_pos.clone(),
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let new_txouts = graph_additions.txouts().collect::<BTreeMap<_, _>>();
+    for txid in received {
+        let tx = match graph.get_tx(txid).or_else(|| {
+            graph_additions
+                .tx
+                .iter()
+                .find(|tx| tx.as_tx().txid() == txid)
+        }) {
+            Some(tx) => tx.as_tx(),
+            None => continue,
+        };
+        for txin in &tx.input {
+            let outpoint = txin.previous_output;
+            let txout = graph
+                .get_txout(outpoint)
+                .or_else(|| new_txouts.get(&outpoint).copied());
+            if let Some((keychain, index)) =
+                txout.and_then(|txout| spk_index.index_of_spk(&txout.script_pubkey))
+            {
+                events.push(WalletEvent::UtxoSpent {
+                    outpoint,
+                    keychain: keychain.clone(),
+                    index: *index,
+                    spent_by: txid,
+                });
+            }
+        }
+    }
+
+    events
+}
@@ -0,0 +1,180 @@
+use alloc::string::String;
+use bitcoin::{OutPoint, Txid};
+
+use crate::collections::BTreeMap;
+
+/// A reference to something that can be labelled.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LabelRef<K> {
+    /// The script pubkey at a derivation index of a keychain (an address).
+    Spk((K, u32)),
+    /// A transaction.
+    Tx(Txid),
+    /// A transaction output.
+    Output(OutPoint),
+}
+
+/// Represents changes to [`Labels`].
+///
+/// Each entry is either the new label (`Some`) or the removal of the label (`None`). It can be
+/// applied to [`Labels`] with [`Labels::apply_changeset`].
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(
+        crate = "serde_crate",
+        bound(
+            deserialize = "K: Ord + serde::Deserialize<'de>",
+            serialize = "K: Ord + serde::Serialize"
+        )
+    )
+)]
+#[must_use]
+pub struct LabelChangeSet<K> {
+    /// Changes to the labels of script pubkeys
+    pub spks: BTreeMap<(K, u32), Option<String>>,
+    /// Changes to the labels of transactions
+    pub txs: BTreeMap<Txid, Option<String>>,
+    /// Changes to the labels of transaction outputs
+    pub outputs: BTreeMap<OutPoint, Option<String>>,
+}
+
+impl<K> Default for LabelChangeSet<K> {
+    fn default() -> Self {
+        Self {
+            spks: Default::default(),
+            txs: Default::default(),
+            outputs: Default::default(),
+        }
+    }
+}
+
+impl<K> LabelChangeSet<K> {
+    /// Returns whether the changeset is empty.
+    pub fn is_empty(&self) -> bool {
+        self.spks.is_empty() && self.txs.is_empty() && self.outputs.is_empty()
+    }
+}
+
+impl<K: Ord> LabelChangeSet<K> {
+    /// Appends the changes in `other` into `self` (entries of `other` take precedence).
+    pub fn append(&mut self, mut other: Self) {
+        self.spks.append(&mut other.spks);
+        self.txs.append(&mut other.txs);
+        self.outputs.append(&mut other.outputs);
+    }
+
+    fn insert(&mut self, label_ref: LabelRef<K>, label: Option<String>) {
+        match label_ref {
+            LabelRef::Spk(index) => self.spks.insert(index, label),
+            LabelRef::Tx(txid) => self.txs.insert(txid, label),
+            LabelRef::Output(outpoint) => self.outputs.insert(outpoint, label),
+        };
+    }
+}
+
+/// Labels of script pubkeys, transactions and transaction outputs.
+///
+/// Changes are made by determining a [`LabelChangeSet`] with [`set_label_preview`] and applying it
+/// with [`apply_changeset`] so that labels can be persisted.
+///
+/// [`set_label_preview`]: Self::set_label_preview
+/// [`apply_changeset`]: Self::apply_changeset
+#[derive(Clone, Debug, PartialEq)]
+pub struct Labels<K> {
+    spks: BTreeMap<(K, u32), String>,
+    txs: BTreeMap<Txid, String>,
+    outputs: BTreeMap<OutPoint, String>,
+}
+
+impl<K> Default for Labels<K> {
+    fn default() -> Self {
+        Self {
+            spks: Default::default(),
+            txs: Default::default(),
+            outputs: Default::default(),
+        }
+    }
+}
+
+impl<K> Labels<K> {
+    /// Returns whether there are no labels.
+    pub fn is_empty(&self) -> bool {
+        self.spks.is_empty() && self.txs.is_empty() && self.outputs.is_empty()
+    }
+}
+
+impl<K: Ord + Clone> Labels<K> {
+    /// Get the label of `label_ref`.
+    pub fn get(&self, label_ref: &LabelRef<K>) -> Option<&str> {
+        match label_ref {
+            LabelRef::Spk(index) => self.spk_label(index),
+            LabelRef::Tx(txid) => self.tx_label(*txid),
+            LabelRef::Output(outpoint) => self.output_label(*outpoint),
+        }
+    }
+
+    /// Get the label of the script pubkey at `index`.
+    pub fn spk_label(&self, index: &(K, u32)) -> Option<&str> {
+        self.spks.get(index).map(String::as_str)
+    }
+
+    /// Get the label of the transaction of `txid`.
+    pub fn tx_label(&self, txid: Txid) -> Option<&str> {
+        self.txs.get(&txid).map(String::as_str)
+    }
+
+    /// Get the label of the transaction output at `outpoint`.
+    pub fn output_label(&self, outpoint: OutPoint) -> Option<&str> {
+        self.outputs.get(&outpoint).map(String::as_str)
+    }
+
+    /// Iterate over all labels.
+    pub fn iter(&self) -> impl Iterator<Item = (LabelRef<K>, &str)> + '_ {
+        let spks = self
+            .spks
+            .iter()
+            .map(|(index, label)| (LabelRef::Spk(index.clone()), label.as_str()));
+        let txs = self
+            .txs
+            .iter()
+            .map(|(txid, label)| (LabelRef::Tx(*txid), label.as_str()));
+        let outputs = self
+            .outputs
+            .iter()
+            .map(|(outpoint, label)| (LabelRef::Output(*outpoint), label.as_str()));
+        spks.chain(txs).chain(outputs)
+    }
+
+    /// Determines the changes as result of setting the label of `label_ref` to `label` (or removing
+    /// it if `label` is `None`).
+    ///
+    /// The returned changeset is empty if the label is unchanged.
+    pub fn set_label_preview(
+        &self,
+        label_ref: LabelRef<K>,
+        label: Option<String>,
+    ) -> LabelChangeSet<K> {
+        let mut changeset = LabelChangeSet::default();
+        if self.get(&label_ref) != label.as_deref() {
+            changeset.insert(label_ref, label);
+        }
+        changeset
+    }
+
+    /// Applies a [`LabelChangeSet`].
+    pub fn apply_changeset(&mut self, changeset: LabelChangeSet<K>) {
+        fn apply<I: Ord>(labels: &mut BTreeMap<I, String>, changes: BTreeMap<I, Option<String>>) {
+            for (key, label) in changes {
+                match label {
+                    Some(label) => labels.insert(key, label),
+                    None => labels.remove(&key),
+                };
+            }
+        }
+        apply(&mut self.spks, changeset.spks);
+        apply(&mut self.txs, changeset.txs);
+        apply(&mut self.outputs, changeset.outputs);
+    }
+}
@@ -0,0 +1,307 @@
+use bitcoin::Transaction;
+
+use crate::{
+    chain_graph::{self, ChainGraph},
+    collections::*,
+    keychain::{DerivationAdditions, KeychainScan, KeychainTxOutIndex, KeychainView},
+    sparse_chain::{self, SparseChain},
+    tx_graph::TxGraph,
+    AsTransaction, BlockId, ForEachTxOut, IntoOwned,
+};
+
+/// Many [`KeychainTxOutIndex`]es (one per tenant) sharing a single [`ChainGraph`].
+///
+/// This is for applications that track many wallets over the same blockchain data. Checkpoints and
+/// transactions are stored once in the shared [`ChainGraph`] and each tenant has its own
+/// [`KeychainTxOutIndex`]. Per-tenant queries (UTXOs, balances and history) are made with the
+/// tenant's [`KeychainView`] (refer to [`view`]).
+///
+/// Updates are [`KeychainScan`]s where the keychains are `(tenant, keychain)` pairs. Changes are
+/// reported as [`MultiKeychainChangeSet`]s.
+///
+/// [`view`]: Self::view
+#[derive(Clone, Debug)]
+pub struct MultiKeychainTracker<Tn, K, P, T = Transaction> {
+    tenants: BTreeMap<Tn, KeychainTxOutIndex<K>>,
+    chain_graph: ChainGraph<P, T>,
+}
+
+impl<Tn, K, P, T> Default for MultiKeychainTracker<Tn, K, P, T> {
+    fn default() -> Self {
+        Self {
+            tenants: Default::default(),
+            chain_graph: Default::default(),
+        }
+    }
+}
+
+impl<Tn, K, P, T> MultiKeychainTracker<Tn, K, P, T>
+where
+    Tn: Ord + Clone + core::fmt::Debug,
+    K: Ord + Clone + core::fmt::Debug,
+    P: sparse_chain::ChainPosition,
+    T: AsTransaction + Clone + Ord,
+{
+    /// Add a tenant with its `txout_index` (which should already have its keychains added).
+    ///
+    /// The shared [`ChainGraph`] is scanned into the `txout_index` so the tenant's view includes
+    /// existing chain data. If the tenant already exists, its old [`KeychainTxOutIndex`] is
+    /// replaced and returned.
+    pub fn add_tenant(
+        &mut self,
+        tenant: Tn,
+        mut txout_index: KeychainTxOutIndex<K>,
+    ) -> Option<KeychainTxOutIndex<K>> {
+        let _ = txout_index.scan(&self.chain_graph);
+        self.tenants.insert(tenant, txout_index)
+    }
+
+    /// Remove a tenant, returning its [`KeychainTxOutIndex`].
+    pub fn remove_tenant(&mut self, tenant: &Tn) -> Option<KeychainTxOutIndex<K>> {
+        self.tenants.remove(tenant)
+    }
+
+    /// Returns the map of tenants to their [`KeychainTxOutIndex`].
+    pub fn tenants(&self) -> &BTreeMap<Tn, KeychainTxOutIndex<K>> {
+        &self.tenants
+    }
+
+    /// Returns a mutable reference to the [`KeychainTxOutIndex`] of `tenant` (i.e. to reveal script
+    /// pubkeys).
+    ///
+    /// The [`DerivationAdditions`] returned by the index should be recorded in a
+    /// [`MultiKeychainChangeSet`] under the tenant if they need to be persisted.
+    pub fn txout_index_mut(&mut self, tenant: &Tn) -> Option<&mut KeychainTxOutIndex<K>> {
+        self.tenants.get_mut(tenant)
+    }
+
+    /// Returns a [`KeychainView`] of the shared chain data that is relevant to `tenant`.
+    pub fn view(&self, tenant: &Tn) -> Option<KeychainView<'_, K, P, T>> {
+        let txout_index = self.tenants.get(tenant)?;
+        Some(KeychainView::new(txout_index, &self.chain_graph))
+    }
+
+    /// Returns a reference to the shared [`ChainGraph`].
+    pub fn chain_graph(&self) -> &ChainGraph<P, T> {
+        &self.chain_graph
+    }
+
+    /// Returns a reference to the shared [`TxGraph`] (which is part of the [`ChainGraph`]).
+    pub fn graph(&self) -> &TxGraph<T> {
+        self.chain_graph.graph()
+    }
+
+    /// Returns a reference to the shared [`SparseChain`] (which is part of the [`ChainGraph`]).
+    pub fn chain(&self) -> &SparseChain<P> {
+        self.chain_graph.chain()
+    }
+
+    /// Determines the resultant [`MultiKeychainChangeSet`] if the given [`KeychainScan`] is
+    /// applied.
+    ///
+    /// The keychains of the `scan` are `(tenant, keychain)` pairs. Last active indices of unknown
+    /// tenants are ignored.
+    pub fn determine_changeset<T2>(
+        &self,
+        scan: &KeychainScan<(Tn, K), P, T2>,
+    ) -> Result<MultiKeychainChangeSet<Tn, K, P, T>, chain_graph::UpdateError<P>>
+    where
+        T2: IntoOwned<T> + Clone,
+    {
+        let mut derivation_indices = BTreeMap::<Tn, BTreeMap<K, u32>>::new();
+        for ((tenant, keychain), &index) in &scan.last_active_indices {
+            let txout_index = match self.tenants.get(tenant) {
+                Some(txout_index) => txout_index,
+                None => continue,
+            };
+            if txout_index
+                .last_revealed_index(keychain)
+                .is_none_or(|existing| index > existing)
+            {
+                derivation_indices
+                    .entry(tenant.clone())
+                    .or_default()
+                    .insert(keychain.clone(), index);
+            }
+        }
+
+        Ok(MultiKeychainChangeSet {
+            derivation_indices: derivation_indices
+                .into_iter()
+                .map(|(tenant, indices)| (tenant, DerivationAdditions(indices)))
+                .collect(),
+            chain_graph: self.chain_graph.determine_changeset(&scan.update)?,
+        })
+    }
+
+    /// Directly applies a [`KeychainScan`] on [`MultiKeychainTracker`].
+    ///
+    /// This is equivalent to calling [`determine_changeset`] and [`apply_changeset`] in sequence.
+    ///
+    /// [`determine_changeset`]: Self::determine_changeset
+    /// [`apply_changeset`]: Self::apply_changeset
+    pub fn apply_update<T2>(
+        &mut self,
+        scan: KeychainScan<(Tn, K), P, T2>,
+    ) -> Result<MultiKeychainChangeSet<Tn, K, P, T>, chain_graph::UpdateError<P>>
+    where
+        T2: IntoOwned<T> + Clone,
+    {
+        let changeset = self.determine_changeset(&scan)?;
+        self.apply_changeset(changeset.clone());
+        Ok(changeset)
+    }
+
+    /// Applies the changes in `changeset` to [`MultiKeychainTracker`].
+    ///
+    /// The derivation additions of each tenant are applied to the tenant's [`KeychainTxOutIndex`]
+    /// (additions of unknown tenants are ignored) and the chain data is scanned into every tenant's
+    /// index before being applied to the shared [`ChainGraph`].
+    pub fn apply_changeset(&mut self, changeset: MultiKeychainChangeSet<Tn, K, P, T>) {
+        let MultiKeychainChangeSet {
+            derivation_indices,
+            chain_graph,
+        } = changeset;
+        for (tenant, additions) in derivation_indices {
+            if let Some(txout_index) = self.tenants.get_mut(&tenant) {
+                txout_index.apply_additions(additions);
+            }
+        }
+        for txout_index in self.tenants.values_mut() {
+            let _ = txout_index.scan(&chain_graph);
+        }
+        self.chain_graph.apply_changeset(chain_graph)
+    }
+
+    /// Determines the changes as result of inserting `block_id` (a height and block hash) into the
+    /// shared [`ChainGraph`].
+    ///
+    /// Refer to [`ChainGraph::insert_checkpoint_preview`] for more.
+    pub fn insert_checkpoint_preview(
+        &self,
+        block_id: BlockId,
+    ) -> Result<MultiKeychainChangeSet<Tn, K, P, T>, chain_graph::InsertCheckpointError> {
+        Ok(self.chain_graph.insert_checkpoint_preview(block_id)?.into())
+    }
+
+    /// Directly insert a `block_id` into the shared [`ChainGraph`].
+    ///
+    /// This is equivalent of calling [`insert_checkpoint_preview`] and [`apply_changeset`] in
+    /// sequence.
+    ///
+    /// [`insert_checkpoint_preview`]: Self::insert_checkpoint_preview
+    /// [`apply_changeset`]: Self::apply_changeset
+    pub fn insert_checkpoint(
+        &mut self,
+        block_id: BlockId,
+    ) -> Result<MultiKeychainChangeSet<Tn, K, P, T>, chain_graph::InsertCheckpointError> {
+        let changeset = self.insert_checkpoint_preview(block_id)?;
+        self.apply_changeset(changeset.clone());
+        Ok(changeset)
+    }
+
+    /// Determines the changes as result of inserting a transaction into the shared [`ChainGraph`]
+    /// at `pos`.
+    ///
+    /// Refer to [`ChainGraph::insert_tx_preview`] for more.
+    pub fn insert_tx_preview(
+        &self,
+        tx: T,
+        pos: P,
+    ) -> Result<MultiKeychainChangeSet<Tn, K, P, T>, chain_graph::InsertTxError<P>> {
+        Ok(self.chain_graph.insert_tx_preview(tx, pos)?.into())
+    }
+
+    /// Directly insert a transaction into the shared [`ChainGraph`] at `pos`.
+    ///
+    /// This is equivalent of calling [`insert_tx_preview`] and [`apply_changeset`] in sequence.
+    ///
+    /// [`insert_tx_preview`]: Self::insert_tx_preview
+    /// [`apply_changeset`]: Self::apply_changeset
+    pub fn insert_tx(
+        &mut self,
+        tx: T,
+        pos: P,
+    ) -> Result<MultiKeychainChangeSet<Tn, K, P, T>, chain_graph::InsertTxError<P>> {
+        let changeset = self.insert_tx_preview(tx, pos)?;
+        self.apply_changeset(changeset.clone());
+        Ok(changeset)
+    }
+}
+
+/// Represents changes to a [`MultiKeychainTracker`].
+///
+/// The `chain_graph` changes are shared by all tenants while `derivation_indices` are recorded per
+/// tenant, so each can be persisted separately.
+#[derive(Clone, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(
+        crate = "serde_crate",
+        bound(
+            deserialize = "Tn: Ord + serde::Deserialize<'de>, K: Ord + serde::Deserialize<'de>, P: serde::Deserialize<'de>, T: Ord + serde::Deserialize<'de>",
+            serialize = "Tn: Ord + serde::Serialize, K: Ord + serde::Serialize, P: serde::Serialize, T: Ord + serde::Serialize"
+        )
+    )
+)]
+#[must_use]
+pub struct MultiKeychainChangeSet<Tn, K, P, T = Transaction> {
+    /// The changes in derivation indices of each tenant
+    pub derivation_indices: BTreeMap<Tn, DerivationAdditions<K>>,
+    /// The changes that have occurred in the shared blockchain data
+    pub chain_graph: chain_graph::ChangeSet<P, T>,
+}
+
+impl<Tn, K, P, T> Default for MultiKeychainChangeSet<Tn, K, P, T> {
+    fn default() -> Self {
+        Self {
+            derivation_indices: Default::default(),
+            chain_graph: Default::default(),
+        }
+    }
+}
+
+impl<Tn, K, P, T> MultiKeychainChangeSet<Tn, K, P, T> {
+    /// Returns whether the [`MultiKeychainChangeSet`] is empty (no changes recorded).
+    pub fn is_empty(&self) -> bool {
+        self.chain_graph.is_empty()
+            && self
+                .derivation_indices
+                .values()
+                .all(DerivationAdditions::is_empty)
+    }
+
+    /// Appends the changes in `other` into `self` such that applying `self` afterwards has the same
+    /// effect as sequentially applying the original `self` and `other`.
+    pub fn append(&mut self, other: MultiKeychainChangeSet<Tn, K, P, T>)
+    where
+        Tn: Ord,
+        K: Ord,
+        P: sparse_chain::ChainPosition,
+        T: Ord,
+    {
+        for (tenant, additions) in other.derivation_indices {
+            self.derivation_indices
+                .entry(tenant)
+                .or_default()
+                .append(additions);
+        }
+        self.chain_graph.append(other.chain_graph);
+    }
+}
+
+impl<Tn, K, P, T> From<chain_graph::ChangeSet<P, T>> for MultiKeychainChangeSet<Tn, K, P, T> {
+    fn from(changeset: chain_graph::ChangeSet<P, T>) -> Self {
+        Self {
+            chain_graph: changeset,
+            ..Default::default()
+        }
+    }
+}
+
+impl<Tn, K, P, T: AsTransaction> ForEachTxOut for MultiKeychainChangeSet<Tn, K, P, T> {
+    fn for_each_txout(&self, f: impl FnMut((bitcoin::OutPoint, &bitcoin::TxOut))) {
+        self.chain_graph.for_each_txout(f)
+    }
+}
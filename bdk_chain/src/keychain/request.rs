@@ -0,0 +1,429 @@
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use bitcoin::{BlockHash, OutPoint, Script, Txid};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{
+    collections::{BTreeMap, BTreeSet},
+    Birthday, BlockId,
+};
+
+/// Data required by a chain source to perform a full scan of keychains.
+///
+/// A full scan discovers the transaction histories of each keychain by deriving script pubkeys
+/// until a gap of unused script pubkeys is found. This is needed when restoring a wallet (when we
+/// do not know which script pubkeys have been used).
+///
+/// Chain sources (such as `bdk_esplora` and `bdk_electrum`) take this and return an update that
+/// can be applied to a [`KeychainTracker`]. It can be built from a tracker with
+/// [`KeychainTracker::full_scan_request`].
+///
+/// [`KeychainTracker`]: crate::keychain::KeychainTracker
+/// [`KeychainTracker::full_scan_request`]: crate::keychain::KeychainTracker::full_scan_request
+#[derive(Debug, Clone)]
+pub struct FullScanRequest<K, I> {
+    /// The most recent block hashes present locally
+    pub local_chain: BTreeMap<u32, BlockHash>,
+    /// Unbounded iterators of `(derivation index, script pubkey)` of each keychain
+    pub spks_by_keychain: BTreeMap<K, I>,
+    /// Script pubkeys outside of the keychains that we want the transaction histories of
+    ///
+    /// Their whole histories are scanned as they are not bound by the `birthday` of the keychains.
+    pub spks: Vec<Script>,
+    /// Outpoints that we want the residing and spending transactions of
+    pub outpoints: Vec<OutPoint>,
+    /// Keychain transactions confirmed before this are left out of the update
+    pub birthday: Option<Birthday>,
+    /// The progress of a previous full scan that did not finish
+    pub progress: ScanProgress<K>,
+}
+
+impl<K, I> FullScanRequest<K, I> {
+    /// Create a request to scan the given keychains on top of `local_chain`.
+    pub fn new(local_chain: BTreeMap<u32, BlockHash>, spks_by_keychain: BTreeMap<K, I>) -> Self {
+        Self {
+            local_chain,
+            spks_by_keychain,
+            spks: Vec::new(),
+            outpoints: Vec::new(),
+            birthday: None,
+            progress: ScanProgress::default(),
+        }
+    }
+
+    /// Add `spks` (that are not in a keychain) to the request.
+    pub fn with_spks(mut self, spks: impl IntoIterator<Item = Script>) -> Self {
+        self.spks.extend(spks);
+        self
+    }
+
+    /// Add `outpoints` to the request.
+    pub fn with_outpoints(mut self, outpoints: impl IntoIterator<Item = OutPoint>) -> Self {
+        self.outpoints.extend(outpoints);
+        self
+    }
+
+    /// Set the [`Birthday`] of the request.
+    pub fn with_birthday(mut self, birthday: Option<Birthday>) -> Self {
+        self.birthday = birthday;
+        self
+    }
+
+    /// Set the [`ScanProgress`] of a previous full scan to resume from.
+    pub fn with_progress(mut self, progress: ScanProgress<K>) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Transform the script pubkey iterator of each keychain with `f`.
+    ///
+    /// This is useful for inspecting the script pubkeys as they are scanned.
+    pub fn map_spks<I2>(self, mut f: impl FnMut(&K, I) -> I2) -> FullScanRequest<K, I2>
+    where
+        K: Ord,
+    {
+        FullScanRequest {
+            local_chain: self.local_chain,
+            spks_by_keychain: self
+                .spks_by_keychain
+                .into_iter()
+                .map(|(keychain, spks)| {
+                    let spks = f(&keychain, spks);
+                    (keychain, spks)
+                })
+                .collect(),
+            spks: self.spks,
+            outpoints: self.outpoints,
+            birthday: self.birthday,
+            progress: self.progress,
+        }
+    }
+}
+
+/// The progress of a full scan that did not finish.
+///
+/// Chain sources report this with the partial update of an interrupted full scan. Once applied to a
+/// [`KeychainTracker`] it is persisted and included in the next [`FullScanRequest`] so that the
+/// scan resumes where it stopped. Progress is only resumed if the block at `tip` is still in the
+/// best chain, otherwise the scan starts over.
+///
+/// An empty progress means that there is no full scan to resume.
+///
+/// [`KeychainTracker`]: crate::keychain::KeychainTracker
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(
+        crate = "serde_crate",
+        bound(
+            deserialize = "K: Ord + serde::Deserialize<'de>",
+            serialize = "K: Ord + serde::Serialize"
+        )
+    )
+)]
+pub struct ScanProgress<K> {
+    /// The chain tip that the script pubkeys were scanned at
+    pub tip: BlockId,
+    /// The last scanned derivation index of each keychain
+    pub last_scanned_indices: BTreeMap<K, u32>,
+    /// Keychains that were scanned until the stop gap was reached
+    pub finished_keychains: BTreeSet<K>,
+}
+
+impl<K> Default for ScanProgress<K> {
+    fn default() -> Self {
+        Self {
+            tip: Default::default(),
+            last_scanned_indices: Default::default(),
+            finished_keychains: Default::default(),
+        }
+    }
+}
+
+impl<K> ScanProgress<K> {
+    /// Whether there is nothing to resume.
+    pub fn is_empty(&self) -> bool {
+        self.last_scanned_indices.is_empty() && self.finished_keychains.is_empty()
+    }
+}
+
+impl<K: Ord + Clone> ScanProgress<K> {
+    /// Create an empty progress of a scan at `tip`.
+    pub fn new(tip: BlockId) -> Self {
+        Self {
+            tip,
+            ..Default::default()
+        }
+    }
+
+    /// Record that the script pubkey at `index` of `keychain` is scanned.
+    pub fn record_scanned(&mut self, keychain: &K, index: u32) {
+        self.last_scanned_indices.insert(keychain.clone(), index);
+    }
+
+    /// Record that `keychain` was scanned until the stop gap was reached.
+    pub fn record_finished(&mut self, keychain: K) {
+        self.finished_keychains.insert(keychain);
+    }
+
+    /// Merge the progress of a scan that resumed from `self` into `self`.
+    pub fn merge(&mut self, resumed: Self) {
+        self.tip = resumed.tip;
+        self.last_scanned_indices
+            .extend(resumed.last_scanned_indices);
+        self.finished_keychains.extend(resumed.finished_keychains);
+    }
+
+    /// Leave out what has already been scanned from the script pubkey iterators of
+    /// `spks_by_keychain`.
+    ///
+    /// Finished keychains are removed and the script pubkeys of other keychains up to (and
+    /// including) the last scanned index are skipped.
+    pub fn resume<I>(
+        &self,
+        spks_by_keychain: BTreeMap<K, I>,
+    ) -> BTreeMap<K, impl Iterator<Item = (u32, Script)>>
+    where
+        I: IntoIterator<Item = (u32, Script)>,
+    {
+        spks_by_keychain
+            .into_iter()
+            .filter(|(keychain, _)| !self.finished_keychains.contains(keychain))
+            .map(|(keychain, spks)| {
+                let last_scanned = self.last_scanned_indices.get(&keychain).copied();
+                let spks = spks
+                    .into_iter()
+                    .skip_while(move |(index, _)| last_scanned.is_some_and(|last| *index <= last));
+                (keychain, spks)
+            })
+            .collect()
+    }
+}
+
+/// Data required by a chain source to sync the state of things we already know about.
+///
+/// Unlike a [`FullScanRequest`], a sync does not derive new script pubkeys. Only the given script
+/// pubkeys, transactions and outpoints are checked for changes.
+///
+/// It can be built from a tracker with [`KeychainTracker::sync_request`].
+///
+/// [`KeychainTracker::sync_request`]: crate::keychain::KeychainTracker::sync_request
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SyncRequest {
+    /// The most recent block hashes present locally
+    pub local_chain: BTreeMap<u32, BlockHash>,
+    /// Script pubkeys that we want the transaction histories of
+    pub spks: Vec<Script>,
+    /// Transactions that we want updated chain positions for
+    pub txids: Vec<Txid>,
+    /// Outpoints that we want the residing and spending transactions of
+    pub outpoints: Vec<OutPoint>,
+}
+
+impl SyncRequest {
+    /// Create an empty request on top of `local_chain`.
+    pub fn new(local_chain: BTreeMap<u32, BlockHash>) -> Self {
+        Self {
+            local_chain,
+            ..Default::default()
+        }
+    }
+
+    /// Add `spks` to the request.
+    pub fn with_spks(mut self, spks: impl IntoIterator<Item = Script>) -> Self {
+        self.spks.extend(spks);
+        self
+    }
+
+    /// Add `txids` to the request.
+    pub fn with_txids(mut self, txids: impl IntoIterator<Item = Txid>) -> Self {
+        self.txids.extend(txids);
+        self
+    }
+
+    /// Add `outpoints` to the request.
+    pub fn with_outpoints(mut self, outpoints: impl IntoIterator<Item = OutPoint>) -> Self {
+        self.outpoints.extend(outpoints);
+        self
+    }
+
+    /// Whether there is nothing to sync.
+    pub fn is_empty(&self) -> bool {
+        self.spks.is_empty() && self.txids.is_empty() && self.outpoints.is_empty()
+    }
+}
+
+/// Keeps count of consecutive unused script pubkeys while a keychain is scanned.
+///
+/// Chain sources use this to decide when to stop deriving script pubkeys of a keychain during a
+/// full scan. `I` is the index of the script pubkeys (usually the derivation index).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StopGap<I = u32> {
+    stop_gap: usize,
+    unused_count: usize,
+    last_active_index: Option<I>,
+}
+
+impl<I: Clone> StopGap<I> {
+    /// Create a counter that is reached after `stop_gap` consecutive unused script pubkeys.
+    pub fn new(stop_gap: usize) -> Self {
+        Self {
+            stop_gap,
+            unused_count: 0,
+            last_active_index: None,
+        }
+    }
+
+    /// Record whether the script pubkey at `index` has a transaction history.
+    ///
+    /// Script pubkeys must be recorded in derivation order. Returns whether the stop gap is
+    /// reached.
+    pub fn record(&mut self, index: I, is_used: bool) -> bool {
+        if is_used {
+            self.unused_count = 0;
+            self.last_active_index = Some(index);
+        } else {
+            self.unused_count += 1;
+        }
+        self.is_reached()
+    }
+
+    /// Whether the stop gap is reached.
+    pub fn is_reached(&self) -> bool {
+        self.unused_count >= self.stop_gap
+    }
+
+    /// The last recorded index of a script pubkey with a transaction history.
+    pub fn last_active_index(&self) -> Option<I> {
+        self.last_active_index.clone()
+    }
+}
+
+/// Something that was checked by a chain source during a scan.
+///
+/// Chain sources pass these to an inspection callback so that the progress of a scan can be
+/// reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScanItem<'a, K> {
+    /// The script pubkey at `index` of `keychain` was scanned and the transactions of `txids` were
+    /// found in its history.
+    Spk {
+        /// The keychain of the script pubkey
+        keychain: &'a K,
+        /// The derivation index of the script pubkey
+        index: u32,
+        /// The script pubkey
+        spk: &'a Script,
+        /// The transactions found in the history of the script pubkey
+        txids: &'a [Txid],
+    },
+    /// A script pubkey outside of the keychains was scanned and the transactions of `txids` were
+    /// found in its history.
+    MiscSpk {
+        /// The script pubkey
+        spk: &'a Script,
+        /// The transactions found in the history of the script pubkey
+        txids: &'a [Txid],
+    },
+    /// The chain position of a transaction was checked.
+    Txid(Txid),
+    /// The residing and spending transactions of an outpoint were checked.
+    OutPoint(OutPoint),
+}
+
+/// A token that can cancel a scan from another thread.
+///
+/// Clones of the token share the same state so a clone can be handed to the scan while the
+/// original is kept to [`cancel`] it.
+///
+/// [`cancel`]: Self::cancel
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    /// Create a token that is not cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancel the scans that this token (or a clone of it) was given to.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether the token is cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Error that may occur when a chain source scans.
+///
+/// `U` is the update type of the chain source and `E` is the error type of the chain source.
+#[derive(Debug)]
+pub enum ScanError<U, E> {
+    /// The chain source returned an error.
+    Source(E),
+    /// The scan was cancelled with a [`CancelToken`].
+    ///
+    /// This contains the update of what was scanned before the cancellation. It can be applied
+    /// but it is incomplete. The update is boxed to keep the error small.
+    Cancelled(Box<U>),
+    /// The chain source returned an error after part of the scan was done.
+    ///
+    /// Like [`ScanError::Cancelled`], `partial` is the incomplete update of what was scanned
+    /// before the error.
+    Interrupted {
+        /// The error of the chain source
+        error: E,
+        /// The update of what was scanned before the error
+        partial: Box<U>,
+    },
+}
+
+impl<U, E> ScanError<U, E> {
+    /// Maps the partial update of a cancelled or interrupted scan with `f`.
+    pub fn map_partial<U2>(self, f: impl FnOnce(U) -> U2) -> ScanError<U2, E> {
+        match self {
+            ScanError::Source(err) => ScanError::Source(err),
+            ScanError::Cancelled(partial) => ScanError::Cancelled(Box::new(f(*partial))),
+            ScanError::Interrupted { error, partial } => ScanError::Interrupted {
+                error,
+                partial: Box::new(f(*partial)),
+            },
+        }
+    }
+
+    /// Maps the error of the chain source with `f`.
+    pub fn map_source<E2>(self, f: impl FnOnce(E) -> E2) -> ScanError<U, E2> {
+        match self {
+            ScanError::Source(err) => ScanError::Source(f(err)),
+            ScanError::Cancelled(partial) => ScanError::Cancelled(partial),
+            ScanError::Interrupted { error, partial } => ScanError::Interrupted {
+                error: f(error),
+                partial,
+            },
+        }
+    }
+}
+
+impl<U, E> From<E> for ScanError<U, E> {
+    fn from(err: E) -> Self {
+        Self::Source(err)
+    }
+}
+
+impl<U, E: core::fmt::Display> core::fmt::Display for ScanError<U, E> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ScanError::Source(err) => core::fmt::Display::fmt(err, f),
+            ScanError::Cancelled(_) => write!(f, "the scan was cancelled"),
+            ScanError::Interrupted { error, .. } => {
+                write!(f, "the scan was interrupted: {}", error)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<U: core::fmt::Debug, E: std::error::Error> std::error::Error for ScanError<U, E> {}
@@ -1,16 +1,21 @@
-use bitcoin::Transaction;
+use alloc::{string::String, vec::Vec};
+use bitcoin::{OutPoint, Script, Transaction, Txid};
+use core::ops::RangeBounds;
 use miniscript::{Descriptor, DescriptorPublicKey};
 
 use crate::{
-    chain_graph::{self, ChainGraph},
+    chain_graph::{self, ChainGraph, ConflictPolicy},
     collections::*,
     keychain::{KeychainChangeSet, KeychainScan, KeychainTxOutIndex},
-    sparse_chain::{self, SparseChain},
+    sparse_chain::{self, CheckpointRetention, SparseChain},
     tx_graph::TxGraph,
     AsTransaction, BlockId, FullTxOut, IntoOwned, TxHeight,
 };
 
-use super::{Balance, DerivationAdditions};
+use super::{
+    Balance, DerivationAdditions, FullScanRequest, KeychainView, LabelRef, Labels, ScanProgress,
+    SyncRequest, WalletTx,
+};
 
 /// A convenient combination of a [`KeychainTxOutIndex`] and a [`ChainGraph`].
 ///
@@ -21,6 +26,13 @@ pub struct KeychainTracker<K, P, T = Transaction> {
     /// Index between script pubkeys to transaction outputs
     pub txout_index: KeychainTxOutIndex<K>,
     chain_graph: ChainGraph<P, T>,
+    finality_depth: Option<u32>,
+    locked: BTreeMap<OutPoint, u64>,
+    frozen: BTreeSet<OutPoint>,
+    labels: Labels<K>,
+    watched_spks: BTreeSet<Script>,
+    watched_outpoints: BTreeSet<OutPoint>,
+    scan_progress: ScanProgress<K>,
 }
 
 impl<K, P, T> KeychainTracker<K, P, T>
@@ -59,6 +71,105 @@ where
         self.chain_graph.set_checkpoint_limit(limit)
     }
 
+    /// Get the finality depth.
+    ///
+    /// Refer to [`set_finality_depth`] for more.
+    ///
+    /// [`set_finality_depth`]: Self::set_finality_depth
+    pub fn finality_depth(&self) -> Option<u32> {
+        self.finality_depth
+    }
+
+    /// Set the finality depth: the number of confirmations after which a transaction is considered
+    /// safe from reorgs.
+    ///
+    /// When set, [`balance`] accounts confirmed outputs that have not reached the finality depth
+    /// as [`Balance::shallow`].
+    ///
+    /// [`balance`]: Self::balance
+    pub fn set_finality_depth(&mut self, finality_depth: Option<u32>) {
+        self.finality_depth = finality_depth;
+    }
+
+    /// Returns the number of confirmations of a transaction.
+    ///
+    /// Refer to [`SparseChain::confirmations`] for more.
+    pub fn confirmations(&self, txid: Txid) -> Option<u32> {
+        self.chain_graph.confirmations(txid)
+    }
+
+    /// Returns whether the transaction has reached the finality depth. If no finality depth is
+    /// set, every confirmed transaction is considered final.
+    pub fn is_final(&self, txid: Txid) -> bool {
+        self.view().is_final(txid)
+    }
+
+    /// Get the checkpoint retention policy of the internal [`SparseChain`].
+    ///
+    /// Refer to [`SparseChain::checkpoint_retention`] for more.
+    pub fn checkpoint_retention(&self) -> Option<CheckpointRetention> {
+        self.chain_graph.checkpoint_retention()
+    }
+
+    /// Set the checkpoint retention policy of the internal [`SparseChain`].
+    ///
+    /// Refer to [`SparseChain::set_checkpoint_retention`] for more.
+    pub fn set_checkpoint_retention(&mut self, retention: Option<CheckpointRetention>) {
+        self.chain_graph.set_checkpoint_retention(retention)
+    }
+
+    /// Determines the changes as result of pruning checkpoints according to the checkpoint
+    /// retention policy.
+    ///
+    /// Refer to [`SparseChain::prune_checkpoints_preview`] for more.
+    pub fn prune_checkpoints_preview(&self) -> KeychainChangeSet<K, P, T> {
+        KeychainChangeSet {
+            chain_graph: self.chain_graph.prune_checkpoints_preview(),
+            ..Default::default()
+        }
+    }
+
+    /// Directly prune checkpoints according to the checkpoint retention policy.
+    ///
+    /// This is equivalent of calling [`prune_checkpoints_preview`] and [`apply_changeset`] in
+    /// sequence.
+    ///
+    /// [`prune_checkpoints_preview`]: Self::prune_checkpoints_preview
+    /// [`apply_changeset`]: Self::apply_changeset
+    pub fn prune_checkpoints(&mut self) -> KeychainChangeSet<K, P, T> {
+        let changeset = self.prune_checkpoints_preview();
+        self.apply_changeset(changeset.clone());
+        changeset
+    }
+
+    /// Get the unconfirmed transaction expiry of the internal [`SparseChain`].
+    ///
+    /// Refer to [`SparseChain::unconfirmed_expiry`] for more.
+    pub fn unconfirmed_expiry(&self) -> u64 {
+        self.chain_graph.unconfirmed_expiry()
+    }
+
+    /// Set the unconfirmed transaction expiry of the internal [`SparseChain`].
+    ///
+    /// Refer to [`SparseChain::set_unconfirmed_expiry`] for more.
+    pub fn set_unconfirmed_expiry(&mut self, expiry: u64) {
+        self.chain_graph.set_unconfirmed_expiry(expiry)
+    }
+
+    /// Get the conflict policy of the internal [`ChainGraph`].
+    ///
+    /// Refer to [`ChainGraph::conflict_policy`] for more.
+    pub fn conflict_policy(&self) -> &ConflictPolicy {
+        self.chain_graph.conflict_policy()
+    }
+
+    /// Set the conflict policy of the internal [`ChainGraph`].
+    ///
+    /// Refer to [`ChainGraph::set_conflict_policy`] for more.
+    pub fn set_conflict_policy(&mut self, policy: ConflictPolicy) {
+        self.chain_graph.set_conflict_policy(policy)
+    }
+
     /// Determines the resultant [`KeychainChangeSet`] if the given [`KeychainScan`] is applied.
     ///
     /// Internally, we call [`ChainGraph::determine_changeset`] and also determine the additions of
@@ -82,6 +193,11 @@ where
         Ok(KeychainChangeSet {
             derivation_indices: DerivationAdditions(derivation_indices),
             chain_graph: self.chain_graph.determine_changeset(&scan.update)?,
+            scan_progress: scan
+                .progress
+                .clone()
+                .filter(|progress| *progress != self.scan_progress),
+            ..Default::default()
         })
     }
 
@@ -111,8 +227,20 @@ where
         let KeychainChangeSet {
             derivation_indices,
             chain_graph,
+            frozen,
+            labels,
+            watched_spks,
+            watched_outpoints,
+            scan_progress,
         } = changeset;
         self.txout_index.apply_additions(derivation_indices);
+        apply_set_changes(&mut self.frozen, frozen);
+        self.labels.apply_changeset(labels);
+        apply_set_changes(&mut self.watched_spks, watched_spks);
+        apply_set_changes(&mut self.watched_outpoints, watched_outpoints);
+        if let Some(scan_progress) = scan_progress {
+            self.scan_progress = scan_progress;
+        }
         let _ = self.txout_index.scan(&chain_graph);
         self.chain_graph.apply_changeset(chain_graph)
     }
@@ -123,19 +251,469 @@ where
     /// In other words, these are `txout`s of confirmed and in-mempool transactions, based on our
     /// view of the blockchain/mempool.
     pub fn full_txouts(&self) -> impl Iterator<Item = (&(K, u32), FullTxOut<P>)> + '_ {
-        self.txout_index
-            .txouts()
-            .filter_map(|(spk_i, op, _)| Some((spk_i, self.chain_graph.full_txout(op)?)))
+        self.view().full_txouts()
     }
 
     /// Iterates through [`FullTxOut`]s that are unspent outputs.
     ///
-    /// Refer to [`full_txouts`] for more.
+    /// This includes locked and frozen outputs; use [`spendable_utxos`] to leave them out. Refer to
+    /// [`full_txouts`] for more.
     ///
     /// [`full_txouts`]: Self::full_txouts
+    /// [`spendable_utxos`]: Self::spendable_utxos
     pub fn full_utxos(&self) -> impl Iterator<Item = (&(K, u32), FullTxOut<P>)> + '_ {
-        self.full_txouts()
-            .filter(|(_, txout)| txout.spent_by.is_none())
+        self.view().full_utxos()
+    }
+
+    /// Iterates through [`FullTxOut`]s that are unspent outputs which are not locked or frozen.
+    ///
+    /// These are the outputs that can be used to fund new transactions.
+    pub fn spendable_utxos(&self) -> impl Iterator<Item = (&(K, u32), FullTxOut<P>)> + '_ {
+        self.view().spendable_utxos()
+    }
+
+    /// Temporarily lock `outpoint` so that it is not used to fund new transactions (i.e. while a
+    /// transaction spending it is being built and signed).
+    ///
+    /// The lock expires at `expires_at` (a UNIX timestamp in seconds). The output stays hidden
+    /// until the expired lock is removed with [`unlock_expired`] (the tracker never reads the clock
+    /// itself). Locks are kept in memory only; for a persistent lock refer to [`freeze_utxo`].
+    ///
+    /// [`unlock_expired`]: Self::unlock_expired
+    /// [`freeze_utxo`]: Self::freeze_utxo
+    pub fn lock_utxo(&mut self, outpoint: OutPoint, expires_at: u64) {
+        self.locked.insert(outpoint, expires_at);
+    }
+
+    /// Remove the lock of `outpoint`, returning whether it was locked.
+    pub fn unlock_utxo(&mut self, outpoint: OutPoint) -> bool {
+        self.locked.remove(&outpoint).is_some()
+    }
+
+    /// Remove the locks that have expired at `now` (a UNIX timestamp in seconds), returning the
+    /// outpoints that were unlocked.
+    pub fn unlock_expired(&mut self, now: u64) -> Vec<OutPoint> {
+        let expired = self
+            .locked
+            .iter()
+            .filter(|(_, &expires_at)| expires_at <= now)
+            .map(|(&outpoint, _)| outpoint)
+            .collect::<Vec<_>>();
+        for outpoint in &expired {
+            self.locked.remove(outpoint);
+        }
+        expired
+    }
+
+    /// Returns the locked outpoints and the time their locks expire at.
+    pub fn locked_utxos(&self) -> &BTreeMap<OutPoint, u64> {
+        &self.locked
+    }
+
+    /// Determines the changes as result of freezing `outpoint`.
+    ///
+    /// Frozen outpoints are never used to fund new transactions until they are unfrozen. Unlike
+    /// locks, freezes are recorded in the [`KeychainChangeSet`] so they can be persisted.
+    pub fn freeze_utxo_preview(&self, outpoint: OutPoint) -> KeychainChangeSet<K, P, T> {
+        KeychainChangeSet {
+            frozen: match self.frozen.contains(&outpoint) {
+                true => Default::default(),
+                false => [(outpoint, true)].into(),
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Directly freeze `outpoint`.
+    ///
+    /// This is equivalent of calling [`freeze_utxo_preview`] and [`apply_changeset`] in sequence.
+    ///
+    /// [`freeze_utxo_preview`]: Self::freeze_utxo_preview
+    /// [`apply_changeset`]: Self::apply_changeset
+    pub fn freeze_utxo(&mut self, outpoint: OutPoint) -> KeychainChangeSet<K, P, T> {
+        let changeset = self.freeze_utxo_preview(outpoint);
+        self.apply_changeset(changeset.clone());
+        changeset
+    }
+
+    /// Determines the changes as result of unfreezing `outpoint`.
+    ///
+    /// Refer to [`freeze_utxo_preview`] for more.
+    ///
+    /// [`freeze_utxo_preview`]: Self::freeze_utxo_preview
+    pub fn unfreeze_utxo_preview(&self, outpoint: OutPoint) -> KeychainChangeSet<K, P, T> {
+        KeychainChangeSet {
+            frozen: match self.frozen.contains(&outpoint) {
+                true => [(outpoint, false)].into(),
+                false => Default::default(),
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Directly unfreeze `outpoint`.
+    ///
+    /// This is equivalent of calling [`unfreeze_utxo_preview`] and [`apply_changeset`] in
+    /// sequence.
+    ///
+    /// [`unfreeze_utxo_preview`]: Self::unfreeze_utxo_preview
+    /// [`apply_changeset`]: Self::apply_changeset
+    pub fn unfreeze_utxo(&mut self, outpoint: OutPoint) -> KeychainChangeSet<K, P, T> {
+        let changeset = self.unfreeze_utxo_preview(outpoint);
+        self.apply_changeset(changeset.clone());
+        changeset
+    }
+
+    /// Returns the frozen outpoints.
+    pub fn frozen_utxos(&self) -> &BTreeSet<OutPoint> {
+        &self.frozen
+    }
+
+    /// Returns the labels of script pubkeys, transactions and transaction outputs.
+    pub fn labels(&self) -> &Labels<K> {
+        &self.labels
+    }
+
+    /// Get the label of the transaction output at `outpoint` which has the script pubkey at
+    /// `spk_index`.
+    ///
+    /// The label of the output takes precedence over the label of its script pubkey.
+    pub fn txout_label(&self, spk_index: &(K, u32), outpoint: OutPoint) -> Option<&str> {
+        self.labels
+            .output_label(outpoint)
+            .or_else(|| self.labels.spk_label(spk_index))
+    }
+
+    /// Determines the changes as result of setting the label of `label_ref` to `label` (or removing
+    /// it if `label` is `None`).
+    ///
+    /// The returned changeset is empty if the label is unchanged.
+    pub fn set_label_preview(
+        &self,
+        label_ref: LabelRef<K>,
+        label: Option<String>,
+    ) -> KeychainChangeSet<K, P, T> {
+        KeychainChangeSet {
+            labels: self.labels.set_label_preview(label_ref, label),
+            ..Default::default()
+        }
+    }
+
+    /// Directly set the label of `label_ref` to `label` (or remove it if `label` is `None`).
+    ///
+    /// This is equivalent of calling [`set_label_preview`] and [`apply_changeset`] in sequence.
+    ///
+    /// [`set_label_preview`]: Self::set_label_preview
+    /// [`apply_changeset`]: Self::apply_changeset
+    pub fn set_label(
+        &mut self,
+        label_ref: LabelRef<K>,
+        label: Option<String>,
+    ) -> KeychainChangeSet<K, P, T> {
+        let changeset = self.set_label_preview(label_ref, label);
+        self.apply_changeset(changeset.clone());
+        changeset
+    }
+
+    /// Determines the changes as result of watching `spk`.
+    ///
+    /// Outputs to watched script pubkeys (and the transactions that create and spend them) are
+    /// tracked separately from the keychains. Refer to [`watched_txouts`] and
+    /// [`watched_transactions`].
+    ///
+    /// [`watched_txouts`]: Self::watched_txouts
+    /// [`watched_transactions`]: Self::watched_transactions
+    pub fn watch_spk_preview(&self, spk: Script) -> KeychainChangeSet<K, P, T> {
+        KeychainChangeSet {
+            watched_spks: match self.watched_spks.contains(&spk) {
+                true => Default::default(),
+                false => [(spk, true)].into(),
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Directly watch `spk`.
+    ///
+    /// This is equivalent of calling [`watch_spk_preview`] and [`apply_changeset`] in sequence.
+    ///
+    /// [`watch_spk_preview`]: Self::watch_spk_preview
+    /// [`apply_changeset`]: Self::apply_changeset
+    pub fn watch_spk(&mut self, spk: Script) -> KeychainChangeSet<K, P, T> {
+        let changeset = self.watch_spk_preview(spk);
+        self.apply_changeset(changeset.clone());
+        changeset
+    }
+
+    /// Determines the changes as result of no longer watching `spk`.
+    pub fn unwatch_spk_preview(&self, spk: Script) -> KeychainChangeSet<K, P, T> {
+        KeychainChangeSet {
+            watched_spks: match self.watched_spks.contains(&spk) {
+                true => [(spk, false)].into(),
+                false => Default::default(),
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Directly stop watching `spk`.
+    ///
+    /// This is equivalent of calling [`unwatch_spk_preview`] and [`apply_changeset`] in sequence.
+    ///
+    /// [`unwatch_spk_preview`]: Self::unwatch_spk_preview
+    /// [`apply_changeset`]: Self::apply_changeset
+    pub fn unwatch_spk(&mut self, spk: Script) -> KeychainChangeSet<K, P, T> {
+        let changeset = self.unwatch_spk_preview(spk);
+        self.apply_changeset(changeset.clone());
+        changeset
+    }
+
+    /// Determines the changes as result of watching `outpoint`.
+    ///
+    /// Refer to [`watch_spk_preview`] for more.
+    ///
+    /// [`watch_spk_preview`]: Self::watch_spk_preview
+    pub fn watch_outpoint_preview(&self, outpoint: OutPoint) -> KeychainChangeSet<K, P, T> {
+        KeychainChangeSet {
+            watched_outpoints: match self.watched_outpoints.contains(&outpoint) {
+                true => Default::default(),
+                false => [(outpoint, true)].into(),
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Directly watch `outpoint`.
+    ///
+    /// This is equivalent of calling [`watch_outpoint_preview`] and [`apply_changeset`] in
+    /// sequence.
+    ///
+    /// [`watch_outpoint_preview`]: Self::watch_outpoint_preview
+    /// [`apply_changeset`]: Self::apply_changeset
+    pub fn watch_outpoint(&mut self, outpoint: OutPoint) -> KeychainChangeSet<K, P, T> {
+        let changeset = self.watch_outpoint_preview(outpoint);
+        self.apply_changeset(changeset.clone());
+        changeset
+    }
+
+    /// Determines the changes as result of no longer watching `outpoint`.
+    pub fn unwatch_outpoint_preview(&self, outpoint: OutPoint) -> KeychainChangeSet<K, P, T> {
+        KeychainChangeSet {
+            watched_outpoints: match self.watched_outpoints.contains(&outpoint) {
+                true => [(outpoint, false)].into(),
+                false => Default::default(),
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Directly stop watching `outpoint`.
+    ///
+    /// This is equivalent of calling [`unwatch_outpoint_preview`] and [`apply_changeset`] in
+    /// sequence.
+    ///
+    /// [`unwatch_outpoint_preview`]: Self::unwatch_outpoint_preview
+    /// [`apply_changeset`]: Self::apply_changeset
+    pub fn unwatch_outpoint(&mut self, outpoint: OutPoint) -> KeychainChangeSet<K, P, T> {
+        let changeset = self.unwatch_outpoint_preview(outpoint);
+        self.apply_changeset(changeset.clone());
+        changeset
+    }
+
+    /// Returns the watched script pubkeys.
+    ///
+    /// These should be included in the script pubkeys of scan requests.
+    pub fn watched_spks(&self) -> &BTreeSet<Script> {
+        &self.watched_spks
+    }
+
+    /// Returns the watched outpoints.
+    ///
+    /// These should be included in the outpoints of scan requests.
+    pub fn watched_outpoints(&self) -> &BTreeSet<OutPoint> {
+        &self.watched_outpoints
+    }
+
+    /// Whether the output at `outpoint` with `txout` is watched (either by its outpoint or its
+    /// script pubkey).
+    fn is_watched(&self, outpoint: OutPoint, txout: &bitcoin::TxOut) -> bool {
+        self.watched_outpoints.contains(&outpoint)
+            || self.watched_spks.contains(&txout.script_pubkey)
+    }
+
+    /// Iterates through [`FullTxOut`]s of watched outpoints and outputs to watched script pubkeys
+    /// that exist in the chain (in chain order).
+    ///
+    /// These are reported separately from the outputs of the keychains (i.e. [`full_txouts`]) and
+    /// are not included in the [`balance`].
+    ///
+    /// [`full_txouts`]: Self::full_txouts
+    /// [`balance`]: Self::balance
+    pub fn watched_txouts(&self) -> impl Iterator<Item = FullTxOut<P>> + '_ {
+        self.chain_graph
+            .transactions_in_chain()
+            .flat_map(move |(_, tx)| {
+                let tx = tx.as_tx();
+                let txid = tx.txid();
+                tx.output
+                    .iter()
+                    .enumerate()
+                    .map(move |(vout, txout)| (OutPoint::new(txid, vout as u32), txout))
+            })
+            .filter(|(outpoint, txout)| self.is_watched(*outpoint, txout))
+            .filter_map(|(outpoint, _)| self.chain_graph.full_txout(outpoint))
+    }
+
+    /// Iterates through [`FullTxOut`]s of watched outputs that are unspent.
+    ///
+    /// Refer to [`watched_txouts`] for more.
+    ///
+    /// [`watched_txouts`]: Self::watched_txouts
+    pub fn watched_utxos(&self) -> impl Iterator<Item = FullTxOut<P>> + '_ {
+        self.watched_txouts()
+            .filter(|txout| txout.spent_by.is_none())
+    }
+
+    /// Iterates through the transactions in the chain that create or spend watched outputs (in
+    /// chain order).
+    pub fn watched_transactions(&self) -> impl DoubleEndedIterator<Item = (&P, &T)> + '_ {
+        self.chain_graph
+            .transactions_in_chain()
+            .filter(move |(_, tx)| {
+                let tx = tx.as_tx();
+                let txid = tx.txid();
+                let creates =
+                    tx.output.iter().enumerate().any(|(vout, txout)| {
+                        self.is_watched(OutPoint::new(txid, vout as u32), txout)
+                    });
+                creates
+                    || tx.input.iter().any(|txin| {
+                        self.graph()
+                            .get_txout(txin.previous_output)
+                            .is_some_and(|txout| self.is_watched(txin.previous_output, txout))
+                            || self.watched_outpoints.contains(&txin.previous_output)
+                    })
+            })
+    }
+
+    /// Creates a [`FullScanRequest`] for discovering the transactions of all keychains.
+    ///
+    /// The script pubkey iterators of the request are unbounded and do not borrow the tracker so
+    /// the scan can happen without holding on to it. Watched script pubkeys, watched outpoints and
+    /// the progress of an unfinished full scan are included in the request. The [`Birthday`] of the wallet can be set
+    /// with [`FullScanRequest::with_birthday`].
+    ///
+    /// [`Birthday`]: crate::Birthday
+    pub fn full_scan_request(
+        &self,
+    ) -> FullScanRequest<K, impl Iterator<Item = (u32, Script)> + Clone> {
+        FullScanRequest::new(
+            self.chain().checkpoints().clone(),
+            self.txout_index.spks_of_all_keychains(),
+        )
+        .with_spks(self.watched_spks.iter().cloned())
+        .with_outpoints(self.watched_outpoints.iter().copied())
+        .with_progress(self.scan_progress.clone())
+    }
+
+    /// Returns the progress of an unfinished full scan.
+    ///
+    /// This is set by applying the partial update of an interrupted full scan and is empty if there
+    /// is no full scan to resume. Refer to [`ScanProgress`] for more.
+    pub fn scan_progress(&self) -> &ScanProgress<K> {
+        &self.scan_progress
+    }
+
+    /// Creates a [`SyncRequest`] for updating what the tracker already knows about.
+    ///
+    /// The request contains the revealed script pubkeys that are unused, the unconfirmed
+    /// transactions and the unspent outputs of the tracker, as well as watched script pubkeys and
+    /// outpoints.
+    pub fn sync_request(&self) -> SyncRequest {
+        SyncRequest::new(self.chain().checkpoints().clone())
+            .with_spks(
+                self.txout_index
+                    .unused_spks(..)
+                    .map(|(_, spk)| spk.clone())
+                    .chain(self.watched_spks.iter().cloned()),
+            )
+            .with_txids(
+                self.chain()
+                    .range_txids_by_height(TxHeight::Unconfirmed..)
+                    .map(|(_, txid)| *txid),
+            )
+            .with_outpoints(
+                self.full_utxos()
+                    .map(|(_, utxo)| utxo.outpoint)
+                    .chain(self.watched_outpoints.iter().copied()),
+            )
+    }
+
+    /// Iterates through [`FullTxOut`]s that are unspent outputs with at least `min_confirmations`
+    /// confirmations.
+    ///
+    /// A `min_confirmations` of `0` includes unconfirmed outputs (the same as [`full_utxos`]).
+    ///
+    /// [`full_utxos`]: Self::full_utxos
+    pub fn full_utxos_with_confirmations(
+        &self,
+        min_confirmations: u32,
+    ) -> impl Iterator<Item = (&(K, u32), FullTxOut<P>)> + '_ {
+        self.view().full_utxos_with_confirmations(min_confirmations)
+    }
+
+    /// Iterates through [`WalletTx`]s of transactions in the chain that are relevant to the
+    /// tracked keychains (in chain order).
+    ///
+    /// A transaction is relevant if it spends from or sends to a script pubkey in the `txout_index`.
+    pub fn transactions(&self) -> impl DoubleEndedIterator<Item = WalletTx<'_, K, P, T>> + '_ {
+        self.view().transactions()
+    }
+
+    /// Iterates through [`WalletTx`]s of relevant transactions in the chain that are within the
+    /// given height `range`.
+    ///
+    /// Refer to [`transactions`] for more.
+    ///
+    /// [`transactions`]: Self::transactions
+    pub fn range_transactions<R>(
+        &self,
+        range: R,
+    ) -> impl DoubleEndedIterator<Item = WalletTx<'_, K, P, T>> + '_
+    where
+        R: RangeBounds<TxHeight>,
+    {
+        self.view().range_transactions(range)
+    }
+
+    /// Returns a page of [`WalletTx`]s of relevant transactions within the given height `range`,
+    /// ordered from the most recent.
+    ///
+    /// `offset` is the number of transactions to skip and `limit` is the maximum number of
+    /// transactions in the page.
+    pub fn transactions_page<R>(
+        &self,
+        range: R,
+        offset: usize,
+        limit: usize,
+    ) -> Vec<WalletTx<'_, K, P, T>>
+    where
+        R: RangeBounds<TxHeight>,
+    {
+        self.view().transactions_page(range, offset, limit)
+    }
+
+    /// Returns a [`KeychainView`] of the tracker (which the tracker's queries are made with).
+    ///
+    /// The view has no time so every lock is in effect until it is removed (refer to
+    /// [`lock_utxo`]). Use [`KeychainView::with_time`] to query as if expired locks were removed.
+    ///
+    /// [`lock_utxo`]: Self::lock_utxo
+    pub fn view(&self) -> KeychainView<'_, K, P, T> {
+        KeychainView::new(&self.txout_index, &self.chain_graph)
+            .with_finality_depth(self.finality_depth)
+            .with_locks(&self.locked, &self.frozen)
+            .with_labels(&self.labels)
     }
 
     /// Returns a reference to the internal [`ChainGraph`].
@@ -222,6 +800,30 @@ where
         Ok(changeset)
     }
 
+    /// Determines the changes as result of removing unconfirmed transactions that have expired at
+    /// `now` (a UNIX timestamp in seconds).
+    ///
+    /// Refer to [`ChainGraph::expire_unconfirmed_preview`] for more.
+    pub fn expire_unconfirmed_preview(&self, now: u64) -> KeychainChangeSet<K, P, T> {
+        KeychainChangeSet {
+            chain_graph: self.chain_graph.expire_unconfirmed_preview(now),
+            ..Default::default()
+        }
+    }
+
+    /// Directly remove unconfirmed transactions that have expired at `now`.
+    ///
+    /// This is equivalent of calling [`expire_unconfirmed_preview`] and [`apply_changeset`] in
+    /// sequence.
+    ///
+    /// [`expire_unconfirmed_preview`]: Self::expire_unconfirmed_preview
+    /// [`apply_changeset`]: Self::apply_changeset
+    pub fn expire_unconfirmed(&mut self, now: u64) -> KeychainChangeSet<K, P, T> {
+        let changeset = self.expire_unconfirmed_preview(now);
+        self.apply_changeset(changeset.clone());
+        changeset
+    }
+
     /// Returns the *balance* of the keychain i.e. the value of unspent transaction outputs tracked.
     ///
     /// The caller provides a `should_trust` predicate which must decide whether the value of
@@ -235,55 +837,44 @@ where
     ///
     /// When in doubt set `should_trust` to return false. This doesn't do anything other than change
     /// where the unconfirmed output's value is accounted for in `Balance`.
-    pub fn balance(&self, mut should_trust: impl FnMut(&K) -> bool) -> Balance {
-        let mut immature = 0;
-        let mut trusted_pending = 0;
-        let mut untrusted_pending = 0;
-        let mut confirmed = 0;
-        let last_sync_height = self.chain().latest_checkpoint().map(|latest| latest.height);
-        for ((keychain, _), utxo) in self.full_utxos() {
-            let chain_position = &utxo.chain_position;
-
-            match chain_position.height() {
-                TxHeight::Confirmed(_) => {
-                    if utxo.is_on_coinbase {
-                        if utxo.is_mature(
-                            last_sync_height
-                                .expect("since it's confirmed we must have a checkpoint"),
-                        ) {
-                            confirmed += utxo.txout.value;
-                        } else {
-                            immature += utxo.txout.value;
-                        }
-                    } else {
-                        confirmed += utxo.txout.value;
-                    }
-                }
-                TxHeight::Unconfirmed => {
-                    if should_trust(keychain) {
-                        trusted_pending += utxo.txout.value;
-                    } else {
-                        untrusted_pending += utxo.txout.value;
-                    }
-                }
-            }
-        }
+    ///
+    /// If a finality depth is set, confirmed outputs that have not reached it are accounted for in
+    /// [`Balance::shallow`] instead of [`Balance::confirmed`].
+    pub fn balance(&self, should_trust: impl FnMut(&K) -> bool) -> Balance {
+        self.view().balance(should_trust)
+    }
 
-        Balance {
-            immature,
-            trusted_pending,
-            untrusted_pending,
-            confirmed,
-        }
+    /// Calculates the [`Balance`] of each keychain in one pass over the unspent outputs.
+    ///
+    /// Keychains without any unspent outputs are left out. Refer to [`balance`] for more.
+    ///
+    /// [`balance`]: Self::balance
+    pub fn balance_per_keychain(
+        &self,
+        should_trust: impl FnMut(&K) -> bool,
+    ) -> BTreeMap<K, Balance> {
+        self.view().balance_per_keychain(should_trust)
     }
 
     /// Returns the balance of all spendable confirmed unspent outputs of this tracker at a
     /// particular height.
     pub fn balance_at(&self, height: u32) -> u64 {
-        self.full_txouts()
-            .filter(|(_, full_txout)| full_txout.is_spendable_at(height))
-            .map(|(_, full_txout)| full_txout.txout.value)
-            .sum()
+        self.view().balance_at(height)
+    }
+
+    /// Calculates the [`Balance`] of each keychain as it was at a particular `height`.
+    ///
+    /// `should_trust` decides whether unconfirmed outputs of a keychain are trusted (like in
+    /// [`balance`]). Refer to [`KeychainView::balance_at_per_keychain`] for more.
+    ///
+    /// [`balance`]: Self::balance
+    /// [`KeychainView::balance_at_per_keychain`]: crate::keychain::KeychainView::balance_at_per_keychain
+    pub fn balance_at_per_keychain(
+        &self,
+        height: u32,
+        should_trust: impl FnMut(&K) -> bool,
+    ) -> BTreeMap<K, Balance> {
+        self.view().balance_at_per_keychain(height, should_trust)
     }
 }
 
@@ -292,6 +883,13 @@ impl<K, P> Default for KeychainTracker<K, P> {
         Self {
             txout_index: Default::default(),
             chain_graph: Default::default(),
+            finality_depth: Default::default(),
+            locked: Default::default(),
+            frozen: Default::default(),
+            labels: Default::default(),
+            watched_spks: Default::default(),
+            watched_outpoints: Default::default(),
+            scan_progress: Default::default(),
         }
     }
 }
@@ -313,3 +911,13 @@ impl<K, P> AsRef<ChainGraph<P>> for KeychainTracker<K, P> {
         &self.chain_graph
     }
 }
+
+fn apply_set_changes<I: Ord>(set: &mut BTreeSet<I>, changes: BTreeMap<I, bool>) {
+    for (item, is_included) in changes {
+        if is_included {
+            set.insert(item);
+        } else {
+            set.remove(&item);
+        }
+    }
+}
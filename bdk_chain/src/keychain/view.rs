@@ -0,0 +1,418 @@
+use alloc::vec::Vec;
+use bitcoin::{OutPoint, Transaction, Txid};
+use core::ops::RangeBounds;
+
+use crate::{
+    chain_graph::ChainGraph,
+    collections::*,
+    keychain::{Balance, KeychainTxOutIndex, Labels, WalletTx},
+    sparse_chain::{ChainPosition, SparseChain},
+    tx_graph::TxGraph,
+    AsTransaction, FullTxOut, TxHeight,
+};
+
+/// A read-only view of the data of a [`ChainGraph`] that is relevant to a [`KeychainTxOutIndex`].
+///
+/// This is what [`KeychainTracker`] uses for its queries. It allows many [`KeychainTxOutIndex`]es
+/// to be queried against a single shared [`ChainGraph`] (refer to [`MultiKeychainTracker`]).
+///
+/// [`KeychainTracker`]: crate::keychain::KeychainTracker
+/// [`MultiKeychainTracker`]: crate::keychain::MultiKeychainTracker
+#[derive(Debug)]
+pub struct KeychainView<'a, K, P, T = Transaction> {
+    txout_index: &'a KeychainTxOutIndex<K>,
+    chain_graph: &'a ChainGraph<P, T>,
+    finality_depth: Option<u32>,
+    locked: Option<&'a BTreeMap<OutPoint, u64>>,
+    frozen: Option<&'a BTreeSet<OutPoint>>,
+    labels: Option<&'a Labels<K>>,
+    now: Option<u64>,
+}
+
+impl<'a, K, P, T> Clone for KeychainView<'a, K, P, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, K, P, T> Copy for KeychainView<'a, K, P, T> {}
+
+impl<'a, K, P, T> KeychainView<'a, K, P, T>
+where
+    P: ChainPosition,
+    K: Ord + Clone + core::fmt::Debug,
+    T: AsTransaction + Clone + Ord,
+{
+    /// Create a view of the data in `chain_graph` that is relevant to `txout_index`.
+    pub fn new(txout_index: &'a KeychainTxOutIndex<K>, chain_graph: &'a ChainGraph<P, T>) -> Self {
+        Self {
+            txout_index,
+            chain_graph,
+            finality_depth: None,
+            locked: None,
+            frozen: None,
+            labels: None,
+            now: None,
+        }
+    }
+
+    /// Set the finality depth of the view.
+    ///
+    /// Refer to [`KeychainTracker::set_finality_depth`] for more.
+    ///
+    /// [`KeychainTracker::set_finality_depth`]: crate::keychain::KeychainTracker::set_finality_depth
+    pub fn with_finality_depth(mut self, finality_depth: Option<u32>) -> Self {
+        self.finality_depth = finality_depth;
+        self
+    }
+
+    /// Set the `locked` (outpoint to lock expiry) and `frozen` outpoints of the view.
+    ///
+    /// Refer to [`KeychainTracker::lock_utxo`] and [`KeychainTracker::freeze_utxo`] for more.
+    ///
+    /// [`KeychainTracker::lock_utxo`]: crate::keychain::KeychainTracker::lock_utxo
+    /// [`KeychainTracker::freeze_utxo`]: crate::keychain::KeychainTracker::freeze_utxo
+    pub fn with_locks(
+        mut self,
+        locked: &'a BTreeMap<OutPoint, u64>,
+        frozen: &'a BTreeSet<OutPoint>,
+    ) -> Self {
+        self.locked = Some(locked);
+        self.frozen = Some(frozen);
+        self
+    }
+
+    /// Set the time of the view (a UNIX timestamp in seconds) which locks expire against.
+    ///
+    /// Without a time, every lock is treated as unexpired.
+    pub fn with_time(mut self, now: u64) -> Self {
+        self.now = Some(now);
+        self
+    }
+
+    /// Set the labels of the view (used to label the [`WalletTx`]s it returns).
+    pub fn with_labels(mut self, labels: &'a Labels<K>) -> Self {
+        self.labels = Some(labels);
+        self
+    }
+
+    /// Returns whether `outpoint` is locked (with a lock that has not expired at the time of the
+    /// view) or frozen.
+    pub fn is_locked(&self, outpoint: OutPoint) -> bool {
+        let is_lock_active = |&expires_at: &u64| self.now.is_none_or(|now| expires_at > now);
+        self.locked
+            .is_some_and(|locked| locked.get(&outpoint).is_some_and(is_lock_active))
+            || self.frozen.is_some_and(|frozen| frozen.contains(&outpoint))
+    }
+
+    /// Returns the [`KeychainTxOutIndex`] of the view.
+    pub fn txout_index(&self) -> &'a KeychainTxOutIndex<K> {
+        self.txout_index
+    }
+
+    /// Returns the [`ChainGraph`] of the view.
+    pub fn chain_graph(&self) -> &'a ChainGraph<P, T> {
+        self.chain_graph
+    }
+
+    /// Returns the [`TxGraph`] of the view (which is part of the [`ChainGraph`]).
+    pub fn graph(&self) -> &'a TxGraph<T> {
+        self.chain_graph.graph()
+    }
+
+    /// Returns the [`SparseChain`] of the view (which is part of the [`ChainGraph`]).
+    pub fn chain(&self) -> &'a SparseChain<P> {
+        self.chain_graph.chain()
+    }
+
+    /// Returns the number of confirmations of a transaction.
+    ///
+    /// Refer to [`SparseChain::confirmations`] for more.
+    pub fn confirmations(&self, txid: Txid) -> Option<u32> {
+        self.chain_graph.confirmations(txid)
+    }
+
+    /// Returns whether the transaction has reached the finality depth. If no finality depth is
+    /// set, every confirmed transaction is considered final.
+    pub fn is_final(&self, txid: Txid) -> bool {
+        self.confirmations(txid)
+            .is_some_and(|confirmations| confirmations >= self.finality_depth.unwrap_or(1).max(1))
+    }
+
+    /// Iterates through [`FullTxOut`]s that are considered to exist in our representation of the
+    /// blockchain/mempool.
+    ///
+    /// In other words, these are `txout`s of confirmed and in-mempool transactions, based on our
+    /// view of the blockchain/mempool.
+    pub fn full_txouts(&self) -> impl Iterator<Item = (&'a (K, u32), FullTxOut<P>)> + 'a {
+        let chain_graph = self.chain_graph;
+        self.txout_index
+            .txouts()
+            .filter_map(move |(spk_i, op, _)| Some((spk_i, chain_graph.full_txout(op)?)))
+    }
+
+    /// Iterates through [`FullTxOut`]s that are unspent outputs (including locked and frozen ones).
+    ///
+    /// Refer to [`full_txouts`] for more.
+    ///
+    /// [`full_txouts`]: Self::full_txouts
+    pub fn full_utxos(&self) -> impl Iterator<Item = (&'a (K, u32), FullTxOut<P>)> + 'a {
+        self.full_txouts()
+            .filter(|(_, txout)| txout.spent_by.is_none())
+    }
+
+    /// Iterates through [`FullTxOut`]s that are unspent outputs which are not locked or frozen.
+    ///
+    /// These are the outputs that can be used to fund new transactions.
+    pub fn spendable_utxos(&self) -> impl Iterator<Item = (&'a (K, u32), FullTxOut<P>)> + 'a {
+        let view = *self;
+        self.full_utxos()
+            .filter(move |(_, utxo)| !view.is_locked(utxo.outpoint))
+    }
+
+    /// Iterates through [`FullTxOut`]s that are unspent outputs with at least `min_confirmations`
+    /// confirmations.
+    ///
+    /// A `min_confirmations` of `0` includes unconfirmed outputs (the same as [`full_utxos`]).
+    ///
+    /// [`full_utxos`]: Self::full_utxos
+    pub fn full_utxos_with_confirmations(
+        &self,
+        min_confirmations: u32,
+    ) -> impl Iterator<Item = (&'a (K, u32), FullTxOut<P>)> + 'a {
+        let tip_height = self.chain().latest_checkpoint().map_or(0, |cp| cp.height);
+        self.full_utxos().filter(move |(_, utxo)| {
+            utxo.chain_position.height().confirmations(tip_height) >= min_confirmations
+        })
+    }
+
+    /// Iterates through [`WalletTx`]s of transactions in the chain that are relevant to the
+    /// keychains (in chain order).
+    ///
+    /// A transaction is relevant if it spends from or sends to a script pubkey in the `txout_index`.
+    pub fn transactions(&self) -> impl DoubleEndedIterator<Item = WalletTx<'a, K, P, T>> + 'a {
+        self.range_transactions(..)
+    }
+
+    /// Iterates through [`WalletTx`]s of relevant transactions in the chain that are within the
+    /// given height `range`.
+    ///
+    /// Refer to [`transactions`] for more.
+    ///
+    /// [`transactions`]: Self::transactions
+    pub fn range_transactions<R>(
+        &self,
+        range: R,
+    ) -> impl DoubleEndedIterator<Item = WalletTx<'a, K, P, T>> + 'a
+    where
+        R: RangeBounds<TxHeight>,
+    {
+        let view = *self;
+        self.chain()
+            .range_txids_by_height(range)
+            .filter_map(move |(pos, txid)| view.wallet_tx(pos, *txid))
+    }
+
+    /// Returns a page of [`WalletTx`]s of relevant transactions within the given height `range`,
+    /// ordered from the most recent.
+    ///
+    /// `offset` is the number of transactions to skip and `limit` is the maximum number of
+    /// transactions in the page.
+    pub fn transactions_page<R>(
+        &self,
+        range: R,
+        offset: usize,
+        limit: usize,
+    ) -> Vec<WalletTx<'a, K, P, T>>
+    where
+        R: RangeBounds<TxHeight>,
+    {
+        self.range_transactions(range)
+            .rev()
+            .skip(offset)
+            .take(limit)
+            .collect()
+    }
+
+    fn wallet_tx(&self, chain_position: &P, txid: Txid) -> Option<WalletTx<'a, K, P, T>> {
+        let tx = self
+            .graph()
+            .get_tx(txid)
+            .expect("tx of chain must exist in graph");
+        let raw_tx = tx.as_tx();
+        if !self.txout_index.is_relevant(raw_tx) {
+            return None;
+        }
+
+        let (sent, received) = self.txout_index.sent_and_received(raw_tx);
+        let mut keychains = BTreeSet::new();
+        let mut is_self_transfer = !raw_tx.is_coin_base();
+        for txin in &raw_tx.input {
+            match self.txout_index.txout(txin.previous_output) {
+                Some(((keychain, _), _)) => {
+                    keychains.insert(keychain.clone());
+                }
+                None => is_self_transfer = false,
+            }
+        }
+        for txout in &raw_tx.output {
+            match self.txout_index.index_of_spk(&txout.script_pubkey) {
+                Some((keychain, _)) => {
+                    keychains.insert(keychain.clone());
+                }
+                None => is_self_transfer = false,
+            }
+        }
+
+        let fee = self
+            .graph()
+            .calculate_fee(raw_tx)
+            .and_then(|fee| u64::try_from(fee).ok());
+        let feerate = fee.map(|fee| fee as f32 * 4.0 / raw_tx.weight() as f32);
+
+        Some(WalletTx {
+            txid,
+            chain_position: chain_position.clone(),
+            tx,
+            sent,
+            received,
+            fee,
+            feerate,
+            keychains,
+            is_self_transfer,
+            label: self.labels.and_then(|labels| labels.tx_label(txid)),
+        })
+    }
+
+    /// Returns the *balance* of the keychains i.e. the value of unspent transaction outputs tracked.
+    ///
+    /// Refer to [`KeychainTracker::balance`] for more.
+    ///
+    /// [`KeychainTracker::balance`]: crate::keychain::KeychainTracker::balance
+    pub fn balance(&self, should_trust: impl FnMut(&K) -> bool) -> Balance {
+        self.balance_per_keychain(should_trust)
+            .into_values()
+            .fold(Balance::default(), |acc, balance| acc + balance)
+    }
+
+    /// Calculates the [`Balance`] of each keychain in one pass over the unspent outputs.
+    ///
+    /// Keychains without any unspent outputs are left out. Refer to [`balance`] for more.
+    ///
+    /// [`balance`]: Self::balance
+    pub fn balance_per_keychain(
+        &self,
+        mut should_trust: impl FnMut(&K) -> bool,
+    ) -> BTreeMap<K, Balance> {
+        let mut balances = BTreeMap::<K, Balance>::new();
+        let last_sync_height = self.chain().latest_checkpoint().map(|latest| latest.height);
+        for ((keychain, _), utxo) in self.full_utxos() {
+            let chain_position = &utxo.chain_position;
+            let balance = balances.entry(keychain.clone()).or_default();
+            if self.is_locked(utxo.outpoint) {
+                balance.locked += utxo.txout.value;
+                continue;
+            }
+
+            match chain_position.height() {
+                height @ TxHeight::Confirmed(_) => {
+                    let tip_height =
+                        last_sync_height.expect("since it's confirmed we must have a checkpoint");
+                    let is_shallow = self
+                        .finality_depth
+                        .is_some_and(|depth| height.confirmations(tip_height) < depth);
+                    if utxo.is_on_coinbase && !utxo.is_mature(tip_height) {
+                        balance.immature += utxo.txout.value;
+                    } else if is_shallow {
+                        balance.shallow += utxo.txout.value;
+                    } else {
+                        balance.confirmed += utxo.txout.value;
+                    }
+                }
+                TxHeight::Unconfirmed => {
+                    if should_trust(keychain) {
+                        balance.trusted_pending += utxo.txout.value;
+                    } else {
+                        balance.untrusted_pending += utxo.txout.value;
+                    }
+                }
+            }
+        }
+
+        balances
+    }
+
+    /// Returns the balance of all spendable confirmed unspent outputs at a particular height.
+    pub fn balance_at(&self, height: u32) -> u64 {
+        self.full_txouts()
+            .filter(|(_, full_txout)| full_txout.is_spendable_at(height))
+            .map(|(_, full_txout)| full_txout.txout.value)
+            .sum()
+    }
+
+    /// Calculates the [`Balance`] of each keychain as it was at a particular `height`.
+    ///
+    /// Outputs count towards the balance at `height` if they were created by a transaction
+    /// confirmed at or below `height` and were not spent by a transaction confirmed at or below
+    /// `height`. Coinbase outputs that were not mature at `height` are [`immature`] and outputs
+    /// that had not reached the finality depth at `height` are [`shallow`].
+    ///
+    /// We do not know what was in the mempool in the past, so outputs of unconfirmed transactions
+    /// are only [pending] at heights at or above the tip (where unconfirmed spends are taken into
+    /// account as in [`balance_per_keychain`]). Locks are not historical so [`locked`] is always
+    /// `0`. Keychains without any outputs at `height` are left out.
+    ///
+    /// [`immature`]: Balance::immature
+    /// [`shallow`]: Balance::shallow
+    /// [pending]: Balance::trusted_pending
+    /// [`locked`]: Balance::locked
+    /// [`balance_per_keychain`]: Self::balance_per_keychain
+    pub fn balance_at_per_keychain(
+        &self,
+        height: u32,
+        mut should_trust: impl FnMut(&K) -> bool,
+    ) -> BTreeMap<K, Balance> {
+        let mut balances = BTreeMap::<K, Balance>::new();
+        let at_tip = self
+            .chain()
+            .latest_checkpoint()
+            .is_none_or(|tip| height >= tip.height);
+        for ((keychain, _), txout) in self.full_txouts() {
+            let is_spent = match &txout.spent_by {
+                Some(_) if at_tip => true,
+                Some((spent_at, _)) => spent_at.height() <= TxHeight::Confirmed(height),
+                None => false,
+            };
+            if is_spent {
+                continue;
+            }
+
+            let value = txout.txout.value;
+            match txout.chain_position.height() {
+                TxHeight::Confirmed(tx_height) if tx_height <= height => {
+                    let balance = balances.entry(keychain.clone()).or_default();
+                    let is_shallow = self.finality_depth.is_some_and(|depth| {
+                        TxHeight::Confirmed(tx_height).confirmations(height) < depth
+                    });
+                    if !txout.is_mature(height) {
+                        balance.immature += value;
+                    } else if is_shallow {
+                        balance.shallow += value;
+                    } else {
+                        balance.confirmed += value;
+                    }
+                }
+                TxHeight::Unconfirmed if at_tip => {
+                    let balance = balances.entry(keychain.clone()).or_default();
+                    if should_trust(keychain) {
+                        balance.trusted_pending += value;
+                    } else {
+                        balance.untrusted_pending += value;
+                    }
+                }
+                _ => {}
+            }
+        }
+        balances
+    }
+}
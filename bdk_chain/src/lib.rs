@@ -20,12 +20,14 @@
 #![no_std]
 pub use bitcoin;
 pub mod chain_graph;
+pub mod chain_oracle;
 mod spk_txout_index;
 pub use spk_txout_index::*;
 mod chain_data;
 pub use chain_data::*;
 pub mod keychain;
 pub mod sparse_chain;
+pub mod spv;
 mod tx_data_traits;
 pub mod tx_graph;
 pub use tx_data_traits::*;
@@ -146,6 +146,12 @@
 //! [`SparseChain`] maintains a list of txids ordered by [`ChainPosition`]. By default, [`TxHeight`]
 //! is used, however additional data can be incorporated into the implementation.
 //!
+//! A [`ChainPosition`] can also be anchored to a block (see [`ChainPosition::anchor_block`]). The
+//! [`SparseChain`] will reject updates and insertions where an anchored position does not match the
+//! checkpoint of the same height. [`AnchoredPosition`] is a built-in anchored position.
+//!
+//! [`AnchoredPosition`]: crate::AnchoredPosition
+//!
 //! For example, we can have "perfect ordering" of transactions if our positional index is a
 //! combination of block height and transaction position in block.
 //!
@@ -215,6 +221,7 @@ use core::{
 };
 
 use crate::{collections::*, tx_graph::TxGraph, AsTransaction, BlockId, FullTxOut, TxHeight};
+use alloc::vec::Vec;
 use bitcoin::{hashes::Hash, BlockHash, OutPoint, Txid};
 
 /// This is a non-monotone structure that tracks relevant [`Txid`]s that are ordered by chain
@@ -242,8 +249,79 @@ pub struct SparseChain<P = TxHeight> {
     txid_to_pos: HashMap<Txid, P>,
     /// Limit number of checkpoints.
     checkpoint_limit: Option<usize>,
+    /// Policy of which checkpoints to keep.
+    checkpoint_retention: Option<CheckpointRetention>,
+    /// Seconds after an unconfirmed transaction was last seen before it expires.
+    unconfirmed_expiry: u64,
+}
+
+/// Policy of which checkpoints a [`SparseChain`] keeps.
+///
+/// Checkpoints within [`dense_window`] blocks of the tip are always kept. Older checkpoints are
+/// exponentially spaced: for every `k`, only the lowest checkpoint with a depth (below the tip) in
+/// `dense_window * 2^k..dense_window * 2^(k+1)` is kept. This allows a reorg of any depth to be
+/// located while keeping the number of checkpoints logarithmic to the chain's length.
+///
+/// If [`keep_tx_heights`] is set, checkpoints at heights where transactions are confirmed are also
+/// kept.
+///
+/// Refer to [`SparseChain::set_checkpoint_retention`].
+///
+/// [`dense_window`]: Self::dense_window
+/// [`keep_tx_heights`]: Self::keep_tx_heights
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(crate = "serde_crate")
+)]
+pub struct CheckpointRetention {
+    /// The number of most recent blocks in which all checkpoints are kept.
+    pub dense_window: u32,
+    /// Whether to keep checkpoints at the heights of confirmed transactions.
+    pub keep_tx_heights: bool,
 }
 
+impl Default for CheckpointRetention {
+    fn default() -> Self {
+        Self {
+            dense_window: 100,
+            keep_tx_heights: true,
+        }
+    }
+}
+
+impl CheckpointRetention {
+    /// Returns the `heights` (in ascending order) of the checkpoints that should be pruned.
+    fn heights_to_prune(&self, heights: &[u32], tx_heights: &BTreeSet<u32>) -> Vec<u32> {
+        let tip = match heights.last() {
+            Some(&tip) => tip,
+            None => return Vec::new(),
+        };
+        let window = self.dense_window.max(1);
+        let mut kept_buckets = BTreeSet::new();
+
+        heights
+            .iter()
+            .copied()
+            .filter(|height| {
+                let depth = tip - height;
+                if depth < window || (self.keep_tx_heights && tx_heights.contains(height)) {
+                    return false;
+                }
+                // heights are ascending, so the lowest checkpoint of each bucket is kept
+                !kept_buckets.insert((depth / window).ilog2())
+            })
+            .collect()
+    }
+}
+
+/// The default number of seconds after an unconfirmed transaction was last seen before it expires
+/// (two weeks, the same as Bitcoin Core's default mempool expiry).
+///
+/// Refer to [`SparseChain::set_unconfirmed_expiry`].
+pub const DEFAULT_UNCONFIRMED_EXPIRY: u64 = 14 * 24 * 60 * 60;
+
 impl<P> AsRef<SparseChain<P>> for SparseChain<P> {
     fn as_ref(&self) -> &SparseChain<P> {
         self
@@ -257,6 +335,8 @@ impl<P> Default for SparseChain<P> {
             ordered_txids: Default::default(),
             txid_to_pos: Default::default(),
             checkpoint_limit: Default::default(),
+            checkpoint_retention: Default::default(),
+            unconfirmed_expiry: DEFAULT_UNCONFIRMED_EXPIRY,
         }
     }
 }
@@ -277,6 +357,13 @@ pub enum InsertTxError<P> {
         original_pos: P,
         update_pos: P,
     },
+    /// Occurs when the [`Txid`] is to be inserted at a position anchored to a block which does not
+    /// match the [`SparseChain`]'s checkpoint of the same height.
+    AnchorNotMatching {
+        txid: Txid,
+        anchor: BlockId,
+        checkpoint_hash: BlockHash,
+    },
 }
 
 impl<P: core::fmt::Debug> core::fmt::Display for InsertTxError<P> {
@@ -300,6 +387,15 @@ impl<P: core::fmt::Debug> core::fmt::Display for InsertTxError<P> {
                 "txid ({}) insertion resulted in an expected positional move from {:?} to {:?}",
                 txid, original_pos, update_pos
             ),
+            InsertTxError::AnchorNotMatching {
+                txid,
+                anchor,
+                checkpoint_hash,
+            } => write!(
+                f,
+                "txid ({}) is anchored to block {:?} which does not match checkpoint ({})",
+                txid, anchor, checkpoint_hash
+            ),
         }
     }
 }
@@ -341,6 +437,13 @@ pub enum UpdateError<P = TxHeight> {
         original_pos: P,
         update_pos: P,
     },
+    /// A transaction position (in the update or the original chain) is anchored to a block which
+    /// does not match the checkpoint of the same height.
+    AnchorNotMatching {
+        txid: Txid,
+        anchor: BlockId,
+        checkpoint_hash: BlockHash,
+    },
 }
 
 impl<P: core::fmt::Debug> core::fmt::Display for UpdateError<P> {
@@ -352,6 +455,9 @@ impl<P: core::fmt::Debug> core::fmt::Display for UpdateError<P> {
             Self::TxInconsistent { txid, original_pos, update_pos } =>
                 write!(f, "tx ({}) had position ({:?}), but is ({:?}) in the update",
                     txid, original_pos, update_pos),
+            Self::AnchorNotMatching { txid, anchor, checkpoint_hash } =>
+                write!(f, "tx ({}) is anchored to block {:?} which does not match checkpoint ({})",
+                    txid, anchor, checkpoint_hash),
         }
     }
 }
@@ -471,6 +577,43 @@ impl<P: ChainPosition> SparseChain<P> {
             }
         }
 
+        // ensure anchored positions agree with the checkpoints of the resultant chain
+        let resultant_hash = |height: u32| match update.checkpoints.get(&height) {
+            Some(hash) => Some(hash),
+            None if height < invalid_lb => self.checkpoints.get(&height),
+            None => None,
+        };
+        for (&txid, update_pos) in &update.txid_to_pos {
+            if let Some(anchor) = update_pos.anchor_block() {
+                match resultant_hash(anchor.height) {
+                    Some(&checkpoint_hash) if checkpoint_hash != anchor.hash => {
+                        return Err(UpdateError::AnchorNotMatching {
+                            txid,
+                            anchor,
+                            checkpoint_hash,
+                        })
+                    }
+                    _ => {}
+                }
+            }
+        }
+        for (&height, &checkpoint_hash) in update.checkpoints.range(..invalid_lb) {
+            let original_txs = self
+                .range_txids_by_height(TxHeight::Confirmed(height)..=TxHeight::Confirmed(height))
+                .filter(|(_, txid)| !update.txid_to_pos.contains_key(txid));
+            for (original_pos, txid) in original_txs {
+                if let Some(anchor) = original_pos.anchor_block() {
+                    if anchor.hash != checkpoint_hash {
+                        return Err(UpdateError::AnchorNotMatching {
+                            txid: *txid,
+                            anchor,
+                            checkpoint_hash,
+                        });
+                    }
+                }
+            }
+        }
+
         // create initial change-set, based on checkpoints and txids that are to be "invalidated"
         let mut changeset = invalid_from
             .map(|from_height| self.invalidate_checkpoints_preview(from_height))
@@ -493,6 +636,16 @@ impl<P: ChainPosition> SparseChain<P> {
         for (txid, new_pos) in &update.txid_to_pos {
             let original_pos = self.txid_to_pos.get(txid).cloned();
 
+            let new_pos = &match &original_pos {
+                Some(original_pos)
+                    if original_pos.height() == TxHeight::Unconfirmed
+                        && new_pos.height() == TxHeight::Unconfirmed =>
+                {
+                    original_pos.merge_unconfirmed(new_pos)
+                }
+                _ => new_pos.clone(),
+            };
+
             let update_pos = changeset
                 .txids
                 .entry(*txid)
@@ -504,6 +657,7 @@ impl<P: ChainPosition> SparseChain<P> {
             }
         }
 
+        self.retain_checkpoints(&mut changeset);
         Ok(changeset)
     }
 
@@ -540,7 +694,7 @@ impl<P: ChainPosition> SparseChain<P> {
             }
         }
 
-        self.prune_checkpoints();
+        self.enforce_checkpoint_limit();
     }
 
     /// Derives a [`ChangeSet`] that assumes that there are no preceding changesets.
@@ -574,7 +728,7 @@ impl<P: ChainPosition> SparseChain<P> {
             // invalidated transactions become unconfirmed
             txids: self
                 .range_txids_by_height(TxHeight::Confirmed(from_height)..TxHeight::Unconfirmed)
-                .map(|(_, txid)| (*txid, Some(P::max_ord_of_height(TxHeight::Unconfirmed))))
+                .map(|(_, txid)| (*txid, Some(P::unconfirmed())))
                 .collect(),
         }
     }
@@ -625,6 +779,50 @@ impl<P: ChainPosition> SparseChain<P> {
         changeset
     }
 
+    /// Determines the [`ChangeSet`] when unconfirmed transactions that have expired at `now` (a
+    /// UNIX timestamp in seconds) are removed.
+    ///
+    /// A transaction expires when it was last seen (refer to [`ChainPosition::last_seen`]) more
+    /// than [`unconfirmed_expiry`] seconds before `now`. Transactions without a last-seen time never
+    /// expire.
+    ///
+    /// [`unconfirmed_expiry`]: Self::unconfirmed_expiry
+    pub fn expire_unconfirmed_preview(&self, now: u64) -> ChangeSet<P> {
+        let mempool_range = &(
+            P::min_ord_of_height(TxHeight::Unconfirmed),
+            Txid::all_zeros(),
+        )..;
+
+        let txids = self
+            .ordered_txids
+            .range(mempool_range)
+            .filter(|(pos, _)| {
+                pos.last_seen().is_some_and(|last_seen| {
+                    last_seen.saturating_add(self.unconfirmed_expiry) < now
+                })
+            })
+            .map(|(_, txid)| (*txid, None))
+            .collect();
+
+        ChangeSet::<P> {
+            txids,
+            ..Default::default()
+        }
+    }
+
+    /// Removes unconfirmed transactions that have expired at `now` (a UNIX timestamp in seconds).
+    ///
+    /// This is equivalent to calling [`expire_unconfirmed_preview`] and [`apply_changeset`] in
+    /// sequence.
+    ///
+    /// [`expire_unconfirmed_preview`]: Self::expire_unconfirmed_preview
+    /// [`apply_changeset`]: Self::apply_changeset
+    pub fn expire_unconfirmed(&mut self, now: u64) -> ChangeSet<P> {
+        let changeset = self.expire_unconfirmed_preview(now);
+        self.apply_changeset(changeset.clone());
+        changeset
+    }
+
     /// Determines the resultant [`ChangeSet`] if [`Txid`] was inserted at position `pos`.
     ///
     /// Changes to the [`Txid`]'s position is allowed (under the rules noted in
@@ -668,6 +866,15 @@ impl<P: ChainPosition> SparseChain<P> {
                 original_pos,
                 update_pos,
             }),
+            Err(UpdateError::AnchorNotMatching {
+                txid,
+                anchor,
+                checkpoint_hash,
+            }) => Err(InsertTxError::AnchorNotMatching {
+                txid,
+                anchor,
+                checkpoint_hash,
+            }),
         }
     }
 
@@ -712,6 +919,16 @@ impl<P: ChainPosition> SparseChain<P> {
             Ok(changeset) => Ok(changeset),
             Err(UpdateError::NotConnected(_)) => panic!("error should have caught above"),
             Err(UpdateError::TxInconsistent { .. }) => panic!("should never add txs"),
+            // an existing transaction is anchored to a different block of the same height
+            Err(UpdateError::AnchorNotMatching {
+                anchor,
+                checkpoint_hash,
+                ..
+            }) => Err(InsertCheckpointError::HashNotMatching {
+                height: anchor.height,
+                original_hash: anchor.hash,
+                update_hash: checkpoint_hash,
+            }),
         }
     }
 
@@ -825,6 +1042,16 @@ impl<P: ChainPosition> SparseChain<P> {
         })
     }
 
+    /// Returns the number of confirmations of `txid` according to the latest checkpoint.
+    ///
+    /// This returns `Some(0)` if the transaction is unconfirmed and [`None`] if the transaction
+    /// does not exist in the chain.
+    pub fn confirmations(&self, txid: Txid) -> Option<u32> {
+        let tip_height = self.latest_checkpoint().map_or(0, |cp| cp.height);
+        self.tx_position(txid)
+            .map(|pos| pos.height().confirmations(tip_height))
+    }
+
     /// Returns the value set as the checkpoint limit.
     ///
     /// Refer to [`set_checkpoint_limit`].
@@ -840,7 +1067,69 @@ impl<P: ChainPosition> SparseChain<P> {
     /// Oldest checkpoints are pruned first.
     pub fn set_checkpoint_limit(&mut self, limit: Option<usize>) {
         self.checkpoint_limit = limit;
-        self.prune_checkpoints();
+        self.enforce_checkpoint_limit();
+    }
+
+    /// Returns the checkpoint retention policy.
+    ///
+    /// Refer to [`set_checkpoint_retention`].
+    ///
+    /// [`set_checkpoint_retention`]: Self::set_checkpoint_retention
+    pub fn checkpoint_retention(&self) -> Option<CheckpointRetention> {
+        self.checkpoint_retention
+    }
+
+    /// Set the checkpoint retention policy.
+    ///
+    /// Unlike the checkpoint limit, checkpoints pruned by the retention policy are recorded in
+    /// the [`ChangeSet`] returned by [`determine_changeset`] (and methods based on it). Setting
+    /// the policy does not prune existing checkpoints until [`prune_checkpoints`] is called or an
+    /// update is applied.
+    ///
+    /// [`determine_changeset`]: Self::determine_changeset
+    /// [`prune_checkpoints`]: Self::prune_checkpoints
+    pub fn set_checkpoint_retention(&mut self, retention: Option<CheckpointRetention>) {
+        self.checkpoint_retention = retention;
+    }
+
+    /// Determines the [`ChangeSet`] that prunes the checkpoints which should not be kept according
+    /// to the checkpoint retention policy.
+    ///
+    /// Refer to [`CheckpointRetention`] for more.
+    pub fn prune_checkpoints_preview(&self) -> ChangeSet<P> {
+        let mut changeset = ChangeSet::default();
+        self.retain_checkpoints(&mut changeset);
+        changeset
+    }
+
+    /// Prunes checkpoints which should not be kept according to the checkpoint retention policy.
+    ///
+    /// This is equivalent to calling [`prune_checkpoints_preview`] and [`apply_changeset`] in
+    /// sequence.
+    ///
+    /// [`prune_checkpoints_preview`]: Self::prune_checkpoints_preview
+    /// [`apply_changeset`]: Self::apply_changeset
+    pub fn prune_checkpoints(&mut self) -> ChangeSet<P> {
+        let changeset = self.prune_checkpoints_preview();
+        self.apply_changeset(changeset.clone());
+        changeset
+    }
+
+    /// Returns the number of seconds after an unconfirmed transaction was last seen before it
+    /// expires.
+    ///
+    /// Refer to [`expire_unconfirmed_preview`].
+    ///
+    /// [`expire_unconfirmed_preview`]: Self::expire_unconfirmed_preview
+    pub fn unconfirmed_expiry(&self) -> u64 {
+        self.unconfirmed_expiry
+    }
+
+    /// Set the number of seconds after an unconfirmed transaction was last seen before it expires.
+    ///
+    /// The default is [`DEFAULT_UNCONFIRMED_EXPIRY`].
+    pub fn set_unconfirmed_expiry(&mut self, expiry: u64) {
+        self.unconfirmed_expiry = expiry;
     }
 
     /// Return [`Txid`]s that would be added to the sparse chain if this `changeset` was applied.
@@ -858,7 +1147,45 @@ impl<P: ChainPosition> SparseChain<P> {
             .map(|(&txid, _)| txid)
     }
 
-    fn prune_checkpoints(&mut self) -> Option<BTreeMap<u32, BlockHash>> {
+    /// Modify `changeset` so that the resultant chain only has the checkpoints that the retention
+    /// policy keeps.
+    fn retain_checkpoints(&self, changeset: &mut ChangeSet<P>) {
+        let retention = match &self.checkpoint_retention {
+            Some(retention) => retention,
+            None => return,
+        };
+
+        let mut resultant_checkpoints = self.checkpoints.keys().copied().collect::<BTreeSet<_>>();
+        for (&height, change) in &changeset.checkpoints {
+            match change {
+                Some(_) => resultant_checkpoints.insert(height),
+                None => resultant_checkpoints.remove(&height),
+            };
+        }
+        let resultant_checkpoints = resultant_checkpoints.into_iter().collect::<Vec<_>>();
+
+        let tx_heights = self
+            .txid_to_pos
+            .iter()
+            .filter(|(txid, _)| !changeset.txids.contains_key(*txid))
+            .map(|(_, pos)| pos)
+            .chain(changeset.txids.values().flatten())
+            .filter_map(|pos| match pos.height() {
+                TxHeight::Confirmed(height) => Some(height),
+                TxHeight::Unconfirmed => None,
+            })
+            .collect::<BTreeSet<_>>();
+
+        for height in retention.heights_to_prune(&resultant_checkpoints, &tx_heights) {
+            if self.checkpoints.contains_key(&height) {
+                changeset.checkpoints.insert(height, None);
+            } else {
+                changeset.checkpoints.remove(&height);
+            }
+        }
+    }
+
+    fn enforce_checkpoint_limit(&mut self) -> Option<BTreeMap<u32, BlockHash>> {
         let limit = self.checkpoint_limit?;
 
         // find last height to be pruned
@@ -959,11 +1286,33 @@ pub trait ChainPosition:
     fn unconfirmed() -> Self {
         Self::max_ord_of_height(TxHeight::Unconfirmed)
     }
+
+    /// Get the last time an unconfirmed position was seen (if known).
+    fn last_seen(&self) -> Option<u64> {
+        None
+    }
+
+    /// Determine the resultant position when an unconfirmed position (`self`) is updated with
+    /// another unconfirmed position (`update`).
+    ///
+    /// By default, the `update` position replaces the original.
+    fn merge_unconfirmed(&self, update: &Self) -> Self {
+        update.clone()
+    }
+
+    /// Get the block that the position is anchored to (if any).
+    ///
+    /// [`SparseChain`] ensures that anchored positions agree with its checkpoints.
+    fn anchor_block(&self) -> Option<BlockId> {
+        None
+    }
 }
 
 #[cfg(test)]
 pub mod verify_chain_position {
-    use crate::{sparse_chain::ChainPosition, ConfirmationTime, TxHeight};
+    use crate::{
+        sparse_chain::ChainPosition, AnchoredPosition, ConfirmationTime, ObservedPosition, TxHeight,
+    };
     use alloc::vec::Vec;
 
     pub fn verify_chain_position<P: ChainPosition>(head_count: u32, tail_count: u32) {
@@ -1005,4 +1354,14 @@ pub mod verify_chain_position {
     fn verify_confirmation_time() {
         verify_chain_position::<ConfirmationTime>(1000, 1000);
     }
+
+    #[test]
+    fn verify_anchored_position() {
+        verify_chain_position::<AnchoredPosition>(1000, 1000);
+    }
+
+    #[test]
+    fn verify_observed_position() {
+        verify_chain_position::<ObservedPosition>(1000, 1000);
+    }
 }
@@ -0,0 +1,341 @@
+//! Module for verifying transaction confirmations with merkle proofs (SPV).
+//!
+//! Chain sources tell us the height of the block in which a transaction is confirmed, but a buggy
+//! or malicious source can report confirmations that never happened. A [`TxMerkleProof`] together
+//! with the [`BlockHeader`] it commits to (a [`ConfirmationProof`]) lets us check a confirmation
+//! without trusting the source.
+//!
+//! The expected flow is:
+//!
+//! 1. Obtain an update [`SparseChain`] from a chain source.
+//! 2. Determine which confirmations need verification with [`txids_to_verify`] (confirmations that
+//!    are already in the local chain do not need to be verified again).
+//! 3. Fetch a [`ConfirmationProof`] for each of these from the chain source.
+//! 4. Call [`verify_update`]. The resultant [`VerifiedUpdate`] only has confirmed positions for
+//!    transactions with a valid proof. Transactions with unverified confirmations are repositioned
+//!    as unconfirmed and are reported in [`VerifiedUpdate::unverified`].
+//!
+//! The header of a proof is untrusted (as is the update it came with), so it is only accepted if it
+//! is anchored to something we trust (a [`TrustAnchor`]). This is the checkpoint of the local chain
+//! at the confirmation height or, for heights the local chain has no checkpoint at, a maximum
+//! target (so that the chain source must spend real work to forge a header).
+use crate::{
+    collections::BTreeMap,
+    sparse_chain::{ChainPosition, SparseChain},
+    TxHeight,
+};
+use alloc::vec::Vec;
+use bitcoin::{
+    hashes::{Hash, HashEngine},
+    util::uint::Uint256,
+    BlockHash, BlockHeader, TxMerkleNode, Txid,
+};
+
+/// A merkle branch which proves that a transaction is included in a block.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(crate = "serde_crate")
+)]
+pub struct TxMerkleProof {
+    /// Height of the block which the proof is for.
+    pub block_height: u32,
+    /// Position of the transaction in the block.
+    pub pos: usize,
+    /// Sibling hashes from the transaction (leaf) up to (but not including) the merkle root.
+    pub merkle: Vec<TxMerkleNode>,
+}
+
+impl TxMerkleProof {
+    /// Compute the merkle root that this proof commits `txid` to.
+    pub fn merkle_root(&self, txid: Txid) -> TxMerkleNode {
+        let mut pos = self.pos;
+        let mut node = TxMerkleNode::from_inner(txid.into_inner());
+
+        for sibling in &self.merkle {
+            let (left, right) = if pos & 1 == 0 {
+                (&node, sibling)
+            } else {
+                (sibling, &node)
+            };
+            let mut engine = TxMerkleNode::engine();
+            engine.input(&left[..]);
+            engine.input(&right[..]);
+            node = TxMerkleNode::from_engine(engine);
+            pos >>= 1;
+        }
+
+        node
+    }
+
+    /// Whether this proof shows that `txid` is included in the block of `header`.
+    ///
+    /// The proof is rejected if `pos` does not fit in the depth of the branch (the higher bits
+    /// would be ignored when computing the merkle root).
+    pub fn verify(&self, txid: Txid, header: &BlockHeader) -> bool {
+        let pos_fits = self
+            .pos
+            .checked_shr(self.merkle.len() as u32)
+            .is_none_or(|rest| rest == 0);
+        pos_fits && self.merkle_root(txid) == header.merkle_root
+    }
+}
+
+/// A [`TxMerkleProof`] alongside the header of the block that the proof is for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(crate = "serde_crate")
+)]
+pub struct ConfirmationProof {
+    /// The header of the block which confirms the transaction.
+    pub header: BlockHeader,
+    /// The merkle branch of the transaction.
+    pub merkle_proof: TxMerkleProof,
+}
+
+/// What the header of a [`ConfirmationProof`] is trusted through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrustAnchor {
+    /// The header must hash to this (trusted) block hash.
+    Checkpoint(BlockHash),
+    /// The header must satisfy proof-of-work of a target that is no easier than this.
+    ///
+    /// This should be derived from a trusted header chain (e.g. the target of a recent block of
+    /// the local chain) and never from the update that is being verified.
+    MaxTarget(Uint256),
+}
+
+impl ConfirmationProof {
+    /// Verify that `txid` is confirmed at `height`.
+    ///
+    /// The header is checked against the trusted `anchor`. Without an anchor, the header cannot be
+    /// trusted so verification fails with [`VerificationFailure::NoTrustedAnchor`].
+    pub fn verify(
+        &self,
+        txid: Txid,
+        height: u32,
+        anchor: Option<TrustAnchor>,
+    ) -> Result<(), VerificationFailure> {
+        if self.merkle_proof.block_height != height {
+            return Err(VerificationFailure::HeightMismatch {
+                proof_height: self.merkle_proof.block_height,
+            });
+        }
+
+        let header_hash = self
+            .header
+            .validate_pow(&self.header.target())
+            .map_err(|_| VerificationFailure::InvalidProofOfWork)?;
+
+        match anchor {
+            Some(TrustAnchor::Checkpoint(checkpoint_hash)) => {
+                if checkpoint_hash != header_hash {
+                    return Err(VerificationFailure::HeaderNotInChain {
+                        checkpoint_hash,
+                        header_hash,
+                    });
+                }
+            }
+            Some(TrustAnchor::MaxTarget(max_target)) => {
+                if self.header.target() > max_target {
+                    return Err(VerificationFailure::InsufficientWork);
+                }
+            }
+            None => return Err(VerificationFailure::NoTrustedAnchor),
+        }
+
+        if !self.merkle_proof.verify(txid, &self.header) {
+            return Err(VerificationFailure::MerkleRootMismatch);
+        }
+
+        Ok(())
+    }
+}
+
+/// The reason why a confirmation could not be verified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerificationFailure {
+    /// No [`ConfirmationProof`] was provided for the transaction.
+    MissingProof,
+    /// The proof is for a block at a different height than the one the transaction is claimed to
+    /// be confirmed at.
+    HeightMismatch { proof_height: u32 },
+    /// The block header does not satisfy the proof-of-work target that it commits to.
+    InvalidProofOfWork,
+    /// The block header is not anchored to anything we trust (there is no checkpoint at its
+    /// height in the local chain and no maximum target was provided).
+    NoTrustedAnchor,
+    /// The block header's target is easier than the maximum target of its [`TrustAnchor`].
+    InsufficientWork,
+    /// The block header does not hash to the checkpoint of the same height.
+    HeaderNotInChain {
+        checkpoint_hash: BlockHash,
+        header_hash: BlockHash,
+    },
+    /// The merkle branch does not connect the transaction to the header's merkle root.
+    MerkleRootMismatch,
+}
+
+impl core::fmt::Display for VerificationFailure {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::MissingProof => write!(f, "no confirmation proof was provided"),
+            Self::HeightMismatch { proof_height } => write!(
+                f,
+                "confirmation proof is for a block at a different height ({})",
+                proof_height
+            ),
+            Self::InvalidProofOfWork => write!(f, "block header has invalid proof-of-work"),
+            Self::NoTrustedAnchor => write!(f, "block header is not anchored to a trusted block"),
+            Self::InsufficientWork => write!(f, "block header has insufficient proof-of-work"),
+            Self::HeaderNotInChain {
+                checkpoint_hash,
+                header_hash,
+            } => write!(
+                f,
+                "block header ({}) does not match checkpoint ({})",
+                header_hash, checkpoint_hash
+            ),
+            Self::MerkleRootMismatch => write!(f, "merkle proof does not match block header"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for VerificationFailure {}
+
+/// The result of [`verify_update`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifiedUpdate<P> {
+    /// The update where all newly confirmed transactions have been verified.
+    pub update: SparseChain<P>,
+    /// Transactions with confirmations that could not be verified, alongside the position reported
+    /// by the chain source and the reason of failure. These are unconfirmed in [`Self::update`].
+    pub unverified: BTreeMap<Txid, (P, VerificationFailure)>,
+}
+
+/// Returns the confirmed transactions of `update` (alongside their confirmation heights) that need
+/// to be verified before `update` is applied to `chain`.
+///
+/// Confirmations that `chain` already has are skipped unless `update` invalidates the block they
+/// are in.
+pub fn txids_to_verify<'a, P1, P2>(
+    chain: &'a SparseChain<P1>,
+    update: &'a SparseChain<P2>,
+) -> impl Iterator<Item = (u32, Txid)> + 'a
+where
+    P1: ChainPosition,
+    P2: ChainPosition,
+{
+    let invalid_from = invalidated_height(chain, update);
+
+    update
+        .range_txids_by_height(..TxHeight::Unconfirmed)
+        .filter_map(move |(pos, txid)| {
+            let height = match pos.height() {
+                TxHeight::Confirmed(height) => height,
+                TxHeight::Unconfirmed => return None,
+            };
+            let is_known = height < invalid_from
+                && chain.tx_position(*txid).map(ChainPosition::height) == Some(pos.height());
+            if is_known {
+                None
+            } else {
+                Some((height, *txid))
+            }
+        })
+}
+
+/// Verify the confirmations of `update` with the provided `proofs`.
+///
+/// Only confirmations returned by [`txids_to_verify`] are checked. The header of each proof must
+/// match the checkpoint of `chain` at the same height or (if `chain` has no checkpoint there that
+/// `update` keeps) have a target no easier than `max_target`. A header that conflicts with a
+/// checkpoint of `update` is rejected too. Transactions which fail verification are repositioned
+/// to [`ChainPosition::unconfirmed`].
+///
+/// Refer to [module-level documentation] for more.
+///
+/// [module-level documentation]: crate::spv
+pub fn verify_update<P1, P2>(
+    chain: &SparseChain<P1>,
+    update: &SparseChain<P2>,
+    proofs: &BTreeMap<Txid, ConfirmationProof>,
+    max_target: Option<Uint256>,
+) -> VerifiedUpdate<P2>
+where
+    P1: ChainPosition,
+    P2: ChainPosition,
+{
+    let invalid_from = invalidated_height(chain, update);
+    let unverified = txids_to_verify(chain, update)
+        .filter_map(|(height, txid)| {
+            let result = match proofs.get(&txid) {
+                Some(proof) => {
+                    let header_hash = proof.header.block_hash();
+                    match update.checkpoint_at(height) {
+                        Some(cp) if cp.hash != header_hash => {
+                            Err(VerificationFailure::HeaderNotInChain {
+                                checkpoint_hash: cp.hash,
+                                header_hash,
+                            })
+                        }
+                        _ => {
+                            // checkpoints that the update invalidates cannot anchor the header
+                            let anchor = chain
+                                .checkpoint_at(height)
+                                .filter(|_| height < invalid_from)
+                                .map(|cp| TrustAnchor::Checkpoint(cp.hash))
+                                .or(max_target.map(TrustAnchor::MaxTarget));
+                            proof.verify(txid, height, anchor)
+                        }
+                    }
+                }
+                None => Err(VerificationFailure::MissingProof),
+            };
+            let pos = update.tx_position(txid).expect("must exist").clone();
+            result.err().map(|failure| (txid, (pos, failure)))
+        })
+        .collect::<BTreeMap<_, _>>();
+
+    let mut verified = SparseChain::from_checkpoints(update.range_checkpoints(..));
+    for (pos, txid) in update.txids() {
+        let pos = if unverified.contains_key(txid) {
+            P2::unconfirmed()
+        } else {
+            pos.clone()
+        };
+        let _ = verified
+            .insert_tx(*txid, pos)
+            .expect("must insert as positions are from a consistent update");
+    }
+
+    VerifiedUpdate {
+        update: verified,
+        unverified,
+    }
+}
+
+/// The lowest height of `chain` that will be invalidated by `update` (or [`u32::MAX`] if there is
+/// no invalidation).
+fn invalidated_height<P1: ChainPosition, P2: ChainPosition>(
+    chain: &SparseChain<P1>,
+    update: &SparseChain<P2>,
+) -> u32 {
+    let agreement_point = update
+        .checkpoints()
+        .iter()
+        .rev()
+        .find(|&(height, hash)| chain.checkpoints().get(height) == Some(hash))
+        .map(|(&h, _)| h);
+
+    match update.latest_checkpoint() {
+        Some(last_update_cp) if Some(last_update_cp.height) != agreement_point => {
+            agreement_point.map(|h| h + 1).unwrap_or(0)
+        }
+        _ => u32::MAX,
+    }
+}
@@ -189,6 +189,36 @@ impl<T: AsTransaction> TxGraph<T> {
 
         Some(inputs_sum - outputs_sum)
     }
+
+    /// Returns the outpoints spent by `tx` that we do not have the `TxOut` of in the graph.
+    ///
+    /// These are the outputs that need to be fetched (and inserted with [`insert_txout`]) for
+    /// [`calculate_fee`] to work on `tx`. Coinbase transactions have no missing prevouts.
+    ///
+    /// [`insert_txout`]: Self::insert_txout
+    /// [`calculate_fee`]: Self::calculate_fee
+    pub fn missing_prevouts_of<'a>(
+        &'a self,
+        tx: &'a Transaction,
+    ) -> impl Iterator<Item = OutPoint> + 'a {
+        tx.input
+            .iter()
+            .filter(move |_| !tx.is_coin_base())
+            .map(|txin| txin.previous_output)
+            .filter(move |&op| self.get_txout(op).is_none())
+    }
+
+    /// Returns the outpoints spent by the full transactions of the graph that we do not have the
+    /// `TxOut` of.
+    ///
+    /// Refer to [`missing_prevouts_of`] for more.
+    ///
+    /// [`missing_prevouts_of`]: Self::missing_prevouts_of
+    pub fn missing_prevouts(&self) -> BTreeSet<OutPoint> {
+        self.full_transactions()
+            .flat_map(|tx| self.missing_prevouts_of(tx.as_tx()))
+            .collect()
+    }
 }
 
 impl<T: AsTransaction + Ord + Clone> TxGraph<T> {
@@ -58,3 +58,33 @@ pub fn new_tx(lt: u32) -> bitcoin::Transaction {
         output: vec![],
     }
 }
+
+/// The fixed (origin prefixed) test xprv that the keychains of [`tr_descriptor`] derive from.
+#[allow(unused)]
+pub const TEST_XPRV: &str = "[73c5da0a/86'/0'/0']xprv9xgqHN7yz9MwCkxsBPN5qetuNdQSUttZNKw1dcYTV4mkaAFiBVGQziHs3NRSWMkCzvgjEe3n9xV8oYywvM8at9yRqyaZVz6TYYhX98VjsUk";
+
+/// The taproot descriptor of the `/<keychain>/*` branch of [`TEST_XPRV`].
+#[cfg(feature = "miniscript")]
+#[allow(unused)]
+pub fn tr_descriptor(
+    keychain: u32,
+) -> bdk_chain::miniscript::Descriptor<bdk_chain::miniscript::DescriptorPublicKey> {
+    let secp = bitcoin::secp256k1::Secp256k1::signing_only();
+    let descriptor = format!("tr({}/{}/*)", TEST_XPRV, keychain);
+    let (descriptor, _) =
+        bdk_chain::miniscript::Descriptor::parse_descriptor(&secp, &descriptor).unwrap();
+    descriptor
+}
+
+/// A tracker with the single keychain `()` of [`tr_descriptor`]`(0)`, which is also returned.
+#[cfg(feature = "miniscript")]
+#[allow(unused)]
+pub fn single_keychain_tracker<P: bdk_chain::sparse_chain::ChainPosition>() -> (
+    bdk_chain::keychain::KeychainTracker<(), P>,
+    bdk_chain::miniscript::Descriptor<bdk_chain::miniscript::DescriptorPublicKey>,
+) {
+    let descriptor = tr_descriptor(0);
+    let mut tracker = bdk_chain::keychain::KeychainTracker::default();
+    tracker.add_keychain((), descriptor.clone());
+    (tracker, descriptor)
+}
@@ -6,7 +6,7 @@ use bdk_chain::{
     collections::HashSet,
     sparse_chain,
     tx_graph::{self, TxGraph},
-    BlockId, TxHeight,
+    BlockId, ObservedPosition, TxHeight,
 };
 use bitcoin::{OutPoint, PackedLockTime, Script, Sequence, Transaction, TxIn, TxOut, Witness};
 
@@ -137,6 +137,14 @@ fn update_evicts_conflicting_tx() {
                 tx: [tx_b2.clone()].into(),
                 txout: [].into(),
             },
+            evicted: [(
+                tx_b.txid(),
+                Eviction {
+                    by: tx_b2.txid(),
+                    reason: EvictionReason::ReplacedByUpdate,
+                },
+            )]
+            .into(),
         };
         assert_eq!(
             cg1.determine_changeset(&cg2),
@@ -216,6 +224,14 @@ fn update_evicts_conflicting_tx() {
                 tx: [tx_b2.clone()].into(),
                 txout: [].into(),
             },
+            evicted: [(
+                tx_b.txid(),
+                Eviction {
+                    by: tx_b2.txid(),
+                    reason: EvictionReason::ReplacedByUpdate,
+                },
+            )]
+            .into(),
         };
         assert_eq!(
             cg1.determine_changeset(&cg2),
@@ -486,6 +502,14 @@ fn test_apply_changes_reintroduce_tx() {
                 tx: [tx2b.clone()].into(),
                 ..Default::default()
             },
+            evicted: [(
+                tx2a.txid(),
+                Eviction {
+                    by: tx2b.txid(),
+                    reason: EvictionReason::ConflictConfirmed,
+                },
+            )]
+            .into(),
         }
     );
 
@@ -506,6 +530,14 @@ fn test_apply_changes_reintroduce_tx() {
                 checkpoints: [(2, Some(block2c.hash))],
                 txids: [(tx2b.txid(), None), (tx2a.txid(), Some(TxHeight::Confirmed(2)))]
             },
+            evicted: [(
+                tx2b.txid(),
+                Eviction {
+                    by: tx2a.txid(),
+                    reason: EvictionReason::ConflictConfirmed,
+                },
+            )]
+            .into(),
             ..Default::default()
         }
     );
@@ -642,7 +674,22 @@ fn test_evict_descendants() {
             graph: tx_graph::Additions {
                 tx: [tx_conflict.clone()].into(),
                 ..Default::default()
-            }
+            },
+            evicted: [
+                (txid_2, EvictionReason::ConflictConfirmed),
+                (txid_3, EvictionReason::AncestorEvicted { ancestor: txid_2 }),
+                (txid_4, EvictionReason::AncestorEvicted { ancestor: txid_2 }),
+                (txid_5, EvictionReason::AncestorEvicted { ancestor: txid_2 }),
+            ]
+            .into_iter()
+            .map(|(txid, reason)| (
+                txid,
+                Eviction {
+                    by: txid_conflict,
+                    reason,
+                }
+            ))
+            .collect(),
         })
     );
 
@@ -651,3 +698,270 @@ fn test_evict_descendants() {
         .expect_err("must fail due to conflicts");
     assert!(matches!(err, InsertTxError::UnresolvableConflict(_)));
 }
+
+#[test]
+fn conflict_policy_decides_which_tx_is_kept() {
+    let tx_parent = Transaction {
+        output: vec![TxOut {
+            value: 100_000,
+            script_pubkey: Script::new(),
+        }],
+        ..common::new_tx(0)
+    };
+    let spend_parent = |value: u64, lt: u32| Transaction {
+        input: vec![TxIn {
+            previous_output: OutPoint::new(tx_parent.txid(), 0),
+            ..Default::default()
+        }],
+        output: vec![TxOut {
+            value,
+            script_pubkey: Script::new(),
+        }],
+        ..common::new_tx(lt)
+    };
+    // both txs are the same size but `tx_high` pays the higher fee
+    let tx_low = spend_parent(99_000, 1);
+    let tx_high = spend_parent(90_000, 2);
+
+    let chain_graph_with = |tx: &Transaction, pos: ObservedPosition, policy: ConflictPolicy| {
+        let mut cg = ChainGraph::<ObservedPosition>::default();
+        cg.set_conflict_policy(policy);
+        let _ = cg.insert_checkpoint(BlockId {
+            height: 1,
+            hash: h!("A"),
+        });
+        let _ = cg
+            .insert_tx(
+                tx_parent.clone(),
+                ObservedPosition::Confirmed { height: 1, time: 1 },
+            )
+            .expect("should insert parent");
+        let _ = cg.insert_tx(tx.clone(), pos).expect("should insert tx");
+        cg
+    };
+    let evicted = |txid, by, reason| [(txid, Eviction { by, reason })].into();
+
+    // the default policy always evicts the existing tx
+    let cg = chain_graph_with(
+        &tx_high,
+        ObservedPosition::seen_at(20),
+        ConflictPolicy::default(),
+    );
+    let changeset = cg
+        .insert_tx_preview(tx_low.clone(), ObservedPosition::seen_at(10))
+        .expect("should resolve conflict");
+    assert_eq!(changeset.chain.txids.get(&tx_high.txid()), Some(&None));
+    assert_eq!(
+        changeset.evicted,
+        evicted(
+            tx_high.txid(),
+            tx_low.txid(),
+            EvictionReason::ReplacedByUpdate
+        )
+    );
+
+    // the tx with the lower feerate is rejected
+    let cg = chain_graph_with(
+        &tx_high,
+        ObservedPosition::seen_at(10),
+        ConflictPolicy::PreferHigherFeerate,
+    );
+    let changeset = cg
+        .insert_tx_preview(tx_low.clone(), ObservedPosition::seen_at(20))
+        .expect("should resolve conflict");
+    assert_eq!(changeset.chain.txids.get(&tx_low.txid()), None);
+    assert_eq!(changeset.chain.txids.get(&tx_high.txid()), None);
+    assert_eq!(
+        changeset.evicted,
+        evicted(tx_low.txid(), tx_high.txid(), EvictionReason::LowerFeerate)
+    );
+    let changeset = chain_graph_with(
+        &tx_low,
+        ObservedPosition::seen_at(10),
+        ConflictPolicy::PreferHigherFeerate,
+    )
+    .insert_tx_preview(tx_high.clone(), ObservedPosition::seen_at(5))
+    .expect("should resolve conflict");
+    assert_eq!(
+        changeset.evicted,
+        evicted(tx_low.txid(), tx_high.txid(), EvictionReason::LowerFeerate)
+    );
+
+    // the tx that was seen earlier is rejected
+    let mut cg = chain_graph_with(
+        &tx_low,
+        ObservedPosition::seen_at(20),
+        ConflictPolicy::PreferLastSeen,
+    );
+    let changeset = cg
+        .insert_tx(tx_high.clone(), ObservedPosition::seen_at(10))
+        .expect("should resolve conflict");
+    assert_eq!(
+        changeset.evicted,
+        evicted(tx_high.txid(), tx_low.txid(), EvictionReason::SeenEarlier)
+    );
+    assert!(cg.chain().tx_position(tx_high.txid()).is_none());
+    assert!(cg.chain().tx_position(tx_low.txid()).is_some());
+
+    // txs we want to keep are never evicted by unconfirmed txs
+    let keep = ConflictPolicy::Keep([tx_low.txid()].into());
+    let changeset = chain_graph_with(&tx_low, ObservedPosition::seen_at(10), keep.clone())
+        .insert_tx_preview(tx_high.clone(), ObservedPosition::seen_at(20))
+        .expect("should resolve conflict");
+    assert_eq!(
+        changeset.evicted,
+        evicted(tx_high.txid(), tx_low.txid(), EvictionReason::ConflictKept)
+    );
+
+    // ... but are evicted by confirmed txs
+    let changeset = chain_graph_with(&tx_low, ObservedPosition::seen_at(10), keep)
+        .insert_tx_preview(
+            tx_high.clone(),
+            ObservedPosition::Confirmed { height: 1, time: 1 },
+        )
+        .expect("should resolve conflict");
+    assert_eq!(
+        changeset.evicted,
+        evicted(
+            tx_low.txid(),
+            tx_high.txid(),
+            EvictionReason::ConflictConfirmed
+        )
+    );
+}
+
+#[test]
+fn eviction_includes_unconfirmed_descendants() {
+    let tx_parent = Transaction {
+        output: vec![TxOut {
+            value: 100_000,
+            script_pubkey: Script::new(),
+        }],
+        ..common::new_tx(0)
+    };
+    let spend = |outpoint: OutPoint, lt: u32| Transaction {
+        input: vec![TxIn {
+            previous_output: outpoint,
+            ..Default::default()
+        }],
+        output: vec![TxOut {
+            value: 90_000,
+            script_pubkey: Script::new(),
+        }],
+        ..common::new_tx(lt)
+    };
+    let tx_a = spend(OutPoint::new(tx_parent.txid(), 0), 1);
+    let tx_a_child = spend(OutPoint::new(tx_a.txid(), 0), 2);
+    let tx_b = spend(OutPoint::new(tx_parent.txid(), 0), 3);
+    let tx_b_child = spend(OutPoint::new(tx_b.txid(), 0), 4);
+
+    let mut cg = ChainGraph::<TxHeight>::default();
+    let _ = cg.insert_checkpoint(BlockId {
+        height: 1,
+        hash: h!("A"),
+    });
+    let _ = cg
+        .insert_tx(tx_parent, TxHeight::Confirmed(1))
+        .expect("should insert parent");
+    let _ = cg
+        .insert_tx(tx_a.clone(), TxHeight::Unconfirmed)
+        .expect("should insert tx");
+    let _ = cg
+        .insert_tx(tx_a_child.clone(), TxHeight::Unconfirmed)
+        .expect("should insert tx");
+
+    let update = {
+        let mut update = ChainGraph::<TxHeight>::default();
+        let _ = update
+            .insert_tx(tx_b.clone(), TxHeight::Unconfirmed)
+            .expect("should insert tx");
+        let _ = update
+            .insert_tx(tx_b_child.clone(), TxHeight::Unconfirmed)
+            .expect("should insert tx");
+        update
+    };
+
+    // the rejected tx takes its descendants (which only exist in the update) with it
+    let mut keep_a = cg.clone();
+    keep_a.set_conflict_policy(ConflictPolicy::Keep([tx_a.txid()].into()));
+    let changeset = keep_a
+        .determine_changeset(&update)
+        .expect("should resolve conflict");
+    assert!(changeset.chain.txids.is_empty());
+    assert_eq!(
+        changeset.evicted,
+        [
+            (
+                tx_b.txid(),
+                Eviction {
+                    by: tx_a.txid(),
+                    reason: EvictionReason::ConflictKept,
+                }
+            ),
+            (
+                tx_b_child.txid(),
+                Eviction {
+                    by: tx_a.txid(),
+                    reason: EvictionReason::AncestorEvicted {
+                        ancestor: tx_b.txid()
+                    },
+                }
+            ),
+        ]
+        .into()
+    );
+
+    // the evicted tx takes its descendants (which are in the chain) with it
+    let changeset = cg.apply_update(update).expect("should resolve conflict");
+    assert_eq!(
+        changeset.chain.txids,
+        [
+            (tx_a.txid(), None),
+            (tx_a_child.txid(), None),
+            (tx_b.txid(), Some(TxHeight::Unconfirmed)),
+            (tx_b_child.txid(), Some(TxHeight::Unconfirmed)),
+        ]
+        .into()
+    );
+    assert_eq!(
+        changeset.evicted[&tx_a_child.txid()],
+        Eviction {
+            by: tx_b.txid(),
+            reason: EvictionReason::AncestorEvicted {
+                ancestor: tx_a.txid()
+            },
+        }
+    );
+    assert!(cg.full_txout(OutPoint::new(tx_a_child.txid(), 0)).is_none());
+    assert!(cg.full_txout(OutPoint::new(tx_b_child.txid(), 0)).is_some());
+}
+
+#[test]
+fn expired_txs_take_descendants_with_them() {
+    let tx_a = common::new_tx(0);
+    let tx_b = Transaction {
+        input: vec![TxIn {
+            previous_output: OutPoint::new(tx_a.txid(), 0),
+            ..Default::default()
+        }],
+        ..common::new_tx(1)
+    };
+
+    let mut cg = ChainGraph::<ObservedPosition>::default();
+    let _ = cg
+        .insert_tx(tx_a.clone(), ObservedPosition::seen_at(0))
+        .expect("should insert tx");
+    // the child was seen recently but can't be valid without its parent
+    let _ = cg
+        .insert_tx(tx_b.clone(), ObservedPosition::seen_at(1_000_000_000))
+        .expect("should insert tx");
+
+    assert!(cg.expire_unconfirmed_preview(1_000_000).is_empty());
+    let changeset = cg.expire_unconfirmed(1_000_000_000);
+    assert_eq!(
+        changeset.chain.txids,
+        [(tx_a.txid(), None), (tx_b.txid(), None)].into()
+    );
+    assert!(cg.chain().is_empty());
+    assert!(cg.graph().get_tx(tx_b.txid()).is_some());
+}
@@ -0,0 +1,186 @@
+#[macro_use]
+mod common;
+
+use bdk_chain::{
+    chain_oracle::{
+        checkpoint_update, ChainOracle, CheckpointUpdateError, CHECKPOINT_UPDATE_ATTEMPTS,
+    },
+    collections::BTreeMap,
+    sparse_chain::SparseChain,
+    Birthday, BlockId, TxHeight,
+};
+use bitcoin::BlockHash;
+use core::{cell::Cell, convert::Infallible};
+
+fn local_chain(checkpoints: &[(u32, BlockHash)]) -> BTreeMap<u32, BlockHash> {
+    checkpoints.iter().cloned().collect()
+}
+
+#[test]
+fn sparse_chain_as_oracle() {
+    let oracle = chain!([1, h!("A")], [2, h!("B")]);
+
+    assert_eq!(
+        oracle.get_tip(),
+        Ok(Some(BlockId {
+            height: 2,
+            hash: h!("B")
+        }))
+    );
+    assert_eq!(oracle.get_block_hash(1), Ok(Some(h!("A"))));
+    assert_eq!(oracle.get_block_hash(3), Ok(None));
+    assert_eq!(
+        oracle.is_block_in_best_chain((1, h!("A")).into()),
+        Ok(Some(true))
+    );
+    assert_eq!(
+        oracle.is_block_in_best_chain((1, h!("A'")).into()),
+        Ok(Some(false))
+    );
+    assert_eq!(oracle.is_block_in_best_chain((5, h!("E")).into()), Ok(None));
+}
+
+#[test]
+fn checkpoint_update_extends_local_chain() {
+    let oracle = chain!([1, h!("A")], [2, h!("B")], [3, h!("C")], [4, h!("D")]);
+    let local = local_chain(&[(1, h!("A")), (2, h!("B"))]);
+
+    let update = checkpoint_update::<TxHeight, _>(&oracle, &local).unwrap();
+    assert_eq!(update, chain!([2, h!("B")], [4, h!("D")]));
+
+    let mut chain = SparseChain::<TxHeight>::from_checkpoints(
+        local
+            .iter()
+            .map(|(&height, &hash)| BlockId { height, hash }),
+    );
+    let _ = chain.apply_update(update).expect("update must connect");
+    assert_eq!(chain.latest_checkpoint(), oracle.latest_checkpoint());
+}
+
+#[test]
+fn checkpoint_update_invalidates_reorged_blocks() {
+    let oracle: BTreeMap<u32, BlockHash> =
+        local_chain(&[(1, h!("A")), (2, h!("B'")), (3, h!("C'"))]);
+    let local = local_chain(&[(1, h!("A")), (2, h!("B")), (3, h!("C"))]);
+
+    let update = checkpoint_update::<TxHeight, _>(&oracle, &local).unwrap();
+    assert_eq!(update, chain!([1, h!("A")], [2, h!("B'")], [3, h!("C'")]));
+
+    let chain = SparseChain::<TxHeight>::from_checkpoints(
+        local
+            .iter()
+            .map(|(&height, &hash)| BlockId { height, hash }),
+    );
+    let changeset = chain
+        .determine_changeset(&update)
+        .expect("update must connect");
+    assert_eq!(
+        changeset,
+        changeset!(checkpoints: [(2, Some(h!("B'"))), (3, Some(h!("C'")))])
+    );
+}
+
+#[test]
+fn checkpoint_update_of_empty_local_chain() {
+    let oracle = chain!([1, h!("A")], [2, h!("B")]);
+    let update = checkpoint_update::<TxHeight, _>(&oracle, &BTreeMap::new()).unwrap();
+    assert_eq!(update, chain!([2, h!("B")]));
+
+    let empty_oracle = SparseChain::<TxHeight>::default();
+    let update = checkpoint_update::<TxHeight, _>(&empty_oracle, &BTreeMap::new()).unwrap();
+    assert!(update.is_empty());
+}
+
+/// An oracle which reorgs block 2 every time its tip is asked for (until `reorgs` run out).
+struct ReorgingOracle {
+    reorgs: Cell<usize>,
+}
+
+impl ReorgingOracle {
+    fn block_2(&self) -> BlockHash {
+        bitcoin::hashes::Hash::hash(&self.reorgs.get().to_le_bytes())
+    }
+}
+
+impl ChainOracle for ReorgingOracle {
+    type Error = Infallible;
+
+    fn get_tip(&self) -> Result<Option<BlockId>, Self::Error> {
+        self.reorgs.set(self.reorgs.get().saturating_sub(1));
+        Ok(Some(BlockId {
+            height: 2,
+            hash: self.block_2(),
+        }))
+    }
+
+    fn get_block_hash(&self, height: u32) -> Result<Option<BlockHash>, Self::Error> {
+        Ok(match height {
+            1 => Some(h!("A")),
+            2 => Some(self.block_2()),
+            _ => None,
+        })
+    }
+}
+
+#[test]
+fn checkpoint_update_retries_reorgs() {
+    let local = local_chain(&[(1, h!("A")), (2, h!("B"))]);
+
+    let oracle = ReorgingOracle {
+        reorgs: Cell::new(3),
+    };
+    let update = checkpoint_update::<TxHeight, _>(&oracle, &local).unwrap();
+    let block_2 = bitcoin::hashes::Hash::hash(&0_usize.to_le_bytes());
+    assert_eq!(update, chain!([1, h!("A")], [2, block_2]));
+
+    // a tip that never stops changing must not keep us spinning forever
+    let oracle = ReorgingOracle {
+        reorgs: Cell::new(usize::MAX),
+    };
+    assert_eq!(
+        checkpoint_update::<TxHeight, _>(&oracle, &local),
+        Err(CheckpointUpdateError::UnstableTip)
+    );
+    assert_eq!(oracle.reorgs.get(), usize::MAX - CHECKPOINT_UPDATE_ATTEMPTS);
+}
+
+#[test]
+fn birthday_start_height() {
+    // block times of a header store (not strictly increasing, like real block timestamps)
+    let block_times = [1_000, 2_000, 11_000, 10_000, 20_000, 30_000];
+    let block_time = |height: u32| -> Result<u64, ()> { Ok(block_times[height as usize]) };
+    let tip_height = block_times.len() as u32 - 1;
+
+    assert_eq!("800000".parse(), Ok(Birthday::Height(800_000)));
+    assert_eq!("1700000000".parse(), Ok(Birthday::Time(1_700_000_000)));
+    assert_eq!(Birthday::Time(1_700_000_000).to_string(), "1700000000");
+
+    assert_eq!(
+        Birthday::Height(3).start_height(tip_height, block_time),
+        Ok(3)
+    );
+    assert_eq!(
+        Birthday::Height(9).start_height(tip_height, block_time),
+        Ok(6)
+    );
+    // blocks within the timestamp window before the birthday do not predate it
+    let window = Birthday::TIMESTAMP_WINDOW;
+    assert_eq!(
+        Birthday::Time(10_000 + window).start_height(tip_height, block_time),
+        Ok(2)
+    );
+    assert_eq!(
+        Birthday::Time(20_001 + window).start_height(tip_height, block_time),
+        Ok(5)
+    );
+    assert_eq!(
+        Birthday::Time(0).start_height(tip_height, block_time),
+        Ok(0)
+    );
+    assert_eq!(
+        Birthday::Time(u64::MAX).start_height(tip_height, block_time),
+        Ok(6)
+    );
+    assert!(Birthday::Time(30_001 + window).predates(5, 30_000));
+    assert!(!Birthday::Time(30_000 + window).predates(5, 30_000));
+}
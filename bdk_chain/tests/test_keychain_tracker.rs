@@ -2,12 +2,16 @@
 #[macro_use]
 mod common;
 use bdk_chain::{
-    keychain::{Balance, KeychainTracker},
+    chain_graph::{ChainGraph, Eviction, EvictionReason},
+    keychain::{
+        wallet_events, Balance, CancelToken, KeychainScan, KeychainTracker, LabelRef, ScanError,
+        ScanProgress, StopGap, WalletEvent,
+    },
     miniscript::{
         bitcoin::{secp256k1::Secp256k1, OutPoint, PackedLockTime, Transaction, TxOut},
         Descriptor,
     },
-    BlockId, ConfirmationTime, TxHeight,
+    Birthday, BlockId, ConfirmationTime, TxHeight,
 };
 use bitcoin::TxIn;
 
@@ -183,6 +187,8 @@ fn test_balance() {
             untrusted_pending: 0,
             immature: 11_000,
             confirmed: 13_000,
+            shallow: 0,
+            locked: 0,
         }
     );
 
@@ -197,6 +203,8 @@ fn test_balance() {
             untrusted_pending: 0,
             immature: 11_000,
             confirmed: 20_000,
+            shallow: 0,
+            locked: 0,
         }
     );
 
@@ -214,6 +222,8 @@ fn test_balance() {
             untrusted_pending: 0,
             immature: 11_000,
             confirmed: 20_000,
+            shallow: 0,
+            locked: 0,
         }
     );
 
@@ -231,6 +241,8 @@ fn test_balance() {
             untrusted_pending: 0,
             immature: 0,
             confirmed: 31_000,
+            shallow: 0,
+            locked: 0,
         }
     );
 
@@ -240,4 +252,915 @@ fn test_balance() {
     assert_eq!(tracker.balance_at(98), 20_000);
     assert_eq!(tracker.balance_at(99), 31_000);
     assert_eq!(tracker.balance_at(100), 31_000);
+
+    assert_eq!(
+        tracker.balance_per_keychain(should_trust),
+        [
+            (
+                Keychain::One,
+                Balance {
+                    confirmed: 13_000,
+                    ..Default::default()
+                }
+            ),
+            (
+                Keychain::Two,
+                Balance {
+                    confirmed: 18_000,
+                    ..Default::default()
+                }
+            ),
+        ]
+        .into()
+    );
+    let confirmed = |confirmed| Balance {
+        confirmed,
+        ..Default::default()
+    };
+    assert_eq!(
+        tracker.balance_at_per_keychain(0, should_trust),
+        [(
+            Keychain::Two,
+            Balance {
+                immature: 11_000,
+                ..Default::default()
+            }
+        )]
+        .into()
+    );
+    assert_eq!(
+        tracker.balance_at_per_keychain(1, should_trust),
+        [
+            (Keychain::One, confirmed(13_000)),
+            (
+                Keychain::Two,
+                Balance {
+                    immature: 11_000,
+                    ..Default::default()
+                }
+            )
+        ]
+        .into()
+    );
+    assert_eq!(
+        tracker.balance_at_per_keychain(98, should_trust),
+        [
+            (Keychain::One, confirmed(13_000)),
+            (
+                Keychain::Two,
+                Balance {
+                    immature: 11_000,
+                    confirmed: 7_000,
+                    ..Default::default()
+                }
+            )
+        ]
+        .into()
+    );
+    assert_eq!(
+        tracker.balance_at_per_keychain(99, should_trust),
+        tracker.balance_per_keychain(should_trust)
+    );
+
+    // unconfirmed outputs are only pending at the tip and spends only count once confirmed
+    let spend_tx1 = Transaction {
+        version: 0x01,
+        lock_time: PackedLockTime(0),
+        input: vec![TxIn {
+            previous_output: OutPoint::new(tx1.txid(), 0),
+            ..Default::default()
+        }],
+        output: vec![TxOut {
+            value: 12_000,
+            script_pubkey: tracker
+                .txout_index
+                .reveal_next_spk(&Keychain::Two)
+                .0
+                 .1
+                .clone(),
+        }],
+    };
+    let _ = tracker
+        .insert_tx(spend_tx1.clone(), TxHeight::Unconfirmed)
+        .unwrap();
+    assert_eq!(
+        tracker.balance_at_per_keychain(98, should_trust)[&Keychain::One],
+        confirmed(13_000)
+    );
+    assert_eq!(
+        tracker.balance_at_per_keychain(99, should_trust),
+        [(
+            Keychain::Two,
+            Balance {
+                trusted_pending: 12_000,
+                confirmed: 18_000,
+                ..Default::default()
+            }
+        )]
+        .into()
+    );
+    let should_trust_none = |_: &Keychain| false;
+    assert_eq!(
+        tracker.balance_at_per_keychain(99, should_trust_none)[&Keychain::Two],
+        Balance {
+            untrusted_pending: 12_000,
+            confirmed: 18_000,
+            ..Default::default()
+        }
+    );
+
+    let _ = tracker
+        .insert_tx(spend_tx1, TxHeight::Confirmed(99))
+        .unwrap();
+    assert_eq!(
+        tracker.balance_at_per_keychain(98, should_trust)[&Keychain::One],
+        confirmed(13_000)
+    );
+    assert!(!tracker
+        .balance_at_per_keychain(99, should_trust)
+        .contains_key(&Keychain::One));
+}
+
+#[test]
+fn test_finality_depth() {
+    let (mut tracker, _) = common::single_keychain_tracker::<TxHeight>();
+    let spk = tracker.txout_index.reveal_next_spk(&()).0 .1.clone();
+    let new_tx = |value: u64, lock_time: u32| Transaction {
+        version: 0x01,
+        lock_time: PackedLockTime(lock_time),
+        input: vec![],
+        output: vec![TxOut {
+            value,
+            script_pubkey: spk.clone(),
+        }],
+    };
+    let (tx_deep, tx_shallow, tx_unconfirmed) =
+        (new_tx(10_000, 0), new_tx(5_000, 1), new_tx(1_000, 2));
+
+    for height in [1, 3, 4] {
+        let _ = tracker
+            .insert_checkpoint(BlockId {
+                height,
+                hash: h!("block"),
+            })
+            .unwrap();
+    }
+    let _ = tracker
+        .insert_tx(tx_deep.clone(), TxHeight::Confirmed(1))
+        .unwrap();
+    let _ = tracker
+        .insert_tx(tx_shallow.clone(), TxHeight::Confirmed(3))
+        .unwrap();
+    let _ = tracker
+        .insert_tx(tx_unconfirmed.clone(), TxHeight::Unconfirmed)
+        .unwrap();
+
+    assert_eq!(tracker.confirmations(tx_deep.txid()), Some(4));
+    assert_eq!(tracker.confirmations(tx_shallow.txid()), Some(2));
+    assert_eq!(tracker.confirmations(tx_unconfirmed.txid()), Some(0));
+    assert_eq!(tracker.confirmations(new_tx(0, 3).txid()), None);
+    assert_eq!(TxHeight::Confirmed(0).confirmations(u32::MAX), u32::MAX);
+
+    // without a finality depth, all confirmed txs are final
+    assert!(tracker.is_final(tx_shallow.txid()));
+    assert!(!tracker.is_final(tx_unconfirmed.txid()));
+    assert_eq!(tracker.balance(|_| false).confirmed, 15_000);
+
+    tracker.set_finality_depth(Some(3));
+    assert!(tracker.is_final(tx_deep.txid()));
+    assert!(!tracker.is_final(tx_shallow.txid()));
+    assert_eq!(
+        tracker.balance(|_| false),
+        Balance {
+            untrusted_pending: 1_000,
+            confirmed: 10_000,
+            shallow: 5_000,
+            ..Default::default()
+        }
+    );
+
+    let utxo_values = |min_confirmations| {
+        let mut values = tracker
+            .full_utxos_with_confirmations(min_confirmations)
+            .map(|(_, utxo)| utxo.txout.value)
+            .collect::<Vec<_>>();
+        values.sort_unstable();
+        values
+    };
+    assert_eq!(utxo_values(0).len(), 3);
+    assert_eq!(utxo_values(2), vec![5_000, 10_000]);
+    assert_eq!(utxo_values(3), vec![10_000]);
+    assert_eq!(utxo_values(5), Vec::<u64>::new());
+}
+
+#[test]
+fn test_utxo_locks() {
+    let (mut tracker, _) = common::single_keychain_tracker::<TxHeight>();
+    let spk = tracker.txout_index.reveal_next_spk(&()).0 .1.clone();
+    let tx = Transaction {
+        version: 0x01,
+        lock_time: PackedLockTime(0),
+        input: vec![],
+        output: [1_000, 2_000, 4_000]
+            .into_iter()
+            .map(|value| TxOut {
+                value,
+                script_pubkey: spk.clone(),
+            })
+            .collect(),
+    };
+    let _ = tracker
+        .insert_checkpoint(BlockId {
+            height: 1,
+            hash: h!("block"),
+        })
+        .unwrap();
+    let _ = tracker
+        .insert_tx(tx.clone(), TxHeight::Confirmed(1))
+        .unwrap();
+    let outpoint = |vout| OutPoint::new(tx.txid(), vout);
+
+    let spendable_values = |tracker: &KeychainTracker<(), TxHeight>| {
+        let mut values = tracker
+            .spendable_utxos()
+            .map(|(_, utxo)| utxo.txout.value)
+            .collect::<Vec<_>>();
+        values.sort_unstable();
+        values
+    };
+
+    let now = std::time::UNIX_EPOCH.elapsed().unwrap().as_secs();
+    tracker.lock_utxo(outpoint(0), now + 100);
+    let changeset = tracker.freeze_utxo(outpoint(1));
+    assert_eq!(changeset.frozen, [(outpoint(1), true)].into());
+    assert!(tracker.freeze_utxo(outpoint(1)).is_empty());
+
+    assert_eq!(tracker.full_utxos().count(), 3);
+    assert_eq!(spendable_values(&tracker), vec![4_000]);
+    assert_eq!(
+        tracker.balance(|_| false),
+        Balance {
+            confirmed: 4_000,
+            locked: 3_000,
+            ..Default::default()
+        }
+    );
+    assert_eq!(tracker.balance(|_| false).total(), 7_000);
+
+    // locks expire but freezes do not
+    let view = tracker.view();
+    assert!(view.with_time(now + 99).is_locked(outpoint(0)));
+    assert!(!view.with_time(now + 100).is_locked(outpoint(0)));
+    assert!(view.with_time(now + 100).is_locked(outpoint(1)));
+    assert_eq!(
+        view.with_time(now + 100)
+            .spendable_utxos()
+            .map(|(_, utxo)| utxo.outpoint)
+            .collect::<Vec<_>>(),
+        vec![outpoint(0), outpoint(2)]
+    );
+    assert_eq!(tracker.unlock_expired(now + 99), vec![]);
+    assert_eq!(tracker.unlock_expired(now + 100), vec![outpoint(0)]);
+    assert!(!tracker.unlock_utxo(outpoint(0)));
+    assert_eq!(spendable_values(&tracker), vec![1_000, 4_000]);
+
+    // an expired lock hides the output until it is removed
+    tracker.lock_utxo(outpoint(0), now - 1);
+    assert_eq!(spendable_values(&tracker), vec![4_000]);
+    assert_eq!(tracker.balance(|_| false).locked, 3_000);
+    assert_eq!(tracker.unlock_expired(now), vec![outpoint(0)]);
+    assert_eq!(spendable_values(&tracker), vec![1_000, 4_000]);
+
+    // freezes are recorded in the changeset
+    let mut restored = KeychainTracker::<(), TxHeight>::default();
+    restored.apply_changeset(changeset);
+    assert_eq!(restored.frozen_utxos(), &[outpoint(1)].into());
+
+    let changeset = tracker.unfreeze_utxo(outpoint(1));
+    assert_eq!(changeset.frozen, [(outpoint(1), false)].into());
+    assert!(tracker.unfreeze_utxo(outpoint(1)).is_empty());
+    assert_eq!(spendable_values(&tracker), vec![1_000, 2_000, 4_000]);
+    restored.apply_changeset(changeset);
+    assert!(restored.frozen_utxos().is_empty());
+}
+
+#[test]
+fn test_labels() {
+    let (mut tracker, _) = common::single_keychain_tracker::<TxHeight>();
+    let spk = tracker.txout_index.reveal_next_spk(&()).0 .1.clone();
+    let tx = Transaction {
+        version: 0x01,
+        lock_time: PackedLockTime(0),
+        input: vec![],
+        output: vec![
+            TxOut {
+                value: 1_000,
+                script_pubkey: spk.clone(),
+            },
+            TxOut {
+                value: 2_000,
+                script_pubkey: spk,
+            },
+        ],
+    };
+    let _ = tracker
+        .insert_tx(tx.clone(), TxHeight::Unconfirmed)
+        .unwrap();
+
+    let changeset = tracker.set_label(LabelRef::Tx(tx.txid()), Some("salary".into()));
+    assert_eq!(
+        changeset.labels.txs,
+        [(tx.txid(), Some("salary".to_string()))].into()
+    );
+    assert!(tracker
+        .set_label(LabelRef::Tx(tx.txid()), Some("salary".into()))
+        .is_empty());
+    assert_eq!(
+        tracker
+            .transactions()
+            .map(|wallet_tx| wallet_tx.label)
+            .collect::<Vec<_>>(),
+        vec![Some("salary")]
+    );
+
+    // the label of an output takes precedence over the label of its script pubkey
+    let _ = tracker.set_label(LabelRef::Spk(((), 0)), Some("donations".into()));
+    let _ = tracker.set_label(
+        LabelRef::Output(OutPoint::new(tx.txid(), 1)),
+        Some("big one".into()),
+    );
+    assert_eq!(
+        tracker.txout_label(&((), 0), OutPoint::new(tx.txid(), 0)),
+        Some("donations")
+    );
+    assert_eq!(
+        tracker.txout_label(&((), 0), OutPoint::new(tx.txid(), 1)),
+        Some("big one")
+    );
+
+    let changeset = tracker.set_label(LabelRef::Spk(((), 0)), None);
+    assert_eq!(changeset.labels.spks, [(((), 0), None)].into());
+    assert_eq!(tracker.labels().iter().count(), 2);
+}
+
+#[test]
+fn test_watched() {
+    let (mut tracker, descriptor) = common::single_keychain_tracker::<TxHeight>();
+    let foreign_spk = descriptor.at_derivation_index(1000).script_pubkey();
+    let foreign_tx = Transaction {
+        version: 0x01,
+        lock_time: PackedLockTime(0),
+        input: vec![],
+        output: vec![TxOut {
+            value: 50_000,
+            script_pubkey: bitcoin::Script::new(),
+        }],
+    };
+    let receive_tx = Transaction {
+        version: 0x01,
+        lock_time: PackedLockTime(1),
+        input: vec![],
+        output: vec![TxOut {
+            value: 10_000,
+            script_pubkey: foreign_spk.clone(),
+        }],
+    };
+    let spend_tx = Transaction {
+        version: 0x01,
+        lock_time: PackedLockTime(2),
+        input: vec![TxIn {
+            previous_output: OutPoint::new(receive_tx.txid(), 0),
+            ..Default::default()
+        }],
+        output: vec![],
+    };
+    for tx in [&foreign_tx, &receive_tx, &spend_tx] {
+        let _ = tracker
+            .insert_tx(tx.clone(), TxHeight::Unconfirmed)
+            .unwrap();
+    }
+    assert_eq!(tracker.watched_txouts().count(), 0);
+
+    let mut changeset = tracker.watch_spk(foreign_spk.clone());
+    assert!(tracker.watch_spk(foreign_spk.clone()).is_empty());
+    let watched_txouts = tracker.watched_txouts().collect::<Vec<_>>();
+    assert_eq!(watched_txouts.len(), 1);
+    assert_eq!(watched_txouts[0].txout.value, 10_000);
+    assert_eq!(
+        watched_txouts[0].spent_by.map(|(_, txid)| txid),
+        Some(spend_tx.txid())
+    );
+    assert_eq!(tracker.watched_utxos().count(), 0);
+    let mut watched_txids = tracker
+        .watched_transactions()
+        .map(|(_, tx)| tx.txid())
+        .collect::<Vec<_>>();
+    watched_txids.sort();
+    let mut expected_txids = vec![receive_tx.txid(), spend_tx.txid()];
+    expected_txids.sort();
+    assert_eq!(watched_txids, expected_txids);
+
+    // watched outputs are not part of the keychains
+    assert_eq!(tracker.full_txouts().count(), 0);
+    assert_eq!(tracker.balance(|_| false).total(), 0);
+
+    changeset.append(tracker.watch_outpoint(OutPoint::new(foreign_tx.txid(), 0)));
+    assert_eq!(
+        tracker
+            .watched_utxos()
+            .map(|utxo| utxo.txout.value)
+            .collect::<Vec<_>>(),
+        vec![50_000]
+    );
+
+    // watches are recorded in the changeset
+    let mut restored = KeychainTracker::<(), TxHeight>::default();
+    restored.apply_changeset(changeset);
+    assert_eq!(restored.watched_spks(), tracker.watched_spks());
+    assert_eq!(restored.watched_outpoints(), tracker.watched_outpoints());
+
+    let changeset = tracker.unwatch_spk(foreign_spk.clone());
+    assert_eq!(changeset.watched_spks, [(foreign_spk, false)].into());
+    assert_eq!(tracker.watched_transactions().count(), 1);
+}
+
+#[test]
+fn test_wallet_events() {
+    let (mut tracker, _) = common::single_keychain_tracker::<TxHeight>();
+    let spk = tracker.txout_index.reveal_next_spk(&()).0 .1.clone();
+
+    let tx_a = Transaction {
+        version: 0x01,
+        lock_time: PackedLockTime(0),
+        input: vec![],
+        output: vec![TxOut {
+            value: 10_000,
+            script_pubkey: spk.clone(),
+        }],
+    };
+    let tx_b = Transaction {
+        version: 0x01,
+        lock_time: PackedLockTime(0),
+        input: vec![TxIn {
+            previous_output: OutPoint::new(tx_a.txid(), 0),
+            ..Default::default()
+        }],
+        output: vec![TxOut {
+            value: 9_000,
+            script_pubkey: spk,
+        }],
+    };
+
+    let changeset = tracker
+        .insert_tx_preview(tx_a.clone(), TxHeight::Unconfirmed)
+        .unwrap();
+    assert_eq!(
+        wallet_events(&tracker, &changeset),
+        vec![WalletEvent::TxReceived {
+            txid: tx_a.txid(),
+            position: TxHeight::Unconfirmed
+        }]
+    );
+    tracker.apply_changeset(changeset);
+
+    let block_1 = BlockId {
+        height: 1,
+        hash: h!("1"),
+    };
+    let changeset = tracker.insert_checkpoint_preview(block_1).unwrap();
+    assert_eq!(
+        wallet_events(&tracker, &changeset),
+        vec![WalletEvent::TipChanged {
+            from: None,
+            to: Some(block_1)
+        }]
+    );
+    tracker.apply_changeset(changeset);
+
+    let changeset = tracker
+        .insert_tx_preview(tx_a.clone(), TxHeight::Confirmed(1))
+        .unwrap();
+    assert_eq!(
+        wallet_events(&tracker, &changeset),
+        vec![WalletEvent::TxConfirmed {
+            txid: tx_a.txid(),
+            position: TxHeight::Confirmed(1)
+        }]
+    );
+    tracker.apply_changeset(changeset);
+
+    let changeset = tracker
+        .insert_tx_preview(tx_b.clone(), TxHeight::Unconfirmed)
+        .unwrap();
+    assert_eq!(
+        wallet_events(&tracker, &changeset),
+        vec![
+            WalletEvent::TxReceived {
+                txid: tx_b.txid(),
+                position: TxHeight::Unconfirmed
+            },
+            WalletEvent::UtxoSpent {
+                outpoint: OutPoint::new(tx_a.txid(), 0),
+                keychain: (),
+                index: 0,
+                spent_by: tx_b.txid()
+            }
+        ]
+    );
+    tracker.apply_changeset(changeset);
+
+    // block 1 is reorged out and tx_a goes back to the mempool
+    let block_1b = BlockId {
+        height: 1,
+        hash: h!("1b"),
+    };
+    let mut update = ChainGraph::<TxHeight>::default();
+    let _ = update.insert_checkpoint(block_1b).unwrap();
+    let _ = update
+        .insert_tx(tx_a.clone(), TxHeight::Unconfirmed)
+        .unwrap();
+    let scan = KeychainScan {
+        update,
+        last_active_indices: [((), 2)].into(),
+        progress: None,
+    };
+    let changeset = tracker.determine_changeset(&scan).unwrap();
+    assert_eq!(
+        wallet_events(&tracker, &changeset),
+        vec![
+            WalletEvent::TipChanged {
+                from: Some(block_1),
+                to: Some(block_1b)
+            },
+            WalletEvent::AddressRevealed {
+                keychain: (),
+                index: 1
+            },
+            WalletEvent::AddressRevealed {
+                keychain: (),
+                index: 2
+            },
+            WalletEvent::TxReorged {
+                txid: tx_a.txid(),
+                from: TxHeight::Confirmed(1)
+            },
+        ]
+    );
+    tracker.apply_changeset(changeset);
+
+    // tx_c double spends tx_b, which gets evicted
+    let tx_c = Transaction {
+        output: vec![],
+        ..tx_b.clone()
+    };
+    let changeset = tracker
+        .insert_tx_preview(tx_c.clone(), TxHeight::Unconfirmed)
+        .unwrap();
+    let events = wallet_events(&tracker, &changeset);
+    assert_eq!(events.len(), 3);
+    assert!(events.contains(&WalletEvent::TxEvicted {
+        txid: tx_b.txid(),
+        position: TxHeight::Unconfirmed,
+        eviction: Some(Eviction {
+            by: tx_c.txid(),
+            reason: EvictionReason::ReplacedByUpdate
+        })
+    }));
+    assert!(events.contains(&WalletEvent::TxReceived {
+        txid: tx_c.txid(),
+        position: TxHeight::Unconfirmed
+    }));
+    assert_eq!(
+        events.last(),
+        Some(&WalletEvent::UtxoSpent {
+            outpoint: OutPoint::new(tx_a.txid(), 0),
+            keychain: (),
+            index: 0,
+            spent_by: tx_c.txid()
+        })
+    );
+}
+
+#[test]
+fn test_transactions() {
+    use core::str::FromStr;
+    #[derive(Debug, Clone, PartialEq, Eq, Ord, PartialOrd)]
+    enum Keychain {
+        External,
+        Internal,
+    }
+    let mut tracker = KeychainTracker::<Keychain, TxHeight>::default();
+    let external = Descriptor::from_str("tr([73c5da0a/86'/0'/0']xpub6BgBgsespWvERF3LHQu6CnqdvfEvtMcQjYrcRzx53QJjSxarj2afYWcLteoGVky7D3UKDP9QyrLprQ3VCECoY49yfdDEHGCtMMj92pReUsQ/0/*)#rg247h69").unwrap();
+    let internal = Descriptor::from_str("tr([73c5da0a/86'/0'/0']xpub6BgBgsespWvERF3LHQu6CnqdvfEvtMcQjYrcRzx53QJjSxarj2afYWcLteoGVky7D3UKDP9QyrLprQ3VCECoY49yfdDEHGCtMMj92pReUsQ/1/*)#ju05rz2a").unwrap();
+    tracker.add_keychain(Keychain::External, external);
+    tracker.add_keychain(Keychain::Internal, internal);
+    let external_spk = tracker
+        .txout_index
+        .reveal_next_spk(&Keychain::External)
+        .0
+         .1
+        .clone();
+    let internal_spk = tracker
+        .txout_index
+        .reveal_next_spk(&Keychain::Internal)
+        .0
+         .1
+        .clone();
+
+    let tx_recv = Transaction {
+        version: 0x01,
+        lock_time: PackedLockTime(0),
+        input: vec![TxIn {
+            previous_output: OutPoint::new(h!("foreign"), 0),
+            ..Default::default()
+        }],
+        output: vec![TxOut {
+            value: 50_000,
+            script_pubkey: external_spk,
+        }],
+    };
+    let tx_self = Transaction {
+        version: 0x01,
+        lock_time: PackedLockTime(0),
+        input: vec![TxIn {
+            previous_output: OutPoint::new(tx_recv.txid(), 0),
+            ..Default::default()
+        }],
+        output: vec![TxOut {
+            value: 49_000,
+            script_pubkey: internal_spk.clone(),
+        }],
+    };
+    let tx_send = Transaction {
+        version: 0x01,
+        lock_time: PackedLockTime(0),
+        input: vec![TxIn {
+            previous_output: OutPoint::new(tx_self.txid(), 0),
+            ..Default::default()
+        }],
+        output: vec![
+            TxOut {
+                value: 20_000,
+                script_pubkey: Default::default(),
+            },
+            TxOut {
+                value: 28_000,
+                script_pubkey: internal_spk,
+            },
+        ],
+    };
+    let tx_unrelated = Transaction {
+        version: 0x01,
+        lock_time: PackedLockTime(0),
+        input: vec![],
+        output: vec![TxOut::default()],
+    };
+
+    let _ = tracker
+        .insert_checkpoint(BlockId {
+            height: 2,
+            hash: h!("2"),
+        })
+        .unwrap();
+    for (tx, height) in [
+        (&tx_recv, TxHeight::Confirmed(1)),
+        (&tx_self, TxHeight::Confirmed(2)),
+        (&tx_send, TxHeight::Unconfirmed),
+        (&tx_unrelated, TxHeight::Confirmed(2)),
+    ] {
+        let _ = tracker.insert_tx(tx.clone(), height).unwrap();
+    }
+
+    let txs = tracker.transactions().collect::<Vec<_>>();
+    assert_eq!(
+        txs.iter().map(|wtx| wtx.txid).collect::<Vec<_>>(),
+        vec![tx_recv.txid(), tx_self.txid(), tx_send.txid()]
+    );
+
+    let (recv, self_transfer, send) = (&txs[0], &txs[1], &txs[2]);
+    assert_eq!((recv.sent, recv.received), (0, 50_000));
+    assert_eq!(recv.fee, None);
+    assert_eq!(recv.keychains, [Keychain::External].into());
+    assert!(!recv.is_self_transfer);
+
+    assert_eq!(
+        (self_transfer.sent, self_transfer.received),
+        (50_000, 49_000)
+    );
+    assert_eq!(self_transfer.fee, Some(1_000));
+    assert_eq!(
+        self_transfer.feerate,
+        Some(1_000.0 * 4.0 / tx_self.weight() as f32)
+    );
+    assert_eq!(
+        self_transfer.keychains,
+        [Keychain::External, Keychain::Internal].into()
+    );
+    assert!(self_transfer.is_self_transfer);
+
+    assert_eq!(send.chain_position, TxHeight::Unconfirmed);
+    assert_eq!(send.net_value(), -21_000);
+    assert_eq!(send.fee, Some(1_000));
+    assert!(!send.is_self_transfer);
+
+    assert_eq!(
+        tracker
+            .range_transactions(TxHeight::Confirmed(2)..TxHeight::Unconfirmed)
+            .map(|wtx| wtx.txid)
+            .collect::<Vec<_>>(),
+        vec![tx_self.txid()]
+    );
+    assert_eq!(
+        tracker
+            .transactions_page(.., 0, 2)
+            .iter()
+            .map(|wtx| wtx.txid)
+            .collect::<Vec<_>>(),
+        vec![tx_send.txid(), tx_self.txid()]
+    );
+    assert_eq!(
+        tracker
+            .transactions_page(.., 2, 2)
+            .iter()
+            .map(|wtx| wtx.txid)
+            .collect::<Vec<_>>(),
+        vec![tx_recv.txid()]
+    );
+}
+
+#[test]
+fn test_scan_requests() {
+    let (mut tracker, descriptor) = common::single_keychain_tracker::<TxHeight>();
+    let _ = tracker.txout_index.reveal_to_target(&(), 2);
+    let spk = |i: u32| descriptor.at_derivation_index(i).script_pubkey();
+
+    let _ = tracker
+        .insert_checkpoint(BlockId {
+            height: 1,
+            hash: h!("block 1"),
+        })
+        .unwrap();
+    let confirmed_tx = Transaction {
+        version: 0x01,
+        lock_time: PackedLockTime(0),
+        input: vec![],
+        output: vec![TxOut {
+            value: 10_000,
+            script_pubkey: spk(0),
+        }],
+    };
+    let unconfirmed_tx = Transaction {
+        version: 0x01,
+        lock_time: PackedLockTime(1),
+        input: vec![],
+        output: vec![TxOut {
+            value: 20_000,
+            script_pubkey: spk(1),
+        }],
+    };
+    let _ = tracker
+        .insert_tx(confirmed_tx.clone(), TxHeight::Confirmed(1))
+        .unwrap();
+    let _ = tracker
+        .insert_tx(unconfirmed_tx.clone(), TxHeight::Unconfirmed)
+        .unwrap();
+
+    let foreign_spk = spk(1000);
+    let foreign_outpoint = OutPoint::new(h!("foreign tx"), 0);
+    let _ = tracker.watch_spk(foreign_spk.clone());
+    let _ = tracker.watch_outpoint(foreign_outpoint);
+
+    let request = tracker.sync_request();
+    assert_eq!(&request.local_chain, tracker.chain().checkpoints());
+    assert_eq!(request.spks, vec![spk(2), foreign_spk.clone()]);
+    assert_eq!(request.txids, vec![unconfirmed_tx.txid()]);
+    let mut expected_outpoints = vec![
+        OutPoint::new(confirmed_tx.txid(), 0),
+        OutPoint::new(unconfirmed_tx.txid(), 0),
+        foreign_outpoint,
+    ];
+    let mut outpoints = request.outpoints;
+    expected_outpoints.sort();
+    outpoints.sort();
+    assert_eq!(outpoints, expected_outpoints);
+
+    let request = tracker
+        .full_scan_request()
+        .with_birthday(Some(Birthday::Height(1)));
+    assert_eq!(&request.local_chain, tracker.chain().checkpoints());
+    assert_eq!(request.spks, vec![foreign_spk]);
+    assert_eq!(request.outpoints, vec![foreign_outpoint]);
+    assert_eq!(request.birthday, Some(Birthday::Height(1)));
+    // the script pubkey iterators are unbounded (they go past the revealed script pubkeys)
+    let spks = request.spks_by_keychain[&()]
+        .clone()
+        .take(5)
+        .collect::<Vec<_>>();
+    assert_eq!(spks, (0..5).map(|i| (i, spk(i))).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_stop_gap() {
+    let mut stop_gap = StopGap::new(2);
+    assert!(!stop_gap.record(0, false));
+    assert!(!stop_gap.record(1, true));
+    assert!(!stop_gap.record(2, false));
+    assert!(!stop_gap.record(3, true));
+    assert!(!stop_gap.record(4, false));
+    assert!(stop_gap.record(5, false));
+    assert!(stop_gap.is_reached());
+    assert_eq!(stop_gap.last_active_index(), Some(3));
+
+    let mut stop_gap = StopGap::<u32>::new(1);
+    assert!(stop_gap.record(0, false));
+    assert_eq!(stop_gap.last_active_index(), None);
+}
+
+#[test]
+fn test_cancel_token() {
+    let token = CancelToken::new();
+    let handed_to_scan = token.clone();
+    assert!(!handed_to_scan.is_cancelled());
+    token.cancel();
+    assert!(handed_to_scan.is_cancelled());
+
+    let err = ScanError::<KeychainScan<(), TxHeight>, String>::Cancelled(Box::default());
+    assert_eq!(err.to_string(), "the scan was cancelled");
+    let err = ScanError::<KeychainScan<(), TxHeight>, _>::from("connection lost".to_string());
+    assert_eq!(err.to_string(), "connection lost");
+}
+
+#[test]
+fn test_scan_progress() {
+    let mut tracker = KeychainTracker::<u8, TxHeight>::default();
+    tracker.add_keychain(0, common::tr_descriptor(0));
+    tracker.add_keychain(1, common::tr_descriptor(1));
+    assert!(tracker.full_scan_request().progress.is_empty());
+
+    // the scan is interrupted after keychain 0 is finished and keychain 1 is scanned up to index 4
+    let tip = BlockId {
+        height: 10,
+        hash: h!("10"),
+    };
+    let mut progress = ScanProgress::new(tip);
+    progress.record_scanned(&0, 19);
+    progress.record_finished(0);
+    progress.record_scanned(&1, 4);
+    let changeset = tracker
+        .apply_update(KeychainScan::<u8, TxHeight> {
+            progress: Some(progress.clone()),
+            ..Default::default()
+        })
+        .unwrap();
+    assert_eq!(changeset.scan_progress.as_ref(), Some(&progress));
+    assert_eq!(tracker.scan_progress(), &progress);
+
+    // applying the same progress again is not a change
+    let changeset = tracker
+        .determine_changeset(&KeychainScan::<u8, TxHeight> {
+            progress: Some(progress.clone()),
+            ..Default::default()
+        })
+        .unwrap();
+    assert!(changeset.is_empty());
+
+    let request = tracker.full_scan_request();
+    let resumed = request.progress.resume(request.spks_by_keychain);
+    assert_eq!(resumed.keys().collect::<Vec<_>>(), vec![&1]);
+    let resumed_indices = resumed
+        .into_values()
+        .flat_map(|spks| spks.take(3).map(|(index, _)| index))
+        .collect::<Vec<_>>();
+    assert_eq!(resumed_indices, vec![5, 6, 7]);
+
+    // the resumed scan finishes keychain 1 at a new tip
+    let new_tip = BlockId {
+        height: 11,
+        hash: h!("11"),
+    };
+    let mut resumed_progress = ScanProgress::new(new_tip);
+    resumed_progress.record_scanned(&1, 25);
+    resumed_progress.record_finished(1);
+    progress.merge(resumed_progress);
+    assert_eq!(progress.tip, new_tip);
+    assert_eq!(
+        progress.last_scanned_indices,
+        [(0, 19), (1, 25)].into_iter().collect()
+    );
+    assert_eq!(progress.finished_keychains, [0, 1].into_iter().collect());
+
+    // a finished full scan clears the progress
+    let _ = tracker
+        .apply_update(KeychainScan::<u8, TxHeight> {
+            progress: Some(ScanProgress::default()),
+            ..Default::default()
+        })
+        .unwrap();
+    assert!(tracker.scan_progress().is_empty());
+
+    let err = ScanError::<(), _>::Interrupted {
+        error: "connection lost".to_string(),
+        partial: Box::new(()),
+    };
+    assert_eq!(err.to_string(), "the scan was interrupted: connection lost");
 }
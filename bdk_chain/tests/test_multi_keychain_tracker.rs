@@ -0,0 +1,130 @@
+#![cfg(feature = "miniscript")]
+#[macro_use]
+mod common;
+use bdk_chain::{
+    chain_graph::ChainGraph,
+    keychain::{KeychainScan, KeychainTxOutIndex, MultiKeychainTracker},
+    miniscript::{
+        bitcoin::{PackedLockTime, Transaction, TxOut},
+        Descriptor, DescriptorPublicKey,
+    },
+    BlockId, TxHeight,
+};
+use core::str::FromStr;
+
+fn txout_index(descriptor: &str) -> KeychainTxOutIndex<()> {
+    let mut txout_index = KeychainTxOutIndex::default();
+    txout_index.add_keychain(
+        (),
+        Descriptor::<DescriptorPublicKey>::from_str(descriptor).unwrap(),
+    );
+    txout_index.set_lookahead(&(), 10);
+    txout_index
+}
+
+const ALICE: &str = "tr([73c5da0a/86'/0'/0']xpub6BgBgsespWvERF3LHQu6CnqdvfEvtMcQjYrcRzx53QJjSxarj2afYWcLteoGVky7D3UKDP9QyrLprQ3VCECoY49yfdDEHGCtMMj92pReUsQ/0/*)#rg247h69";
+const BOB: &str = "tr([73c5da0a/86'/0'/0']xpub6BgBgsespWvERF3LHQu6CnqdvfEvtMcQjYrcRzx53QJjSxarj2afYWcLteoGVky7D3UKDP9QyrLprQ3VCECoY49yfdDEHGCtMMj92pReUsQ/1/*)#ju05rz2a";
+
+#[test]
+fn tenants_share_chain_data() {
+    let mut tracker = MultiKeychainTracker::<&str, (), TxHeight>::default();
+    let _ = tracker.add_tenant("alice", txout_index(ALICE));
+
+    let alice_spk = tracker
+        .txout_index_mut(&"alice")
+        .unwrap()
+        .reveal_next_spk(&())
+        .0
+         .1
+        .clone();
+    let bob_spk = txout_index(BOB).spks_of_keychain(&()).nth(3).unwrap().1;
+
+    let tx = Transaction {
+        version: 0x01,
+        lock_time: PackedLockTime(0),
+        input: vec![],
+        output: vec![
+            TxOut {
+                value: 10_000,
+                script_pubkey: alice_spk,
+            },
+            TxOut {
+                value: 20_000,
+                script_pubkey: bob_spk,
+            },
+        ],
+    };
+
+    let mut update = ChainGraph::default();
+    let _ = update
+        .insert_checkpoint(BlockId {
+            height: 1,
+            hash: h!("1"),
+        })
+        .unwrap();
+    let _ = update
+        .insert_tx(tx.clone(), TxHeight::Confirmed(1))
+        .unwrap();
+    let scan = KeychainScan {
+        update,
+        last_active_indices: [(("alice", ()), 0), (("bob", ()), 3)].into(),
+        progress: None,
+    };
+
+    let changeset = tracker.apply_update(scan).unwrap();
+    // bob is not a tenant yet
+    assert_eq!(
+        changeset.derivation_indices.keys().collect::<Vec<_>>(),
+        Vec::<&&str>::new()
+    );
+    assert_eq!(changeset.chain_graph.chain.txids.len(), 1);
+
+    // adding a tenant scans in the existing chain data
+    assert!(tracker.add_tenant("bob", txout_index(BOB)).is_none());
+    assert_eq!(tracker.tenants()[&"bob"].last_revealed_index(&()), Some(3));
+
+    let alice = tracker.view(&"alice").unwrap();
+    let bob = tracker.view(&"bob").unwrap();
+    assert_eq!(alice.balance(|_| false).confirmed, 10_000);
+    assert_eq!(bob.balance(|_| false).confirmed, 20_000);
+    assert_eq!(alice.transactions().count(), 1);
+    assert_eq!(bob.transactions().next().unwrap().received, 20_000);
+    assert!(tracker.view(&"carol").is_none());
+
+    // only one copy of the chain data exists
+    assert_eq!(tracker.graph().full_transactions().count(), 1);
+}
+
+#[test]
+fn changesets_record_indices_per_tenant() {
+    let mut tracker = MultiKeychainTracker::<&str, (), TxHeight>::default();
+    let _ = tracker.add_tenant("alice", txout_index(ALICE));
+    let _ = tracker.add_tenant("bob", txout_index(BOB));
+
+    let scan = KeychainScan {
+        update: ChainGraph::<_, Transaction>::default(),
+        last_active_indices: [(("alice", ()), 2), (("bob", ()), 5), (("carol", ()), 1)].into(),
+        progress: None,
+    };
+    let changeset = tracker.apply_update(scan).unwrap();
+    assert_eq!(
+        changeset
+            .derivation_indices
+            .iter()
+            .map(|(tenant, additions)| (*tenant, additions.as_inner().clone()))
+            .collect::<Vec<_>>(),
+        vec![("alice", [((), 2)].into()), ("bob", [((), 5)].into())]
+    );
+
+    // a tracker recovered from the changeset has the same indices
+    let mut recovered = MultiKeychainTracker::<&str, (), TxHeight>::default();
+    let _ = recovered.add_tenant("alice", txout_index(ALICE));
+    let _ = recovered.add_tenant("bob", txout_index(BOB));
+    recovered.apply_changeset(changeset);
+    for tenant in ["alice", "bob"] {
+        assert_eq!(
+            recovered.tenants()[&tenant].last_revealed_index(&()),
+            tracker.tenants()[&tenant].last_revealed_index(&())
+        );
+    }
+}
@@ -1,7 +1,9 @@
 #[macro_use]
 mod common;
 
-use bdk_chain::{collections::BTreeSet, sparse_chain::*, BlockId, TxHeight};
+use bdk_chain::{
+    collections::BTreeSet, sparse_chain::*, AnchoredPosition, BlockId, ObservedPosition, TxHeight,
+};
 use bitcoin::{hashes::Hash, Txid};
 use core::ops::Bound;
 
@@ -771,3 +773,244 @@ fn change_tx_position_from_unconfirmed_to_confirmed() {
 
     assert_eq!(chain.tx_position(txid), Some(&TxHeight::Confirmed(0)));
 }
+
+#[test]
+fn anchored_positions_must_match_checkpoints() {
+    let anchored = |height: u32, hash| AnchoredPosition::Confirmed {
+        block: BlockId { height, hash },
+        index: None,
+    };
+
+    let mut chain = SparseChain::<AnchoredPosition>::from_checkpoints([
+        (1, h!("A")).into(),
+        (3, h!("C")).into(),
+    ]);
+
+    // anchored to a block matching the checkpoint
+    let _ = chain
+        .insert_tx(h!("tx_a"), anchored(1, h!("A")))
+        .expect("anchor matches checkpoint");
+
+    // anchored to a block that conflicts with the checkpoint
+    assert_eq!(
+        chain.insert_tx_preview(h!("tx_b"), anchored(3, h!("C'"))),
+        Err(InsertTxError::AnchorNotMatching {
+            txid: h!("tx_b"),
+            anchor: (3, h!("C'")).into(),
+            checkpoint_hash: h!("C"),
+        })
+    );
+
+    // there is no checkpoint at height 2, so any anchor is accepted
+    let _ = chain
+        .insert_tx(h!("tx_c"), anchored(2, h!("B")))
+        .expect("no checkpoint to conflict with");
+
+    // a checkpoint cannot be inserted that conflicts with the anchor of an existing tx
+    assert_eq!(
+        chain.insert_checkpoint_preview((2, h!("B'")).into()),
+        Err(InsertCheckpointError::HashNotMatching {
+            height: 2,
+            original_hash: h!("B"),
+            update_hash: h!("B'"),
+        })
+    );
+    let _ = chain
+        .insert_checkpoint((2, h!("B")).into())
+        .expect("checkpoint matches anchor");
+
+    // an update which anchors a tx to a block that does not match its own checkpoints
+    let mut update = SparseChain::<AnchoredPosition>::from_checkpoints([
+        (3, h!("C")).into(),
+        (4, h!("D")).into(),
+    ]);
+    let _ = update.insert_tx(h!("tx_d"), anchored(4, h!("D"))).unwrap();
+    assert_eq!(chain.determine_changeset(&update).map(|_| ()), Ok(()));
+    let _ = update.insert_tx(h!("tx_e"), anchored(1, h!("A'"))).unwrap();
+    assert_eq!(
+        chain.determine_changeset(&update),
+        Err(UpdateError::AnchorNotMatching {
+            txid: h!("tx_e"),
+            anchor: (1, h!("A'")).into(),
+            checkpoint_hash: h!("A"),
+        })
+    );
+
+    // a reorg moves anchored txs of invalidated blocks to unconfirmed
+    let reorg = SparseChain::<AnchoredPosition>::from_checkpoints([
+        (1, h!("A")).into(),
+        (2, h!("B'")).into(),
+        (3, h!("C'")).into(),
+    ]);
+    let _ = chain.apply_update(reorg).expect("reorg must succeed");
+    assert_eq!(
+        chain.tx_position(h!("tx_c")),
+        Some(&AnchoredPosition::Unconfirmed)
+    );
+    assert_eq!(chain.tx_position(h!("tx_a")), Some(&anchored(1, h!("A"))));
+}
+
+#[test]
+fn observed_positions_keep_first_and_last_seen() {
+    let mut chain = SparseChain::<ObservedPosition>::from_checkpoints([(1, h!("A")).into()]);
+
+    let _ = chain
+        .insert_tx(h!("tx_a"), ObservedPosition::seen_at(10))
+        .unwrap();
+    let _ = chain
+        .insert_tx(h!("tx_b"), ObservedPosition::seen_at(5))
+        .unwrap();
+
+    // seen again later
+    let changeset = chain
+        .insert_tx(h!("tx_a"), ObservedPosition::seen_at(20))
+        .unwrap();
+    let first_and_last = ObservedPosition::Unconfirmed {
+        first_seen: 10,
+        last_seen: 20,
+    };
+    assert_eq!(changeset.txids, [(h!("tx_a"), Some(first_and_last))].into());
+    assert_eq!(first_and_last.last_seen(), Some(20));
+
+    // an older observation does not change the last seen time
+    let _ = chain
+        .insert_tx(h!("tx_a"), ObservedPosition::seen_at(15))
+        .unwrap();
+    assert_eq!(chain.tx_position(h!("tx_a")), Some(&first_and_last));
+
+    // unconfirmed txs are ordered by first seen time (after confirmed txs)
+    let _ = chain
+        .insert_tx(
+            h!("tx_c"),
+            ObservedPosition::Confirmed {
+                height: 1,
+                time: 100,
+            },
+        )
+        .unwrap();
+    assert_eq!(
+        chain.txids().map(|(_, txid)| *txid).collect::<Vec<_>>(),
+        vec![h!("tx_c"), h!("tx_b"), h!("tx_a")]
+    );
+
+    // txs displaced by a reorg become unconfirmed, but have not been seen yet
+    let _ = chain
+        .apply_update(SparseChain::from_checkpoints([(1, h!("A'")).into()]))
+        .unwrap();
+    let displaced = chain.tx_position(h!("tx_c")).unwrap();
+    assert_eq!(displaced.height(), TxHeight::Unconfirmed);
+    assert_eq!(displaced.last_seen(), None);
+    let _ = chain
+        .insert_tx(h!("tx_c"), ObservedPosition::seen_at(30))
+        .unwrap();
+    assert_eq!(
+        chain.tx_position(h!("tx_c")),
+        Some(&ObservedPosition::seen_at(30))
+    );
+}
+
+#[test]
+fn unconfirmed_txs_expire() {
+    let mut chain = SparseChain::<ObservedPosition>::from_checkpoints([(1, h!("A")).into()]);
+    assert_eq!(chain.unconfirmed_expiry(), DEFAULT_UNCONFIRMED_EXPIRY);
+    chain.set_unconfirmed_expiry(100);
+
+    let _ = chain
+        .insert_tx(
+            h!("confirmed"),
+            ObservedPosition::Confirmed { height: 1, time: 0 },
+        )
+        .unwrap();
+    let _ = chain
+        .insert_tx(h!("stale"), ObservedPosition::seen_at(10))
+        .unwrap();
+    let _ = chain
+        .insert_tx(h!("fresh"), ObservedPosition::seen_at(150))
+        .unwrap();
+    // first seen long ago, but seen again recently
+    let _ = chain
+        .insert_tx(h!("rebroadcast"), ObservedPosition::seen_at(0))
+        .unwrap();
+    let _ = chain
+        .insert_tx(h!("rebroadcast"), ObservedPosition::seen_at(120))
+        .unwrap();
+
+    assert!(chain.expire_unconfirmed_preview(110).is_empty());
+    assert_eq!(
+        chain.expire_unconfirmed_preview(200).txids,
+        [(h!("stale"), None)].into()
+    );
+
+    let changeset = chain.expire_unconfirmed(1_000);
+    assert_eq!(
+        changeset.txids,
+        [
+            (h!("stale"), None),
+            (h!("fresh"), None),
+            (h!("rebroadcast"), None)
+        ]
+        .into()
+    );
+    assert!(changeset.checkpoints.is_empty());
+    assert_eq!(
+        chain.txids().map(|(_, txid)| *txid).collect::<Vec<_>>(),
+        vec![h!("confirmed")]
+    );
+
+    // txs without a last seen time never expire
+    let mut chain = SparseChain::<TxHeight>::default();
+    let _ = chain.insert_tx(h!("tx"), TxHeight::Unconfirmed).unwrap();
+    assert!(chain.expire_unconfirmed_preview(u64::MAX).is_empty());
+}
+
+#[test]
+fn checkpoint_retention_is_reflected_in_changesets() {
+    let mut chain = SparseChain::<TxHeight>::from_checkpoints(
+        (0..=1000).map(|height| BlockId::from((height, h!("block")))),
+    );
+    let _ = chain.insert_tx(h!("tx"), TxHeight::Confirmed(500)).unwrap();
+    chain.set_checkpoint_retention(Some(CheckpointRetention {
+        dense_window: 10,
+        keep_tx_heights: true,
+    }));
+
+    // setting the policy does not prune anything until asked to
+    assert_eq!(chain.checkpoints().len(), 1001);
+    let changeset = chain.prune_checkpoints();
+    assert!(changeset.txids.is_empty());
+    assert!(changeset.checkpoints.values().all(Option::is_none));
+
+    let kept = chain.checkpoints().keys().copied().collect::<Vec<_>>();
+    // dense window, the confirmed tx's height and one checkpoint per exponential bucket of depth:
+    // 10..20, 20..40, 40..80, 80..160, 160..320, 320..640 and 640..1280
+    assert_eq!(
+        kept,
+        [0, 361, 500, 681, 841, 921, 961, 981]
+            .into_iter()
+            .chain(991..=1000)
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(changeset.checkpoints.len(), 1001 - kept.len());
+    assert!(chain.prune_checkpoints_preview().is_empty());
+
+    // extending the tip prunes checkpoints that fall out of the dense window
+    let update = chain!([1000, h!("block")], [1011, h!("tip")]);
+    let changeset = chain.determine_changeset(&update).unwrap();
+    assert_eq!(changeset.checkpoints.get(&1011), Some(&Some(h!("tip"))));
+    // 991 is now in the same bucket as 981, and 992 is the lowest of the bucket below
+    assert_eq!(changeset.checkpoints.get(&991), Some(&None));
+    assert_eq!(changeset.checkpoints.get(&992), None);
+    assert_eq!(changeset.checkpoints.get(&993), Some(&None));
+    chain.apply_changeset(changeset);
+    assert!(chain.prune_checkpoints_preview().is_empty());
+    assert!(chain.checkpoint_at(500).is_some());
+    assert!(chain.checkpoint_at(0).is_some());
+
+    // a checkpoint that is pruned immediately is not recorded as an addition
+    let update = chain!(
+        checkpoints: [[0, h!("block")], [1, h!("B")], [1011, h!("tip")]],
+        txids: []
+    );
+    let changeset = chain.determine_changeset(&update).unwrap();
+    assert!(changeset.is_empty());
+}
@@ -0,0 +1,314 @@
+#[macro_use]
+mod common;
+
+use bdk_chain::{
+    collections::BTreeMap,
+    sparse_chain::SparseChain,
+    spv::{self, ConfirmationProof, TrustAnchor, TxMerkleProof, VerificationFailure},
+    TxHeight,
+};
+use bitcoin::{
+    hashes::{Hash, HashEngine},
+    BlockHash, BlockHeader, TxMerkleNode, Txid,
+};
+
+/// Build the merkle branch of the leaf at `pos` (the same way bitcoind does).
+fn merkle_branch(txids: &[Txid], mut pos: usize) -> Vec<TxMerkleNode> {
+    let mut level = txids
+        .iter()
+        .map(|txid| TxMerkleNode::from_inner(txid.into_inner()))
+        .collect::<Vec<_>>();
+    let mut branch = Vec::new();
+
+    while level.len() > 1 {
+        if level.len() % 2 == 1 {
+            level.push(*level.last().unwrap());
+        }
+        branch.push(level[pos ^ 1]);
+        level = level
+            .chunks(2)
+            .map(|pair| {
+                let mut engine = TxMerkleNode::engine();
+                engine.input(&pair[0][..]);
+                engine.input(&pair[1][..]);
+                TxMerkleNode::from_engine(engine)
+            })
+            .collect();
+        pos >>= 1;
+    }
+
+    branch
+}
+
+/// Mine a (regtest difficulty) header that commits to `txids`.
+fn mine_header(prev_blockhash: BlockHash, txids: &[Txid]) -> BlockHeader {
+    let merkle_root = bitcoin::util::hash::bitcoin_merkle_root(txids.iter().map(|t| t.as_hash()))
+        .map(TxMerkleNode::from_hash)
+        .unwrap();
+    let mut header = BlockHeader {
+        version: 1,
+        prev_blockhash,
+        merkle_root,
+        time: 0,
+        bits: 0x207fffff,
+        nonce: 0,
+    };
+    while header.validate_pow(&header.target()).is_err() {
+        header.nonce += 1;
+    }
+    header
+}
+
+fn txids(n: u32) -> Vec<Txid> {
+    (0..n).map(|lt| common::new_tx(lt).txid()).collect()
+}
+
+#[test]
+fn merkle_proof_commits_to_every_tx() {
+    let txids = txids(5);
+    let header = mine_header(h!("genesis"), &txids);
+
+    for (pos, &txid) in txids.iter().enumerate() {
+        let proof = TxMerkleProof {
+            block_height: 1,
+            pos,
+            merkle: merkle_branch(&txids, pos),
+        };
+        assert!(
+            proof.verify(txid, &header),
+            "proof of tx {} must verify",
+            pos
+        );
+
+        let wrong_pos = TxMerkleProof {
+            pos: (pos + 1) % txids.len(),
+            ..proof.clone()
+        };
+        assert!(!wrong_pos.verify(txid, &header));
+
+        // the bits of `pos` above the depth of the branch would not change the merkle root
+        let out_of_range_pos = TxMerkleProof {
+            pos: pos | (1 << proof.merkle.len()),
+            ..proof.clone()
+        };
+        assert_eq!(out_of_range_pos.merkle_root(txid), header.merkle_root);
+        assert!(!out_of_range_pos.verify(txid, &header));
+
+        let truncated = TxMerkleProof {
+            merkle: proof.merkle[..proof.merkle.len() - 1].to_vec(),
+            ..proof.clone()
+        };
+        assert!(!truncated.verify(txid, &header));
+    }
+}
+
+#[test]
+fn only_verified_confirmations_are_confirmed() {
+    let block_txids = txids(4);
+    let header = mine_header(h!("A"), &block_txids);
+    let [tx_valid, tx_no_proof, tx_bad_proof, tx_wrong_height] = [
+        block_txids[0],
+        block_txids[1],
+        block_txids[2],
+        block_txids[3],
+    ];
+    let tx_unconfirmed = common::new_tx(100).txid();
+
+    let chain = chain!([1, h!("A")]);
+    let update = chain!(
+        checkpoints: [[1, h!("A")], [2, header.block_hash()]],
+        txids: [
+            (tx_valid, TxHeight::Confirmed(2)),
+            (tx_no_proof, TxHeight::Confirmed(2)),
+            (tx_bad_proof, TxHeight::Confirmed(2)),
+            (tx_wrong_height, TxHeight::Confirmed(2)),
+            (tx_unconfirmed, TxHeight::Unconfirmed)
+        ]
+    );
+
+    let to_verify = spv::txids_to_verify(&chain, &update).collect::<Vec<_>>();
+    assert_eq!(to_verify.len(), 4);
+    assert!(to_verify.iter().all(|&(height, _)| height == 2));
+
+    let proof = |pos: usize, block_height: u32| ConfirmationProof {
+        header,
+        merkle_proof: TxMerkleProof {
+            block_height,
+            pos,
+            merkle: merkle_branch(&block_txids, pos),
+        },
+    };
+    let proofs: BTreeMap<Txid, ConfirmationProof> = [
+        (tx_valid, proof(0, 2)),
+        (tx_bad_proof, proof(0, 2)),
+        (tx_wrong_height, proof(3, 3)),
+    ]
+    .into();
+
+    let regtest_target = BlockHeader::u256_from_compact_target(0x207fffff);
+    let verified = spv::verify_update(&chain, &update, &proofs, Some(regtest_target));
+
+    assert_eq!(
+        verified.update.tx_position(tx_valid),
+        Some(&TxHeight::Confirmed(2))
+    );
+    for txid in [tx_no_proof, tx_bad_proof, tx_wrong_height, tx_unconfirmed] {
+        assert_eq!(
+            verified.update.tx_position(txid),
+            Some(&TxHeight::Unconfirmed)
+        );
+    }
+    assert_eq!(
+        verified.unverified,
+        [
+            (
+                tx_no_proof,
+                (TxHeight::Confirmed(2), VerificationFailure::MissingProof)
+            ),
+            (
+                tx_bad_proof,
+                (
+                    TxHeight::Confirmed(2),
+                    VerificationFailure::MerkleRootMismatch
+                )
+            ),
+            (
+                tx_wrong_height,
+                (
+                    TxHeight::Confirmed(2),
+                    VerificationFailure::HeightMismatch { proof_height: 3 }
+                )
+            ),
+        ]
+        .into()
+    );
+    assert_eq!(
+        verified.update.checkpoints(),
+        update.checkpoints(),
+        "checkpoints must be kept as is"
+    );
+}
+
+#[test]
+fn header_must_match_checkpoint() {
+    let block_txids = txids(2);
+    let header = mine_header(h!("A"), &block_txids);
+
+    let chain = SparseChain::<TxHeight>::default();
+    let update = chain!(
+        checkpoints: [[2, h!("not the header")]],
+        txids: [(block_txids[0], TxHeight::Confirmed(2))]
+    );
+    let proofs = [(
+        block_txids[0],
+        ConfirmationProof {
+            header,
+            merkle_proof: TxMerkleProof {
+                block_height: 2,
+                pos: 0,
+                merkle: merkle_branch(&block_txids, 0),
+            },
+        },
+    )]
+    .into();
+
+    let verified = spv::verify_update(&chain, &update, &proofs, None);
+    assert_eq!(
+        verified.unverified[&block_txids[0]].1,
+        VerificationFailure::HeaderNotInChain {
+            checkpoint_hash: h!("not the header"),
+            header_hash: header.block_hash(),
+        }
+    );
+}
+
+#[test]
+fn known_confirmations_are_not_verified_again() {
+    let [tx_a, tx_b] = [common::new_tx(0).txid(), common::new_tx(1).txid()];
+
+    let chain = chain!(
+        checkpoints: [[1, h!("A")], [2, h!("B")]],
+        txids: [(tx_a, TxHeight::Confirmed(1)), (tx_b, TxHeight::Confirmed(2))]
+    );
+
+    // the update agrees with the local chain
+    let update = chain!(
+        checkpoints: [[2, h!("B")], [3, h!("C")]],
+        txids: [(tx_a, TxHeight::Confirmed(1)), (tx_b, TxHeight::Confirmed(2))]
+    );
+    assert_eq!(spv::txids_to_verify(&chain, &update).count(), 0);
+
+    // the update invalidates block 2, so tx_b's confirmation must be verified again
+    let update = chain!(
+        checkpoints: [[1, h!("A")], [2, h!("B'")]],
+        txids: [(tx_a, TxHeight::Confirmed(1)), (tx_b, TxHeight::Confirmed(2))]
+    );
+    assert_eq!(
+        spv::txids_to_verify(&chain, &update).collect::<Vec<_>>(),
+        vec![(2, tx_b)]
+    );
+
+    let verified = spv::verify_update(&chain, &update, &BTreeMap::new(), None);
+    assert_eq!(
+        verified.update.tx_position(tx_b),
+        Some(&TxHeight::Unconfirmed)
+    );
+}
+
+#[test]
+fn header_must_be_anchored_to_trusted_chain() {
+    let block_txids = txids(2);
+    // a header a malicious chain source can produce at no cost
+    let header = mine_header(h!("A"), &block_txids);
+    assert_eq!(header.bits, 0x207fffff);
+    let proof = ConfirmationProof {
+        header,
+        merkle_proof: TxMerkleProof {
+            block_height: 2,
+            pos: 0,
+            merkle: merkle_branch(&block_txids, 0),
+        },
+    };
+    let proofs = [(block_txids[0], proof.clone())].into();
+
+    // the update vouches for the header but the local chain has no checkpoint at its height
+    let chain = chain!([1, h!("A")]);
+    let update = chain!(
+        checkpoints: [[1, h!("A")], [2, header.block_hash()]],
+        txids: [(block_txids[0], TxHeight::Confirmed(2))]
+    );
+
+    let verified = spv::verify_update(&chain, &update, &proofs, None);
+    assert_eq!(
+        verified.unverified[&block_txids[0]].1,
+        VerificationFailure::NoTrustedAnchor
+    );
+    assert_eq!(
+        verified.update.tx_position(block_txids[0]),
+        Some(&TxHeight::Unconfirmed)
+    );
+
+    let mainnet_target = BlockHeader::u256_from_compact_target(0x1d00ffff);
+    let verified = spv::verify_update(&chain, &update, &proofs, Some(mainnet_target));
+    assert_eq!(
+        verified.unverified[&block_txids[0]].1,
+        VerificationFailure::InsufficientWork
+    );
+
+    // a checkpoint of the local chain anchors the header
+    assert_eq!(
+        proof.verify(
+            block_txids[0],
+            2,
+            Some(TrustAnchor::Checkpoint(header.block_hash()))
+        ),
+        Ok(())
+    );
+    assert_eq!(
+        proof.verify(block_txids[0], 2, Some(TrustAnchor::Checkpoint(h!("B")))),
+        Err(VerificationFailure::HeaderNotInChain {
+            checkpoint_hash: h!("B"),
+            header_hash: header.block_hash(),
+        })
+    );
+}
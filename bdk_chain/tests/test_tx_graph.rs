@@ -350,6 +350,53 @@ fn test_calculate_fee() {
     assert_eq!(graph.calculate_fee(&tx), None);
 }
 
+#[test]
+fn test_missing_prevouts() {
+    let mut graph = TxGraph::default();
+    let parent = Transaction {
+        version: 0x01,
+        lock_time: PackedLockTime(0),
+        input: vec![],
+        output: vec![TxOut::default(), TxOut::default()],
+    };
+    let known_op = OutPoint::new(h!("partial"), 1);
+    let unknown_op = OutPoint::new(h!("unknown"), 0);
+    let tx = Transaction {
+        version: 0x01,
+        lock_time: PackedLockTime(0),
+        input: [OutPoint::new(parent.txid(), 1), known_op, unknown_op]
+            .into_iter()
+            .map(|previous_output| TxIn {
+                previous_output,
+                ..Default::default()
+            })
+            .collect(),
+        output: vec![TxOut::default()],
+    };
+    let coinbase = Transaction {
+        version: 0x01,
+        lock_time: PackedLockTime(0),
+        input: vec![TxIn::default()],
+        output: vec![TxOut::default()],
+    };
+
+    let _ = graph.insert_tx(tx.clone());
+    let _ = graph.insert_tx(coinbase);
+    assert_eq!(
+        graph.missing_prevouts(),
+        [OutPoint::new(parent.txid(), 1), known_op, unknown_op].into()
+    );
+
+    let _ = graph.insert_tx(parent);
+    let _ = graph.insert_txout(known_op, TxOut::default());
+    assert_eq!(graph.missing_prevouts(), [unknown_op].into());
+    assert_eq!(graph.calculate_fee(&tx), None);
+
+    let _ = graph.insert_txout(unknown_op, TxOut::default());
+    assert!(graph.missing_prevouts().is_empty());
+    assert!(graph.calculate_fee(&tx).is_some());
+}
+
 #[test]
 fn test_calculate_fee_on_coinbase() {
     let tx = Transaction {
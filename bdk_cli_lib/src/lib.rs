@@ -4,25 +4,36 @@ use bdk_chain::{
     bitcoin::{
         secp256k1::Secp256k1,
         util::sighash::{Prevouts, SighashCache},
-        Address, LockTime, Network, Sequence, Transaction, TxIn, TxOut,
+        Address, LockTime, Network, OutPoint, Sequence, Transaction, TxIn, TxOut, Txid,
     },
     chain_graph::InsertTxError,
-    keychain::{DerivationAdditions, KeychainChangeSet, KeychainTracker},
+    keychain::{DerivationAdditions, KeychainChangeSet, KeychainTracker, LabelRef, SyncRequest},
     miniscript::{
         descriptor::{DescriptorSecretKey, KeyMap},
         Descriptor, DescriptorPublicKey,
     },
     sparse_chain::{self, ChainPosition},
-    DescriptorExt, FullTxOut,
+    Birthday, DescriptorExt, FullTxOut, TxHeight,
 };
 use bdk_coin_select::{coin_select_bnb, CoinSelector, CoinSelectorOpt, WeightedValue};
 use bdk_file_store::KeychainStore;
 pub use clap;
 use clap::{Parser, Subcommand};
 use std::{
-    cmp::Reverse, collections::HashMap, fmt::Debug, path::PathBuf, sync::Mutex, time::Duration,
+    cmp::Reverse,
+    collections::HashMap,
+    fmt::Debug,
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+/// How long (in seconds) the inputs of a transaction we are creating stay locked.
+const INPUT_LOCK_DURATION: u64 = 600;
+
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
 #[clap(propagate_version = true)]
@@ -41,6 +52,11 @@ pub struct Args<C: clap::Subcommand> {
     #[clap(env = "BDK_CP_LIMIT", long, default_value = "20")]
     pub cp_limit: usize,
 
+    /// The wallet birthday as a block height or a UNIX timestamp (if >= 500000000). Scans skip the
+    /// blocks before the birthday. This is stored in the database once set.
+    #[clap(env = "BDK_BIRTHDAY", long)]
+    pub birthday: Option<Birthday>,
+
     #[clap(subcommand)]
     pub command: Commands<C>,
 }
@@ -62,6 +78,16 @@ pub enum Commands<C: clap::Subcommand> {
         #[clap(subcommand)]
         txout_cmd: TxOutCmd,
     },
+    /// Watch addresses and outpoints that are not derived from the descriptors
+    Watch {
+        #[clap(subcommand)]
+        watch_cmd: WatchCmd,
+    },
+    /// Label import/export (BIP329)
+    Label {
+        #[clap(subcommand)]
+        label_cmd: LabelCmd,
+    },
     /// Send coins to an address
     Send {
         value: u64,
@@ -149,6 +175,42 @@ pub enum TxOutCmd {
         #[clap(long)]
         unconfirmed: bool,
     },
+    /// Freeze an output so that it is never used to fund transactions
+    Freeze { outpoint: OutPoint },
+    /// Unfreeze a previously frozen output
+    Unfreeze { outpoint: OutPoint },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum WatchCmd {
+    /// Watch an address
+    Address {
+        address: Address,
+        /// Stop watching the address instead
+        #[clap(long)]
+        remove: bool,
+    },
+    /// Watch an outpoint
+    Outpoint {
+        outpoint: OutPoint,
+        /// Stop watching the outpoint instead
+        #[clap(long)]
+        remove: bool,
+    },
+    /// List the watched outputs
+    List {
+        /// Return only unspent outputs
+        #[clap(short, long)]
+        unspent: bool,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum LabelCmd {
+    /// Import labels from a BIP329 JSONL file
+    Import { path: PathBuf },
+    /// Export labels as BIP329 JSONL (to stdout if no path is given)
+    Export { path: Option<PathBuf> },
 }
 
 #[derive(
@@ -243,7 +305,7 @@ pub fn run_balance_cmd<P: ChainPosition>(tracker: &Mutex<KeychainTracker<Keychai
     let tracker = tracker.lock().unwrap();
     let (confirmed, unconfirmed) =
         tracker
-            .full_utxos()
+            .spendable_utxos()
             .fold((0, 0), |(confirmed, unconfirmed), (_, utxo)| {
                 if utxo.chain_position.height().is_confirmed() {
                     (confirmed + utxo.txout.value, unconfirmed)
@@ -251,16 +313,67 @@ pub fn run_balance_cmd<P: ChainPosition>(tracker: &Mutex<KeychainTracker<Keychai
                     (confirmed, unconfirmed + utxo.txout.value)
                 }
             });
+    let locked = tracker.balance(|_| false).locked;
 
     println!("confirmed: {}", confirmed);
     println!("unconfirmed: {}", unconfirmed);
+    println!("locked: {}", locked);
+}
+
+/// Creates the [`SyncRequest`] for the `sync` command of the examples.
+///
+/// Without any of the flags, this is the tracker's default [`KeychainTracker::sync_request`].
+/// Otherwise the request only contains what the flags ask for (`all_spks` takes precedence over
+/// `unused_spks`) on top of the watched script pubkeys and outpoints.
+pub fn sync_request<K: Debug + Clone + Ord, P: ChainPosition>(
+    tracker: &Mutex<KeychainTracker<K, P>>,
+    unused_spks: bool,
+    all_spks: bool,
+    utxos: bool,
+    unconfirmed: bool,
+) -> SyncRequest {
+    // Get a short lock on the tracker to get the spks we're interested in
+    let tracker = tracker.lock().unwrap();
+    if !(all_spks || unused_spks || utxos || unconfirmed) {
+        return tracker.sync_request();
+    }
+
+    let mut request = SyncRequest::new(tracker.chain().checkpoints().clone())
+        .with_spks(tracker.watched_spks().iter().cloned())
+        .with_outpoints(tracker.watched_outpoints().iter().copied());
+    if all_spks {
+        request = request.with_spks(tracker.txout_index.all_spks().values().cloned());
+    } else if unused_spks {
+        request = request.with_spks(
+            tracker
+                .txout_index
+                .unused_spks(..)
+                .map(|(_, spk)| spk.clone()),
+        );
+    }
+    if utxos {
+        request = request.with_outpoints(tracker.full_utxos().map(|(_, utxo)| utxo.outpoint));
+    }
+    if unconfirmed {
+        request = request.with_txids(
+            tracker
+                .chain()
+                .range_txids_by_height(TxHeight::Unconfirmed..)
+                .map(|(_, txid)| *txid),
+        );
+    }
+    request
 }
 
 pub fn run_txo_cmd<K: Debug + Clone + Ord, P: ChainPosition>(
     txout_cmd: TxOutCmd,
     tracker: &Mutex<KeychainTracker<K, P>>,
+    db: &Mutex<KeychainStore<K, P>>,
     network: Network,
-) {
+) -> Result<()>
+where
+    KeychainChangeSet<K, P>: serde::Serialize + serde::de::DeserializeOwned,
+{
     match txout_cmd {
         TxOutCmd::List {
             unspent,
@@ -296,14 +409,298 @@ pub fn run_txo_cmd<K: Debug + Clone + Ord, P: ChainPosition>(
                     Address::from_script(&full_txout.txout.script_pubkey, network).unwrap();
 
                 println!(
-                    "{:?} {} {} {} spent:{:?}",
+                    "{:?} {} {} {} spent:{:?} frozen:{} locked_until:{:?} label:{:?}",
                     spk_index,
                     full_txout.txout.value,
                     full_txout.outpoint,
                     address,
-                    full_txout.spent_by
+                    full_txout.spent_by,
+                    tracker.frozen_utxos().contains(&full_txout.outpoint),
+                    tracker.locked_utxos().get(&full_txout.outpoint),
+                    tracker.txout_label(spk_index, full_txout.outpoint),
                 )
             }
+            Ok(())
+        }
+        TxOutCmd::Freeze { outpoint } => {
+            let mut tracker = tracker.lock().unwrap();
+            let changeset = tracker.freeze_utxo_preview(outpoint);
+            if changeset.is_empty() {
+                eprintln!("{} is already frozen", outpoint);
+                return Ok(());
+            }
+            db.lock().unwrap().append_changeset(&changeset)?;
+            tracker.apply_changeset(changeset);
+            Ok(())
+        }
+        TxOutCmd::Unfreeze { outpoint } => {
+            let mut tracker = tracker.lock().unwrap();
+            let changeset = tracker.unfreeze_utxo_preview(outpoint);
+            if changeset.is_empty() {
+                eprintln!("{} is not frozen", outpoint);
+                return Ok(());
+            }
+            db.lock().unwrap().append_changeset(&changeset)?;
+            tracker.apply_changeset(changeset);
+            Ok(())
+        }
+    }
+}
+
+pub fn run_watch_cmd<P>(
+    tracker: &Mutex<KeychainTracker<Keychain, P>>,
+    db: &Mutex<KeychainStore<Keychain, P>>,
+    watch_cmd: WatchCmd,
+    network: Network,
+) -> Result<()>
+where
+    P: ChainPosition,
+    KeychainChangeSet<Keychain, P>: serde::Serialize + serde::de::DeserializeOwned,
+{
+    let mut tracker = tracker.lock().unwrap();
+    let changeset = match watch_cmd {
+        WatchCmd::Address { address, remove } => match remove {
+            true => tracker.unwatch_spk_preview(address.script_pubkey()),
+            false => tracker.watch_spk_preview(address.script_pubkey()),
+        },
+        WatchCmd::Outpoint { outpoint, remove } => match remove {
+            true => tracker.unwatch_outpoint_preview(outpoint),
+            false => tracker.watch_outpoint_preview(outpoint),
+        },
+        WatchCmd::List { unspent } => {
+            let txouts: Box<dyn Iterator<Item = FullTxOut<P>>> = match unspent {
+                true => Box::new(tracker.watched_utxos()),
+                false => Box::new(tracker.watched_txouts()),
+            };
+            for full_txout in txouts {
+                let address = Address::from_script(&full_txout.txout.script_pubkey, network)
+                    .map(|address| address.to_string())
+                    .unwrap_or_else(|_| full_txout.txout.script_pubkey.to_string());
+                println!(
+                    "{} {} {} spent:{:?}",
+                    full_txout.txout.value, full_txout.outpoint, address, full_txout.spent_by
+                );
+            }
+            return Ok(());
+        }
+    };
+    if changeset.is_empty() {
+        eprintln!("nothing to change");
+        return Ok(());
+    }
+    // persist before applying so the tracker and the db do not diverge
+    db.lock().unwrap().append_changeset(&changeset)?;
+    tracker.apply_changeset(changeset);
+    eprintln!("⚠ Run a sync to find the transactions of newly watched addresses and outpoints.");
+    Ok(())
+}
+
+/// A label record as specified in [BIP329].
+///
+/// [BIP329]: https://github.com/bitcoin/bips/blob/master/bip-0329.mediawiki
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct Bip329Label {
+    #[serde(rename = "type")]
+    pub ty: String,
+    #[serde(rename = "ref")]
+    pub reference: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spendable: Option<bool>,
+}
+
+/// Export the labels of the `tracker` as [`Bip329Label`]s.
+///
+/// Outputs are exported with `spendable` set to whether they are frozen.
+pub fn export_labels<P: ChainPosition>(
+    tracker: &KeychainTracker<Keychain, P>,
+    network: Network,
+) -> Vec<Bip329Label> {
+    let mut records = Vec::new();
+    for (label_ref, label) in tracker.labels().iter() {
+        let (ty, reference) = match label_ref {
+            LabelRef::Spk((keychain, index)) => {
+                let spk = match tracker.txout_index.keychains().get(&keychain) {
+                    Some(descriptor) => descriptor.at_derivation_index(index).script_pubkey(),
+                    None => continue,
+                };
+                match Address::from_script(&spk, network) {
+                    Ok(address) => ("addr", address.to_string()),
+                    Err(_) => continue,
+                }
+            }
+            LabelRef::Tx(txid) => ("tx", txid.to_string()),
+            // outputs are handled below so they can be merged with the frozen outputs
+            LabelRef::Output(_) => continue,
+        };
+        records.push(Bip329Label {
+            ty: ty.to_string(),
+            reference,
+            label: Some(label.to_string()),
+            origin: None,
+            spendable: None,
+        });
+    }
+
+    let labelled_outputs = tracker
+        .labels()
+        .iter()
+        .filter_map(|(label_ref, _)| match label_ref {
+            LabelRef::Output(outpoint) => Some(outpoint),
+            _ => None,
+        })
+        .collect::<std::collections::BTreeSet<_>>();
+    for outpoint in labelled_outputs.union(tracker.frozen_utxos()) {
+        records.push(Bip329Label {
+            ty: "output".to_string(),
+            reference: outpoint.to_string(),
+            label: tracker.labels().output_label(*outpoint).map(str::to_string),
+            origin: None,
+            spendable: Some(!tracker.frozen_utxos().contains(outpoint)),
+        });
+    }
+
+    records
+}
+
+/// How many indices past the stored script pubkeys of each keychain are searched when importing
+/// address labels.
+pub const LABEL_IMPORT_SEARCH_GAP: u32 = 1_000;
+
+/// Determine the changes to `tracker` as result of importing the `records`.
+///
+/// Labels of transactions, addresses and outputs are imported and outputs with `spendable` set are
+/// frozen (or unfrozen). Addresses are searched for up to [`LABEL_IMPORT_SEARCH_GAP`] indices past
+/// the last revealed index (and lookahead) of each keychain, and keychains are revealed up to the
+/// index of any address found. Records of other types and of addresses that are not derived from
+/// our keychains are skipped and returned.
+pub fn import_labels_preview<P: ChainPosition>(
+    tracker: &KeychainTracker<Keychain, P>,
+    records: impl IntoIterator<Item = Bip329Label>,
+) -> Result<(KeychainChangeSet<Keychain, P>, Vec<Bip329Label>)> {
+    let mut changeset = KeychainChangeSet::default();
+    let mut skipped = Vec::new();
+    // addresses may belong to indices that are not revealed yet, so we reveal on a copy of the
+    // index and only derive past the stored script pubkeys when we first need to
+    let mut txout_index = tracker.txout_index.clone();
+    let mut derived_spks = None::<HashMap<_, _>>;
+    for record in records {
+        let label_ref = match record.ty.as_str() {
+            "tx" => LabelRef::Tx(
+                Txid::from_str(&record.reference)
+                    .with_context(|| format!("invalid txid '{}'", record.reference))?,
+            ),
+            "addr" => {
+                let address = Address::from_str(&record.reference)
+                    .with_context(|| format!("invalid address '{}'", record.reference))?;
+                let spk = address.script_pubkey();
+                let index = match txout_index.index_of_spk(&spk) {
+                    Some(&index) => Some(index),
+                    None => derived_spks
+                        .get_or_insert_with(|| {
+                            txout_index
+                                .keychains()
+                                .keys()
+                                .flat_map(|keychain| {
+                                    let end = txout_index
+                                        .last_revealed_index(keychain)
+                                        .map_or(0, |index| index + 1)
+                                        .saturating_add(
+                                            txout_index
+                                                .lookaheads()
+                                                .get(keychain)
+                                                .copied()
+                                                .unwrap_or(0),
+                                        )
+                                        .saturating_add(LABEL_IMPORT_SEARCH_GAP);
+                                    txout_index
+                                        .spks_of_keychain(keychain)
+                                        .take_while(move |(index, _)| *index < end)
+                                        .map(move |(index, spk)| (spk, (*keychain, index)))
+                                })
+                                .collect()
+                        })
+                        .get(&spk)
+                        .cloned(),
+                };
+                match index {
+                    Some((keychain, index)) => {
+                        let (_, additions) = txout_index.reveal_to_target(&keychain, index);
+                        changeset.derivation_indices.append(additions);
+                        LabelRef::Spk((keychain, index))
+                    }
+                    None => {
+                        skipped.push(record);
+                        continue;
+                    }
+                }
+            }
+            "output" => {
+                let outpoint = OutPoint::from_str(&record.reference)
+                    .with_context(|| format!("invalid outpoint '{}'", record.reference))?;
+                match record.spendable {
+                    Some(true) => changeset.append(tracker.unfreeze_utxo_preview(outpoint)),
+                    Some(false) => changeset.append(tracker.freeze_utxo_preview(outpoint)),
+                    None => {}
+                }
+                LabelRef::Output(outpoint)
+            }
+            _ => {
+                skipped.push(record);
+                continue;
+            }
+        };
+        if let Some(label) = record.label {
+            changeset.append(tracker.set_label_preview(label_ref, Some(label)));
+        }
+    }
+    Ok((changeset, skipped))
+}
+
+pub fn run_label_cmd<P>(
+    tracker: &Mutex<KeychainTracker<Keychain, P>>,
+    db: &Mutex<KeychainStore<Keychain, P>>,
+    label_cmd: LabelCmd,
+    network: Network,
+) -> Result<()>
+where
+    P: ChainPosition,
+    KeychainChangeSet<Keychain, P>: serde::Serialize + serde::de::DeserializeOwned,
+{
+    match label_cmd {
+        LabelCmd::Import { path } => {
+            let records = BufReader::new(File::open(&path)?)
+                .lines()
+                .enumerate()
+                .filter(|(_, line)| line.as_ref().map_or(true, |line| !line.trim().is_empty()))
+                .map(|(i, line)| {
+                    serde_json::from_str::<Bip329Label>(&line?)
+                        .with_context(|| format!("invalid label record at line {}", i + 1))
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            let mut tracker = tracker.lock().unwrap();
+            let (changeset, skipped) = import_labels_preview(&tracker, records)?;
+            db.lock().unwrap().append_changeset(&changeset)?;
+            tracker.apply_changeset(changeset);
+            for record in skipped {
+                eprintln!("skipped {} label of {}", record.ty, record.reference);
+            }
+            Ok(())
+        }
+        LabelCmd::Export { path } => {
+            let records = export_labels(&tracker.lock().unwrap(), network);
+            let mut out: Box<dyn Write> = match path {
+                Some(path) => Box::new(File::create(path)?),
+                None => Box::new(std::io::stdout()),
+            };
+            for record in records {
+                writeln!(out, "{}", serde_json::to_string(&record)?)?;
+            }
+            Ok(())
         }
     }
 }
@@ -542,10 +939,9 @@ where
             run_balance_cmd(&tracker);
             Ok(())
         }
-        Commands::TxOut { txout_cmd } => {
-            run_txo_cmd(txout_cmd, &tracker, network);
-            Ok(())
-        }
+        Commands::TxOut { txout_cmd } => run_txo_cmd(txout_cmd, tracker, store, network),
+        Commands::Watch { watch_cmd } => run_watch_cmd(tracker, store, watch_cmd, network),
+        Commands::Label { label_cmd } => run_label_cmd(tracker, store, label_cmd, network),
         Commands::Send {
             value,
             address,
@@ -554,9 +950,17 @@ where
             let (transaction, change_index) = {
                 // take mutable ref to construct tx -- it is only open for a short time while building it.
                 let tracker = &mut *tracker.lock().unwrap();
+                let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+                tracker.unlock_expired(now);
                 let (transaction, change_info) =
                     create_tx(value, address, coin_select, tracker, &keymap)?;
 
+                // Lock the inputs so other callers/threads don't try to spend them while we
+                // broadcast.
+                for txin in &transaction.input {
+                    tracker.lock_utxo(txin.previous_output, now + INPUT_LOCK_DURATION);
+                }
+
                 if let Some((change_derivation_changes, (change_keychain, index))) = change_info {
                     // We must first persist to disk the fact that we've got a new address from the
                     // change keychain so future scans will find the tx we're about to broadcast.
@@ -590,7 +994,7 @@ where
                         Err(e) => match e {
                             InsertTxError::Chain(e) => match e {
                                 // TODO: add insert_unconfirmed_tx to chain graph and sparse chain
-                                sparse_chain::InsertTxError::TxTooHigh { .. } => unreachable!("we are inserting at unconfirmed position"),
+                                sparse_chain::InsertTxError::TxTooHigh { .. } | sparse_chain::InsertTxError::AnchorNotMatching { .. } => unreachable!("we are inserting at unconfirmed position"),
                                 sparse_chain::InsertTxError::TxMovedUnexpectedly { txid, original_pos, ..} => Err(anyhow!("the tx we created {} has already been confirmed at block {:?}", txid, original_pos)),
                             },
                             InsertTxError::UnresolvableConflict(e) => Err(e).context("another tx that conflicts with the one we tried to create has been confirmed"),
@@ -603,6 +1007,10 @@ where
                         // We failed to broadcast so allow our change address to be used in the future
                         tracker.txout_index.unmark_used(&keychain, index);
                     }
+                    // ...and the inputs to be spent in the future
+                    for txin in &transaction.input {
+                        tracker.unlock_utxo(txin.previous_output);
+                    }
                     Err(e.into())
                 }
             }
@@ -651,6 +1059,14 @@ where
 
     let mut db = KeychainStore::<Keychain, P>::new_from_path(args.db_path.as_path())?;
 
+    if let Some(birthday) = args.birthday {
+        if db.metadata().birthday != Some(birthday) {
+            let mut metadata = db.metadata().clone();
+            metadata.birthday = Some(birthday);
+            db.set_metadata(metadata)?;
+        }
+    }
+
     if let Err(e) = db.load_into_keychain_tracker(&mut tracker) {
         match tracker.chain().latest_checkpoint()  {
             Some(checkpoint) => eprintln!("Failed to load all changesets from {}. Last checkpoint was at height {}. Error: {}", args.db_path.display(), checkpoint.height, e),
@@ -668,7 +1084,7 @@ pub fn planned_utxos<'a, AK: bdk_tmp_plan::CanDerive + Clone, P: ChainPosition>(
     assets: &'a bdk_tmp_plan::Assets<AK>,
 ) -> impl Iterator<Item = (bdk_tmp_plan::Plan<AK>, FullTxOut<P>)> + 'a {
     tracker
-        .full_utxos()
+        .spendable_utxos()
         .filter_map(|((keychain, derivation_index), full_txout)| {
             Some((
                 bdk_tmp_plan::plan_satisfaction(
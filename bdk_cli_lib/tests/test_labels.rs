@@ -0,0 +1,205 @@
+use bdk_chain::{
+    bitcoin::{secp256k1::Secp256k1, Address, Network, OutPoint, Txid},
+    keychain::{KeychainTracker, LabelRef},
+    miniscript::Descriptor,
+    TxHeight,
+};
+use bdk_cli::{export_labels, import_labels_preview, Bip329Label, Keychain};
+use std::str::FromStr;
+
+const XPRV: &str = "[73c5da0a/86'/0'/0']xprv9xgqHN7yz9MwCkxsBPN5qetuNdQSUttZNKw1dcYTV4mkaAFiBVGQziHs3NRSWMkCzvgjEe3n9xV8oYywvM8at9yRqyaZVz6TYYhX98VjsUk";
+
+fn new_tracker() -> KeychainTracker<Keychain, TxHeight> {
+    let secp = Secp256k1::signing_only();
+    let mut tracker = KeychainTracker::default();
+    for (keychain, path) in [(Keychain::External, 0), (Keychain::Internal, 1)] {
+        let (descriptor, _) =
+            Descriptor::parse_descriptor(&secp, &format!("tr({}/{}/*)", XPRV, path)).unwrap();
+        tracker.add_keychain(keychain, descriptor);
+    }
+    tracker.txout_index.set_lookahead(&Keychain::External, 10);
+    tracker
+}
+
+fn address_at(
+    tracker: &KeychainTracker<Keychain, TxHeight>,
+    keychain: Keychain,
+    index: u32,
+) -> Address {
+    let spk = tracker.txout_index.keychains()[&keychain]
+        .at_derivation_index(index)
+        .script_pubkey();
+    Address::from_script(&spk, Network::Bitcoin).unwrap()
+}
+
+fn record(ty: &str, reference: impl ToString, label: Option<&str>) -> Bip329Label {
+    Bip329Label {
+        ty: ty.to_string(),
+        reference: reference.to_string(),
+        label: label.map(str::to_string),
+        origin: None,
+        spendable: None,
+    }
+}
+
+#[test]
+fn labels_round_trip() {
+    let txid =
+        Txid::from_str("4d3b4a5e1e8a4f4b2b7a0f3c4e5d6a7b8c9d0e1f2a3b4c5d6e7f8091a2b3c4d5").unwrap();
+    let labelled_outpoint = OutPoint::new(txid, 0);
+    let frozen_outpoint = OutPoint::new(txid, 1);
+
+    let mut tracker = new_tracker();
+    let _ = tracker.txout_index.reveal_to_target(&Keychain::External, 2);
+    let _ = tracker.set_label(LabelRef::Spk((Keychain::External, 2)), Some("addr".into()));
+    let _ = tracker.set_label(LabelRef::Tx(txid), Some("tx".into()));
+    let _ = tracker.set_label(LabelRef::Output(labelled_outpoint), Some("output".into()));
+    let _ = tracker.freeze_utxo(frozen_outpoint);
+
+    let records = export_labels(&tracker, Network::Bitcoin);
+    assert!(records.contains(&record(
+        "addr",
+        address_at(&tracker, Keychain::External, 2),
+        Some("addr")
+    )));
+    assert!(records.contains(&record("tx", txid, Some("tx"))));
+    assert!(records.contains(&Bip329Label {
+        spendable: Some(true),
+        ..record("output", labelled_outpoint, Some("output"))
+    }));
+    assert!(records.contains(&Bip329Label {
+        spendable: Some(false),
+        ..record("output", frozen_outpoint, None)
+    }));
+    assert_eq!(records.len(), 4);
+
+    // records survive being written as JSON lines
+    let records = records
+        .iter()
+        .map(|record| serde_json::to_string(record).unwrap())
+        .map(|line| serde_json::from_str::<Bip329Label>(&line).unwrap())
+        .collect::<Vec<_>>();
+
+    let mut imported = new_tracker();
+    let (changeset, skipped) = import_labels_preview(&imported, records).unwrap();
+    assert!(skipped.is_empty());
+    imported.apply_changeset(changeset);
+
+    assert_eq!(
+        imported.labels().iter().collect::<Vec<_>>(),
+        tracker.labels().iter().collect::<Vec<_>>()
+    );
+    assert_eq!(imported.frozen_utxos(), tracker.frozen_utxos());
+    assert_eq!(
+        imported
+            .txout_index
+            .last_revealed_index(&Keychain::External),
+        Some(2)
+    );
+}
+
+#[test]
+fn spendable_freezes_and_unfreezes_outputs() {
+    let frozen =
+        OutPoint::from_str("4d3b4a5e1e8a4f4b2b7a0f3c4e5d6a7b8c9d0e1f2a3b4c5d6e7f8091a2b3c4d5:0")
+            .unwrap();
+    let unfrozen = OutPoint { vout: 1, ..frozen };
+    let untouched = OutPoint { vout: 2, ..frozen };
+
+    let mut tracker = new_tracker();
+    let _ = tracker.freeze_utxo(frozen);
+    let _ = tracker.freeze_utxo(untouched);
+
+    let records = vec![
+        Bip329Label {
+            spendable: Some(true),
+            ..record("output", frozen, None)
+        },
+        Bip329Label {
+            spendable: Some(false),
+            ..record("output", unfrozen, None)
+        },
+        record("output", untouched, Some("still frozen")),
+    ];
+    let (changeset, skipped) = import_labels_preview(&tracker, records).unwrap();
+    assert!(skipped.is_empty());
+    assert_eq!(changeset.frozen, [(frozen, false), (unfrozen, true)].into());
+    tracker.apply_changeset(changeset);
+
+    assert_eq!(tracker.frozen_utxos(), &[unfrozen, untouched].into());
+    assert_eq!(
+        tracker.labels().output_label(untouched),
+        Some("still frozen")
+    );
+}
+
+#[test]
+fn unknown_records_are_skipped() {
+    let secp = Secp256k1::signing_only();
+    let (foreign_descriptor, _) =
+        Descriptor::parse_descriptor(&secp, &format!("tr({}/2/*)", XPRV)).unwrap();
+    let foreign_address = Address::from_script(
+        &foreign_descriptor.at_derivation_index(0).script_pubkey(),
+        Network::Bitcoin,
+    )
+    .unwrap();
+
+    let tracker = new_tracker();
+    let records = vec![
+        record("xpub", XPRV, Some("unsupported type")),
+        record("addr", foreign_address, Some("not ours")),
+    ];
+    let (changeset, skipped) = import_labels_preview(&tracker, records.clone()).unwrap();
+    assert!(changeset.is_empty());
+    assert_eq!(skipped, records);
+
+    assert!(import_labels_preview(&tracker, [record("tx", "not a txid", None)]).is_err());
+}
+
+#[test]
+fn unrevealed_addresses_are_revealed() {
+    let tracker = new_tracker();
+    // within the lookahead, past the lookahead and past the search gap
+    let records = vec![
+        record(
+            "addr",
+            address_at(&tracker, Keychain::External, 5),
+            Some("lookahead"),
+        ),
+        record(
+            "addr",
+            address_at(&tracker, Keychain::Internal, 500),
+            Some("derived"),
+        ),
+        record(
+            "addr",
+            address_at(&tracker, Keychain::Internal, 5_000),
+            Some("too far"),
+        ),
+    ];
+    let (changeset, skipped) = import_labels_preview(&tracker, records.clone()).unwrap();
+    assert_eq!(skipped, records[2..]);
+    assert_eq!(
+        changeset.derivation_indices.as_ref(),
+        &[(Keychain::External, 5), (Keychain::Internal, 500)].into()
+    );
+
+    let mut tracker = tracker;
+    tracker.apply_changeset(changeset);
+    assert_eq!(
+        tracker
+            .labels()
+            .get(&LabelRef::Spk((Keychain::External, 5))),
+        Some("lookahead")
+    );
+    assert_eq!(
+        tracker
+            .labels()
+            .get(&LabelRef::Spk((Keychain::Internal, 500))),
+        Some("derived")
+    );
+    assert_eq!(
+        tracker.txout_index.last_revealed_index(&Keychain::Internal),
+        Some(500)
+    );
+}
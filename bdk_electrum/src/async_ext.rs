@@ -0,0 +1,790 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::Debug,
+    future::Future,
+    sync::Arc,
+};
+
+use bdk_chain::{
+    bitcoin::{BlockHash, BlockHeader, OutPoint, Script, Transaction, Txid},
+    chain_oracle::{CheckpointUpdateError, CHECKPOINT_UPDATE_ATTEMPTS},
+    keychain::{
+        CancelToken, FullScanRequest, KeychainScan, ScanError, ScanItem, ScanProgress, StopGap,
+        SyncRequest,
+    },
+    sparse_chain::SparseChain,
+    tx_graph::TxGraph,
+    AsTransaction, Birthday, BlockId, ConfirmationTime, TxHeight,
+};
+use electrum_client::{Client, ElectrumApi, Error, GetHistoryRes};
+use futures_util::future::try_join_all;
+
+use crate::{
+    check_cancelled, insert_spk_histories, insert_tx, outpoint_history, sync_spks, ElectrumUpdate,
+    InternalError, ScannedSpks,
+};
+
+/// The electrum methods that [`AsyncElectrumExt`] needs from an async electrum client.
+///
+/// This can be implemented for the async electrum client of your runtime. [`ThreadedClient`]
+/// implements it for the blocking [`Client`] by running each call on its own thread.
+pub trait AsyncElectrumApi {
+    /// Get the height and hash of the tip of the best chain.
+    fn get_tip(&self) -> impl Future<Output = Result<(u32, BlockHash), Error>> + Send;
+
+    /// Get the block headers at the given `heights`.
+    fn batch_block_header(
+        &self,
+        heights: Vec<u32>,
+    ) -> impl Future<Output = Result<Vec<BlockHeader>, Error>> + Send;
+
+    /// Get the transaction histories of the given `scripts`.
+    fn batch_script_get_history(
+        &self,
+        scripts: Vec<Script>,
+    ) -> impl Future<Output = Result<Vec<Vec<GetHistoryRes>>, Error>> + Send;
+
+    /// Get the transactions of the given `txids`.
+    fn batch_transaction_get(
+        &self,
+        txids: Vec<Txid>,
+    ) -> impl Future<Output = Result<Vec<Transaction>, Error>> + Send;
+}
+
+/// An [`AsyncElectrumApi`] that runs each call of a blocking [`Client`] on its own thread.
+///
+/// This does not depend on a particular async runtime. Prefer a native async electrum client
+/// where one is available.
+#[derive(Clone)]
+pub struct ThreadedClient(pub Arc<Client>);
+
+impl ThreadedClient {
+    /// Run `call` with the client on a new thread.
+    fn spawn<R: Send + 'static>(
+        &self,
+        call: impl FnOnce(&Client) -> Result<R, Error> + Send + 'static,
+    ) -> impl Future<Output = Result<R, Error>> + Send {
+        let (sender, receiver) = futures_channel::oneshot::channel();
+        let client = self.0.clone();
+        std::thread::spawn(move || {
+            let _ = sender.send(call(&client));
+        });
+        async move { receiver.await.map_err(|_| Error::Mpsc)? }
+    }
+}
+
+impl AsyncElectrumApi for ThreadedClient {
+    fn get_tip(&self) -> impl Future<Output = Result<(u32, BlockHash), Error>> + Send {
+        self.spawn(crate::get_tip)
+    }
+
+    fn batch_block_header(
+        &self,
+        heights: Vec<u32>,
+    ) -> impl Future<Output = Result<Vec<BlockHeader>, Error>> + Send {
+        self.spawn(move |client| client.batch_block_header(heights))
+    }
+
+    fn batch_script_get_history(
+        &self,
+        scripts: Vec<Script>,
+    ) -> impl Future<Output = Result<Vec<Vec<GetHistoryRes>>, Error>> + Send {
+        self.spawn(move |client| client.batch_script_get_history(&scripts))
+    }
+
+    fn batch_transaction_get(
+        &self,
+        txids: Vec<Txid>,
+    ) -> impl Future<Output = Result<Vec<Transaction>, Error>> + Send {
+        self.spawn(move |client| client.batch_transaction_get(&txids))
+    }
+}
+
+/// Async version of [`ElectrumExt`] for any [`AsyncElectrumApi`].
+///
+/// The requests of a scan are batched (`batch_size` items per request) and up to
+/// `parallel_requests` batches are in flight at a time. The results are the same
+/// [`ElectrumUpdate`]s that [`ElectrumExt`] returns.
+///
+/// [`ElectrumExt`]: crate::ElectrumExt
+#[allow(async_fn_in_trait)]
+pub trait AsyncElectrumExt: AsyncElectrumApi + Sync + Sized {
+    /// Async version of [`ElectrumExt::scan`].
+    ///
+    /// [`ElectrumExt::scan`]: crate::ElectrumExt::scan
+    #[allow(clippy::too_many_arguments)]
+    async fn scan<K: Ord + Clone>(
+        &self,
+        local_chain: &BTreeMap<u32, BlockHash>,
+        keychain_spks: BTreeMap<K, impl IntoIterator<Item = (u32, Script)>>,
+        spks: impl IntoIterator<Item = Script>,
+        txids: impl IntoIterator<Item = Txid>,
+        outpoints: impl IntoIterator<Item = OutPoint>,
+        birthday: Option<Birthday>,
+        stop_gap: usize,
+        batch_size: usize,
+        parallel_requests: usize,
+        mut inspect: impl FnMut(ScanItem<'_, K>),
+        cancel: Option<&CancelToken>,
+    ) -> Result<ElectrumUpdate<K, TxHeight>, ScanError<ElectrumUpdate<K, TxHeight>, Error>> {
+        let batch_size = batch_size.max(1);
+        let parallel_requests = parallel_requests.max(1);
+        let mut request_spks = keychain_spks
+            .into_iter()
+            .map(|(k, s)| (k, s.into_iter()))
+            .collect::<BTreeMap<K, _>>();
+        let mut scanned_spks = ScannedSpks::<K>::default();
+
+        let spks = spks.into_iter().collect::<Vec<_>>();
+        let txids = txids.into_iter().collect::<Vec<_>>();
+        let outpoints = outpoints.into_iter().collect::<Vec<_>>();
+
+        let start_height = match birthday {
+            Some(birthday) => start_height(self, birthday).await?,
+            None => 0,
+        };
+
+        let update = 'scan: loop {
+            let mut update = prepare_update(self, local_chain).await?;
+
+            let mut rescanned_spks = BTreeMap::new();
+            match populate_with_spks(
+                self,
+                &mut update,
+                &mut scanned_spks.to_rescan().into_iter(),
+                start_height,
+                usize::MAX,
+                (batch_size, parallel_requests),
+                &mut rescanned_spks,
+                &mut |_, _, _| {},
+                cancel,
+            )
+            .await
+            {
+                Err(InternalError::Reorg) => continue,
+                Err(err) => return Err(err.with_partial(scanned_spks.partial_update(update))),
+                Ok(()) => scanned_spks.record_rescanned(rescanned_spks),
+            }
+
+            for (keychain, keychain_spks) in &mut request_spks {
+                if scanned_spks.is_finished(keychain) {
+                    continue;
+                }
+                let mut keychain_scanned_spks = BTreeMap::new();
+                let result = populate_with_spks(
+                    self,
+                    &mut update,
+                    keychain_spks,
+                    start_height,
+                    stop_gap,
+                    (batch_size, parallel_requests),
+                    &mut keychain_scanned_spks,
+                    &mut |&index, spk, txids| {
+                        inspect(ScanItem::Spk {
+                            keychain,
+                            index,
+                            spk,
+                            txids,
+                        })
+                    },
+                    cancel,
+                )
+                .await;
+                scanned_spks.record(keychain, keychain_scanned_spks);
+                match result {
+                    Err(InternalError::Reorg) => continue 'scan,
+                    Err(err) => return Err(err.with_partial(scanned_spks.partial_update(update))),
+                    Ok(()) => scanned_spks.finish(keychain),
+                }
+            }
+
+            // the script pubkeys outside of the keychains are scanned in full on every attempt
+            match populate_with_spks(
+                self,
+                &mut update,
+                &mut spks.iter().map(|spk| (spk.clone(), spk.clone())),
+                0,
+                usize::MAX,
+                (batch_size, parallel_requests),
+                &mut BTreeMap::new(),
+                &mut |_, spk, txids| inspect(ScanItem::MiscSpk { spk, txids }),
+                cancel,
+            )
+            .await
+            {
+                Err(InternalError::Reorg) => continue,
+                Err(err) => return Err(err.with_partial(scanned_spks.partial_update(update))),
+                Ok(()) => {}
+            }
+
+            let result = match populate_with_txids(
+                self,
+                &mut update,
+                &txids,
+                (batch_size, parallel_requests),
+                &mut inspect,
+                cancel,
+            )
+            .await
+            {
+                Ok(()) => {
+                    populate_with_outpoints(
+                        self,
+                        &mut update,
+                        &outpoints,
+                        (batch_size, parallel_requests),
+                        &mut inspect,
+                        cancel,
+                    )
+                    .await
+                }
+                err => err,
+            };
+            match result {
+                Err(InternalError::Reorg) => continue,
+                Err(err) => return Err(err.with_partial(scanned_spks.partial_update(update))),
+                Ok(()) => {}
+            }
+
+            // check for reorgs during scan process
+            let our_tip = update
+                .latest_checkpoint()
+                .expect("update must have atleast one checkpoint");
+            match self.batch_block_header(vec![our_tip.height]).await {
+                Ok(headers) if headers[0].block_hash() != our_tip.hash => continue, // reorg
+                Ok(_) => break update,
+                Err(err) => {
+                    return Err(
+                        InternalError::from(err).with_partial(scanned_spks.partial_update(update))
+                    )
+                }
+            }
+        };
+
+        Ok(scanned_spks.update(update))
+    }
+
+    /// Async version of [`ElectrumExt::full_scan`].
+    ///
+    /// [`ElectrumExt::full_scan`]: crate::ElectrumExt::full_scan
+    #[allow(clippy::type_complexity)]
+    async fn full_scan<K: Ord + Clone + Debug>(
+        &self,
+        request: FullScanRequest<K, impl Iterator<Item = (u32, Script)>>,
+        stop_gap: usize,
+        batch_size: usize,
+        parallel_requests: usize,
+        inspect: impl FnMut(ScanItem<'_, K>),
+        cancel: Option<&CancelToken>,
+    ) -> Result<
+        KeychainScan<K, ConfirmationTime>,
+        ScanError<KeychainScan<K, ConfirmationTime>, Error>,
+    > {
+        let result = full_scan_update(
+            self,
+            request,
+            stop_gap,
+            batch_size,
+            parallel_requests,
+            inspect,
+            cancel,
+        )
+        .await;
+        finalize_scan(self, result, batch_size, parallel_requests).await
+    }
+
+    /// Async version of [`ElectrumExt::sync`].
+    ///
+    /// [`ElectrumExt::sync`]: crate::ElectrumExt::sync
+    #[allow(clippy::type_complexity)]
+    async fn sync<K: Ord + Clone + Debug>(
+        &self,
+        request: SyncRequest,
+        batch_size: usize,
+        parallel_requests: usize,
+        inspect: impl FnMut(ScanItem<'_, ()>),
+        cancel: Option<&CancelToken>,
+    ) -> Result<
+        KeychainScan<K, ConfirmationTime>,
+        ScanError<KeychainScan<K, ConfirmationTime>, Error>,
+    > {
+        let result = sync_update(
+            self,
+            request,
+            batch_size,
+            parallel_requests,
+            inspect,
+            cancel,
+        )
+        .await;
+        finalize_scan(self, result, batch_size, parallel_requests).await
+    }
+
+    /// Async version of [`ElectrumUpdate::finalize`].
+    async fn finalize<K, T, G>(
+        &self,
+        update: ElectrumUpdate<K, TxHeight>,
+        graph: G,
+        headers: &mut BTreeMap<u32, BlockHeader>,
+        batch_size: usize,
+        parallel_requests: usize,
+    ) -> Result<KeychainScan<K, ConfirmationTime>, Error>
+    where
+        K: Ord + Clone + Debug,
+        T: AsTransaction,
+        G: AsRef<TxGraph<T>> + Send,
+    {
+        let missing_txids = update.txids_missing_from(graph.as_ref());
+        let fetched = self
+            .fetch_txs(missing_txids, batch_size, parallel_requests)
+            .await?;
+        let (mut update, txs) = update.with_fetched_txs(graph.as_ref(), fetched);
+        let missing_prevouts = update.missing_prevouts(&graph, &txs);
+        let parent_txids = missing_prevouts
+            .iter()
+            .map(|op| op.txid)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        let parent_txs = self
+            .fetch_txs(parent_txids, batch_size, parallel_requests)
+            .await?;
+        update.insert_prevouts(&missing_prevouts, parent_txs);
+
+        let heights = update.headers_to_fetch(headers);
+        let fetched = batched(
+            heights.clone(),
+            (batch_size.max(1), parallel_requests.max(1)),
+            |heights| self.batch_block_header(heights),
+        )
+        .await?;
+        headers.extend(heights.into_iter().zip(fetched));
+
+        update.with_confirmation_times(headers)?.with_full_txs(txs)
+    }
+
+    /// Fetch the transactions of `txids` (leaving out the ones the server does not have).
+    async fn fetch_txs(
+        &self,
+        txids: Vec<Txid>,
+        batch_size: usize,
+        parallel_requests: usize,
+    ) -> Result<Vec<Transaction>, Error> {
+        batched(
+            txids,
+            (batch_size.max(1), parallel_requests.max(1)),
+            |txids| async move {
+                match self.batch_transaction_get(txids.clone()).await {
+                    // one of the transactions is missing so we get them one by one
+                    Err(Error::Protocol(_)) => {
+                        let mut txs = Vec::with_capacity(txids.len());
+                        for txid in txids {
+                            match self.batch_transaction_get(vec![txid]).await {
+                                Ok(tx) => txs.extend(tx),
+                                Err(Error::Protocol(_)) => continue,
+                                Err(err) => return Err(err),
+                            }
+                        }
+                        Ok(txs)
+                    }
+                    result => result,
+                }
+            },
+        )
+        .await
+    }
+
+    /// Async version of [`ElectrumUpdate::into_confirmation_time_update`].
+    async fn confirmation_time_update<K: Ord + Clone + Debug>(
+        &self,
+        update: ElectrumUpdate<K, TxHeight>,
+        batch_size: usize,
+        parallel_requests: usize,
+    ) -> Result<ElectrumUpdate<K, ConfirmationTime>, Error> {
+        let heights = update
+            .confirmation_heights()
+            .into_iter()
+            .collect::<Vec<_>>();
+        let headers = batched(
+            heights.clone(),
+            (batch_size.max(1), parallel_requests.max(1)),
+            |heights| self.batch_block_header(heights),
+        )
+        .await?;
+        update.with_confirmation_times(&heights.into_iter().zip(headers).collect())
+    }
+}
+
+impl<C: AsyncElectrumApi + Sync> AsyncElectrumExt for C {}
+
+/// Split `items` into batches of `batch_size` and call `f` on each (with up to
+/// `parallel_requests` calls in flight at a time). The results are returned in order.
+async fn batched<T, R, Fut>(
+    items: Vec<T>,
+    (batch_size, parallel_requests): (usize, usize),
+    f: impl Fn(Vec<T>) -> Fut,
+) -> Result<Vec<R>, Error>
+where
+    Fut: Future<Output = Result<Vec<R>, Error>>,
+{
+    let mut results = Vec::with_capacity(items.len());
+    let mut items = items.into_iter().peekable();
+    while items.peek().is_some() {
+        let calls = (0..parallel_requests)
+            .map(|_| items.by_ref().take(batch_size).collect::<Vec<_>>())
+            .filter(|batch| !batch.is_empty())
+            .map(&f)
+            .collect::<Vec<_>>();
+        for batch_results in try_join_all(calls).await? {
+            results.extend(batch_results);
+        }
+    }
+    Ok(results)
+}
+
+/// The unfinalized update of [`AsyncElectrumExt::full_scan`].
+#[allow(clippy::type_complexity)]
+async fn full_scan_update<K: Ord + Clone>(
+    client: &impl AsyncElectrumExt,
+    request: FullScanRequest<K, impl Iterator<Item = (u32, Script)>>,
+    stop_gap: usize,
+    batch_size: usize,
+    parallel_requests: usize,
+    inspect: impl FnMut(ScanItem<'_, K>),
+    cancel: Option<&CancelToken>,
+) -> Result<ElectrumUpdate<K, TxHeight>, ScanError<ElectrumUpdate<K, TxHeight>, Error>> {
+    let FullScanRequest {
+        local_chain,
+        spks_by_keychain,
+        spks,
+        outpoints,
+        birthday,
+        mut progress,
+    } = request;
+
+    // a reorg past the tip of the progress may have invalidated what was scanned
+    if !progress.is_empty()
+        && get_block_hash(client, progress.tip.height).await? != Some(progress.tip.hash)
+    {
+        progress = ScanProgress::default();
+    }
+
+    client
+        .scan(
+            &local_chain,
+            progress.resume(spks_by_keychain),
+            spks,
+            [],
+            outpoints,
+            birthday,
+            stop_gap,
+            batch_size,
+            parallel_requests,
+            inspect,
+            cancel,
+        )
+        .await
+        .map_err(|err| err.map_partial(|partial| partial.resumed_from(progress)))
+}
+
+/// The unfinalized update of [`AsyncElectrumExt::sync`].
+#[allow(clippy::type_complexity)]
+async fn sync_update<K>(
+    client: &impl AsyncElectrumExt,
+    request: SyncRequest,
+    batch_size: usize,
+    parallel_requests: usize,
+    inspect: impl FnMut(ScanItem<'_, ()>),
+    cancel: Option<&CancelToken>,
+) -> Result<ElectrumUpdate<K, TxHeight>, ScanError<ElectrumUpdate<K, TxHeight>, Error>> {
+    match client
+        .scan(
+            &request.local_chain,
+            [((), sync_spks(request.spks))].into(),
+            [],
+            request.txids,
+            request.outpoints,
+            None,
+            usize::MAX,
+            batch_size,
+            parallel_requests,
+            inspect,
+            cancel,
+        )
+        .await
+    {
+        Ok(update) => Ok(update.into_sync_update()),
+        Err(err) => Err(err.map_partial(ElectrumUpdate::into_sync_update)),
+    }
+}
+
+/// Async version of [`finalize_scan`](crate::finalize_scan) which finalizes the update with
+/// [`AsyncElectrumExt::finalize`].
+#[allow(clippy::type_complexity)]
+async fn finalize_scan<K: Ord + Clone + Debug>(
+    client: &impl AsyncElectrumExt,
+    result: Result<ElectrumUpdate<K, TxHeight>, ScanError<ElectrumUpdate<K, TxHeight>, Error>>,
+    batch_size: usize,
+    parallel_requests: usize,
+) -> Result<KeychainScan<K, ConfirmationTime>, ScanError<KeychainScan<K, ConfirmationTime>, Error>>
+{
+    let update = match &result {
+        Ok(update) => update.clone(),
+        Err(ScanError::Cancelled(partial)) | Err(ScanError::Interrupted { partial, .. }) => {
+            ElectrumUpdate::clone(partial)
+        }
+        Err(ScanError::Source(_)) => {
+            return crate::finalize_scan(result, |_| unreachable!("there is no update to finalize"))
+        }
+    };
+    let finalized = client
+        .finalize(
+            update,
+            TxGraph::<Transaction>::default(),
+            &mut BTreeMap::new(),
+            batch_size,
+            parallel_requests,
+        )
+        .await;
+    crate::finalize_scan(result, |_| finalized)
+}
+
+/// Async version of [`ElectrumOracle::get_block_hash`].
+///
+/// [`ElectrumOracle::get_block_hash`]: crate::ElectrumOracle
+async fn get_block_hash(
+    client: &impl AsyncElectrumApi,
+    height: u32,
+) -> Result<Option<BlockHash>, Error> {
+    match client.batch_block_header(vec![height]).await {
+        Ok(headers) => Ok(headers.first().map(BlockHeader::block_hash)),
+        // electrum responds with an error for heights above the tip
+        Err(Error::Protocol(_)) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Async version of [`Birthday::start_height`].
+async fn start_height(client: &impl AsyncElectrumApi, birthday: Birthday) -> Result<u32, Error> {
+    let (tip_height, _) = client.get_tip().await?;
+    if let Birthday::Height(_) = birthday {
+        return birthday.start_height(tip_height, |_| Ok(0));
+    }
+    let (mut low, mut high) = (0_u32, tip_height + 1);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        let time = client.batch_block_header(vec![mid]).await?[0].time as u64;
+        if birthday.predates(mid, time) {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    Ok(low)
+}
+
+/// Async version of [`checkpoint_update`] (fetching the block hashes of `local_chain` in one
+/// batch).
+///
+/// [`checkpoint_update`]: bdk_chain::chain_oracle::checkpoint_update
+async fn prepare_update(
+    client: &impl AsyncElectrumApi,
+    local_chain: &BTreeMap<u32, BlockHash>,
+) -> Result<SparseChain, Error> {
+    for _ in 0..CHECKPOINT_UPDATE_ATTEMPTS {
+        let (tip_height, tip_hash) = client.get_tip().await?;
+        let heights = local_chain
+            .keys()
+            .copied()
+            .filter(|&height| height <= tip_height)
+            .collect::<Vec<_>>();
+        let headers = client.batch_block_header(heights.clone()).await?;
+
+        let mut update = SparseChain::default();
+        for (height, header) in heights.into_iter().zip(headers).rev() {
+            let hash = header.block_hash();
+            let _ = update
+                .insert_checkpoint(BlockId { height, hash })
+                .expect("cannot repeat height here");
+            if local_chain.get(&height) == Some(&hash) {
+                break;
+            }
+        }
+
+        let tip = BlockId {
+            height: tip_height,
+            hash: tip_hash,
+        };
+        if update.insert_checkpoint(tip).is_err() {
+            // there was a reorg while we were obtaining checkpoints
+            continue;
+        }
+        return Ok(update);
+    }
+
+    Err(Error::Message(
+        CheckpointUpdateError::<Error>::UnstableTip.to_string(),
+    ))
+}
+
+/// Async version of `populate_with_spks` of [`ElectrumExt`].
+///
+/// [`ElectrumExt`]: crate::ElectrumExt
+#[allow(clippy::too_many_arguments)]
+async fn populate_with_spks<I: Ord + Clone>(
+    client: &impl AsyncElectrumApi,
+    update: &mut SparseChain,
+    spks: &mut impl Iterator<Item = (I, Script)>,
+    start_height: u32,
+    stop_gap: usize,
+    (batch_size, parallel_requests): (usize, usize),
+    scanned_spks: &mut BTreeMap<I, (Script, bool)>,
+    inspect: &mut impl FnMut(&I, &Script, &[Txid]),
+    cancel: Option<&CancelToken>,
+) -> Result<(), InternalError> {
+    let mut stop_gap = StopGap::new(stop_gap);
+
+    loop {
+        check_cancelled(cancel)?;
+        let spks = (0..batch_size.saturating_mul(parallel_requests))
+            .map_while(|_| spks.next())
+            .collect::<Vec<_>>();
+        if spks.is_empty() {
+            return Ok(());
+        }
+
+        let spk_histories = batched(
+            spks.iter().map(|(_, spk)| spk.clone()).collect(),
+            (batch_size, parallel_requests),
+            |scripts| client.batch_script_get_history(scripts),
+        )
+        .await?;
+
+        if insert_spk_histories(
+            update,
+            spks,
+            spk_histories,
+            start_height,
+            &mut stop_gap,
+            scanned_spks,
+            inspect,
+        )? {
+            return Ok(());
+        }
+    }
+}
+
+/// Async version of `populate_with_txids` of [`ElectrumExt`].
+///
+/// [`ElectrumExt`]: crate::ElectrumExt
+async fn populate_with_txids<K>(
+    client: &impl AsyncElectrumExt,
+    update: &mut SparseChain,
+    txids: &[Txid],
+    (batch_size, parallel_requests): (usize, usize),
+    inspect: &mut impl FnMut(ScanItem<'_, K>),
+    cancel: Option<&CancelToken>,
+) -> Result<(), InternalError> {
+    for txids in txids.chunks(batch_size.saturating_mul(parallel_requests)) {
+        check_cancelled(cancel)?;
+        for &txid in txids {
+            inspect(ScanItem::Txid(txid));
+        }
+
+        let txs = client
+            .fetch_txs(txids.to_vec(), batch_size, parallel_requests)
+            .await?;
+        let spks = txs
+            .iter()
+            .map(|tx| {
+                tx.output
+                    .first()
+                    .map(|txo| txo.script_pubkey.clone())
+                    .expect("tx must have an output")
+            })
+            .collect::<Vec<_>>();
+        let histories = batched(spks, (batch_size, parallel_requests), |scripts| {
+            client.batch_script_get_history(scripts)
+        })
+        .await?;
+
+        for (tx, history) in txs.iter().zip(histories) {
+            let txid = tx.txid();
+            if let Some(res) = history.into_iter().find(|res| res.tx_hash == txid) {
+                insert_tx(update, txid, res.height)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Async version of `populate_with_outpoints` of [`ElectrumExt`].
+///
+/// The residing transaction of each outpoint is fetched, then the history of the outpoint's script
+/// pubkey is searched for the transaction spending it.
+///
+/// [`ElectrumExt`]: crate::ElectrumExt
+async fn populate_with_outpoints<K>(
+    client: &impl AsyncElectrumExt,
+    update: &mut SparseChain,
+    outpoints: &[OutPoint],
+    (batch_size, parallel_requests): (usize, usize),
+    inspect: &mut impl FnMut(ScanItem<'_, K>),
+    cancel: Option<&CancelToken>,
+) -> Result<(), InternalError> {
+    for outpoints in outpoints.chunks(batch_size.saturating_mul(parallel_requests)) {
+        check_cancelled(cancel)?;
+        for &outpoint in outpoints {
+            inspect(ScanItem::OutPoint(outpoint));
+        }
+
+        let residing_txids = outpoints.iter().map(|op| op.txid).collect::<BTreeSet<_>>();
+        let mut full_txs = client
+            .fetch_txs(
+                residing_txids.into_iter().collect(),
+                batch_size,
+                parallel_requests,
+            )
+            .await?
+            .into_iter()
+            .map(|tx| (tx.txid(), tx))
+            .collect::<HashMap<_, _>>();
+        let outpoints = outpoints
+            .iter()
+            .filter_map(|op| {
+                let txout = full_txs.get(&op.txid)?.output.get(op.vout as usize)?;
+                Some((*op, txout.script_pubkey.clone()))
+            })
+            .collect::<Vec<_>>();
+        let histories = batched(
+            outpoints.iter().map(|(_, spk)| spk.clone()).collect(),
+            (batch_size, parallel_requests),
+            |scripts| client.batch_script_get_history(scripts),
+        )
+        .await?;
+
+        // the transactions in the histories that may spend the outpoints
+        let candidate_txids = histories
+            .iter()
+            .flatten()
+            .map(|res| res.tx_hash)
+            .filter(|txid| !full_txs.contains_key(txid))
+            .collect::<BTreeSet<_>>();
+        let candidate_txs = client
+            .fetch_txs(
+                candidate_txids.into_iter().collect(),
+                batch_size,
+                parallel_requests,
+            )
+            .await?;
+        // a spending transaction may also be the residing transaction of another outpoint
+        full_txs.extend(candidate_txs.into_iter().map(|tx| (tx.txid(), tx)));
+
+        for ((outpoint, _), history) in outpoints.into_iter().zip(histories) {
+            for res in outpoint_history(outpoint, &history, &full_txs) {
+                insert_tx(update, res.tx_hash, res.height)?;
+            }
+        }
+    }
+    Ok(())
+}
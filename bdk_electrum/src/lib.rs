@@ -1,11 +1,19 @@
 //! This crate is used for updating structures of the [`bdk_chain`] crate with data from electrum.
 //!
 //! The star of the show is the [`ElectrumExt::scan`] method, which scans for relevant blockchain
-//! data (via electrum) and outputs an [`ElectrumUpdate`].
+//! data (via electrum) and outputs an [`ElectrumUpdate`]. [`ElectrumExt::full_scan`] and
+//! [`ElectrumExt::sync`] scan for the requests built by a [`KeychainTracker`] and return a
+//! [`KeychainScan`] that is ready to be applied (like the scans of `bdk_esplora`).
+//! For real-time updates, an [`ElectrumSubscription`] subscribes to block headers and script
+//! pubkeys and turns electrum's notifications into incremental [`ElectrumUpdate`]s.
+//! An [`ElectrumPool`] fails over between electrum servers and can cross-check scans against a
+//! second server. With the `async` feature, `AsyncElectrumExt` provides the same scans for async
+//! electrum clients (with concurrent batched requests).
 //!
 //! An [`ElectrumUpdate`] only includes `txid`s and no full transactions. The caller is responsible
-//! for obtaining full transactions before applying. This can be done with
-//! these steps:
+//! for obtaining full transactions before applying. [`ElectrumUpdate::finalize`] fetches the
+//! missing full transactions and confirmation times and returns a [`KeychainScan`] that is ready to
+//! be applied. Otherwise, this can be done with these steps:
 //!
 //! 1. Determine which full transactions are missing. The method [`missing_full_txs`] of
 //! [`ElectrumUpdate`] can be used.
@@ -13,30 +21,56 @@
 //! 2. Obtaining the full transactions. To do this via electrum, the method
 //! [`batch_transaction_get`] can be used.
 //!
+//! 3. Optionally, fetch the outputs spent by the transactions so that fees can be calculated. The
+//!    methods [`missing_prevouts`] and [`fetch_prevouts`] of [`ElectrumUpdate`] can be used.
+//!
 //! Refer to [`bdk_electrum_example`] for a complete example.
 //!
 //! [`ElectrumClient::scan`]: ElectrumClient::scan
 //! [`missing_full_txs`]: ElectrumUpdate::missing_full_txs
 //! [`batch_transaction_get`]: ElectrumApi::batch_transaction_get
+//! [`missing_prevouts`]: ElectrumUpdate::missing_prevouts
+//! [`fetch_prevouts`]: ElectrumUpdate::fetch_prevouts
+//! [`KeychainTracker`]: bdk_chain::keychain::KeychainTracker
+//! [`KeychainScan`]: bdk_chain::keychain::KeychainScan
 //! [`bdk_electrum_example`]: https://github.com/LLFourn/bdk_core_staging/tree/master/bdk_electrum_example
 
 use std::{
     borrow::Cow,
-    collections::{BTreeMap, HashMap},
+    collections::{hash_map, BTreeMap, BTreeSet, HashMap, HashSet},
     fmt::Debug,
 };
 
 pub use bdk_chain;
 use bdk_chain::{
-    bitcoin::{hashes::hex::FromHex, BlockHash, OutPoint, Script, Transaction, Txid},
+    bitcoin::{
+        hashes::{hex::FromHex, Hash},
+        util::uint::Uint256,
+        BlockHash, BlockHeader, OutPoint, Script, Transaction, TxMerkleNode, TxOut, Txid,
+    },
     chain_graph::{self, ChainGraph},
-    keychain::KeychainScan,
+    chain_oracle::{checkpoint_update, ChainOracle, CheckpointUpdateError},
+    keychain::{
+        CancelToken, FullScanRequest, KeychainScan, ScanError, ScanItem, ScanProgress, StopGap,
+        SyncRequest,
+    },
     sparse_chain::{self, ChainPosition, SparseChain},
+    spv::{self, ConfirmationProof, TxMerkleProof, VerificationFailure},
     tx_graph::TxGraph,
-    AsTransaction, BlockId, ConfirmationTime, TxHeight,
+    AnchoredPosition, AsTransaction, Birthday, BlockId, ConfirmationTime, ObservedPosition,
+    TxHeight,
 };
 pub use electrum_client;
-use electrum_client::{Client, ElectrumApi, Error};
+use electrum_client::{Client, ElectrumApi, Error, GetHistoryRes};
+
+#[cfg(feature = "async")]
+mod async_ext;
+#[cfg(feature = "async")]
+pub use async_ext::*;
+mod pool;
+pub use pool::*;
+mod subscription;
+pub use subscription::*;
 
 /// Trait to extend [`electrum_client::Client`] functionality.
 ///
@@ -53,21 +87,32 @@ pub trait ElectrumExt {
     ///
     /// - `local_chain`: the most recent block hashes present locally
     /// - `keychain_spks`: keychains that we want to scan transactions for
+    /// - `spks`: script pubkeys outside of the keychains that we want to scan in full
     /// - `txids`: transactions that we want updated [`ChainPosition`]s for
     /// - `outpoints`: transactions associated with these outpoints (residing, spending) that we
     ///     want to included in the update
+    /// - `birthday`: if provided, keychain transactions confirmed before the [`Birthday`] are skipped
     ///
     /// The scan for each keychain stops after a gap of `stop_gap` script pubkeys with no associated
     /// transactions. `batch_size` specifies how many script pubkeys to request for in one request.
+    ///
+    /// Every script pubkey, txid and outpoint that is checked is passed to `inspect` (as a
+    /// [`ScanItem`]). If `cancel` is cancelled, the scan stops and returns [`ScanError::Cancelled`]
+    /// with what was scanned so far.
+    #[allow(clippy::too_many_arguments)]
     fn scan<K: Ord + Clone>(
         &self,
         local_chain: &BTreeMap<u32, BlockHash>,
         keychain_spks: BTreeMap<K, impl IntoIterator<Item = (u32, Script)>>,
+        spks: impl IntoIterator<Item = Script>,
         txids: impl IntoIterator<Item = Txid>,
         outpoints: impl IntoIterator<Item = OutPoint>,
+        birthday: Option<Birthday>,
         stop_gap: usize,
         batch_size: usize,
-    ) -> Result<ElectrumUpdate<K, TxHeight>, Error>;
+        inspect: impl FnMut(ScanItem<'_, K>),
+        cancel: Option<&CancelToken>,
+    ) -> Result<ElectrumUpdate<K, TxHeight>, ScanError<ElectrumUpdate<K, TxHeight>, Error>>;
 
     /// Convenience method to call [`scan`] without requiring a keychain.
     ///
@@ -85,16 +130,82 @@ pub trait ElectrumExt {
             .enumerate()
             .map(|(i, spk)| (i as u32, spk));
 
-        self.scan(
+        match self.scan(
             local_chain,
             [((), spk_iter)].into(),
+            [],
             txids,
             outpoints,
+            None,
             usize::MAX,
             batch_size,
-        )
-        .map(|u| u.chain_update)
+            |_| {},
+            None,
+        ) {
+            Ok(update) => Ok(update.chain_update),
+            Err(ScanError::Source(err)) | Err(ScanError::Interrupted { error: err, .. }) => {
+                Err(err)
+            }
+            Err(ScanError::Cancelled(_)) => unreachable!("scan has no cancel token"),
+        }
     }
+
+    /// Perform a full scan of the keychains of a [`FullScanRequest`] with [`scan`].
+    ///
+    /// The update is [`finalize`]d (its full transactions and confirmation times are fetched) so
+    /// the returned [`KeychainScan`] can be applied to the tracker that the request was built from.
+    ///
+    /// If the request has the [`ScanProgress`] of an unfinished full scan, the scan resumes from it
+    /// (unless the block it was made at is no longer in the best chain). The progress of a scan
+    /// that is cancelled or interrupted is included in the partial [`KeychainScan`] (which is
+    /// finalized as well). Note that the stop gap of a resumed keychain is counted from where it
+    /// resumed.
+    ///
+    /// The transactions in the histories of the request's `spks` are scanned as `txids`.
+    ///
+    /// [`scan`]: ElectrumExt::scan
+    /// [`finalize`]: ElectrumUpdate::finalize
+    #[allow(clippy::type_complexity)]
+    fn full_scan<K: Ord + Clone + Debug>(
+        &self,
+        request: FullScanRequest<K, impl Iterator<Item = (u32, Script)>>,
+        stop_gap: usize,
+        batch_size: usize,
+        inspect: impl FnMut(ScanItem<'_, K>),
+        cancel: Option<&CancelToken>,
+    ) -> Result<
+        KeychainScan<K, ConfirmationTime>,
+        ScanError<KeychainScan<K, ConfirmationTime>, Error>,
+    >;
+
+    /// Sync the script pubkeys, transactions and outpoints of a [`SyncRequest`].
+    ///
+    /// Like with [`full_scan`], the update is finalized. The returned [`KeychainScan`] has no
+    /// `last_active_indices` (a sync does not derive new script pubkeys). The script pubkeys are
+    /// passed to `inspect` under the `()` keychain (indexed by their position in the request).
+    ///
+    /// [`full_scan`]: ElectrumExt::full_scan
+    #[allow(clippy::type_complexity)]
+    fn sync<K: Ord + Clone + Debug>(
+        &self,
+        request: SyncRequest,
+        batch_size: usize,
+        inspect: impl FnMut(ScanItem<'_, ()>),
+        cancel: Option<&CancelToken>,
+    ) -> Result<
+        KeychainScan<K, ConfirmationTime>,
+        ScanError<KeychainScan<K, ConfirmationTime>, Error>,
+    >;
+
+    /// Fetch a [`ConfirmationProof`] for each of the given confirmed `txids` (alongside the height
+    /// of the block they are confirmed in).
+    ///
+    /// Transactions that the server cannot provide a merkle proof for (at the given height) are
+    /// left out of the returned map.
+    fn fetch_confirmation_proofs(
+        &self,
+        txids: impl IntoIterator<Item = (u32, Txid)>,
+    ) -> Result<BTreeMap<Txid, ConfirmationProof>, Error>;
 }
 
 impl ElectrumExt for Client {
@@ -109,11 +220,15 @@ impl ElectrumExt for Client {
         &self,
         local_chain: &BTreeMap<u32, BlockHash>,
         keychain_spks: BTreeMap<K, impl IntoIterator<Item = (u32, Script)>>,
+        spks: impl IntoIterator<Item = Script>,
         txids: impl IntoIterator<Item = Txid>,
         outpoints: impl IntoIterator<Item = OutPoint>,
+        birthday: Option<Birthday>,
         stop_gap: usize,
         batch_size: usize,
-    ) -> Result<ElectrumUpdate<K, TxHeight>, Error> {
+        mut inspect: impl FnMut(ScanItem<'_, K>),
+        cancel: Option<&CancelToken>,
+    ) -> Result<ElectrumUpdate<K, TxHeight>, ScanError<ElectrumUpdate<K, TxHeight>, Error>> {
         let mut request_spks = keychain_spks
             .into_iter()
             .map(|(k, s)| {
@@ -121,97 +236,241 @@ impl ElectrumExt for Client {
                 (k, iter)
             })
             .collect::<BTreeMap<K, _>>();
-        let mut scanned_spks = BTreeMap::<(K, u32), (Script, bool)>::new();
+        let mut scanned_spks = ScannedSpks::<K>::default();
 
+        let spks = spks.into_iter().collect::<Vec<_>>();
         let txids = txids.into_iter().collect::<Vec<_>>();
         let outpoints = outpoints.into_iter().collect::<Vec<_>>();
 
-        let update = loop {
+        // the height of the first block that is not before the birthday
+        let start_height = match birthday {
+            Some(birthday) => birthday.start_height(self.get_tip()?.0, |height| {
+                Ok::<_, Error>(self.block_header(height as usize)?.time as u64)
+            })?,
+            None => 0,
+        };
+
+        let update = 'scan: loop {
             let mut update = prepare_update(self, local_chain)?;
 
-            if !request_spks.is_empty() {
-                if !scanned_spks.is_empty() {
-                    let mut scanned_spk_iter = scanned_spks
-                        .iter()
-                        .map(|(i, (spk, _))| (i.clone(), spk.clone()));
-                    match populate_with_spks::<K, _, _>(
-                        self,
-                        &mut update,
-                        &mut scanned_spk_iter,
-                        stop_gap,
-                        batch_size,
-                    ) {
-                        Err(InternalError::Reorg) => continue,
-                        Err(InternalError::ElectrumError(e)) => return Err(e.into()),
-                        Ok(mut spks) => scanned_spks.append(&mut spks),
-                    };
+            let mut rescanned_spks = BTreeMap::new();
+            match populate_with_spks(
+                self,
+                &mut update,
+                &mut scanned_spks.to_rescan().into_iter(),
+                start_height,
+                usize::MAX,
+                batch_size,
+                &mut rescanned_spks,
+                &mut |_, _, _| {},
+                cancel,
+            ) {
+                Err(InternalError::Reorg) => continue,
+                Err(err) => return Err(err.with_partial(scanned_spks.partial_update(update))),
+                Ok(()) => scanned_spks.record_rescanned(rescanned_spks),
+            }
+
+            for (keychain, keychain_spks) in &mut request_spks {
+                if scanned_spks.is_finished(keychain) {
+                    continue;
                 }
-                for (keychain, keychain_spks) in &mut request_spks {
-                    match populate_with_spks::<K, u32, _>(
-                        self,
-                        &mut update,
-                        keychain_spks,
-                        stop_gap,
-                        batch_size,
-                    ) {
-                        Err(InternalError::Reorg) => continue,
-                        Err(InternalError::ElectrumError(e)) => return Err(e.into()),
-                        Ok(spks) => scanned_spks.extend(
-                            spks.into_iter()
-                                .map(|(spk_i, spk)| ((keychain.clone(), spk_i), spk)),
-                        ),
-                    };
+                let mut keychain_scanned_spks = BTreeMap::new();
+                let result = populate_with_spks(
+                    self,
+                    &mut update,
+                    keychain_spks,
+                    start_height,
+                    stop_gap,
+                    batch_size,
+                    &mut keychain_scanned_spks,
+                    &mut |&index, spk, txids| {
+                        inspect(ScanItem::Spk {
+                            keychain,
+                            index,
+                            spk,
+                            txids,
+                        })
+                    },
+                    cancel,
+                );
+                scanned_spks.record(keychain, keychain_scanned_spks);
+                match result {
+                    Err(InternalError::Reorg) => continue 'scan,
+                    Err(err) => return Err(err.with_partial(scanned_spks.partial_update(update))),
+                    Ok(()) => scanned_spks.finish(keychain),
                 }
             }
 
-            match populate_with_txids(self, &mut update, &mut txids.iter().cloned()) {
+            // the script pubkeys outside of the keychains are scanned in full on every attempt
+            match populate_with_spks(
+                self,
+                &mut update,
+                &mut spks.iter().map(|spk| (spk.clone(), spk.clone())),
+                0,
+                usize::MAX,
+                batch_size,
+                &mut BTreeMap::new(),
+                &mut |_, spk, txids| inspect(ScanItem::MiscSpk { spk, txids }),
+                cancel,
+            ) {
                 Err(InternalError::Reorg) => continue,
-                Err(InternalError::ElectrumError(e)) => return Err(e.into()),
-                Ok(_) => {}
+                Err(err) => return Err(err.with_partial(scanned_spks.partial_update(update))),
+                Ok(()) => {}
             }
 
-            match populate_with_outpoints(self, &mut update, &mut outpoints.iter().cloned()) {
+            let result = match populate_with_txids(
+                self,
+                &mut update,
+                &mut txids.iter().cloned(),
+                &mut inspect,
+                cancel,
+            ) {
+                Ok(()) => populate_with_outpoints(
+                    self,
+                    &mut update,
+                    &mut outpoints.iter().cloned(),
+                    &mut inspect,
+                    cancel,
+                )
+                // [TODO] cache full txs to reduce bandwidth
+                .map(|_txs| ()),
+                err => err,
+            };
+            match result {
                 Err(InternalError::Reorg) => continue,
-                Err(InternalError::ElectrumError(e)) => return Err(e.into()),
-                Ok(_txs) => { /* [TODO] cache full txs to reduce bandwidth */ }
+                Err(err) => return Err(err.with_partial(scanned_spks.partial_update(update))),
+                Ok(()) => {}
             }
 
             // check for reorgs during scan process
             let our_tip = update
                 .latest_checkpoint()
                 .expect("update must have atleast one checkpoint");
-            let server_blockhash = self.block_header(our_tip.height as usize)?.block_hash();
-            if our_tip.hash != server_blockhash {
-                continue; // reorg
-            } else {
-                break update;
+            match self.block_header(our_tip.height as usize) {
+                Ok(header) if header.block_hash() != our_tip.hash => continue, // reorg
+                Ok(_) => break update,
+                Err(err) => {
+                    return Err(
+                        InternalError::from(err).with_partial(scanned_spks.partial_update(update))
+                    )
+                }
             }
         };
 
-        let last_active_index = request_spks
-            .into_keys()
-            .filter_map(|k| {
-                scanned_spks
-                    .range((k.clone(), u32::MIN)..=(k.clone(), u32::MAX))
-                    .rev()
-                    .find(|(_, (_, active))| *active)
-                    .map(|((_, i), _)| (k, *i))
-            })
-            .collect::<BTreeMap<_, _>>();
+        Ok(scanned_spks.update(update))
+    }
 
-        Ok(ElectrumUpdate {
-            chain_update: update,
-            last_active_indices: last_active_index,
-        })
+    fn full_scan<K: Ord + Clone + Debug>(
+        &self,
+        request: FullScanRequest<K, impl Iterator<Item = (u32, Script)>>,
+        stop_gap: usize,
+        batch_size: usize,
+        inspect: impl FnMut(ScanItem<'_, K>),
+        cancel: Option<&CancelToken>,
+    ) -> Result<
+        KeychainScan<K, ConfirmationTime>,
+        ScanError<KeychainScan<K, ConfirmationTime>, Error>,
+    > {
+        finalize_scan(
+            full_scan_update(self, request, stop_gap, batch_size, inspect, cancel),
+            |update| {
+                update.finalize(
+                    self,
+                    TxGraph::<Transaction>::default(),
+                    &mut BTreeMap::new(),
+                )
+            },
+        )
+    }
+
+    fn sync<K: Ord + Clone + Debug>(
+        &self,
+        request: SyncRequest,
+        batch_size: usize,
+        inspect: impl FnMut(ScanItem<'_, ()>),
+        cancel: Option<&CancelToken>,
+    ) -> Result<
+        KeychainScan<K, ConfirmationTime>,
+        ScanError<KeychainScan<K, ConfirmationTime>, Error>,
+    > {
+        finalize_scan(
+            sync_update(self, request, batch_size, inspect, cancel),
+            |update| {
+                update.finalize(
+                    self,
+                    TxGraph::<Transaction>::default(),
+                    &mut BTreeMap::new(),
+                )
+            },
+        )
+    }
+
+    fn fetch_confirmation_proofs(
+        &self,
+        txids: impl IntoIterator<Item = (u32, Txid)>,
+    ) -> Result<BTreeMap<Txid, ConfirmationProof>, Error> {
+        let txids = txids.into_iter().collect::<Vec<_>>();
+        let heights = txids
+            .iter()
+            .map(|&(height, _)| height)
+            .collect::<BTreeSet<u32>>();
+        let headers = heights
+            .iter()
+            .copied()
+            .zip(self.batch_block_header(heights.iter().copied())?)
+            .collect::<HashMap<u32, _>>();
+
+        let mut proofs = BTreeMap::new();
+        for (height, txid) in txids {
+            let res = match self.transaction_get_merkle(&txid, height as usize) {
+                Ok(res) => res,
+                Err(electrum_client::Error::Protocol(_)) => continue,
+                Err(other_err) => return Err(other_err),
+            };
+            if res.block_height != height as usize {
+                continue;
+            }
+
+            let merkle_proof = TxMerkleProof {
+                block_height: height,
+                pos: res.pos,
+                // electrum serves hashes in the reversed (display) byte order
+                merkle: res
+                    .merkle
+                    .into_iter()
+                    .map(|mut hash| {
+                        hash.reverse();
+                        TxMerkleNode::from_inner(hash)
+                    })
+                    .collect(),
+            };
+
+            proofs.insert(
+                txid,
+                ConfirmationProof {
+                    header: headers[&height],
+                    merkle_proof,
+                },
+            );
+        }
+
+        Ok(proofs)
     }
 }
 
 /// The result of [`ElectrumExt::scan`].
+#[derive(Debug, Clone)]
 pub struct ElectrumUpdate<K, P> {
     /// The internal [`SparseChain`] update.
     pub chain_update: SparseChain<P>,
     /// The last keychain script pubkey indices which had transaction histories.
     pub last_active_indices: BTreeMap<K, u32>,
+    /// Outputs spent by the update's transactions (refer to [`fetch_prevouts`]).
+    ///
+    /// [`fetch_prevouts`]: Self::fetch_prevouts
+    pub prevouts: BTreeMap<OutPoint, TxOut>,
+    /// The progress of a full scan (refer to [`KeychainScan::progress`]).
+    pub progress: Option<ScanProgress<K>>,
 }
 
 impl<K, P> Default for ElectrumUpdate<K, P> {
@@ -219,6 +478,8 @@ impl<K, P> Default for ElectrumUpdate<K, P> {
         Self {
             chain_update: Default::default(),
             last_active_indices: Default::default(),
+            prevouts: Default::default(),
+            progress: None,
         }
     }
 }
@@ -229,6 +490,17 @@ impl<K, P> AsRef<SparseChain<P>> for ElectrumUpdate<K, P> {
     }
 }
 
+impl<K: Ord + Clone, P> ElectrumUpdate<K, P> {
+    /// Include the `progress` of the full scan that this (partial) update resumed from.
+    fn resumed_from(mut self, mut progress: ScanProgress<K>) -> Self {
+        if let Some(resumed) = self.progress.take() {
+            progress.merge(resumed);
+            self.progress = Some(progress);
+        }
+        self
+    }
+}
+
 impl<K: Ord + Clone + Debug, P: ChainPosition> ElectrumUpdate<K, P> {
     /// Return a list of missing full transactions that are required to [`inflate_update`].
     ///
@@ -245,6 +517,83 @@ impl<K: Ord + Clone + Debug, P: ChainPosition> ElectrumUpdate<K, P> {
             .collect()
     }
 
+    /// Return the outpoints spent by the update's transactions that we do not have the `TxOut` of
+    /// in `graph`, `new_txs` (the full transactions fetched for [`missing_full_txs`]) or
+    /// [`prevouts`].
+    ///
+    /// These can be fetched with [`fetch_prevouts`] so that fees of the transactions can be
+    /// calculated.
+    ///
+    /// [`missing_full_txs`]: Self::missing_full_txs
+    /// [`prevouts`]: Self::prevouts
+    /// [`fetch_prevouts`]: Self::fetch_prevouts
+    pub fn missing_prevouts<T, G, N>(&self, graph: G, new_txs: &[N]) -> BTreeSet<OutPoint>
+    where
+        T: AsTransaction,
+        G: AsRef<TxGraph<T>>,
+        N: AsTransaction,
+    {
+        let graph = graph.as_ref();
+        let new_txs = new_txs
+            .iter()
+            .map(|tx| (tx.as_tx().txid(), tx.as_tx()))
+            .collect::<HashMap<_, _>>();
+
+        self.chain_update
+            .txids()
+            .filter_map(|(_, txid)| {
+                new_txs
+                    .get(txid)
+                    .copied()
+                    .or_else(|| graph.get_tx(*txid).map(AsTransaction::as_tx))
+            })
+            .filter(|tx| !tx.is_coin_base())
+            .flat_map(|tx| tx.input.iter().map(|txin| txin.previous_output))
+            .filter(|op| {
+                graph.get_txout(*op).is_none()
+                    && !new_txs.contains_key(&op.txid)
+                    && !self.prevouts.contains_key(op)
+            })
+            .collect()
+    }
+
+    /// Fetch the `TxOut`s of the given `outpoints` (via electrum) and add them to [`prevouts`].
+    ///
+    /// [`prevouts`] are included in the [`KeychainScan`] as partial txouts.
+    ///
+    /// [`prevouts`]: Self::prevouts
+    pub fn fetch_prevouts(
+        &mut self,
+        client: &impl ElectrumApi,
+        outpoints: impl IntoIterator<Item = OutPoint>,
+    ) -> Result<(), Error> {
+        let outpoints = outpoints.into_iter().collect::<BTreeSet<_>>();
+        let parent_txids = outpoints.iter().map(|op| op.txid).collect::<BTreeSet<_>>();
+        let parent_txs = client.batch_transaction_get(&parent_txids)?;
+        self.insert_prevouts(&outpoints, parent_txs);
+
+        Ok(())
+    }
+
+    /// Add the outputs of `parent_txs` that are in `outpoints` to [`prevouts`].
+    ///
+    /// [`prevouts`]: Self::prevouts
+    pub(crate) fn insert_prevouts(
+        &mut self,
+        outpoints: &BTreeSet<OutPoint>,
+        parent_txs: impl IntoIterator<Item = Transaction>,
+    ) {
+        for parent_tx in parent_txs {
+            let parent_txid = parent_tx.txid();
+            for (vout, txout) in parent_tx.output.into_iter().enumerate() {
+                let op = OutPoint::new(parent_txid, vout as _);
+                if outpoints.contains(&op) {
+                    self.prevouts.insert(op, txout);
+                }
+            }
+        }
+    }
+
     /// Transform the [`ElectrumUpdate`] into a [`KeychainScan`] which can be applied to a
     /// `tracker`.
     ///
@@ -258,13 +607,71 @@ impl<K: Ord + Clone + Debug, P: ChainPosition> ElectrumUpdate<K, P> {
         T: AsTransaction + Clone + Ord,
         CG: AsRef<ChainGraph<P, T>>,
     {
+        let mut update = chain_graph
+            .as_ref()
+            .inflate_update(self.chain_update, new_txs)?;
+        for (op, txout) in self.prevouts {
+            let _ = update.insert_txout(op, txout);
+        }
+
         Ok(KeychainScan {
-            update: chain_graph
-                .as_ref()
-                .inflate_update(self.chain_update, new_txs)?,
+            update,
             last_active_indices: self.last_active_indices,
+            progress: self.progress,
         })
     }
+
+    /// Verify the confirmations of the update with merkle proofs before it is applied to `chain`.
+    ///
+    /// This returns the verified update and the transactions with confirmations that could not be
+    /// verified. Confirmations at heights that `chain` has no checkpoint at are only accepted with
+    /// a header of a target no easier than `max_target`. Refer to [`spv::verify_update`] for more.
+    #[allow(clippy::type_complexity)]
+    pub fn verify_confirmations<P2: ChainPosition>(
+        self,
+        client: &impl ElectrumExt,
+        chain: &SparseChain<P2>,
+        max_target: Option<Uint256>,
+    ) -> Result<(Self, BTreeMap<Txid, (P, VerificationFailure)>), Error> {
+        let proofs =
+            client.fetch_confirmation_proofs(spv::txids_to_verify(chain, &self.chain_update))?;
+        let verified = spv::verify_update(chain, &self.chain_update, &proofs, max_target);
+
+        Ok((
+            ElectrumUpdate {
+                chain_update: verified.update,
+                last_active_indices: self.last_active_indices,
+                prevouts: self.prevouts,
+                progress: self.progress,
+            },
+            verified.unverified,
+        ))
+    }
+
+    /// Leave out the transactions of the update that `keep` returns `false` for.
+    fn retain_txids(mut self, keep: impl Fn(&Txid) -> bool) -> Self {
+        let mut chain_update =
+            SparseChain::from_checkpoints(self.chain_update.range_checkpoints(..));
+        for (position, txid) in self.chain_update.txids() {
+            if keep(txid) {
+                let _ = chain_update
+                    .insert_tx(*txid, position.clone())
+                    .expect("positions are valid in the original update");
+            }
+        }
+        self.chain_update = chain_update;
+        self
+    }
+}
+
+impl ElectrumUpdate<(), TxHeight> {
+    /// The update of a sync (which has no keychains).
+    fn into_sync_update<K>(self) -> ElectrumUpdate<K, TxHeight> {
+        ElectrumUpdate {
+            chain_update: self.chain_update,
+            ..Default::default()
+        }
+    }
 }
 
 impl<K: Ord + Clone + Debug> ElectrumUpdate<K, TxHeight> {
@@ -273,44 +680,254 @@ impl<K: Ord + Clone + Debug> ElectrumUpdate<K, TxHeight> {
         self,
         client: &electrum_client::Client,
     ) -> Result<ElectrumUpdate<K, ConfirmationTime>, Error> {
-        let heights = self
-            .chain_update
+        let mut headers = BTreeMap::new();
+        self.fetch_confirmation_headers(client, &mut headers)?;
+        self.with_confirmation_times(&headers)
+    }
+
+    /// Creates [`ElectrumUpdate<K, ObservedPosition>`] from [`ElectrumUpdate<K, TxHeight>`].
+    ///
+    /// Unconfirmed transactions are recorded as seen now.
+    pub fn into_observed_update(
+        self,
+        client: &electrum_client::Client,
+    ) -> Result<ElectrumUpdate<K, ObservedPosition>, Error> {
+        let mut headers = BTreeMap::new();
+        self.fetch_confirmation_headers(client, &mut headers)?;
+        let seen_at = std::time::UNIX_EPOCH
+            .elapsed()
+            .expect("system time must be after unix epoch")
+            .as_secs();
+
+        self.map_positions(|tx_height| match tx_height {
+            TxHeight::Confirmed(height) => ObservedPosition::Confirmed {
+                height,
+                time: headers[&height].time as u64,
+            },
+            TxHeight::Unconfirmed => ObservedPosition::seen_at(seen_at),
+        })
+    }
+
+    /// Creates [`ElectrumUpdate<K, AnchoredPosition>`] from [`ElectrumUpdate<K, TxHeight>`].
+    ///
+    /// Confirmed transactions are anchored to the block header that electrum serves at their
+    /// confirmation height. This fails if the headers do not agree with the update's checkpoints
+    /// (a reorg happened after the scan).
+    pub fn into_anchored_update(
+        self,
+        client: &electrum_client::Client,
+    ) -> Result<ElectrumUpdate<K, AnchoredPosition>, Error> {
+        let mut headers = BTreeMap::new();
+        self.fetch_confirmation_headers(client, &mut headers)?;
+
+        self.map_positions(|tx_height| match tx_height {
+            TxHeight::Confirmed(height) => AnchoredPosition::Confirmed {
+                block: BlockId {
+                    height,
+                    hash: headers[&height].block_hash(),
+                },
+                index: None,
+            },
+            TxHeight::Unconfirmed => AnchoredPosition::Unconfirmed,
+        })
+    }
+
+    /// Fetch what is missing from the update and turn it into a [`KeychainScan`] that is ready to
+    /// be applied to a tracker with the transactions of `graph`.
+    ///
+    /// This fetches the full transactions that are missing from `graph` (with
+    /// [`batch_transaction_get`]) and the block headers for the confirmation times of the
+    /// transactions. Transactions that the server no longer knows of (e.g. they were evicted from
+    /// its mempool) are left out of the update. Headers are cached in `headers` so that a header is
+    /// only fetched once across updates (pass in the same map for each update). Cached headers above
+    /// the update's point of agreement (its lowest checkpoint) may be stale and are fetched again.
+    /// The outputs spent by the transactions that are missing from `graph` are added to the
+    /// [`prevouts`] (so that the fees of the transactions can be calculated) and included.
+    ///
+    /// [`batch_transaction_get`]: ElectrumApi::batch_transaction_get
+    /// [`prevouts`]: Self::prevouts
+    pub fn finalize<T, G>(
+        self,
+        client: &impl ElectrumApi,
+        graph: G,
+        headers: &mut BTreeMap<u32, BlockHeader>,
+    ) -> Result<KeychainScan<K, ConfirmationTime, Transaction>, Error>
+    where
+        T: AsTransaction,
+        G: AsRef<TxGraph<T>>,
+    {
+        let missing_txids = self.txids_missing_from(graph.as_ref());
+        let fetched = fetch_txs(client, &missing_txids)?;
+        let (mut update, txs) = self.with_fetched_txs(graph.as_ref(), fetched);
+        let missing_prevouts = update.missing_prevouts(&graph, &txs);
+        let parent_txids = missing_prevouts
+            .iter()
+            .map(|op| op.txid)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        update.insert_prevouts(&missing_prevouts, fetch_txs(client, &parent_txids)?);
+        update.fetch_confirmation_headers(client, headers)?;
+
+        update.with_confirmation_times(headers)?.with_full_txs(txs)
+    }
+
+    /// The txids of the update's transactions that are not in `graph`.
+    pub(crate) fn txids_missing_from<T: AsTransaction>(&self, graph: &TxGraph<T>) -> Vec<Txid> {
+        self.chain_update
+            .txids()
+            .map(|(_, txid)| *txid)
+            .filter(|&txid| graph.get_tx(txid).is_none())
+            .collect()
+    }
+
+    /// Leave out the transactions that are neither in `graph` nor `fetched` (the server no longer
+    /// knows of them) and return the update with the full transactions of all that are left.
+    pub(crate) fn with_fetched_txs<T: AsTransaction>(
+        self,
+        graph: &TxGraph<T>,
+        mut fetched: Vec<Transaction>,
+    ) -> (Self, Vec<Transaction>) {
+        let fetched_txids = fetched
+            .iter()
+            .map(Transaction::txid)
+            .collect::<HashSet<_>>();
+        let update =
+            self.retain_txids(|txid| graph.get_tx(*txid).is_some() || fetched_txids.contains(txid));
+        fetched.extend(
+            update
+                .chain_update
+                .txids()
+                .filter_map(|(_, txid)| graph.get_tx(*txid))
+                .map(|tx| tx.as_tx().clone()),
+        );
+        (update, fetched)
+    }
+
+    /// Fetch the block headers of all confirmation heights of the update into `headers`.
+    ///
+    /// Refer to [`headers_to_fetch`] for which headers are fetched.
+    ///
+    /// [`headers_to_fetch`]: Self::headers_to_fetch
+    fn fetch_confirmation_headers(
+        &self,
+        client: &impl ElectrumApi,
+        headers: &mut BTreeMap<u32, BlockHeader>,
+    ) -> Result<(), Error> {
+        let heights = self.headers_to_fetch(headers);
+        let fetched = client.batch_block_header(heights.iter().copied())?;
+        headers.extend(heights.into_iter().zip(fetched));
+        Ok(())
+    }
+
+    /// The confirmation heights of the update that are missing from the cached `headers`.
+    ///
+    /// Cached headers that conflict with the checkpoints of the update (and the headers above
+    /// them) are dropped so they are fetched again. So are the cached headers above the update's
+    /// point of agreement, as there may have been a reorg at heights that the update has no
+    /// checkpoint at.
+    pub(crate) fn headers_to_fetch(&self, headers: &mut BTreeMap<u32, BlockHeader>) -> Vec<u32> {
+        let reorged_height = headers
+            .iter()
+            .find(|(&height, header)| {
+                self.chain_update
+                    .checkpoint_at(height)
+                    .is_some_and(|cp| cp.hash != header.block_hash())
+            })
+            .map(|(&height, _)| height);
+        if let Some(height) = reorged_height {
+            headers.split_off(&height);
+        }
+        if let Some(agreement_height) = self.chain_update.checkpoints().keys().next() {
+            headers.split_off(&agreement_height.saturating_add(1));
+        }
+
+        self.confirmation_heights()
+            .into_iter()
+            .filter(|height| !headers.contains_key(height))
+            .collect()
+    }
+
+    /// The heights of the blocks that the transactions of the update are confirmed in.
+    fn confirmation_heights(&self) -> BTreeSet<u32> {
+        self.chain_update
             .range_txids_by_height(..TxHeight::Unconfirmed)
             .map(|(h, _)| match h {
                 TxHeight::Confirmed(h) => *h,
                 _ => unreachable!("already filtered out unconfirmed"),
             })
-            .collect::<Vec<u32>>();
+            .collect()
+    }
 
-        let height_to_time = heights
-            .clone()
-            .into_iter()
-            .zip(
-                client
-                    .batch_block_header(heights.clone())?
-                    .into_iter()
-                    .map(|bh| bh.time as u64),
-            )
-            .collect::<HashMap<u32, u64>>();
+    /// Reposition the transactions of the update with the times of the `headers` of their
+    /// confirmation heights (which must all be in `headers`).
+    pub(crate) fn with_confirmation_times(
+        self,
+        headers: &BTreeMap<u32, BlockHeader>,
+    ) -> Result<ElectrumUpdate<K, ConfirmationTime>, Error> {
+        self.map_positions(|tx_height| match tx_height {
+            TxHeight::Confirmed(height) => ConfirmationTime::Confirmed {
+                height,
+                time: headers[&height].time as u64,
+            },
+            TxHeight::Unconfirmed => ConfirmationTime::Unconfirmed,
+        })
+    }
 
-        let mut new_update = SparseChain::<ConfirmationTime>::from_checkpoints(
-            self.chain_update.range_checkpoints(..),
-        );
+    /// Reposition every transaction of the update with `map`.
+    fn map_positions<P2: ChainPosition>(
+        self,
+        map: impl Fn(TxHeight) -> P2,
+    ) -> Result<ElectrumUpdate<K, P2>, Error> {
+        let mut new_update =
+            SparseChain::<P2>::from_checkpoints(self.chain_update.range_checkpoints(..));
 
         for &(tx_height, txid) in self.chain_update.txids() {
-            let conf_time = match tx_height {
-                TxHeight::Confirmed(height) => ConfirmationTime::Confirmed {
-                    height,
-                    time: height_to_time[&height],
-                },
-                TxHeight::Unconfirmed => ConfirmationTime::Unconfirmed,
-            };
-            let _ = new_update.insert_tx(txid, conf_time).expect("must insert");
+            if let Err(failure) = new_update.insert_tx(txid, map(tx_height)) {
+                match failure {
+                    sparse_chain::InsertTxError::AnchorNotMatching { .. } => {
+                        return Err(Error::Message(format!(
+                            "reorg occurred after scanning: {}",
+                            failure
+                        )));
+                    }
+                    _ => unreachable!("positions are from a consistent update"),
+                }
+            }
         }
 
         Ok(ElectrumUpdate {
             chain_update: new_update,
-            last_active_indices: self.last_active_indices.clone(),
+            last_active_indices: self.last_active_indices,
+            prevouts: self.prevouts,
+            progress: self.progress,
+        })
+    }
+}
+
+impl<K> ElectrumUpdate<K, ConfirmationTime> {
+    /// Turn the update into a [`KeychainScan`] with the full transactions `txs` (which must include
+    /// every transaction of the update) and the [`prevouts`].
+    ///
+    /// [`prevouts`]: Self::prevouts
+    pub(crate) fn with_full_txs(
+        self,
+        txs: impl IntoIterator<Item = Transaction>,
+    ) -> Result<KeychainScan<K, ConfirmationTime>, Error> {
+        let mut graph = TxGraph::<Transaction>::default();
+        for tx in txs {
+            let _ = graph.insert_tx(tx);
+        }
+        for (op, txout) in self.prevouts {
+            let _ = graph.insert_txout(op, txout);
+        }
+        let update = ChainGraph::new(self.chain_update, graph)
+            .map_err(|err| Error::Message(format!("invalid update: {}", err)))?;
+
+        Ok(KeychainScan {
+            update,
+            last_active_indices: self.last_active_indices,
+            progress: self.progress,
         })
     }
 }
@@ -319,6 +936,7 @@ impl<K: Ord + Clone + Debug> ElectrumUpdate<K, TxHeight> {
 enum InternalError {
     ElectrumError(Error),
     Reorg,
+    Cancelled,
 }
 
 impl From<electrum_client::Error> for InternalError {
@@ -327,6 +945,22 @@ impl From<electrum_client::Error> for InternalError {
     }
 }
 
+impl InternalError {
+    /// Turn this into a [`ScanError`] that includes what was scanned before it occurred.
+    ///
+    /// Reorgs are handled within the scan so this should never be called on one.
+    fn with_partial<U>(self, partial: U) -> ScanError<U, Error> {
+        match self {
+            InternalError::ElectrumError(error) => ScanError::Interrupted {
+                error,
+                partial: Box::new(partial),
+            },
+            InternalError::Cancelled => ScanError::Cancelled(Box::new(partial)),
+            InternalError::Reorg => unreachable!("reorgs are handled within the scan"),
+        }
+    }
+}
+
 fn get_tip(client: &Client) -> Result<(u32, BlockHash), Error> {
     // TODO: unsubscribe when added to the client, or is there a better call to use here?
     Ok(client
@@ -334,46 +968,328 @@ fn get_tip(client: &Client) -> Result<(u32, BlockHash), Error> {
         .map(|data| (data.height as u32, data.header.block_hash()))?)
 }
 
+/// The script pubkeys checked by a scan so far (and whether they have a transaction history).
+///
+/// This is shared by the blocking and the async scans. When a scan restarts because of a reorg,
+/// the script pubkeys checked so far are scanned again before the scan continues.
+struct ScannedSpks<K> {
+    spks: BTreeMap<(K, u32), (Script, bool)>,
+    /// Keychains that were scanned until the stop gap was reached
+    finished_keychains: BTreeSet<K>,
+}
+
+impl<K> Default for ScannedSpks<K> {
+    fn default() -> Self {
+        Self {
+            spks: BTreeMap::new(),
+            finished_keychains: BTreeSet::new(),
+        }
+    }
+}
+
+impl<K: Ord + Clone> ScannedSpks<K> {
+    /// The script pubkeys to scan again after a reorg.
+    fn to_rescan(&self) -> Vec<((K, u32), Script)> {
+        self.spks
+            .iter()
+            .map(|(index, (spk, _))| (index.clone(), spk.clone()))
+            .collect()
+    }
+
+    /// Record the script pubkeys of `keychain` that were checked.
+    fn record(&mut self, keychain: &K, scanned: BTreeMap<u32, (Script, bool)>) {
+        self.spks.extend(
+            scanned
+                .into_iter()
+                .map(|(index, spk)| ((keychain.clone(), index), spk)),
+        );
+    }
+
+    /// Record the script pubkeys that were checked again after a reorg.
+    fn record_rescanned(&mut self, mut rescanned: BTreeMap<(K, u32), (Script, bool)>) {
+        self.spks.append(&mut rescanned);
+    }
+
+    /// Record that `keychain` was scanned until the stop gap was reached.
+    fn finish(&mut self, keychain: &K) {
+        self.finished_keychains.insert(keychain.clone());
+    }
+
+    fn is_finished(&self, keychain: &K) -> bool {
+        self.finished_keychains.contains(keychain)
+    }
+
+    /// The last index of each keychain that has a transaction history.
+    fn last_active_indices(&self) -> BTreeMap<K, u32> {
+        self.spks
+            .iter()
+            .filter(|(_, (_, is_used))| *is_used)
+            .map(|((keychain, index), _)| (keychain.clone(), *index))
+            .collect()
+    }
+
+    /// The update of what was scanned before the scan was cancelled or interrupted.
+    fn partial_update(&self, update: SparseChain) -> ElectrumUpdate<K, TxHeight> {
+        let mut progress = ScanProgress::new(
+            update
+                .latest_checkpoint()
+                .expect("update must have atleast one checkpoint"),
+        );
+        for (keychain, index) in self.spks.keys() {
+            progress.record_scanned(keychain, *index);
+        }
+        for keychain in &self.finished_keychains {
+            progress.record_finished(keychain.clone());
+        }
+        ElectrumUpdate {
+            chain_update: update,
+            last_active_indices: self.last_active_indices(),
+            progress: Some(progress),
+            ..Default::default()
+        }
+    }
+
+    /// The update of a scan that finished.
+    fn update(&self, update: SparseChain) -> ElectrumUpdate<K, TxHeight> {
+        ElectrumUpdate {
+            chain_update: update,
+            last_active_indices: self.last_active_indices(),
+            // the scan finished so there is no progress to resume from
+            progress: Some(ScanProgress::default()),
+            ..Default::default()
+        }
+    }
+}
+
+/// The script pubkeys of a [`SyncRequest`] indexed by their position in the request.
+fn sync_spks(spks: Vec<Script>) -> impl Iterator<Item = (u32, Script)> {
+    spks.into_iter().enumerate().map(|(i, spk)| (i as u32, spk))
+}
+
+/// Insert the transactions of the `histories` of a batch of `spks` into `update` and record the
+/// script pubkeys in `scanned_spks`.
+///
+/// Transactions confirmed below `start_height` are left out. Returns whether the `stop_gap` is
+/// reached (in which case the rest of the batch is left out).
+fn insert_spk_histories<I: Ord + Clone>(
+    update: &mut SparseChain,
+    spks: Vec<(I, Script)>,
+    histories: Vec<Vec<GetHistoryRes>>,
+    start_height: u32,
+    stop_gap: &mut StopGap<I>,
+    scanned_spks: &mut BTreeMap<I, (Script, bool)>,
+    inspect: &mut impl FnMut(&I, &Script, &[Txid]),
+) -> Result<bool, InternalError> {
+    for ((spk_index, spk), history) in spks.into_iter().zip(histories) {
+        let is_used = !history.is_empty();
+        let mut txids = Vec::with_capacity(history.len());
+
+        for res in history {
+            if res.height > 0 && (res.height as u32) < start_height {
+                continue;
+            }
+            txids.push(res.tx_hash);
+            insert_tx(update, res.tx_hash, res.height)?;
+        }
+
+        inspect(&spk_index, &spk, &txids);
+        scanned_spks.insert(spk_index.clone(), (spk, is_used));
+        if stop_gap.record(spk_index, is_used) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// The entries of the `history` of the script pubkey of `outpoint` that are relevant to it: the
+/// transaction it resides in and the first transaction that spends it.
+///
+/// `txs` must contain the transactions of the `history` to find the spending transaction.
+fn outpoint_history<'h>(
+    outpoint: OutPoint,
+    history: &'h [GetHistoryRes],
+    txs: &HashMap<Txid, Transaction>,
+) -> impl Iterator<Item = &'h GetHistoryRes> {
+    let residing = history.iter().find(|res| res.tx_hash == outpoint.txid);
+    let spending = history.iter().find(|res| {
+        res.tx_hash != outpoint.txid
+            && txs
+                .get(&res.tx_hash)
+                .is_some_and(|tx| tx.input.iter().any(|txin| txin.previous_output == outpoint))
+    });
+    residing.into_iter().chain(spending)
+}
+
+/// Insert `txid` into the `update` at the height electrum reports (`raw_height`).
+fn insert_tx(update: &mut SparseChain, txid: Txid, raw_height: i32) -> Result<(), InternalError> {
+    let tip = update.latest_checkpoint().map_or(0, |cp| cp.height);
+    let tx_height = determine_tx_height(raw_height, tip, txid);
+    match update.insert_tx(txid, tx_height) {
+        Ok(_) => Ok(()),
+        Err(sparse_chain::InsertTxError::TxTooHigh { .. }) => {
+            unreachable!("we should never encounter this as we ensured height <= tip");
+        }
+        Err(sparse_chain::InsertTxError::TxMovedUnexpectedly { .. }) => Err(InternalError::Reorg),
+        Err(sparse_chain::InsertTxError::AnchorNotMatching { .. }) => {
+            unreachable!("`TxHeight` is never anchored to a block");
+        }
+    }
+}
+
+/// The update of [`ElectrumExt::full_scan`] before it is finalized.
+#[allow(clippy::type_complexity)]
+pub(crate) fn full_scan_update<K: Ord + Clone>(
+    client: &Client,
+    request: FullScanRequest<K, impl Iterator<Item = (u32, Script)>>,
+    stop_gap: usize,
+    batch_size: usize,
+    inspect: impl FnMut(ScanItem<'_, K>),
+    cancel: Option<&CancelToken>,
+) -> Result<ElectrumUpdate<K, TxHeight>, ScanError<ElectrumUpdate<K, TxHeight>, Error>> {
+    let FullScanRequest {
+        local_chain,
+        spks_by_keychain,
+        spks,
+        outpoints,
+        birthday,
+        mut progress,
+    } = request;
+
+    // a reorg past the tip of the progress may have invalidated what was scanned
+    if !progress.is_empty()
+        && ElectrumOracle(client).get_block_hash(progress.tip.height)? != Some(progress.tip.hash)
+    {
+        progress = ScanProgress::default();
+    }
+
+    client
+        .scan(
+            &local_chain,
+            progress.resume(spks_by_keychain),
+            spks,
+            [],
+            outpoints,
+            birthday,
+            stop_gap,
+            batch_size,
+            inspect,
+            cancel,
+        )
+        .map_err(|err| err.map_partial(|partial| partial.resumed_from(progress)))
+}
+
+/// The update of [`ElectrumExt::sync`] before it is finalized.
+#[allow(clippy::type_complexity)]
+pub(crate) fn sync_update<K>(
+    client: &Client,
+    request: SyncRequest,
+    batch_size: usize,
+    inspect: impl FnMut(ScanItem<'_, ()>),
+    cancel: Option<&CancelToken>,
+) -> Result<ElectrumUpdate<K, TxHeight>, ScanError<ElectrumUpdate<K, TxHeight>, Error>> {
+    match client.scan(
+        &request.local_chain,
+        [((), sync_spks(request.spks))].into(),
+        [],
+        request.txids,
+        request.outpoints,
+        None,
+        usize::MAX,
+        batch_size,
+        inspect,
+        cancel,
+    ) {
+        Ok(update) => Ok(update.into_sync_update()),
+        Err(err) => Err(err.map_partial(ElectrumUpdate::into_sync_update)),
+    }
+}
+
+/// Turn the result of a scan into the result of a finalized scan with `finalize`.
+///
+/// The partial update of a cancelled or interrupted scan is finalized as well. If that fails, the
+/// error of the scan is returned without the partial update (or the error of finalizing it if the
+/// scan was cancelled).
+#[allow(clippy::type_complexity)]
+pub(crate) fn finalize_scan<K, E>(
+    result: Result<ElectrumUpdate<K, TxHeight>, ScanError<ElectrumUpdate<K, TxHeight>, E>>,
+    finalize: impl FnOnce(ElectrumUpdate<K, TxHeight>) -> Result<KeychainScan<K, ConfirmationTime>, E>,
+) -> Result<KeychainScan<K, ConfirmationTime>, ScanError<KeychainScan<K, ConfirmationTime>, E>> {
+    match result {
+        Ok(update) => Ok(finalize(update)?),
+        Err(ScanError::Source(err)) => Err(ScanError::Source(err)),
+        Err(ScanError::Cancelled(partial)) => Err(match finalize(*partial) {
+            Ok(partial) => ScanError::Cancelled(Box::new(partial)),
+            Err(err) => ScanError::Source(err),
+        }),
+        Err(ScanError::Interrupted { error, partial }) => Err(match finalize(*partial) {
+            Ok(partial) => ScanError::Interrupted {
+                error,
+                partial: Box::new(partial),
+            },
+            Err(_) => ScanError::Source(error),
+        }),
+    }
+}
+
+/// Fetch the transactions of `txids` that the server knows of.
+///
+/// The server fails a whole batch if one of the transactions is missing, in which case we get them
+/// one by one and leave out the missing ones.
+fn fetch_txs(client: &impl ElectrumApi, txids: &[Txid]) -> Result<Vec<Transaction>, Error> {
+    match client.batch_transaction_get(txids) {
+        Err(Error::Protocol(_)) => {
+            let mut txs = Vec::with_capacity(txids.len());
+            for txid in txids {
+                match client.transaction_get(txid) {
+                    Ok(tx) => txs.push(tx),
+                    Err(Error::Protocol(_)) => continue,
+                    Err(err) => return Err(err),
+                }
+            }
+            Ok(txs)
+        }
+        result => result,
+    }
+}
+
+fn check_cancelled(cancel: Option<&CancelToken>) -> Result<(), InternalError> {
+    match cancel.is_some_and(CancelToken::is_cancelled) {
+        true => Err(InternalError::Cancelled),
+        false => Ok(()),
+    }
+}
+
 /// Prepare an update sparsechain "template" based on the checkpoints of the `local_chain`.
 fn prepare_update(
     client: &Client,
     local_chain: &BTreeMap<u32, BlockHash>,
 ) -> Result<SparseChain, Error> {
-    let mut update = SparseChain::default();
-
-    // Find local chain block that is still there so our update can connect to the local chain.
-    for (&existing_height, &existing_hash) in local_chain.iter().rev() {
-        // TODO: a batch request may be safer, as a reorg that happens when we are obtaining
-        //       `block_header`s will result in inconsistencies
-        let current_hash = client.block_header(existing_height as usize)?.block_hash();
-        let _ = update
-            .insert_checkpoint(BlockId {
-                height: existing_height,
-                hash: current_hash,
-            })
-            .expect("This never errors because we are working with a fresh chain");
+    checkpoint_update(&ElectrumOracle(client), local_chain).map_err(|err| match err {
+        CheckpointUpdateError::Oracle(err) => err,
+        err @ CheckpointUpdateError::UnstableTip => Error::Message(err.to_string()),
+    })
+}
 
-        if current_hash == existing_hash {
-            break;
-        }
+/// A [`ChainOracle`] backed by an electrum [`Client`].
+#[derive(Clone, Copy)]
+pub struct ElectrumOracle<'a>(pub &'a Client);
+
+impl<'a> ChainOracle for ElectrumOracle<'a> {
+    type Error = Error;
+
+    fn get_tip(&self) -> Result<Option<BlockId>, Self::Error> {
+        let (height, hash) = get_tip(self.0)?;
+        Ok(Some(BlockId { height, hash }))
     }
 
-    // Insert the new tip so new transactions will be accepted into the sparse chain.
-    let tip = {
-        let (height, hash) = get_tip(client)?;
-        BlockId { height, hash }
-    };
-    if let Err(failure) = update.insert_checkpoint(tip) {
-        match failure {
-            sparse_chain::InsertCheckpointError::HashNotMatching { .. } => {
-                // There has been a re-org before we even begin scanning addresses.
-                // Just recursively call (this should never happen).
-                return prepare_update(client, local_chain);
-            }
+    fn get_block_hash(&self, height: u32) -> Result<Option<BlockHash>, Self::Error> {
+        match self.0.block_header(height as usize) {
+            Ok(header) => Ok(Some(header.block_hash())),
+            // electrum responds with an error for heights above the tip
+            Err(Error::Protocol(_)) => Ok(None),
+            Err(err) => Err(err),
         }
     }
-
-    Ok(update)
 }
 
 /// This atrocity is required because electrum thinks height of 0 means "unconfirmed", but there is
@@ -415,73 +1331,43 @@ fn determine_tx_height(raw_height: i32, tip_height: u32, txid: Txid) -> TxHeight
 /// Unfortunately this is awkward to implement as electrum does not provide such an API. Instead, we
 /// will get the tx history of the outpoint's spk, and try to find the containing tx and the
 /// spending tx.
-fn populate_with_outpoints(
+fn populate_with_outpoints<K>(
     client: &Client,
     update: &mut SparseChain,
     outpoints: &mut impl Iterator<Item = OutPoint>,
+    inspect: &mut impl FnMut(ScanItem<'_, K>),
+    cancel: Option<&CancelToken>,
 ) -> Result<HashMap<Txid, Transaction>, InternalError> {
-    let tip = update
-        .latest_checkpoint()
-        .expect("update must atleast have one checkpoint");
-
     let mut full_txs = HashMap::new();
     for outpoint in outpoints {
-        let txid = outpoint.txid;
-        let tx = client.transaction_get(&txid)?;
-        debug_assert_eq!(tx.txid(), txid);
-        let txout = match tx.output.get(outpoint.vout as usize) {
-            Some(txout) => txout,
+        check_cancelled(cancel)?;
+        inspect(ScanItem::OutPoint(outpoint));
+        let tx = match full_txs.entry(outpoint.txid) {
+            hash_map::Entry::Occupied(entry) => entry.into_mut(),
+            hash_map::Entry::Vacant(entry) => {
+                let tx = client.transaction_get(&outpoint.txid)?;
+                debug_assert_eq!(tx.txid(), outpoint.txid);
+                entry.insert(tx)
+            }
+        };
+        let spk = match tx.output.get(outpoint.vout as usize) {
+            Some(txout) => txout.script_pubkey.clone(),
             None => continue,
         };
 
-        // attempt to find the following transactions (alongside their chain positions), and
-        // add to our sparsechain `update`:
-        let mut has_residing = false; // tx in which the outpoint resides
-        let mut has_spending = false; // tx that spends the outpoint
-        for res in client.script_get_history(&txout.script_pubkey)? {
-            if has_residing && has_spending {
-                break;
-            }
-
-            if res.tx_hash == txid {
-                if has_residing {
-                    continue;
-                }
-                has_residing = true;
-                full_txs.insert(res.tx_hash, tx.clone());
-            } else {
-                if has_spending {
-                    continue;
-                }
-                let res_tx = match full_txs.get(&res.tx_hash) {
-                    Some(tx) => tx,
-                    None => {
-                        let res_tx = client.transaction_get(&res.tx_hash)?;
-                        full_txs.insert(res.tx_hash, res_tx);
-                        full_txs.get(&res.tx_hash).expect("just inserted")
-                    }
-                };
-                has_spending = res_tx
-                    .input
-                    .iter()
-                    .any(|txin| txin.previous_output == outpoint);
-                if !has_spending {
-                    continue;
-                }
-            };
-
-            let tx_height = determine_tx_height(res.height, tip.height, res.tx_hash);
+        // the transactions of the history that may spend the outpoint
+        let history = client.script_get_history(&spk)?;
+        let candidate_txids = history
+            .iter()
+            .map(|res| res.tx_hash)
+            .filter(|txid| !full_txs.contains_key(txid))
+            .collect::<BTreeSet<_>>();
+        for tx in fetch_txs(client, &candidate_txids.into_iter().collect::<Vec<_>>())? {
+            full_txs.insert(tx.txid(), tx);
+        }
 
-            if let Err(failure) = update.insert_tx(res.tx_hash, tx_height) {
-                match failure {
-                    sparse_chain::InsertTxError::TxTooHigh { .. } => {
-                        unreachable!("we should never encounter this as we ensured height <= tip");
-                    }
-                    sparse_chain::InsertTxError::TxMovedUnexpectedly { .. } => {
-                        return Err(InternalError::Reorg);
-                    }
-                }
-            }
+        for res in outpoint_history(outpoint, &history, &full_txs) {
+            insert_tx(update, res.tx_hash, res.height)?;
         }
     }
     Ok(full_txs)
@@ -489,15 +1375,16 @@ fn populate_with_outpoints(
 
 /// Populate an update [`SparseChain`] with transactions (and associated block positions) from
 /// the given `txids`.
-fn populate_with_txids(
+fn populate_with_txids<K>(
     client: &Client,
     update: &mut SparseChain,
     txids: &mut impl Iterator<Item = Txid>,
+    inspect: &mut impl FnMut(ScanItem<'_, K>),
+    cancel: Option<&CancelToken>,
 ) -> Result<(), InternalError> {
-    let tip = update
-        .latest_checkpoint()
-        .expect("update must have atleast one checkpoint");
     for txid in txids {
+        check_cancelled(cancel)?;
+        inspect(ScanItem::Txid(txid));
         let tx = match client.transaction_get(&txid) {
             Ok(tx) => tx,
             Err(electrum_client::Error::Protocol(_)) => continue,
@@ -506,28 +1393,16 @@ fn populate_with_txids(
 
         let spk = tx
             .output
-            .get(0)
+            .first()
             .map(|txo| &txo.script_pubkey)
             .expect("tx must have an output");
 
-        let tx_height = match client
+        if let Some(res) = client
             .script_get_history(spk)?
             .into_iter()
             .find(|r| r.tx_hash == txid)
         {
-            Some(r) => determine_tx_height(r.height, tip.height, r.tx_hash),
-            None => continue,
-        };
-
-        if let Err(failure) = update.insert_tx(txid, tx_height) {
-            match failure {
-                sparse_chain::InsertTxError::TxTooHigh { .. } => {
-                    unreachable!("we should never encounter this as we ensured height <= tip");
-                }
-                sparse_chain::InsertTxError::TxMovedUnexpectedly { .. } => {
-                    return Err(InternalError::Reorg);
-                }
-            }
+            insert_tx(update, txid, res.height)?;
         }
     }
     Ok(())
@@ -535,61 +1410,43 @@ fn populate_with_txids(
 
 /// Populate an update [`SparseChain`] with transactions (and associated block positions) from
 /// the transaction history of the provided `spks`.
-fn populate_with_spks<K, I, S>(
+///
+/// Transactions confirmed below `start_height` are left out. The scanned script pubkeys (and
+/// whether they have a history) are added to `scanned_spks`.
+#[allow(clippy::too_many_arguments)]
+fn populate_with_spks<I: Ord + Clone>(
     client: &Client,
     update: &mut SparseChain,
-    spks: &mut S,
+    spks: &mut impl Iterator<Item = (I, Script)>,
+    start_height: u32,
     stop_gap: usize,
     batch_size: usize,
-) -> Result<BTreeMap<I, (Script, bool)>, InternalError>
-where
-    K: Ord + Clone,
-    I: Ord + Clone,
-    S: Iterator<Item = (I, Script)>,
-{
-    let tip = update.latest_checkpoint().map_or(0, |cp| cp.height);
-    let mut unused_spk_count = 0_usize;
-    let mut scanned_spks = BTreeMap::new();
+    scanned_spks: &mut BTreeMap<I, (Script, bool)>,
+    inspect: &mut impl FnMut(&I, &Script, &[Txid]),
+    cancel: Option<&CancelToken>,
+) -> Result<(), InternalError> {
+    let mut stop_gap = StopGap::new(stop_gap);
 
     loop {
+        check_cancelled(cancel)?;
         let spks = (0..batch_size)
             .map_while(|_| spks.next())
             .collect::<Vec<_>>();
         if spks.is_empty() {
-            return Ok(scanned_spks);
+            return Ok(());
         }
 
-        let spk_histories = client.batch_script_get_history(spks.iter().map(|(_, s)| s))?;
-
-        for ((spk_index, spk), spk_history) in spks.into_iter().zip(spk_histories) {
-            if spk_history.is_empty() {
-                scanned_spks.insert(spk_index, (spk, false));
-                unused_spk_count += 1;
-                if unused_spk_count > stop_gap {
-                    return Ok(scanned_spks);
-                }
-                continue;
-            } else {
-                scanned_spks.insert(spk_index, (spk, true));
-                unused_spk_count = 0;
-            }
-
-            for tx in spk_history {
-                let tx_height = determine_tx_height(tx.height, tip, tx.tx_hash);
-
-                if let Err(failure) = update.insert_tx(tx.tx_hash, tx_height) {
-                    match failure {
-                        sparse_chain::InsertTxError::TxTooHigh { .. } => {
-                            unreachable!(
-                                "we should never encounter this as we ensured height <= tip"
-                            );
-                        }
-                        sparse_chain::InsertTxError::TxMovedUnexpectedly { .. } => {
-                            return Err(InternalError::Reorg);
-                        }
-                    }
-                }
-            }
+        let histories = client.batch_script_get_history(spks.iter().map(|(_, s)| s))?;
+        if insert_spk_histories(
+            update,
+            spks,
+            histories,
+            start_height,
+            &mut stop_gap,
+            scanned_spks,
+            inspect,
+        )? {
+            return Ok(());
         }
     }
 }
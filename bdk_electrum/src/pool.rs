@@ -0,0 +1,651 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Debug},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use bdk_chain::{
+    bitcoin::{BlockHash, Script, Transaction, Txid},
+    keychain::{CancelToken, FullScanRequest, KeychainScan, ScanError, ScanItem, SyncRequest},
+    sparse_chain::SparseChain,
+    tx_graph::TxGraph,
+    ConfirmationTime, TxHeight,
+};
+use electrum_client::{Client, Config, ElectrumApi, Error};
+
+use crate::{finalize_scan, full_scan_update, sync_update, ElectrumUpdate};
+
+/// Error of the scans of an [`ElectrumPool`].
+#[derive(Debug)]
+pub enum PoolError {
+    /// A server returned an error (or none of the servers could be reached).
+    Electrum(Error),
+    /// The pool does not have enough servers (at least one, or two to cross-check).
+    NotEnoughServers {
+        /// The number of servers that are required
+        required: usize,
+        /// The number of servers of the pool
+        available: usize,
+    },
+    /// The cross-checked servers have different blocks at the lower of their tips.
+    TipMismatch {
+        /// The servers that were compared (the one that was scanned first comes first)
+        servers: (String, String),
+        /// The height that the servers were compared at
+        height: u32,
+    },
+    /// The cross-checked servers disagree on the confirmation of a transaction.
+    ConfirmationMismatch {
+        /// The servers that were compared (the one that was scanned first comes first)
+        servers: (String, String),
+        /// The transaction that the servers disagree on
+        txid: Txid,
+        /// The confirmation height of the transaction of each server (`None` if the server has
+        /// the transaction unconfirmed or did not find it at all)
+        heights: (Option<u32>, Option<u32>),
+    },
+}
+
+impl From<Error> for PoolError {
+    fn from(err: Error) -> Self {
+        Self::Electrum(err)
+    }
+}
+
+impl fmt::Display for PoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PoolError::Electrum(err) => fmt::Display::fmt(err, f),
+            PoolError::NotEnoughServers {
+                required,
+                available,
+            } => write!(
+                f,
+                "at least {} electrum servers are required but the pool has {}",
+                required, available
+            ),
+            PoolError::TipMismatch { servers, height } => write!(
+                f,
+                "servers {} and {} have different blocks at {}",
+                servers.0, servers.1, height
+            ),
+            PoolError::ConfirmationMismatch {
+                servers,
+                txid,
+                heights,
+            } => write!(
+                f,
+                "servers {} and {} disagree on the confirmation of {}: {:?} and {:?}",
+                servers.0, servers.1, txid, heights.0, heights.1
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PoolError {}
+
+/// A pool of electrum servers that fails over to the next server on connection errors.
+///
+/// Servers are tried in order of preference, starting from the last server that worked. A server
+/// is only connected to when it is first needed.
+///
+/// With [`set_cross_check`], scans are done against two servers and a [`PoolError`] is returned if
+/// the servers disagree on the chain or on the confirmations of the transactions found (rather than
+/// silently applying the view of one of them). As blocks may be found between the two scans, the
+/// servers are compared at the lower of their tips and confirmations above it are not compared.
+/// Unconfirmed transactions are not compared as mempools are expected to differ.
+///
+/// [`set_cross_check`]: Self::set_cross_check
+pub struct ElectrumPool {
+    servers: Vec<String>,
+    config: Config,
+    clients: Mutex<Vec<Option<Arc<Client>>>>,
+    current: AtomicUsize,
+    cross_check: bool,
+}
+
+impl ElectrumPool {
+    /// Create a pool of `servers` (in order of preference) which are connected to with `config`.
+    ///
+    /// Returns [`PoolError::NotEnoughServers`] if there are no `servers`.
+    pub fn new(
+        servers: impl IntoIterator<Item = impl Into<String>>,
+        config: Config,
+    ) -> Result<Self, PoolError> {
+        let servers = servers.into_iter().map(Into::into).collect::<Vec<String>>();
+        if servers.is_empty() {
+            return Err(PoolError::NotEnoughServers {
+                required: 1,
+                available: 0,
+            });
+        }
+        Ok(Self {
+            clients: Mutex::new(vec![None; servers.len()]),
+            servers,
+            config,
+            current: AtomicUsize::new(0),
+            cross_check: false,
+        })
+    }
+
+    /// Returns whether scans are cross-checked against a second server.
+    pub fn cross_check(&self) -> bool {
+        self.cross_check
+    }
+
+    /// Set whether scans are cross-checked against a second server.
+    ///
+    /// Cross-checking requires the pool to have at least two servers, otherwise
+    /// [`PoolError::NotEnoughServers`] is returned (and cross-checking stays disabled).
+    pub fn set_cross_check(&mut self, cross_check: bool) -> Result<(), PoolError> {
+        if cross_check && self.servers.len() < 2 {
+            return Err(PoolError::NotEnoughServers {
+                required: 2,
+                available: self.servers.len(),
+            });
+        }
+        self.cross_check = cross_check;
+        Ok(())
+    }
+
+    /// Returns the servers of the pool in order of preference.
+    pub fn servers(&self) -> &[String] {
+        &self.servers
+    }
+
+    /// Returns the server that is tried first (the last server that worked).
+    pub fn current_server(&self) -> &str {
+        &self.servers[self.current.load(Ordering::Relaxed)]
+    }
+
+    /// Get a connected client of the first server that responds to a ping.
+    ///
+    /// This is useful for things that are tied to a connection (such as an
+    /// [`ElectrumSubscription`]).
+    ///
+    /// [`ElectrumSubscription`]: crate::ElectrumSubscription
+    pub fn client(&self) -> Result<Arc<Client>, Error> {
+        let (index, ()) = self.call(None, |client| client.ping())?;
+        self.client_at(index)
+    }
+
+    /// Call `f` with the client of each server until it does not fail with a connection error.
+    ///
+    /// If every server fails, [`Error::AllAttemptsErrored`] is returned with the error of each.
+    pub fn with_failover<R>(&self, f: impl FnMut(&Client) -> Result<R, Error>) -> Result<R, Error> {
+        self.call(None, f).map(|(_, result)| result)
+    }
+
+    /// Perform [`ElectrumExt::full_scan`] with failover (and cross-checking if enabled).
+    ///
+    /// A scan that fails with a connection error is started again with the next server so
+    /// `inspect` may see the same items more than once. The updates are cross-checked before they
+    /// are finalized.
+    ///
+    /// [`ElectrumExt::full_scan`]: crate::ElectrumExt::full_scan
+    #[allow(clippy::type_complexity)]
+    pub fn full_scan<K, I>(
+        &self,
+        request: FullScanRequest<K, I>,
+        stop_gap: usize,
+        batch_size: usize,
+        mut inspect: impl FnMut(ScanItem<'_, K>),
+        cancel: Option<&CancelToken>,
+    ) -> Result<
+        KeychainScan<K, ConfirmationTime>,
+        ScanError<KeychainScan<K, ConfirmationTime>, PoolError>,
+    >
+    where
+        K: Ord + Clone + Debug,
+        I: Iterator<Item = (u32, Script)> + Clone,
+    {
+        let result = self
+            .scan_with_failover(None, |client| {
+                full_scan_update(
+                    client,
+                    request.clone(),
+                    stop_gap,
+                    batch_size,
+                    &mut inspect,
+                    cancel,
+                )
+            })
+            .and_then(|(primary, update)| {
+                if self.cross_check {
+                    let (secondary, other) = self.scan_with_failover(Some(primary), |client| {
+                        full_scan_update(
+                            client,
+                            request.clone(),
+                            stop_gap,
+                            batch_size,
+                            |_| {},
+                            cancel,
+                        )
+                    })?;
+                    self.check_agreement(
+                        (primary, &update.chain_update),
+                        (secondary, &other.chain_update),
+                    )?;
+                }
+                Ok(update)
+            });
+        finalize_scan(result, |update| self.finalize(update))
+    }
+
+    /// Perform [`ElectrumExt::sync`] with failover (and cross-checking if enabled).
+    ///
+    /// Refer to [`full_scan`] for more.
+    ///
+    /// [`ElectrumExt::sync`]: crate::ElectrumExt::sync
+    /// [`full_scan`]: Self::full_scan
+    #[allow(clippy::type_complexity)]
+    pub fn sync<K: Ord + Clone + Debug>(
+        &self,
+        request: SyncRequest,
+        batch_size: usize,
+        mut inspect: impl FnMut(ScanItem<'_, ()>),
+        cancel: Option<&CancelToken>,
+    ) -> Result<
+        KeychainScan<K, ConfirmationTime>,
+        ScanError<KeychainScan<K, ConfirmationTime>, PoolError>,
+    > {
+        let result = self
+            .scan_with_failover(None, |client| {
+                sync_update(client, request.clone(), batch_size, &mut inspect, cancel)
+            })
+            .and_then(|(primary, update)| {
+                if self.cross_check {
+                    let (secondary, other) = self.scan_with_failover(Some(primary), |client| {
+                        sync_update::<K>(client, request.clone(), batch_size, |_| {}, cancel)
+                    })?;
+                    self.check_agreement(
+                        (primary, &update.chain_update),
+                        (secondary, &other.chain_update),
+                    )?;
+                }
+                Ok(update)
+            });
+        finalize_scan(result, |update| self.finalize(update))
+    }
+
+    /// [`finalize`] the `update` of a scan with failover.
+    ///
+    /// [`finalize`]: ElectrumUpdate::finalize
+    fn finalize<K: Ord + Clone + Debug>(
+        &self,
+        update: ElectrumUpdate<K, TxHeight>,
+    ) -> Result<KeychainScan<K, ConfirmationTime>, PoolError> {
+        self.with_failover(|client| {
+            update.clone().finalize(
+                client,
+                TxGraph::<Transaction>::default(),
+                &mut BTreeMap::new(),
+            )
+        })
+        .map_err(PoolError::Electrum)
+    }
+
+    /// Get the client of the server at `index` (connecting to it if we are not connected yet).
+    fn client_at(&self, index: usize) -> Result<Arc<Client>, Error> {
+        let mut clients = self.clients.lock().expect("must not be poisoned");
+        match &clients[index] {
+            Some(client) => Ok(client.clone()),
+            None => {
+                let client = Arc::new(Client::from_config(
+                    &self.servers[index],
+                    self.config.clone(),
+                )?);
+                clients[index] = Some(client.clone());
+                Ok(client)
+            }
+        }
+    }
+
+    /// Call `f` with the client of each server (except `skip`) until it does not fail with a
+    /// connection error. Returns the index of the server alongside the result.
+    ///
+    /// The server that is tried first is updated unless we `skip` one (as we are cross-checking).
+    fn call<R>(
+        &self,
+        skip: Option<usize>,
+        mut f: impl FnMut(&Client) -> Result<R, Error>,
+    ) -> Result<(usize, R), Error> {
+        let start = self.current.load(Ordering::Relaxed);
+        let mut errors = Vec::new();
+
+        for index in (0..self.servers.len())
+            .map(|i| (start + i) % self.servers.len())
+            .filter(|&index| Some(index) != skip)
+        {
+            let client = match self.client_at(index) {
+                Ok(client) => client,
+                Err(err) => {
+                    errors.push(err);
+                    continue;
+                }
+            };
+            match f(&client) {
+                Err(err) if is_connection_error(&err) => {
+                    // reconnect the next time this server is tried
+                    self.clients.lock().expect("must not be poisoned")[index] = None;
+                    errors.push(err);
+                }
+                result => {
+                    if skip.is_none() {
+                        self.current.store(index, Ordering::Relaxed);
+                    }
+                    return result.map(|result| (index, result));
+                }
+            }
+        }
+
+        Err(Error::AllAttemptsErrored(errors))
+    }
+
+    /// Like [`call`] but for scans (which are started again when interrupted by a connection
+    /// error).
+    ///
+    /// [`call`]: Self::call
+    #[allow(clippy::type_complexity)]
+    fn scan_with_failover<U>(
+        &self,
+        skip: Option<usize>,
+        mut scan: impl FnMut(&Client) -> Result<U, ScanError<U, Error>>,
+    ) -> Result<(usize, U), ScanError<U, PoolError>> {
+        match self.call(skip, |client| match scan(client) {
+            Err(ScanError::Source(err)) | Err(ScanError::Interrupted { error: err, .. })
+                if is_connection_error(&err) =>
+            {
+                Err(err)
+            }
+            result => Ok(result),
+        }) {
+            Ok((index, Ok(update))) => Ok((index, update)),
+            Ok((_, Err(err))) => Err(err.map_source(PoolError::Electrum)),
+            Err(err) => Err(ScanError::Source(PoolError::Electrum(err))),
+        }
+    }
+
+    /// Check that the updates of the `primary` and `secondary` servers agree on the chain and on
+    /// the confirmations of the transactions found.
+    ///
+    /// The updates are compared at the lower of their tips (the block at that height is fetched
+    /// from the server with the higher tip if its update has no checkpoint there).
+    fn check_agreement(
+        &self,
+        (primary, update): (usize, &SparseChain),
+        (secondary, other): (usize, &SparseChain),
+    ) -> Result<(), PoolError> {
+        let servers = || {
+            (
+                self.servers[primary].clone(),
+                self.servers[secondary].clone(),
+            )
+        };
+        let tip_height = |update: &SparseChain| {
+            update
+                .latest_checkpoint()
+                .expect("updates of scans must have a tip")
+                .height
+        };
+        let height = tip_height(update).min(tip_height(other));
+        let hash_at = |index: usize, update: &SparseChain| -> Result<BlockHash, Error> {
+            match update.checkpoint_at(height) {
+                Some(checkpoint) => Ok(checkpoint.hash),
+                None => Ok(self
+                    .client_at(index)?
+                    .block_header(height as usize)?
+                    .block_hash()),
+            }
+        };
+        if hash_at(primary, update)? != hash_at(secondary, other)? {
+            return Err(PoolError::TipMismatch {
+                servers: servers(),
+                height,
+            });
+        }
+
+        let confirmed = |update: &SparseChain| {
+            update
+                .range_txids_by_height(..=TxHeight::Confirmed(height))
+                .filter_map(|(tx_height, txid)| match tx_height {
+                    TxHeight::Confirmed(tx_height) => Some((*txid, *tx_height)),
+                    TxHeight::Unconfirmed => None,
+                })
+                .collect::<BTreeMap<_, _>>()
+        };
+        let (confirmed, other_confirmed) = (confirmed(update), confirmed(other));
+        if let Some(&txid) = confirmed
+            .keys()
+            .chain(other_confirmed.keys())
+            .find(|&txid| confirmed.get(txid) != other_confirmed.get(txid))
+        {
+            return Err(PoolError::ConfirmationMismatch {
+                servers: servers(),
+                txid,
+                heights: (
+                    confirmed.get(&txid).copied(),
+                    other_confirmed.get(&txid).copied(),
+                ),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether `err` means we could not talk to the server (rather than the server responding with an
+/// error).
+fn is_connection_error(err: &Error) -> bool {
+    matches!(
+        err,
+        Error::IOError(_)
+            | Error::SharedIOError(_)
+            | Error::AllAttemptsErrored(_)
+            | Error::CouldntLockReader
+            | Error::Mpsc
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io, net::TcpListener};
+
+    use bdk_chain::{bitcoin::hashes::Hash, BlockId};
+
+    use super::*;
+
+    /// Listen on a local port (the electrum client connects without a handshake so accepting the
+    /// connection is enough for it to be established).
+    fn listening_server() -> (TcpListener, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = listener.local_addr().unwrap().to_string();
+        (listener, url)
+    }
+
+    /// An address that nothing listens on.
+    fn unreachable_server() -> String {
+        // the listener is dropped straight away
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    fn connection_error() -> Error {
+        Error::IOError(io::ErrorKind::ConnectionReset.into())
+    }
+
+    fn block(height: u32, fork: u8) -> BlockId {
+        BlockId {
+            height,
+            hash: BlockHash::hash(&[height as u8, fork]),
+        }
+    }
+
+    fn update_of(
+        checkpoints: impl IntoIterator<Item = BlockId>,
+        txs: impl IntoIterator<Item = (TxHeight, u8)>,
+    ) -> SparseChain {
+        let mut update = SparseChain::from_checkpoints(checkpoints);
+        for (height, tx) in txs {
+            let _ = update.insert_tx(Txid::hash(&[tx]), height).unwrap();
+        }
+        update
+    }
+
+    #[test]
+    fn cross_check_compares_at_the_lower_tip() {
+        // the servers are never connected to as the updates have checkpoints at the lower tip
+        let pool = ElectrumPool::new(["primary", "secondary"], Config::default()).unwrap();
+        let update = update_of([block(10, 0)], [(TxHeight::Confirmed(5), 0)]);
+
+        // a block was found between the scans (which confirmed another transaction)
+        let other = update_of(
+            [block(10, 0), block(11, 0)],
+            [(TxHeight::Confirmed(5), 0), (TxHeight::Confirmed(11), 1)],
+        );
+        assert!(pool.check_agreement((0, &update), (1, &other)).is_ok());
+        assert!(pool.check_agreement((1, &other), (0, &update)).is_ok());
+
+        let other = update_of([block(10, 1), block(11, 1)], [(TxHeight::Confirmed(5), 0)]);
+        assert!(matches!(
+            pool.check_agreement((0, &update), (1, &other)),
+            Err(PoolError::TipMismatch { height: 10, .. })
+        ));
+
+        let other = update_of([block(10, 0)], [(TxHeight::Unconfirmed, 0)]);
+        match pool.check_agreement((0, &update), (1, &other)) {
+            Err(PoolError::ConfirmationMismatch {
+                servers,
+                txid,
+                heights,
+            }) => {
+                assert_eq!(servers, ("primary".into(), "secondary".into()));
+                assert_eq!(txid, Txid::hash(&[0]));
+                assert_eq!(heights, (Some(5), None));
+            }
+            other => panic!("expected a confirmation mismatch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn pool_requires_enough_servers() {
+        assert!(matches!(
+            ElectrumPool::new(Vec::<String>::new(), Config::default()),
+            Err(PoolError::NotEnoughServers {
+                required: 1,
+                available: 0
+            })
+        ));
+
+        let mut pool = ElectrumPool::new(["primary"], Config::default()).unwrap();
+        assert!(matches!(
+            pool.set_cross_check(true),
+            Err(PoolError::NotEnoughServers {
+                required: 2,
+                available: 1
+            })
+        ));
+        assert!(!pool.cross_check());
+        assert!(pool.set_cross_check(false).is_ok());
+    }
+
+    #[test]
+    fn call_fails_over_on_connection_errors() {
+        let (_a, a) = listening_server();
+        let (_b, b) = listening_server();
+        let unreachable = unreachable_server();
+        let pool = ElectrumPool::new(
+            [unreachable.clone(), a.clone(), b.clone()],
+            Config::default(),
+        )
+        .unwrap();
+
+        // servers that cannot be connected to are skipped
+        assert_eq!(pool.call(None, |_| Ok(())).unwrap(), (1, ()));
+        assert_eq!(pool.current_server(), a);
+
+        // as are servers that fail with a connection error (which are reconnected to later)
+        let mut calls = 0;
+        let result = pool.call(None, |_| {
+            calls += 1;
+            match calls {
+                1 => Err(connection_error()),
+                _ => Ok(()),
+            }
+        });
+        assert_eq!(result.unwrap(), (2, ()));
+        assert_eq!(pool.current_server(), b);
+        assert!(pool.clients.lock().unwrap()[1].is_none());
+
+        // other errors are responses of the server so we do not fail over
+        let mut calls = 0;
+        let result = pool.call(None, |_| -> Result<(), _> {
+            calls += 1;
+            Err(Error::Protocol("no".into()))
+        });
+        assert!(matches!(result, Err(Error::Protocol(_))));
+        assert_eq!(calls, 1);
+
+        // the skipped server (of cross-checking) does not become the current server
+        assert_eq!(pool.call(Some(2), |_| Ok(())).unwrap(), (1, ()));
+        assert_eq!(pool.current_server(), b);
+
+        let pool =
+            ElectrumPool::new([unreachable.clone(), unreachable], Config::default()).unwrap();
+        match pool.call(None, |_| Ok(())) {
+            Err(Error::AllAttemptsErrored(errors)) => assert_eq!(errors.len(), 2),
+            other => panic!("expected every attempt to error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn scan_with_failover_restarts_interrupted_scans() {
+        let (_a, a) = listening_server();
+        let (_b, b) = listening_server();
+        let pool = ElectrumPool::new([a, b], Config::default()).unwrap();
+
+        let mut calls = 0;
+        let result = pool.scan_with_failover(None, |_| {
+            calls += 1;
+            match calls {
+                1 => Err(ScanError::Interrupted {
+                    error: connection_error(),
+                    partial: Box::new(1),
+                }),
+                _ => Ok(2),
+            }
+        });
+        assert_eq!(result.unwrap(), (1, 2));
+
+        // a scan interrupted by a response of the server keeps its partial update
+        let mut calls = 0;
+        let result = pool.scan_with_failover(None, |_| -> Result<u32, _> {
+            calls += 1;
+            Err(ScanError::Interrupted {
+                error: Error::Protocol("no".into()),
+                partial: Box::new(1),
+            })
+        });
+        assert_eq!(calls, 1);
+        match result {
+            Err(ScanError::Interrupted {
+                error: PoolError::Electrum(Error::Protocol(_)),
+                partial,
+            }) => assert_eq!(*partial, 1),
+            other => panic!("expected an interrupted scan, got {:?}", other),
+        }
+
+        let result = pool.scan_with_failover(None, |_| -> Result<u32, _> {
+            Err(ScanError::Source(connection_error()))
+        });
+        assert!(matches!(
+            result,
+            Err(ScanError::Source(PoolError::Electrum(
+                Error::AllAttemptsErrored(_)
+            )))
+        ));
+    }
+}
@@ -0,0 +1,237 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Debug,
+};
+
+use bdk_chain::{
+    bitcoin::{BlockHash, Script},
+    keychain::{KeychainChangeSet, KeychainTracker},
+    sparse_chain::ChainPosition,
+    AsTransaction, TxHeight,
+};
+use electrum_client::{Client, ElectrumApi, Error, ScriptStatus};
+
+use crate::{populate_with_spks, prepare_update, ElectrumUpdate, InternalError};
+
+/// Real-time updates from electrum via block header and script pubkey subscriptions.
+///
+/// Instead of polling every script pubkey, the subscription only fetches the histories of script
+/// pubkeys that electrum notifies us have changed (and the new tip when a block is found).
+///
+/// New script pubkeys are subscribed to with [`subscribe_spks`] (or [`subscribe_tracker`], which
+/// subscribes to the revealed and lookahead script pubkeys of a [`KeychainTracker`] as well as its
+/// watched script pubkeys).
+///
+/// Notifications are processed into incremental [`ElectrumUpdate`]s with [`poll`]. When the changes
+/// of an update are applied to the tracker with [`apply_changeset`], the script pubkeys that they
+/// reveal are subscribed to automatically. Note that subscriptions do not survive a reconnection
+/// of the [`Client`].
+///
+/// [`subscribe_spks`]: Self::subscribe_spks
+/// [`subscribe_tracker`]: Self::subscribe_tracker
+/// [`poll`]: Self::poll
+/// [`apply_changeset`]: Self::apply_changeset
+#[derive(Debug, Clone)]
+#[allow(clippy::type_complexity)]
+pub struct ElectrumSubscription<K> {
+    /// The subscribed script pubkeys with their keychain index (if any) and last known status
+    spks: BTreeMap<Script, (Option<(K, u32)>, Option<ScriptStatus>)>,
+    /// Script pubkeys with a status we have not fetched the history for yet
+    changed: BTreeSet<Script>,
+    /// Whether we received a new tip since the last update
+    new_tip: bool,
+}
+
+impl<K: Ord + Clone> ElectrumSubscription<K> {
+    /// Create a subscription which is subscribed to the block headers of `client`.
+    pub fn new(client: &Client) -> Result<Self, Error> {
+        client.block_headers_subscribe()?;
+        Ok(Self {
+            spks: BTreeMap::new(),
+            changed: BTreeSet::new(),
+            new_tip: true,
+        })
+    }
+
+    /// Subscribe to the given script pubkeys (alongside their keychain index if they have one).
+    ///
+    /// Script pubkeys that already have a history are fetched in the next [`poll`]. Returns the
+    /// number of script pubkeys that were not subscribed to before.
+    ///
+    /// [`poll`]: Self::poll
+    pub fn subscribe_spks(
+        &mut self,
+        client: &Client,
+        spks: impl IntoIterator<Item = (Option<(K, u32)>, Script)>,
+    ) -> Result<usize, Error> {
+        let mut count = 0;
+        for (index, spk) in spks {
+            if self.spks.contains_key(&spk) {
+                continue;
+            }
+            let status = match client.script_subscribe(&spk) {
+                Ok(status) => status,
+                // someone else subscribed to it with this client so we don't know its status
+                Err(Error::AlreadySubscribed(_)) => {
+                    self.changed.insert(spk.clone());
+                    None
+                }
+                Err(err) => return Err(err),
+            };
+            if status.is_some() {
+                self.changed.insert(spk.clone());
+            }
+            self.spks.insert(spk, (index, status));
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Subscribe to the revealed and lookahead script pubkeys of `tracker` and its watched script
+    /// pubkeys.
+    ///
+    /// Refer to [`subscribe_spks`] for more.
+    ///
+    /// [`subscribe_spks`]: Self::subscribe_spks
+    pub fn subscribe_tracker<P, T>(
+        &mut self,
+        client: &Client,
+        tracker: &KeychainTracker<K, P, T>,
+    ) -> Result<usize, Error>
+    where
+        K: Debug,
+        P: ChainPosition,
+        T: AsTransaction + Clone + Ord,
+    {
+        let keychain_spks = tracker
+            .txout_index
+            .all_spks()
+            .iter()
+            .map(|(index, spk)| (Some(index.clone()), spk.clone()));
+        let watched_spks = tracker.watched_spks().iter().map(|spk| (None, spk.clone()));
+        let spks = keychain_spks
+            .chain(watched_spks)
+            .filter(|(_, spk)| !self.spks.contains_key(spk))
+            .collect::<Vec<_>>();
+        self.subscribe_spks(client, spks)
+    }
+
+    /// Apply the `changeset` of an update to `tracker` and subscribe to the script pubkeys that it
+    /// reveals.
+    ///
+    /// An update of [`poll`] that uses a script pubkey of a keychain reveals the script pubkeys
+    /// after it (up to the lookahead of the tracker's `txout_index`) once it is applied. These (and
+    /// the script pubkeys that the changeset starts to watch) are subscribed to here, so they are
+    /// notified of without calling [`subscribe_tracker`] again. Returns the number of script
+    /// pubkeys that were subscribed to.
+    ///
+    /// [`poll`]: Self::poll
+    /// [`subscribe_tracker`]: Self::subscribe_tracker
+    pub fn apply_changeset<P, T>(
+        &mut self,
+        client: &Client,
+        tracker: &mut KeychainTracker<K, P, T>,
+        changeset: KeychainChangeSet<K, P, T>,
+    ) -> Result<usize, Error>
+    where
+        K: Debug,
+        P: ChainPosition,
+        T: AsTransaction + Clone + Ord,
+    {
+        tracker.apply_changeset(changeset);
+        self.subscribe_tracker(client, tracker)
+    }
+
+    /// Returns whether `spk` is subscribed to.
+    pub fn is_subscribed(&self, spk: &Script) -> bool {
+        self.spks.contains_key(spk)
+    }
+
+    /// Process the notifications received since the last poll into an [`ElectrumUpdate`] that
+    /// connects to `local_chain`.
+    ///
+    /// The update includes the new tip and the transaction histories of the script pubkeys with a
+    /// changed status. If a reorg invalidated checkpoints of `local_chain`, the histories of all
+    /// script pubkeys with a history are fetched again. Returns `None` if nothing has changed.
+    pub fn poll(
+        &mut self,
+        client: &Client,
+        local_chain: &BTreeMap<u32, BlockHash>,
+        batch_size: usize,
+    ) -> Result<Option<ElectrumUpdate<K, TxHeight>>, Error> {
+        // notifications are only read off the connection when a request is made
+        client.ping()?;
+
+        while client.block_headers_pop()?.is_some() {
+            self.new_tip = true;
+        }
+        for (spk, (_, last_status)) in &mut self.spks {
+            while let Some(status) = client.script_pop(spk)? {
+                if *last_status != Some(status) {
+                    *last_status = Some(status);
+                    self.changed.insert(spk.clone());
+                }
+            }
+        }
+
+        if !self.new_tip && self.changed.is_empty() {
+            return Ok(None);
+        }
+
+        let update = loop {
+            let mut update = prepare_update(client, local_chain)?;
+
+            let reorged = local_chain.iter().any(|(&height, hash)| {
+                update
+                    .checkpoint_at(height)
+                    .is_some_and(|cp| cp.hash != *hash)
+            });
+            let spks = match reorged {
+                true => self
+                    .spks
+                    .iter()
+                    .filter(|(_, (_, status))| status.is_some())
+                    .map(|(spk, _)| spk.clone())
+                    .collect::<BTreeSet<_>>(),
+                false => self.changed.clone(),
+            };
+
+            let mut scanned_spks = BTreeMap::<Script, (Script, bool)>::new();
+            match populate_with_spks(
+                client,
+                &mut update,
+                &mut spks.into_iter().map(|spk| (spk.clone(), spk)),
+                0,
+                usize::MAX,
+                batch_size.max(1),
+                &mut scanned_spks,
+                &mut |_, _, _| {},
+                None,
+            ) {
+                Err(InternalError::Reorg) => continue,
+                Err(InternalError::ElectrumError(err)) => return Err(err),
+                Err(InternalError::Cancelled) => unreachable!("there is no cancel token"),
+                Ok(()) => {}
+            }
+
+            let mut last_active_indices = BTreeMap::<K, u32>::new();
+            for (spk, (_, is_used)) in scanned_spks {
+                if let Some((Some((keychain, index)), _)) = self.spks.get(&spk).filter(|_| is_used)
+                {
+                    let last_active = last_active_indices.entry(keychain.clone()).or_default();
+                    *last_active = (*last_active).max(*index);
+                }
+            }
+
+            break ElectrumUpdate {
+                chain_update: update,
+                last_active_indices,
+                ..Default::default()
+            };
+        };
+
+        self.changed.clear();
+        self.new_tip = false;
+        Ok(Some(update))
+    }
+}
@@ -0,0 +1,468 @@
+#![cfg(feature = "async")]
+use bdk_electrum::{
+    bdk_chain::{
+        bitcoin::{
+            hashes::Hash, BlockHash, BlockHeader, OutPoint, PackedLockTime, Script, Transaction,
+            TxIn, TxMerkleNode, TxOut, Txid,
+        },
+        keychain::{CancelToken, FullScanRequest, ScanError, ScanItem, ScanProgress},
+        Birthday, BlockId, ConfirmationTime, TxHeight,
+    },
+    electrum_client::{Error, GetHistoryRes},
+    AsyncElectrumApi, AsyncElectrumExt, ElectrumUpdate,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Wake, Waker},
+};
+
+/// Run `future` to completion on the current thread.
+fn block_on<F: Future>(future: F) -> F::Output {
+    struct ThreadWaker(std::thread::Thread);
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let mut future = Box::pin(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => std::thread::park(),
+        }
+    }
+}
+
+/// A future that is pending `n` times before it is ready.
+struct YieldN(usize);
+
+impl Future for YieldN {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.0 == 0 {
+            return Poll::Ready(());
+        }
+        self.0 -= 1;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+/// An electrum server of a chain of `tip_height + 1` blocks.
+#[derive(Default)]
+struct MockServer {
+    headers: Vec<BlockHeader>,
+    histories: HashMap<Script, Vec<(i32, Txid)>>,
+    txs: HashMap<Txid, Transaction>,
+    /// The number of scripts of each history request (in the order they were made)
+    history_requests: Mutex<Vec<usize>>,
+}
+
+impl MockServer {
+    fn new(tip_height: u32) -> Self {
+        let headers = (0..=tip_height)
+            .map(|height| BlockHeader {
+                version: 1,
+                prev_blockhash: BlockHash::all_zeros(),
+                merkle_root: TxMerkleNode::all_zeros(),
+                // an hour apart so that the birthday timestamp window spans two blocks
+                time: 1_000 + height * 3_600,
+                bits: 0,
+                nonce: height,
+            })
+            .collect();
+        Self {
+            headers,
+            ..Default::default()
+        }
+    }
+
+    /// Add a transaction paying to `spk` at `height` (0 for unconfirmed).
+    fn add_tx(&mut self, spk: &Script, height: i32) -> Txid {
+        self.spend(&[], spk, height)
+    }
+
+    /// Add a transaction spending `outpoints` and paying to `spk` at `height` (0 for unconfirmed).
+    fn spend(&mut self, outpoints: &[OutPoint], spk: &Script, height: i32) -> Txid {
+        let tx = Transaction {
+            version: 1,
+            lock_time: PackedLockTime(self.txs.len() as u32),
+            input: outpoints
+                .iter()
+                .map(|&previous_output| TxIn {
+                    previous_output,
+                    ..Default::default()
+                })
+                .collect(),
+            output: vec![TxOut {
+                value: 10_000,
+                script_pubkey: spk.clone(),
+            }],
+        };
+        let txid = tx.txid();
+        let spent_spks = outpoints
+            .iter()
+            .map(|op| {
+                self.txs[&op.txid].output[op.vout as usize]
+                    .script_pubkey
+                    .clone()
+            })
+            .collect::<Vec<_>>();
+        self.txs.insert(txid, tx);
+        for spk in spent_spks.iter().chain([spk]) {
+            self.histories
+                .entry(spk.clone())
+                .or_default()
+                .push((height, txid));
+        }
+        txid
+    }
+}
+
+impl AsyncElectrumApi for MockServer {
+    fn get_tip(&self) -> impl Future<Output = Result<(u32, BlockHash), Error>> + Send {
+        let tip = self.headers.last().expect("must have a tip");
+        let tip = ((self.headers.len() - 1) as u32, tip.block_hash());
+        async move { Ok(tip) }
+    }
+
+    fn batch_block_header(
+        &self,
+        heights: Vec<u32>,
+    ) -> impl Future<Output = Result<Vec<BlockHeader>, Error>> + Send {
+        let headers = heights
+            .into_iter()
+            .map(|height| {
+                self.headers
+                    .get(height as usize)
+                    .copied()
+                    // electrum responds with a protocol error for heights above the tip
+                    .ok_or_else(|| Error::Protocol("height above tip".into()))
+            })
+            .collect();
+        async move { headers }
+    }
+
+    fn batch_script_get_history(
+        &self,
+        scripts: Vec<Script>,
+    ) -> impl Future<Output = Result<Vec<Vec<GetHistoryRes>>, Error>> + Send {
+        let mut requests = self.history_requests.lock().unwrap();
+        requests.push(scripts.len());
+        // later requests finish first
+        let delay = 10_usize.saturating_sub(requests.len());
+        let histories = scripts
+            .iter()
+            .map(|spk| {
+                self.histories
+                    .get(spk)
+                    .into_iter()
+                    .flatten()
+                    .map(|&(height, tx_hash)| GetHistoryRes {
+                        height,
+                        tx_hash,
+                        fee: None,
+                    })
+                    .collect()
+            })
+            .collect();
+        async move {
+            YieldN(delay).await;
+            Ok(histories)
+        }
+    }
+
+    fn batch_transaction_get(
+        &self,
+        txids: Vec<Txid>,
+    ) -> impl Future<Output = Result<Vec<Transaction>, Error>> + Send {
+        let txs = txids
+            .iter()
+            .map(|txid| {
+                self.txs
+                    .get(txid)
+                    .cloned()
+                    .ok_or_else(|| Error::Message("missing transaction".into()))
+            })
+            .collect();
+        async move { txs }
+    }
+}
+
+fn spk_at(index: u32) -> Script {
+    Script::new_op_return(&index.to_le_bytes())
+}
+
+fn keychain_spks() -> BTreeMap<(), impl Iterator<Item = (u32, Script)>> {
+    [((), (0..).map(|index| (index, spk_at(index))))].into()
+}
+
+fn txids_of(update: &ElectrumUpdate<(), TxHeight>) -> Vec<(TxHeight, Txid)> {
+    update.chain_update.txids().copied().collect()
+}
+
+#[test]
+fn scan_stops_at_stop_gap() {
+    let mut server = MockServer::new(10);
+    let first = server.add_tx(&spk_at(0), 5);
+    let after_gap = server.add_tx(&spk_at(3), 6);
+
+    let mut scanned = Vec::new();
+    let update = block_on(server.scan(
+        &BTreeMap::new(),
+        keychain_spks(),
+        [],
+        [],
+        [],
+        None,
+        2,
+        1,
+        1,
+        |item| {
+            if let ScanItem::Spk { index, .. } = item {
+                scanned.push(index)
+            }
+        },
+        None,
+    ))
+    .expect("should scan");
+    assert_eq!(scanned, vec![0, 1, 2]);
+    assert_eq!(update.last_active_indices, [((), 0)].into());
+    assert_eq!(txids_of(&update), vec![(TxHeight::Confirmed(5), first)]);
+
+    let update = block_on(server.scan(
+        &BTreeMap::new(),
+        keychain_spks(),
+        [],
+        [],
+        [],
+        None,
+        3,
+        1,
+        1,
+        |_| {},
+        None,
+    ))
+    .expect("should scan");
+    assert_eq!(update.last_active_indices, [((), 3)].into());
+    assert_eq!(
+        txids_of(&update),
+        vec![
+            (TxHeight::Confirmed(5), first),
+            (TxHeight::Confirmed(6), after_gap)
+        ]
+    );
+}
+
+#[test]
+fn scan_leaves_out_transactions_before_birthday() {
+    let mut server = MockServer::new(10);
+    let _before = server.add_tx(&spk_at(0), 2);
+    let after = server.add_tx(&spk_at(0), 8);
+    let unconfirmed = server.add_tx(&spk_at(1), 0);
+
+    let birthday_time = Birthday::Time(server.headers[6].time as u64);
+    for birthday in [Birthday::Height(5), birthday_time] {
+        let start_height = birthday
+            .start_height(10, |height| {
+                Ok::<_, ()>(server.headers[height as usize].time as u64)
+            })
+            .unwrap();
+        assert!((3..=8).contains(&start_height));
+
+        let update = block_on(server.scan(
+            &BTreeMap::new(),
+            keychain_spks(),
+            [],
+            [],
+            [],
+            Some(birthday),
+            2,
+            1,
+            1,
+            |_| {},
+            None,
+        ))
+        .expect("should scan");
+        // the used script pubkeys are still active even though some of their history is left out
+        assert_eq!(update.last_active_indices, [((), 1)].into());
+        assert_eq!(
+            txids_of(&update),
+            vec![
+                (TxHeight::Confirmed(8), after),
+                (TxHeight::Unconfirmed, unconfirmed)
+            ]
+        );
+    }
+}
+
+#[test]
+fn cancelled_scan_returns_partial_update() {
+    let mut server = MockServer::new(10);
+    let first = server.add_tx(&spk_at(0), 5);
+    let _second = server.add_tx(&spk_at(1), 6);
+
+    let cancel = CancelToken::new();
+    let result = block_on(server.scan(
+        &BTreeMap::new(),
+        keychain_spks(),
+        [],
+        [],
+        [],
+        None,
+        10,
+        1,
+        1,
+        |_| cancel.cancel(),
+        Some(&cancel),
+    ));
+    let partial = match result {
+        Err(ScanError::Cancelled(partial)) => partial,
+        other => panic!("expected the scan to be cancelled, got {:?}", other),
+    };
+    assert_eq!(txids_of(&partial), vec![(TxHeight::Confirmed(5), first)]);
+    let progress = partial.progress.expect("must have progress");
+    assert_eq!(progress.last_scanned_indices, [((), 0)].into());
+    assert!(progress.finished_keychains.is_empty());
+}
+
+#[test]
+fn scan_finds_spenders_of_chained_outpoints() {
+    let mut server = MockServer::new(10);
+    let parent = server.add_tx(&spk_at(0), 4);
+    let child = server.spend(&[OutPoint::new(parent, 0)], &spk_at(1), 5);
+    let grandchild = server.spend(&[OutPoint::new(child, 0)], &spk_at(2), 6);
+
+    // the child is the residing transaction of the second outpoint and the spender of the first
+    // (the second outpoint does not exist so the child is only found as the spender)
+    let update = block_on(server.scan(
+        &BTreeMap::new(),
+        BTreeMap::<(), std::iter::Empty<(u32, Script)>>::new(),
+        [],
+        [],
+        [OutPoint::new(parent, 0), OutPoint::new(child, 1)],
+        None,
+        10,
+        10,
+        1,
+        |_| {},
+        None,
+    ))
+    .expect("should scan");
+    assert_eq!(
+        txids_of(&update),
+        vec![
+            (TxHeight::Confirmed(4), parent),
+            (TxHeight::Confirmed(5), child)
+        ]
+    );
+    assert!(!txids_of(&update)
+        .iter()
+        .any(|(_, txid)| *txid == grandchild));
+}
+
+#[test]
+fn batched_responses_keep_request_order() {
+    let mut server = MockServer::new(10);
+    let txids = (0..7_u32)
+        .map(|index| server.add_tx(&spk_at(index), 1 + index as i32))
+        .collect::<Vec<_>>();
+
+    let mut found = BTreeMap::new();
+    let update = block_on(server.scan(
+        &BTreeMap::new(),
+        keychain_spks(),
+        [],
+        [],
+        [],
+        None,
+        3,
+        2,
+        3,
+        |item| {
+            if let ScanItem::Spk { index, txids, .. } = item {
+                found.insert(index, txids.to_vec());
+            }
+        },
+        None,
+    ))
+    .expect("should scan");
+
+    // the later batches of each round finish first but are matched to their script pubkeys
+    for (index, txid) in txids.iter().enumerate() {
+        assert_eq!(found[&(index as u32)], vec![*txid]);
+        assert!(update
+            .chain_update
+            .txids()
+            .any(|&(height, t)| t == *txid && height == TxHeight::Confirmed(1 + index as u32)));
+    }
+    assert_eq!(update.last_active_indices, [((), 6)].into());
+    let requests = server.history_requests.lock().unwrap();
+    assert!(requests.iter().all(|&len| len <= 2));
+    // rounds of up to 3 requests of 2 script pubkeys until the stop gap of 3 after index 6
+    assert_eq!(requests.iter().sum::<usize>(), 12);
+}
+
+#[test]
+fn full_scan_includes_watched_spks() {
+    let mut server = MockServer::new(10);
+    let keychain_txid = server.add_tx(&spk_at(0), 5);
+    let watched_spk = spk_at(1_000);
+    let watched_txid = server.add_tx(&watched_spk, 3);
+
+    let request = FullScanRequest::new(BTreeMap::new(), keychain_spks())
+        .with_spks([watched_spk.clone()])
+        .with_birthday(Some(Birthday::Height(4)));
+    let mut inspected = Vec::new();
+    let inspect = |item: ScanItem<'_, ()>| {
+        if let ScanItem::MiscSpk { spk, txids } = item {
+            inspected.push((spk.clone(), txids.to_vec()));
+        }
+    };
+    let update = block_on(server.full_scan(request, 2, 1, 1, inspect, None)).expect("should scan");
+    assert_eq!(inspected, vec![(watched_spk, vec![watched_txid])]);
+    // the watched script pubkey is not bound by the birthday of the keychains
+    let confirmed_at = |height: u32| ConfirmationTime::Confirmed {
+        height,
+        time: server.headers[height as usize].time as u64,
+    };
+    assert_eq!(
+        update.update.chain().txids().copied().collect::<Vec<_>>(),
+        vec![
+            (confirmed_at(3), watched_txid),
+            (confirmed_at(5), keychain_txid)
+        ]
+    );
+    // the update is finalized with the full transactions
+    for txid in [watched_txid, keychain_txid] {
+        assert_eq!(update.update.graph().get_tx(txid), server.txs.get(&txid));
+    }
+    assert_eq!(update.last_active_indices, [((), 0)].into());
+}
+
+#[test]
+fn full_scan_discards_progress_above_the_tip() {
+    let mut server = MockServer::new(10);
+    let txid = server.add_tx(&spk_at(0), 5);
+
+    // progress of a scan of a longer chain (that was reorged away or is served by a server that
+    // is ahead of this one)
+    let mut progress = ScanProgress::new(BlockId {
+        height: 20,
+        hash: BlockHash::all_zeros(),
+    });
+    progress.last_scanned_indices.insert((), 5);
+    let request = FullScanRequest::new(BTreeMap::new(), keychain_spks()).with_progress(progress);
+    let update = block_on(server.full_scan(request, 2, 1, 1, |_| {}, None)).expect("should scan");
+
+    // the progress is discarded so the script pubkeys are scanned from the start
+    assert!(update.update.chain().tx_position(txid).is_some());
+    assert_eq!(update.last_active_indices, [((), 0)].into());
+}
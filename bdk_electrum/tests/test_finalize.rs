@@ -0,0 +1,308 @@
+use bdk_electrum::{
+    bdk_chain::{
+        bitcoin::{
+            consensus::serialize, hashes::Hash, BlockHash, BlockHeader, PackedLockTime, Script,
+            Transaction, TxMerkleNode, TxOut, Txid,
+        },
+        sparse_chain::SparseChain,
+        tx_graph::TxGraph,
+        BlockId, ConfirmationTime, TxHeight,
+    },
+    electrum_client::{
+        Batch, ElectrumApi, Error, GetBalanceRes, GetHeadersRes, GetHistoryRes, GetMerkleRes,
+        ListUnspentRes, Param, RawHeaderNotification, ScriptStatus, ServerFeaturesRes,
+    },
+    ElectrumUpdate,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+};
+
+/// An electrum server of a chain of `tip_height + 1` blocks which only implements what
+/// [`ElectrumUpdate::finalize`] needs.
+struct MockServer {
+    headers: Vec<BlockHeader>,
+    txs: HashMap<Txid, Transaction>,
+    /// The heights of each block header request (in the order they were made)
+    header_requests: Mutex<Vec<Vec<u32>>>,
+}
+
+impl MockServer {
+    fn new(tip_height: u32) -> Self {
+        Self {
+            headers: (0..=tip_height).map(|height| header(height, 0)).collect(),
+            txs: HashMap::new(),
+            header_requests: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn add_tx(&mut self) -> Txid {
+        let tx = new_tx(self.txs.len() as u32);
+        let txid = tx.txid();
+        self.txs.insert(txid, tx);
+        txid
+    }
+
+    fn block_id(&self, height: u32) -> BlockId {
+        BlockId {
+            height,
+            hash: self.headers[height as usize].block_hash(),
+        }
+    }
+}
+
+fn header(height: u32, nonce: u32) -> BlockHeader {
+    BlockHeader {
+        version: 1,
+        prev_blockhash: BlockHash::all_zeros(),
+        merkle_root: TxMerkleNode::all_zeros(),
+        time: 1_000 + height * 600,
+        bits: 0,
+        nonce,
+    }
+}
+
+fn new_tx(lock_time: u32) -> Transaction {
+    Transaction {
+        version: 1,
+        lock_time: PackedLockTime(lock_time),
+        input: vec![],
+        output: vec![TxOut {
+            value: 10_000,
+            script_pubkey: Script::new(),
+        }],
+    }
+}
+
+fn not_found() -> Error {
+    Error::Protocol(serde_json::Value::String("not found".into()))
+}
+
+impl ElectrumApi for MockServer {
+    fn batch_transaction_get_raw<'t, I>(&self, txids: I) -> Result<Vec<Vec<u8>>, Error>
+    where
+        I: IntoIterator<Item = &'t Txid> + Clone,
+    {
+        txids
+            .into_iter()
+            .map(|txid| self.transaction_get_raw(txid))
+            .collect()
+    }
+
+    fn transaction_get_raw(&self, txid: &Txid) -> Result<Vec<u8>, Error> {
+        self.txs.get(txid).map(serialize).ok_or_else(not_found)
+    }
+
+    fn batch_block_header_raw<I>(&self, heights: I) -> Result<Vec<Vec<u8>>, Error>
+    where
+        I: IntoIterator<Item = u32> + Clone,
+    {
+        let heights = heights.into_iter().collect::<Vec<_>>();
+        self.header_requests.lock().unwrap().push(heights.clone());
+        heights
+            .into_iter()
+            .map(|height| self.block_header_raw(height as usize))
+            .collect()
+    }
+
+    fn block_header_raw(&self, height: usize) -> Result<Vec<u8>, Error> {
+        self.headers
+            .get(height)
+            .map(serialize)
+            .ok_or_else(not_found)
+    }
+
+    fn raw_call(
+        &self,
+        _: &str,
+        _: impl IntoIterator<Item = Param>,
+    ) -> Result<serde_json::Value, Error> {
+        unimplemented!()
+    }
+
+    fn batch_call(&self, _: &Batch) -> Result<Vec<serde_json::Value>, Error> {
+        unimplemented!()
+    }
+
+    fn block_headers_subscribe_raw(&self) -> Result<RawHeaderNotification, Error> {
+        unimplemented!()
+    }
+
+    fn block_headers_pop_raw(&self) -> Result<Option<RawHeaderNotification>, Error> {
+        unimplemented!()
+    }
+
+    fn block_headers(&self, _: usize, _: usize) -> Result<GetHeadersRes, Error> {
+        unimplemented!()
+    }
+
+    fn estimate_fee(&self, _: usize) -> Result<f64, Error> {
+        unimplemented!()
+    }
+
+    fn relay_fee(&self) -> Result<f64, Error> {
+        unimplemented!()
+    }
+
+    fn script_subscribe(&self, _: &Script) -> Result<Option<ScriptStatus>, Error> {
+        unimplemented!()
+    }
+
+    fn script_unsubscribe(&self, _: &Script) -> Result<bool, Error> {
+        unimplemented!()
+    }
+
+    fn script_pop(&self, _: &Script) -> Result<Option<ScriptStatus>, Error> {
+        unimplemented!()
+    }
+
+    fn script_get_balance(&self, _: &Script) -> Result<GetBalanceRes, Error> {
+        unimplemented!()
+    }
+
+    fn batch_script_get_balance<'s, I>(&self, _: I) -> Result<Vec<GetBalanceRes>, Error>
+    where
+        I: IntoIterator<Item = &'s Script> + Clone,
+    {
+        unimplemented!()
+    }
+
+    fn script_get_history(&self, _: &Script) -> Result<Vec<GetHistoryRes>, Error> {
+        unimplemented!()
+    }
+
+    fn batch_script_get_history<'s, I>(&self, _: I) -> Result<Vec<Vec<GetHistoryRes>>, Error>
+    where
+        I: IntoIterator<Item = &'s Script> + Clone,
+    {
+        unimplemented!()
+    }
+
+    fn script_list_unspent(&self, _: &Script) -> Result<Vec<ListUnspentRes>, Error> {
+        unimplemented!()
+    }
+
+    fn batch_script_list_unspent<'s, I>(&self, _: I) -> Result<Vec<Vec<ListUnspentRes>>, Error>
+    where
+        I: IntoIterator<Item = &'s Script> + Clone,
+    {
+        unimplemented!()
+    }
+
+    fn batch_estimate_fee<I>(&self, _: I) -> Result<Vec<f64>, Error>
+    where
+        I: IntoIterator<Item = usize> + Clone,
+    {
+        unimplemented!()
+    }
+
+    fn transaction_broadcast_raw(&self, _: &[u8]) -> Result<Txid, Error> {
+        unimplemented!()
+    }
+
+    fn transaction_get_merkle(&self, _: &Txid, _: usize) -> Result<GetMerkleRes, Error> {
+        unimplemented!()
+    }
+
+    fn server_features(&self) -> Result<ServerFeaturesRes, Error> {
+        unimplemented!()
+    }
+
+    fn ping(&self) -> Result<(), Error> {
+        unimplemented!()
+    }
+}
+
+fn update_of(
+    checkpoints: impl IntoIterator<Item = BlockId>,
+    txs: impl IntoIterator<Item = (TxHeight, Txid)>,
+) -> ElectrumUpdate<(), TxHeight> {
+    let mut chain_update = SparseChain::from_checkpoints(checkpoints);
+    for (height, txid) in txs {
+        let _ = chain_update.insert_tx(txid, height).unwrap();
+    }
+    ElectrumUpdate {
+        chain_update,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn finalize_fetches_missing_txs_and_confirmation_times() {
+    let mut server = MockServer::new(10);
+    let confirmed = server.add_tx();
+    let unconfirmed = server.add_tx();
+    let known = new_tx(100);
+    let mut graph = TxGraph::<Transaction>::default();
+    let _ = graph.insert_tx(known.clone());
+    // evicted from the server's mempool after the scan
+    let evicted = new_tx(101).txid();
+
+    let update = update_of(
+        [server.block_id(10)],
+        [
+            (TxHeight::Confirmed(5), confirmed),
+            (TxHeight::Confirmed(6), known.txid()),
+            (TxHeight::Unconfirmed, unconfirmed),
+            (TxHeight::Unconfirmed, evicted),
+        ],
+    );
+    let scan = update
+        .finalize(&server, &graph, &mut BTreeMap::new())
+        .expect("should finalize");
+
+    let chain = scan.update.chain();
+    assert_eq!(
+        chain.tx_position(confirmed),
+        Some(&ConfirmationTime::Confirmed {
+            height: 5,
+            time: server.headers[5].time as u64
+        })
+    );
+    assert_eq!(
+        chain.tx_position(known.txid()),
+        Some(&ConfirmationTime::Confirmed {
+            height: 6,
+            time: server.headers[6].time as u64
+        })
+    );
+    assert_eq!(
+        chain.tx_position(unconfirmed),
+        Some(&ConfirmationTime::Unconfirmed)
+    );
+    assert_eq!(chain.tx_position(evicted), None);
+    assert_eq!(chain.txids().len(), 3);
+
+    let graph = scan.update.graph();
+    for txid in [confirmed, known.txid(), unconfirmed] {
+        assert!(graph.get_tx(txid).is_some());
+    }
+    assert!(graph.get_tx(evicted).is_none());
+}
+
+#[test]
+fn finalize_drops_cached_headers_above_the_point_of_agreement() {
+    let mut server = MockServer::new(10);
+    let txs = [3, 6, 8].map(|height| (TxHeight::Confirmed(height), server.add_tx()));
+
+    // headers cached from before a reorg at height 5 (which has no checkpoint in the update)
+    let mut headers = (0..=8)
+        .map(|height| (height, header(height, u32::from(height >= 5))))
+        .collect::<BTreeMap<u32, _>>();
+    let update = update_of([server.block_id(4), server.block_id(10)], txs);
+    let scan = update
+        .finalize(&server, TxGraph::<Transaction>::default(), &mut headers)
+        .expect("should finalize");
+
+    // only the heights above the point of agreement are fetched again
+    assert_eq!(*server.header_requests.lock().unwrap(), vec![vec![6, 8]]);
+    assert_eq!(
+        headers.keys().copied().collect::<Vec<_>>(),
+        vec![0, 1, 2, 3, 4, 6, 8]
+    );
+    for height in [6, 8] {
+        assert_eq!(headers[&height], server.headers[height as usize]);
+    }
+    assert_eq!(scan.update.chain().txids().len(), 3);
+}
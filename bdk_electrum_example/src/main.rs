@@ -1,14 +1,19 @@
-use bdk_chain::bitcoin::{Address, OutPoint, Txid};
 use bdk_cli::{
     anyhow::{self, Context},
     clap::{self, Parser, Subcommand},
+    Keychain,
+};
+use bdk_electrum::bdk_chain::{
+    bitcoin::{Address, Network},
+    keychain::{KeychainScan, KeychainTracker, ScanError, ScanItem},
+    ConfirmationTime,
 };
-use bdk_electrum::bdk_chain::{self, bitcoin::Network, TxHeight};
 use bdk_electrum::{
     electrum_client::{self, ElectrumApi},
-    ElectrumExt, ElectrumUpdate,
+    ElectrumPool, ElectrumSubscription,
 };
-use std::{collections::BTreeMap, fmt::Debug, io, io::Write};
+use bdk_file_store::KeychainStore;
+use std::{collections::BTreeMap, fmt::Debug, io, io::Write, sync::Mutex, time::Duration};
 
 #[derive(Subcommand, Debug, Clone)]
 enum ElectrumCommands {
@@ -20,7 +25,8 @@ enum ElectrumCommands {
         #[clap(flatten)]
         scan_options: ScanOptions,
     },
-    /// Scans particular addresses using esplora API
+    /// Scans particular addresses using esplora API (watched addresses and outpoints are always
+    /// included)
     Sync {
         /// Scan all the unused addresses
         #[clap(long)]
@@ -37,6 +43,15 @@ enum ElectrumCommands {
         #[clap(flatten)]
         scan_options: ScanOptions,
     },
+    /// Subscribes to the addresses in the wallet and applies updates as electrum notifies us of
+    /// changes (new addresses are subscribed to as they are revealed)
+    Subscribe {
+        /// How many seconds to wait between checking for notifications
+        #[clap(long, default_value = "5")]
+        poll_interval: u64,
+        #[clap(flatten)]
+        scan_options: ScanOptions,
+    },
 }
 
 #[derive(Parser, Debug, Clone, PartialEq)]
@@ -44,16 +59,26 @@ pub struct ScanOptions {
     /// Set batch size for each script_history call to electrum client
     #[clap(long, default_value = "25")]
     pub batch_size: usize,
+    /// Check the results against a second server and fail if they disagree (requires more than one
+    /// server in `ELECTRUM_URLS`)
+    #[clap(long)]
+    pub cross_check: bool,
 }
 
 fn main() -> anyhow::Result<()> {
-    let (args, keymap, mut tracker, mut db) = bdk_cli::init::<ElectrumCommands, _>()?;
+    let (args, keymap, mut tracker, mut db) =
+        bdk_cli::init::<ElectrumCommands, ConfirmationTime>()?;
 
-    let electrum_url = match args.network {
-        Network::Bitcoin => "ssl://electrum.blockstream.info:50002",
-        Network::Testnet => "ssl://electrum.blockstream.info:60002",
-        Network::Regtest => "tcp://localhost:60401",
-        Network::Signet => "tcp://signet-electrumx.wakiyamap.dev:50001",
+    // a comma separated list of servers to fail over between can be set with `ELECTRUM_URLS`
+    let electrum_urls = match std::env::var("ELECTRUM_URLS") {
+        Ok(urls) => urls.split(',').map(|url| url.trim().to_string()).collect(),
+        Err(_) => vec![match args.network {
+            Network::Bitcoin => "ssl://electrum.blockstream.info:50002",
+            Network::Testnet => "ssl://electrum.blockstream.info:60002",
+            Network::Regtest => "tcp://localhost:60401",
+            Network::Signet => "tcp://signet-electrumx.wakiyamap.dev:50001",
+        }
+        .to_string()],
     };
     let config = electrum_client::Config::builder()
         .validate_domain(match args.network {
@@ -62,7 +87,7 @@ fn main() -> anyhow::Result<()> {
         })
         .build();
 
-    let client = electrum_client::Client::from_config(electrum_url, config)?;
+    let mut pool = ElectrumPool::new(electrum_urls, config)?;
 
     let electrum_cmd = match args.command {
         bdk_cli::Commands::ChainSpecific(electrum_cmd) => electrum_cmd,
@@ -70,7 +95,8 @@ fn main() -> anyhow::Result<()> {
             return bdk_cli::handle_commands(
                 general_command,
                 |transaction| {
-                    let _txid = client.transaction_broadcast(transaction)?;
+                    let _txid =
+                        pool.with_failover(|client| client.transaction_broadcast(transaction))?;
                     Ok(())
                 },
                 &mut tracker,
@@ -81,167 +107,157 @@ fn main() -> anyhow::Result<()> {
         }
     };
 
+    // the error of a full scan that was interrupted (what it scanned is still applied)
+    let mut interrupted = None;
+
     let response = match electrum_cmd {
         ElectrumCommands::Scan {
             stop_gap,
             scan_options: scan_option,
         } => {
-            let (spk_iterators, local_chain) = {
+            let request = {
                 // Get a short lock on the tracker to get the spks iterators
                 // and local chain state
                 let tracker = &*tracker.lock().unwrap();
-                let spk_iterators = tracker
-                    .txout_index
-                    .spks_of_all_keychains()
-                    .into_iter()
-                    .map(|(keychain, iter)| {
-                        let mut first = true;
-                        let spk_iter = iter.inspect(move |(i, _)| {
-                            if first {
-                                eprint!("\nscanning {}: ", keychain);
-                                first = false;
-                            }
-
-                            eprint!("{} ", i);
-                            let _ = io::stdout().flush();
-                        });
-                        (keychain, spk_iter)
-                    })
-                    .collect::<BTreeMap<_, _>>();
-                let local_chain = tracker.chain().checkpoints().clone();
-                (spk_iterators, local_chain)
+                tracker
+                    .full_scan_request()
+                    .with_birthday(db.lock().unwrap().metadata().birthday)
+            };
+            let mut scanning_keychain = None;
+            let inspect = |item: ScanItem<'_, Keychain>| {
+                if let ScanItem::Spk {
+                    keychain, index, ..
+                } = item
+                {
+                    if scanning_keychain != Some(*keychain) {
+                        eprint!("\nscanning {}: ", keychain);
+                        scanning_keychain = Some(*keychain);
+                    }
+                    eprint!("{} ", index);
+                    let _ = io::stdout().flush();
+                }
             };
 
             // we scan the spks **without** a lock on the tracker
-            client.scan(
-                &local_chain,
-                spk_iterators,
-                core::iter::empty(),
-                core::iter::empty(),
-                stop_gap,
-                scan_option.batch_size,
-            )?
+            pool.set_cross_check(scan_option.cross_check)?;
+            match pool.full_scan(request, stop_gap, scan_option.batch_size, inspect, None) {
+                Ok(update) => update,
+                // keep what was scanned so that the next scan can resume from it
+                Err(ScanError::Interrupted { error, partial }) => {
+                    interrupted = Some(error);
+                    *partial
+                }
+                Err(err) => return Err(err).context("scanning the blockchain"),
+            }
         }
         ElectrumCommands::Sync {
-            mut unused_spks,
-            mut utxos,
-            mut unconfirmed,
+            unused_spks,
+            utxos,
+            unconfirmed,
             all_spks,
             scan_options,
         } => {
-            // Get a short lock on the tracker to get the spks we're interested in
-            let tracker = tracker.lock().unwrap();
-
-            if !(all_spks || unused_spks || utxos || unconfirmed) {
-                unused_spks = true;
-                unconfirmed = true;
-                utxos = true;
-            } else if all_spks {
-                unused_spks = false;
-            }
-
-            let mut spks: Box<dyn Iterator<Item = bdk_chain::bitcoin::Script>> =
-                Box::new(core::iter::empty());
-            if all_spks {
-                let all_spks = tracker
-                    .txout_index
-                    .all_spks()
-                    .iter()
-                    .map(|(k, v)| (k.clone(), v.clone()))
-                    .collect::<Vec<_>>();
-                spks = Box::new(spks.chain(all_spks.into_iter().map(|(index, script)| {
-                    eprintln!("scanning {:?}", index);
-                    script
-                })));
-            }
-            if unused_spks {
-                let unused_spks = tracker
-                    .txout_index
-                    .unused_spks(..)
-                    .map(|(k, v)| (k.clone(), v.clone()))
-                    .collect::<Vec<_>>();
-                spks = Box::new(spks.chain(unused_spks.into_iter().map(|(index, script)| {
-                    eprintln!(
-                        "Checking if address {} {:?} has been used",
-                        Address::from_script(&script, args.network).unwrap(),
-                        index
-                    );
-
-                    script
-                })));
-            }
-
-            let mut outpoints: Box<dyn Iterator<Item = OutPoint>> = Box::new(core::iter::empty());
-
-            if utxos {
-                let utxos = tracker
-                    .full_utxos()
-                    .map(|(_, utxo)| utxo)
-                    .collect::<Vec<_>>();
-                outpoints = Box::new(
-                    utxos
-                        .into_iter()
-                        .inspect(|utxo| {
-                            eprintln!(
-                                "Checking if outpoint {} (value: {}) has been spent",
-                                utxo.outpoint, utxo.txout.value
-                            );
-                        })
-                        .map(|utxo| utxo.outpoint),
-                );
+            let request =
+                bdk_cli::sync_request(&tracker, unused_spks, all_spks, utxos, unconfirmed);
+            let inspect = |item: ScanItem<'_, ()>| match item {
+                ScanItem::Spk { spk, txids, .. } | ScanItem::MiscSpk { spk, txids } => {
+                    match Address::from_script(spk, args.network) {
+                        Ok(address) => {
+                            eprintln!("Checked address {} ({} transactions)", address, txids.len())
+                        }
+                        Err(_) => {
+                            eprintln!("Checked script {} ({} transactions)", spk, txids.len())
+                        }
+                    }
+                }
+                ScanItem::Txid(txid) => eprintln!("Checking if {} is confirmed yet", txid),
+                ScanItem::OutPoint(outpoint) => {
+                    eprintln!("Checking if outpoint {} has been spent", outpoint)
+                }
             };
 
-            let mut txids: Box<dyn Iterator<Item = Txid>> = Box::new(core::iter::empty());
-
-            if unconfirmed {
-                let unconfirmed_txids = tracker
-                    .chain()
-                    .range_txids_by_height(TxHeight::Unconfirmed..)
-                    .map(|(_, txid)| *txid)
-                    .collect::<Vec<_>>();
-
-                txids = Box::new(unconfirmed_txids.into_iter().inspect(|txid| {
-                    eprintln!("Checking if {} is confirmed yet", txid);
-                }));
-            }
+            // we sync **without** a lock on the tracker
+            pool.set_cross_check(scan_options.cross_check)?;
+            pool.sync(request, scan_options.batch_size, inspect, None)
+                .context("scanning the blockchain")?
+        }
+        ElectrumCommands::Subscribe {
+            poll_interval,
+            scan_options,
+        } => {
+            // subscriptions are tied to the connection of a single server
+            let client = pool.client()?;
+            let mut subscription = ElectrumSubscription::new(&client)?;
+            let n_subscribed = subscription.subscribe_tracker(&client, &tracker.lock().unwrap())?;
+            eprintln!("Subscribed to {} script pubkeys", n_subscribed);
+            // the headers of confirmation heights fetched so far (so they are only fetched once)
+            let mut headers = BTreeMap::new();
+            loop {
+                // Get a short lock on the tracker to get the local chain state
+                let local_chain = tracker.lock().unwrap().chain().checkpoints().clone();
 
-            let local_chain = tracker.chain().checkpoints().clone();
-            // drop lock on tracker
-            drop(tracker);
+                // we wait for notifications **without** a lock on the tracker
+                match subscription
+                    .poll(&client, &local_chain, scan_options.batch_size)
+                    .context("processing notifications")?
+                {
+                    Some(update) => {
+                        eprintln!(
+                            "Applying update with {} transactions",
+                            update.chain_update.txids().count()
+                        );
+                        // only the transactions that the tracker does not have yet are fetched
+                        let scan = {
+                            let tracker = tracker.lock().unwrap();
+                            update.finalize(&*client, tracker.graph(), &mut headers)
+                        }
+                        .context("fetching the transactions of the update")?;
 
-            // we scan the spks **without** a lock on the tracker
-            ElectrumUpdate {
-                chain_update: client
-                    .scan_without_keychain(
-                        &local_chain,
-                        spks,
-                        txids,
-                        outpoints,
-                        scan_options.batch_size,
-                    )
-                    .context("scanning the blockchain")?,
-                ..Default::default()
+                        // applying the update subscribes to the script pubkeys that it reveals
+                        let tracker = &mut *tracker.lock().unwrap();
+                        let changeset = tracker.determine_changeset(&scan)?;
+                        db.lock().unwrap().append_changeset(&changeset)?;
+                        let n_subscribed =
+                            subscription.apply_changeset(&client, tracker, changeset)?;
+                        if n_subscribed > 0 {
+                            eprintln!("Subscribed to {} new script pubkeys", n_subscribed);
+                        }
+                    }
+                    None => std::thread::sleep(Duration::from_secs(poll_interval)),
+                }
             }
         }
     };
 
-    let missing_txids = response.missing_full_txs(&*tracker.lock().unwrap());
-
-    // fetch the missing full transactions **without** a lock on the tracker
-    let new_txs = client
-        .batch_transaction_get(missing_txids)
-        .context("fetching full transactions")?;
-
-    {
-        // Get a final short lock to apply the changes
-        let mut tracker = tracker.lock().unwrap();
-        let changeset = {
-            let scan = response.into_keychain_scan(new_txs, &*tracker)?;
-            tracker.determine_changeset(&scan)?
-        };
-        db.lock().unwrap().append_changeset(&changeset)?;
-        tracker.apply_changeset(changeset);
-    };
+    apply_scan(&tracker, &db, response)?;
 
+    if let Some(error) = interrupted {
+        return Err(error).context("scanning the blockchain (scan again to resume)");
+    }
+
+    Ok(())
+}
+
+/// Apply the `scan` to the `tracker` (and persist the changes to `db`).
+fn apply_scan(
+    tracker: &Mutex<KeychainTracker<Keychain, ConfirmationTime>>,
+    db: &Mutex<KeychainStore<Keychain, ConfirmationTime>>,
+    scan: KeychainScan<Keychain, ConfirmationTime>,
+) -> anyhow::Result<()> {
+    // we take a short lock to apply the results to the tracker and db
+    let tracker = &mut *tracker.lock().unwrap();
+    let changeset = tracker.apply_update(scan)?;
+    db.lock().unwrap().append_changeset(&changeset)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bdk_cli::clap::CommandFactory;
+
+    #[test]
+    fn commands_do_not_collide() {
+        bdk_cli::Args::<ElectrumCommands>::command().debug_assert();
+    }
+}
@@ -2,15 +2,30 @@
 //!
 //! The star of the show is the  [`EsploraExt::scan`] method which scans for relevant
 //! blockchain data (via esplora) and outputs a [`KeychainScan`].
+//!
+//! [`EsploraExt::full_scan`] and [`EsploraExt::sync`] do the same for the requests built by a
+//! [`KeychainTracker`].
+//!
+//! [`KeychainTracker`]: bdk_chain::keychain::KeychainTracker
 
 use bdk_chain::{
-    bitcoin::{BlockHash, OutPoint, Script, Txid},
+    bitcoin::{
+        hashes::Hash, util::uint::Uint256, BlockHash, OutPoint, Script, Transaction, TxMerkleNode,
+        TxOut, Txid,
+    },
     chain_graph::ChainGraph,
-    keychain::KeychainScan,
-    sparse_chain, BlockId, ConfirmationTime,
+    chain_oracle::{checkpoint_update, ChainOracle, CheckpointUpdateError},
+    keychain::{
+        CancelToken, FullScanRequest, KeychainScan, ScanError, ScanItem, ScanProgress, StopGap,
+        SyncRequest,
+    },
+    sparse_chain::{ChainPosition, SparseChain},
+    spv::{self, ConfirmationProof, TxMerkleProof, VerificationFailure},
+    tx_graph::TxGraph,
+    AnchoredPosition, Birthday, BlockId, ConfirmationTime, ObservedPosition, TxHeight,
 };
 use esplora_client::{OutputStatus, TxStatus};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 
 pub use esplora_client;
 use esplora_client::Error;
@@ -25,36 +40,53 @@ pub trait EsploraExt {
     ///
     /// - `local_chain`: the most recent block hashes present locally
     /// - `keychain_spks`: keychains that we want to scan transactions for
+    /// - `spks`: script pubkeys outside of the keychains that we want to scan in full
     /// - `txids`: transactions that we want updated [`ChainPosition`]s for
     /// - `outpoints`: transactions associated with these outpoints (residing, spending) that we
     ///     want to included in the update
+    /// - `birthday`: if provided, keychain transactions confirmed before the [`Birthday`] are skipped
     ///
     /// The scan for each keychain stops after a gap of `stop_gap` script pubkeys with no associated
     /// transactions. `parallel_requests` specifies the max number of HTTP requests to make in
-    /// parallel.
+    /// parallel. The history of each keychain script pubkey is only fetched back to
+    /// the `birthday`.
+    ///
+    /// Every script pubkey, txid and outpoint that is checked is passed to `inspect` (as a
+    /// [`ScanItem`]). If `cancel` is cancelled, the scan stops and returns [`ScanError::Cancelled`]
+    /// with what was scanned so far.
+    ///
+    /// Transactions in the update are positioned with `P` which can be any [`FromTxStatus`]. The
+    /// outputs spent by the transactions are included in the update (as partial txouts) so that
+    /// fees can be calculated.
     ///
     /// [`ChainPosition`]: bdk_chain::sparse_chain::ChainPosition
-    fn scan<K: Ord + Clone>(
+    #[allow(clippy::too_many_arguments, clippy::result_large_err)]
+    fn scan<K: Ord + Clone, P: FromTxStatus>(
         &self,
         local_chain: &BTreeMap<u32, BlockHash>,
         keychain_spks: BTreeMap<K, impl IntoIterator<Item = (u32, Script)>>,
+        spks: impl IntoIterator<Item = Script>,
         txids: impl IntoIterator<Item = Txid>,
         outpoints: impl IntoIterator<Item = OutPoint>,
+        birthday: Option<Birthday>,
         stop_gap: usize,
         parallel_requests: usize,
-    ) -> Result<KeychainScan<K, ConfirmationTime>, Error>;
+        inspect: impl FnMut(ScanItem<'_, K>),
+        cancel: Option<&CancelToken>,
+    ) -> Result<KeychainScan<K, P>, ScanError<KeychainScan<K, P>, Error>>;
 
     /// Convenience method to call [`scan`] without requiring a keychain.
     ///
     /// [`scan`]: EsploraExt::scan
-    fn scan_without_keychain(
+    #[allow(clippy::result_large_err)]
+    fn scan_without_keychain<P: FromTxStatus>(
         &self,
         local_chain: &BTreeMap<u32, BlockHash>,
         misc_spks: impl IntoIterator<Item = Script>,
         txids: impl IntoIterator<Item = Txid>,
         outpoints: impl IntoIterator<Item = OutPoint>,
         parallel_requests: usize,
-    ) -> Result<ChainGraph<ConfirmationTime>, Error> {
+    ) -> Result<ChainGraph<P>, Error> {
         let wallet_scan = self.scan(
             local_chain,
             [(
@@ -65,216 +97,231 @@ pub trait EsploraExt {
                     .map(|(i, spk)| (i as u32, spk)),
             )]
             .into(),
+            [],
             txids,
             outpoints,
+            None,
+            usize::MAX,
+            parallel_requests,
+            |_| {},
+            None,
+        );
+
+        match wallet_scan {
+            Ok(wallet_scan) => Ok(wallet_scan.update),
+            Err(ScanError::Source(err)) | Err(ScanError::Interrupted { error: err, .. }) => {
+                Err(err)
+            }
+            Err(ScanError::Cancelled(_)) => unreachable!("scan has no cancel token"),
+        }
+    }
+
+    /// Perform a full scan of the keychains of a [`FullScanRequest`] with [`scan`].
+    ///
+    /// The returned [`KeychainScan`] can be applied to the tracker that the request was built from.
+    ///
+    /// If the request has the [`ScanProgress`] of an unfinished full scan, the scan resumes from it
+    /// (unless the block it was made at is no longer in the best chain). The progress of a scan
+    /// that is cancelled or interrupted is included in the partial [`KeychainScan`]. Note that the
+    /// stop gap of a resumed keychain is counted from where it resumed.
+    ///
+    /// The request's `spks` are scanned in full alongside the keychains.
+    ///
+    /// [`scan`]: EsploraExt::scan
+    #[allow(clippy::result_large_err)]
+    fn full_scan<K: Ord + Clone, P: FromTxStatus>(
+        &self,
+        request: FullScanRequest<K, impl Iterator<Item = (u32, Script)>>,
+        stop_gap: usize,
+        parallel_requests: usize,
+        inspect: impl FnMut(ScanItem<'_, K>),
+        cancel: Option<&CancelToken>,
+    ) -> Result<KeychainScan<K, P>, ScanError<KeychainScan<K, P>, Error>>;
+
+    /// Sync the script pubkeys, transactions and outpoints of a [`SyncRequest`].
+    ///
+    /// The returned [`KeychainScan`] has no `last_active_indices` (a sync does not derive new script
+    /// pubkeys). The script pubkeys are passed to `inspect` under the `()` keychain (indexed by
+    /// their position in the request).
+    #[allow(clippy::result_large_err)]
+    fn sync<K, P: FromTxStatus>(
+        &self,
+        request: SyncRequest,
+        parallel_requests: usize,
+        inspect: impl FnMut(ScanItem<'_, ()>),
+        cancel: Option<&CancelToken>,
+    ) -> Result<KeychainScan<K, P>, ScanError<KeychainScan<K, P>, Error>> {
+        let spks = request
+            .spks
+            .into_iter()
+            .enumerate()
+            .map(|(i, spk)| (i as u32, spk));
+        match self.scan(
+            &request.local_chain,
+            [((), spks)].into(),
+            [],
+            request.txids,
+            request.outpoints,
+            None,
             usize::MAX,
             parallel_requests,
-        )?;
+            inspect,
+            cancel,
+        ) {
+            Ok(scan) => Ok(scan.update.into()),
+            Err(err) => Err(err.map_partial(|scan| scan.update.into())),
+        }
+    }
+
+    /// Fetch a [`ConfirmationProof`] for each of the given confirmed `txids` (alongside the height
+    /// of the block they are confirmed in).
+    ///
+    /// Transactions that esplora does not have a merkle proof for (or has a proof of a different
+    /// height for) are left out of the returned map.
+    #[allow(clippy::result_large_err)]
+    fn fetch_confirmation_proofs(
+        &self,
+        txids: impl IntoIterator<Item = (u32, Txid)>,
+    ) -> Result<BTreeMap<Txid, ConfirmationProof>, Error>;
+
+    /// Verify the confirmations of a [`KeychainScan`] (as returned by [`scan`]) with merkle proofs
+    /// before it is applied to `chain`.
+    ///
+    /// This returns the verified scan and the transactions with confirmations that could not be
+    /// verified. Confirmations at heights that `chain` has no checkpoint at are only accepted with
+    /// a header of a target no easier than `max_target`. Refer to [`spv::verify_update`] for more.
+    ///
+    /// [`scan`]: EsploraExt::scan
+    #[allow(clippy::type_complexity, clippy::result_large_err)]
+    fn verify_scan<K, P1: ChainPosition, P: ChainPosition>(
+        &self,
+        chain: &SparseChain<P1>,
+        scan: KeychainScan<K, P>,
+        max_target: Option<Uint256>,
+    ) -> Result<(KeychainScan<K, P>, BTreeMap<Txid, (P, VerificationFailure)>), Error> {
+        let proofs =
+            self.fetch_confirmation_proofs(spv::txids_to_verify(chain, scan.update.chain()))?;
+        let verified = spv::verify_update(chain, scan.update.chain(), &proofs, max_target);
+        let update = ChainGraph::new(verified.update, scan.update.graph().clone())
+            .expect("verification must not remove transactions from the chain");
 
-        Ok(wallet_scan.update)
+        Ok((
+            KeychainScan {
+                update,
+                last_active_indices: scan.last_active_indices,
+                progress: scan.progress,
+            },
+            verified.unverified,
+        ))
     }
 }
 
 impl EsploraExt for esplora_client::BlockingClient {
-    fn scan<K: Ord + Clone>(
+    fn scan<K: Ord + Clone, P: FromTxStatus>(
         &self,
         local_chain: &BTreeMap<u32, BlockHash>,
         keychain_spks: BTreeMap<K, impl IntoIterator<Item = (u32, Script)>>,
+        spks: impl IntoIterator<Item = Script>,
         txids: impl IntoIterator<Item = Txid>,
         outpoints: impl IntoIterator<Item = OutPoint>,
+        birthday: Option<Birthday>,
         stop_gap: usize,
         parallel_requests: usize,
-    ) -> Result<KeychainScan<K, ConfirmationTime>, Error> {
+        mut inspect: impl FnMut(ScanItem<'_, K>),
+        cancel: Option<&CancelToken>,
+    ) -> Result<KeychainScan<K, P>, ScanError<KeychainScan<K, P>, Error>> {
         let parallel_requests = parallel_requests.max(1);
-        let mut scan = KeychainScan::default();
-        let update = &mut scan.update;
-        let last_active_indices = &mut scan.last_active_indices;
-
-        for (&height, &original_hash) in local_chain.iter().rev() {
-            let update_block_id = BlockId {
-                height,
-                hash: self.get_block_hash(height)?,
-            };
-            let _ = update
-                .insert_checkpoint(update_block_id)
-                .expect("cannot repeat height here");
-            if update_block_id.hash == original_hash {
-                break;
+        let chain_update = match checkpoint_update(&EsploraOracle(self), local_chain) {
+            Ok(chain_update) => chain_update,
+            Err(CheckpointUpdateError::Oracle(err)) => return Err(ScanError::Source(err)),
+            Err(err @ CheckpointUpdateError::UnstableTip) => {
+                // esplora has no error for this so we report it like a failed request
+                return Err(ScanError::Source(Error::Io(std::io::Error::other(
+                    err.to_string(),
+                ))));
             }
-        }
-        let tip_at_start = BlockId {
-            height: self.get_height()?,
-            hash: self.get_tip_hash()?,
         };
-        if let Err(failure) = update.insert_checkpoint(tip_at_start) {
-            match failure {
-                sparse_chain::InsertCheckpointError::HashNotMatching { .. } => {
-                    // there has been a re-org before we started scanning. We haven't consumed any iterators so it's safe to recursively call.
-                    return EsploraExt::scan(
-                        self,
-                        local_chain,
-                        keychain_spks,
-                        txids,
-                        outpoints,
-                        stop_gap,
-                        parallel_requests,
-                    );
-                }
-            }
-        }
-
-        for (keychain, spks) in keychain_spks {
-            let mut spks = spks.into_iter();
-            let mut last_active_index = None;
-            let mut empty_scripts = 0;
-
-            loop {
-                let handles = (0..parallel_requests)
-                    .filter_map(
-                        |_| -> Option<
-                            std::thread::JoinHandle<Result<(u32, Vec<esplora_client::Tx>), _>>,
-                        > {
-                            let (index, script) = spks.next()?;
-                            let client = self.clone();
-                            Some(std::thread::spawn(move || {
-                                let mut related_txs = client.scripthash_txs(&script, None)?;
-
-                                let n_confirmed =
-                                    related_txs.iter().filter(|tx| tx.status.confirmed).count();
-                                // esplora pages on 25 confirmed transactions. If there's 25 or more we
-                                // keep requesting to see if there's more.
-                                if n_confirmed >= 25 {
-                                    loop {
-                                        let new_related_txs = client.scripthash_txs(
-                                            &script,
-                                            Some(related_txs.last().unwrap().txid),
-                                        )?;
-                                        let n = new_related_txs.len();
-                                        related_txs.extend(new_related_txs);
-                                        // we've reached the end
-                                        if n < 25 {
-                                            break;
-                                        }
-                                    }
-                                }
-
-                                Result::<_, esplora_client::Error>::Ok((index, related_txs))
-                            }))
-                        },
-                    )
-                    .collect::<Vec<_>>();
-
-                let n_handles = handles.len();
-
-                for handle in handles {
-                    let (index, related_txs) = handle.join().unwrap()?; // TODO: don't unwrap
-                    if related_txs.is_empty() {
-                        empty_scripts += 1;
-                    } else {
-                        last_active_index = Some(index);
-                        empty_scripts = 0;
-                    }
-                    for tx in related_txs {
-                        let confirmation_time =
-                            map_confirmation_time(&tx.status, tip_at_start.height);
-
-                        if let Err(failure) = update.insert_tx(tx.to_tx(), confirmation_time) {
-                            use bdk_chain::{
-                                chain_graph::InsertTxError, sparse_chain::InsertTxError::*,
-                            };
-                            match failure {
-                                InsertTxError::Chain(TxTooHigh { .. }) => {
-                                    unreachable!("chain position already checked earlier")
-                                }
-                                InsertTxError::Chain(TxMovedUnexpectedly { .. })
-                                | InsertTxError::UnresolvableConflict(_) => {
-                                    /* implies reorg during scan. We deal with that below */
-                                }
-                            }
-                        }
-                    }
-                }
-
-                if n_handles == 0 || empty_scripts >= stop_gap {
-                    break;
-                }
-            }
+        let tip_at_start = chain_update
+            .latest_checkpoint()
+            .expect("esplora always has a tip");
+        let seen_at = std::time::UNIX_EPOCH
+            .elapsed()
+            .expect("system time must be after unix epoch")
+            .as_secs();
+        let position = |status: &TxStatus| P::from_tx_status(status, tip_at_start.height, seen_at);
+        let mut scan = KeychainScan {
+            update: ChainGraph::new(chain_update, TxGraph::default())
+                .expect("update has no transactions"),
+            last_active_indices: BTreeMap::new(),
+            progress: Some(ScanProgress::new(tip_at_start)),
+        };
 
-            if let Some(last_active_index) = last_active_index {
-                last_active_indices.insert(keychain, last_active_index);
-            }
+        if let Err(err) = populate_with_spks(
+            self,
+            &mut scan,
+            keychain_spks,
+            birthday,
+            stop_gap,
+            parallel_requests,
+            &position,
+            &mut inspect,
+            cancel,
+        ) {
+            return Err(err.with_partial(scan));
         }
 
-        for txid in txids.into_iter() {
-            let (tx, tx_status) = match (self.get_tx(&txid)?, self.get_tx_status(&txid)?) {
-                (Some(tx), Some(tx_status)) => (tx, tx_status),
-                _ => continue,
-            };
+        if let Err(err) = populate_with_misc_spks(
+            self,
+            &mut scan.update,
+            spks,
+            parallel_requests,
+            &position,
+            &mut inspect,
+            cancel,
+        ) {
+            return Err(err.with_partial(scan));
+        }
 
-            let confirmation_time = map_confirmation_time(&tx_status, tip_at_start.height);
-
-            if let Err(failure) = update.insert_tx(tx, confirmation_time) {
-                use bdk_chain::{chain_graph::InsertTxError, sparse_chain::InsertTxError::*};
-                match failure {
-                    InsertTxError::Chain(TxTooHigh { .. }) => {
-                        unreachable!("chain position already checked earlier")
-                    }
-                    InsertTxError::Chain(TxMovedUnexpectedly { .. })
-                    | InsertTxError::UnresolvableConflict(_) => {
-                        /* implies reorg during scan. We deal with that below */
-                    }
-                }
-            }
+        if let Err(err) = populate_with_txids(
+            self,
+            &mut scan.update,
+            txids,
+            &position,
+            &mut inspect,
+            cancel,
+        ) {
+            return Err(err.with_partial(scan));
         }
 
-        for op in outpoints.into_iter() {
-            let mut op_txs = Vec::with_capacity(2);
-            if let (Some(tx), Some(tx_status)) =
-                (self.get_tx(&op.txid)?, self.get_tx_status(&op.txid)?)
-            {
-                op_txs.push((tx, tx_status));
-                if let Some(OutputStatus {
-                    txid: Some(txid),
-                    status: Some(spend_status),
-                    ..
-                }) = self.get_output_status(&op.txid, op.vout as _)?
-                {
-                    if let Some(spend_tx) = self.get_tx(&txid)? {
-                        op_txs.push((spend_tx, spend_status));
-                    }
-                }
-            }
+        if let Err(err) = populate_with_outpoints(
+            self,
+            &mut scan.update,
+            outpoints,
+            &position,
+            &mut inspect,
+            cancel,
+        ) {
+            return Err(err.with_partial(scan));
+        }
 
-            for (tx, status) in op_txs {
-                let confirmation_time = map_confirmation_time(&status, tip_at_start.height);
-
-                if let Err(failure) = update.insert_tx(tx, confirmation_time) {
-                    use bdk_chain::{chain_graph::InsertTxError, sparse_chain::InsertTxError::*};
-                    match failure {
-                        InsertTxError::Chain(TxTooHigh { .. }) => {
-                            unreachable!("chain position already checked earlier")
-                        }
-                        InsertTxError::Chain(TxMovedUnexpectedly { .. })
-                        | InsertTxError::UnresolvableConflict(_) => {
-                            /* implies reorg during scan. We deal with that below */
-                        }
-                    }
-                }
-            }
+        // fetch the outputs spent by the remaining transactions (so fees can be calculated)
+        if let Err(err) = populate_with_prevouts(self, &mut scan.update) {
+            return Err(err.with_partial(scan));
         }
 
-        let reorg_occurred = {
-            if let Some(checkpoint) = update.chain().latest_checkpoint() {
-                self.get_block_hash(checkpoint.height)? != checkpoint.hash
-            } else {
-                false
-            }
+        let reorg_occurred = match scan.update.chain().latest_checkpoint() {
+            Some(checkpoint) => match self.get_block_hash(checkpoint.height) {
+                Ok(hash) => hash != checkpoint.hash,
+                Err(err) => return Err(InternalError::from(err).with_partial(scan)),
+            },
+            None => false,
         };
 
         if reorg_occurred {
             // A reorg occurred so lets find out where all the txids we found are in the chain now.
             // XXX: collect required because of weird type naming issues
-            let txids_found = update
+            let txids_found = scan
+                .update
                 .chain()
                 .txids()
                 .map(|(_, txid)| *txid)
@@ -289,15 +336,503 @@ impl EsploraExt for esplora_client::BlockingClient {
             )?;
         }
 
+        // the scan finished so there is no progress to resume from
+        scan.progress = Some(ScanProgress::default());
         Ok(scan)
     }
+
+    fn full_scan<K: Ord + Clone, P: FromTxStatus>(
+        &self,
+        request: FullScanRequest<K, impl Iterator<Item = (u32, Script)>>,
+        stop_gap: usize,
+        parallel_requests: usize,
+        inspect: impl FnMut(ScanItem<'_, K>),
+        cancel: Option<&CancelToken>,
+    ) -> Result<KeychainScan<K, P>, ScanError<KeychainScan<K, P>, Error>> {
+        let FullScanRequest {
+            local_chain,
+            spks_by_keychain,
+            spks,
+            outpoints,
+            birthday,
+            mut progress,
+        } = request;
+
+        // a reorg past the tip of the progress may have invalidated what was scanned
+        if !progress.is_empty()
+            && EsploraOracle(self).get_block_hash(progress.tip.height)? != Some(progress.tip.hash)
+        {
+            progress = ScanProgress::default();
+        }
+
+        self.scan(
+            &local_chain,
+            progress.resume(spks_by_keychain),
+            spks,
+            [],
+            outpoints,
+            birthday,
+            stop_gap,
+            parallel_requests,
+            inspect,
+            cancel,
+        )
+        .map_err(|err| {
+            err.map_partial(|mut partial| {
+                if let Some(resumed) = partial.progress.take() {
+                    progress.merge(resumed);
+                    partial.progress = Some(progress);
+                }
+                partial
+            })
+        })
+    }
+
+    fn fetch_confirmation_proofs(
+        &self,
+        txids: impl IntoIterator<Item = (u32, Txid)>,
+    ) -> Result<BTreeMap<Txid, ConfirmationProof>, Error> {
+        let mut headers = BTreeMap::new();
+        let mut proofs = BTreeMap::new();
+
+        for (height, txid) in txids {
+            let merkle_proof = match self.get_merkle_proof(&txid)? {
+                Some(proof) if proof.block_height == height => TxMerkleProof {
+                    block_height: proof.block_height,
+                    pos: proof.pos,
+                    merkle: proof
+                        .merkle
+                        .into_iter()
+                        .map(|txid| TxMerkleNode::from_inner(txid.into_inner()))
+                        .collect(),
+                },
+                _ => continue,
+            };
+
+            let header = match headers.get(&height) {
+                Some(header) => *header,
+                None => {
+                    let header = self.get_header_by_hash(&self.get_block_hash(height)?)?;
+                    headers.insert(height, header);
+                    header
+                }
+            };
+
+            proofs.insert(
+                txid,
+                ConfirmationProof {
+                    header,
+                    merkle_proof,
+                },
+            );
+        }
+
+        Ok(proofs)
+    }
+}
+
+/// A [`ChainOracle`] backed by an esplora [`BlockingClient`].
+///
+/// [`BlockingClient`]: esplora_client::BlockingClient
+#[derive(Debug, Clone, Copy)]
+pub struct EsploraOracle<'a>(pub &'a esplora_client::BlockingClient);
+
+impl<'a> ChainOracle for EsploraOracle<'a> {
+    type Error = Error;
+
+    fn get_tip(&self) -> Result<Option<BlockId>, Self::Error> {
+        Ok(Some(BlockId {
+            height: self.0.get_height()?,
+            hash: self.0.get_tip_hash()?,
+        }))
+    }
+
+    fn get_block_hash(&self, height: u32) -> Result<Option<BlockHash>, Self::Error> {
+        match self.0.get_block_hash(height) {
+            Ok(hash) => Ok(Some(hash)),
+            // there is no block at `height` (it is above the tip)
+            Err(Error::HeaderHeightNotFound(_)) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// A [`ChainPosition`] that can be determined from an esplora [`TxStatus`].
+pub trait FromTxStatus: ChainPosition {
+    /// Determine the position from the transaction's `status`.
+    ///
+    /// Transactions confirmed above `height_at_start` (the tip height when the scan started) are
+    /// treated as unconfirmed. `seen_at` is the time (in seconds since the unix epoch) that the
+    /// scan started.
+    fn from_tx_status(status: &TxStatus, height_at_start: u32, seen_at: u64) -> Self;
+}
+
+impl FromTxStatus for TxHeight {
+    fn from_tx_status(status: &TxStatus, height_at_start: u32, _seen_at: u64) -> Self {
+        match status.block_height {
+            Some(height) if height <= height_at_start => TxHeight::Confirmed(height),
+            _ => TxHeight::Unconfirmed,
+        }
+    }
 }
 
-fn map_confirmation_time(tx_status: &TxStatus, height_at_start: u32) -> ConfirmationTime {
-    match (tx_status.block_time, tx_status.block_height) {
-        (Some(time), Some(height)) if height <= height_at_start => {
-            ConfirmationTime::Confirmed { height, time }
+impl FromTxStatus for ConfirmationTime {
+    fn from_tx_status(status: &TxStatus, height_at_start: u32, _seen_at: u64) -> Self {
+        match (status.block_time, status.block_height) {
+            (Some(time), Some(height)) if height <= height_at_start => {
+                ConfirmationTime::Confirmed { height, time }
+            }
+            _ => ConfirmationTime::Unconfirmed,
+        }
+    }
+}
+
+impl FromTxStatus for AnchoredPosition {
+    fn from_tx_status(status: &TxStatus, height_at_start: u32, _seen_at: u64) -> Self {
+        match (status.block_hash, status.block_height) {
+            (Some(hash), Some(height)) if height <= height_at_start => {
+                AnchoredPosition::Confirmed {
+                    block: BlockId { height, hash },
+                    index: None,
+                }
+            }
+            _ => AnchoredPosition::Unconfirmed,
+        }
+    }
+}
+
+impl FromTxStatus for ObservedPosition {
+    fn from_tx_status(status: &TxStatus, height_at_start: u32, seen_at: u64) -> Self {
+        match (status.block_time, status.block_height) {
+            (Some(time), Some(height)) if height <= height_at_start => {
+                ObservedPosition::Confirmed { height, time }
+            }
+            _ => ObservedPosition::seen_at(seen_at),
+        }
+    }
+}
+
+/// Whether the tx with `status` is confirmed in a block that predates the `birthday`.
+fn predates_birthday(birthday: Option<Birthday>, status: &TxStatus) -> bool {
+    match (birthday, status.block_height, status.block_time) {
+        (Some(birthday), Some(height), Some(time)) => birthday.predates(height, time),
+        _ => false,
+    }
+}
+
+#[derive(Debug)]
+enum InternalError {
+    EsploraError(Box<Error>),
+    Cancelled,
+}
+
+impl From<Error> for InternalError {
+    fn from(err: Error) -> Self {
+        Self::EsploraError(Box::new(err))
+    }
+}
+
+impl InternalError {
+    /// Turn this into a [`ScanError`] that includes what was scanned before it occurred.
+    fn with_partial<U>(self, partial: U) -> ScanError<U, Error> {
+        match self {
+            InternalError::EsploraError(error) => ScanError::Interrupted {
+                error: *error,
+                partial: Box::new(partial),
+            },
+            InternalError::Cancelled => ScanError::Cancelled(Box::new(partial)),
+        }
+    }
+}
+
+fn check_cancelled(cancel: Option<&CancelToken>) -> Result<(), InternalError> {
+    match cancel.is_some_and(CancelToken::is_cancelled) {
+        true => Err(InternalError::Cancelled),
+        false => Ok(()),
+    }
+}
+
+/// Insert `tx` into the `update`.
+fn insert_tx<P: ChainPosition>(update: &mut ChainGraph<P>, tx: Transaction, position: P) {
+    if let Err(failure) = update.insert_tx(tx, position) {
+        use bdk_chain::{chain_graph::InsertTxError, sparse_chain::InsertTxError::*};
+        match failure {
+            InsertTxError::Chain(TxTooHigh { .. }) => {
+                unreachable!("chain position already checked earlier")
+            }
+            InsertTxError::Chain(TxMovedUnexpectedly { .. })
+            | InsertTxError::Chain(AnchorNotMatching { .. })
+            | InsertTxError::UnresolvableConflict(_) => {
+                /* implies reorg during scan. We deal with that after scanning */
+            }
+        }
+    }
+}
+
+/// A keychain script pubkey (and its index), whether it is used and its transactions back to the
+/// birthday.
+type SpkTxs = (u32, Script, bool, Vec<esplora_client::Tx>);
+
+/// Populate the update of `scan` with the transaction histories of the script pubkeys of each
+/// keychain (stopping after a gap of `stop_gap` unused script pubkeys).
+///
+/// The last active index and the scan progress of each keychain are recorded in `scan` as we go.
+#[allow(clippy::too_many_arguments, clippy::result_large_err)]
+fn populate_with_spks<K: Ord + Clone, P: ChainPosition>(
+    client: &esplora_client::BlockingClient,
+    scan: &mut KeychainScan<K, P>,
+    keychain_spks: BTreeMap<K, impl IntoIterator<Item = (u32, Script)>>,
+    birthday: Option<Birthday>,
+    stop_gap: usize,
+    parallel_requests: usize,
+    position: &impl Fn(&TxStatus) -> P,
+    inspect: &mut impl FnMut(ScanItem<'_, K>),
+    cancel: Option<&CancelToken>,
+) -> Result<(), InternalError> {
+    for (keychain, spks) in keychain_spks {
+        let mut spks = spks.into_iter();
+        let mut stop_gap = StopGap::new(stop_gap);
+
+        loop {
+            check_cancelled(cancel)?;
+
+            let handles = (0..parallel_requests)
+                .filter_map(|_| -> Option<std::thread::JoinHandle<Result<SpkTxs, _>>> {
+                    let (index, script) = spks.next()?;
+                    let client = client.clone();
+                    Some(std::thread::spawn(move || {
+                        let mut related_txs = spk_txs(&client, &script, birthday)?;
+
+                        let is_used = !related_txs.is_empty();
+                        related_txs.retain(|tx| !predates_birthday(birthday, &tx.status));
+
+                        Result::<_, esplora_client::Error>::Ok((
+                            index,
+                            script,
+                            is_used,
+                            related_txs,
+                        ))
+                    }))
+                })
+                .collect::<Vec<_>>();
+
+            let n_handles = handles.len();
+
+            for handle in handles {
+                let (index, script, is_used, related_txs) = handle.join().unwrap()?; // TODO: don't unwrap
+                inspect(ScanItem::Spk {
+                    keychain: &keychain,
+                    index,
+                    spk: &script,
+                    txids: &related_txs.iter().map(|tx| tx.txid).collect::<Vec<_>>(),
+                });
+                for tx in related_txs {
+                    insert_esplora_tx(&mut scan.update, tx, position);
+                }
+
+                stop_gap.record(index, is_used);
+                if is_used {
+                    scan.last_active_indices.insert(keychain.clone(), index);
+                }
+                if let Some(progress) = &mut scan.progress {
+                    progress.record_scanned(&keychain, index);
+                }
+            }
+
+            if n_handles == 0 || stop_gap.is_reached() {
+                break;
+            }
+        }
+
+        if let Some(progress) = &mut scan.progress {
+            progress.record_finished(keychain);
+        }
+    }
+
+    Ok(())
+}
+
+/// Populate the `update` with the whole transaction histories of `spks` (which are not part of a
+/// keychain).
+#[allow(clippy::result_large_err)]
+fn populate_with_misc_spks<K, P: ChainPosition>(
+    client: &esplora_client::BlockingClient,
+    update: &mut ChainGraph<P>,
+    spks: impl IntoIterator<Item = Script>,
+    parallel_requests: usize,
+    position: &impl Fn(&TxStatus) -> P,
+    inspect: &mut impl FnMut(ScanItem<'_, K>),
+    cancel: Option<&CancelToken>,
+) -> Result<(), InternalError> {
+    let mut spks = spks.into_iter().peekable();
+    while spks.peek().is_some() {
+        check_cancelled(cancel)?;
+
+        let handles = spks
+            .by_ref()
+            .take(parallel_requests)
+            .map(|script| {
+                let client = client.clone();
+                std::thread::spawn(move || {
+                    spk_txs(&client, &script, None).map(|related_txs| (script, related_txs))
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for handle in handles {
+            let (script, related_txs) = handle.join().unwrap()?; // TODO: don't unwrap
+            inspect(ScanItem::MiscSpk {
+                spk: &script,
+                txids: &related_txs.iter().map(|tx| tx.txid).collect::<Vec<_>>(),
+            });
+            for tx in related_txs {
+                insert_esplora_tx(update, tx, position);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Insert the esplora `tx` into the `update` (alongside the outputs it spends).
+fn insert_esplora_tx<P: ChainPosition>(
+    update: &mut ChainGraph<P>,
+    tx: esplora_client::Tx,
+    position: &impl Fn(&TxStatus) -> P,
+) {
+    // esplora gives us the outputs spent by the tx so we can calculate fees
+    for vin in &tx.vin {
+        if let Some(prevout) = &vin.prevout {
+            let _ = update.insert_txout(
+                OutPoint::new(vin.txid, vin.vout),
+                TxOut {
+                    value: prevout.value,
+                    script_pubkey: prevout.scriptpubkey.clone(),
+                },
+            );
+        }
+    }
+
+    insert_tx(update, tx.to_tx(), position(&tx.status));
+}
+
+/// Get the transactions in the history of `script` (from the most recent).
+///
+/// The history is fetched a page at a time until we are past the `birthday`, so some transactions
+/// that predate the `birthday` may be included.
+#[allow(clippy::result_large_err)]
+fn spk_txs(
+    client: &esplora_client::BlockingClient,
+    script: &Script,
+    birthday: Option<Birthday>,
+) -> Result<Vec<esplora_client::Tx>, Error> {
+    let mut related_txs = client.scripthash_txs(script, None)?;
+
+    let n_confirmed = related_txs.iter().filter(|tx| tx.status.confirmed).count();
+    // esplora pages on 25 confirmed transactions. If there's 25 or more we keep requesting to see
+    // if there's more.
+    if n_confirmed >= 25 {
+        loop {
+            // txs are ordered from the most recent so there is nothing more for us once we are
+            // past the birthday
+            if predates_birthday(birthday, &related_txs.last().unwrap().status) {
+                break;
+            }
+            let new_related_txs =
+                client.scripthash_txs(script, Some(related_txs.last().unwrap().txid))?;
+            let n = new_related_txs.len();
+            related_txs.extend(new_related_txs);
+            // we've reached the end
+            if n < 25 {
+                break;
+            }
+        }
+    }
+
+    Ok(related_txs)
+}
+
+/// Populate the `update` with the transactions of `txids` (and their chain positions).
+fn populate_with_txids<K, P: ChainPosition>(
+    client: &esplora_client::BlockingClient,
+    update: &mut ChainGraph<P>,
+    txids: impl IntoIterator<Item = Txid>,
+    position: &impl Fn(&TxStatus) -> P,
+    inspect: &mut impl FnMut(ScanItem<'_, K>),
+    cancel: Option<&CancelToken>,
+) -> Result<(), InternalError> {
+    for txid in txids {
+        check_cancelled(cancel)?;
+        inspect(ScanItem::Txid(txid));
+        let (tx, tx_status) = match (client.get_tx(&txid)?, client.get_tx_status(&txid)?) {
+            (Some(tx), Some(tx_status)) => (tx, tx_status),
+            _ => continue,
+        };
+
+        insert_tx(update, tx, position(&tx_status));
+    }
+    Ok(())
+}
+
+/// Populate the `update` with the transactions that contain and spend `outpoints`.
+fn populate_with_outpoints<K, P: ChainPosition>(
+    client: &esplora_client::BlockingClient,
+    update: &mut ChainGraph<P>,
+    outpoints: impl IntoIterator<Item = OutPoint>,
+    position: &impl Fn(&TxStatus) -> P,
+    inspect: &mut impl FnMut(ScanItem<'_, K>),
+    cancel: Option<&CancelToken>,
+) -> Result<(), InternalError> {
+    for op in outpoints {
+        check_cancelled(cancel)?;
+        inspect(ScanItem::OutPoint(op));
+        let mut op_txs = Vec::with_capacity(2);
+        if let (Some(tx), Some(tx_status)) =
+            (client.get_tx(&op.txid)?, client.get_tx_status(&op.txid)?)
+        {
+            op_txs.push((tx, tx_status));
+            if let Some(OutputStatus {
+                txid: Some(txid),
+                status: Some(spend_status),
+                ..
+            }) = client.get_output_status(&op.txid, op.vout as _)?
+            {
+                if let Some(spend_tx) = client.get_tx(&txid)? {
+                    op_txs.push((spend_tx, spend_status));
+                }
+            }
+        }
+
+        for (tx, status) in op_txs {
+            insert_tx(update, tx, position(&status));
+        }
+    }
+    Ok(())
+}
+
+/// Populate the `update` with the outputs spent by its transactions that it does not have yet.
+fn populate_with_prevouts<P: ChainPosition>(
+    client: &esplora_client::BlockingClient,
+    update: &mut ChainGraph<P>,
+) -> Result<(), InternalError> {
+    let missing_prevouts = update.graph().missing_prevouts();
+    let parent_txids = missing_prevouts
+        .iter()
+        .map(|op| op.txid)
+        .collect::<BTreeSet<_>>();
+    for parent_txid in parent_txids {
+        let parent_tx = match client.get_tx(&parent_txid)? {
+            Some(tx) => tx,
+            None => continue,
+        };
+        for op in missing_prevouts.range(OutPoint::new(parent_txid, 0)..) {
+            if op.txid != parent_txid {
+                break;
+            }
+            if let Some(txout) = parent_tx.output.get(op.vout as usize) {
+                let _ = update.insert_txout(*op, txout.clone());
+            }
         }
-        _ => ConfirmationTime::Unconfirmed,
     }
+    Ok(())
 }
@@ -1,5 +1,8 @@
-use bdk_chain::bitcoin::{Address, OutPoint, Txid};
-use bdk_chain::{bitcoin::Network, TxHeight};
+use bdk_chain::{
+    bitcoin::{Address, Network},
+    keychain::{ScanError, ScanItem},
+    ConfirmationTime,
+};
 use bdk_esplora::esplora_client;
 use bdk_esplora::EsploraExt;
 
@@ -8,6 +11,7 @@ use std::io::{self, Write};
 use bdk_cli::{
     anyhow::{self, Context},
     clap::{self, Parser, Subcommand},
+    Keychain,
 };
 
 #[derive(Subcommand, Debug, Clone)]
@@ -21,7 +25,8 @@ enum EsploraCommands {
         #[clap(flatten)]
         scan_options: ScanOptions,
     },
-    /// Scans particular addresses using esplora API
+    /// Scans particular addresses using esplora API (watched addresses and outpoints are always
+    /// included)
     Sync {
         /// Scan all the unused addresses
         #[clap(long)]
@@ -48,7 +53,8 @@ pub struct ScanOptions {
 }
 
 fn main() -> anyhow::Result<()> {
-    let (args, keymap, keychain_tracker, db) = bdk_cli::init::<EsploraCommands, _>()?;
+    let (args, keymap, keychain_tracker, db) =
+        bdk_cli::init::<EsploraCommands, ConfirmationTime>()?;
     let esplora_url = match args.network {
         Network::Bitcoin => "https://mempool.space/api",
         Network::Testnet => "https://mempool.space/testnet/api",
@@ -77,46 +83,42 @@ fn main() -> anyhow::Result<()> {
             stop_gap,
             scan_options,
         } => {
-            let (spk_iterators, local_chain) = {
+            let request = {
                 // Get a short lock on the tracker to get the spks iterators
                 // and local chain state
                 let tracker = &*keychain_tracker.lock().unwrap();
-                let spk_iterators = tracker
-                    .txout_index
-                    .spks_of_all_keychains()
-                    .into_iter()
-                    .map(|(keychain, iter)| {
-                        let mut first = true;
-                        (
-                            keychain,
-                            iter.inspect(move |(i, _)| {
-                                if first {
-                                    eprint!("\nscanning {}: ", keychain);
-                                    first = false;
-                                }
-
-                                eprint!("{} ", i);
-                                let _ = io::stdout().flush();
-                            }),
-                        )
-                    })
-                    .collect();
-
-                let local_chain = tracker.chain().checkpoints().clone();
-                (spk_iterators, local_chain)
+                tracker
+                    .full_scan_request()
+                    .with_birthday(db.lock().unwrap().metadata().birthday)
+            };
+            let mut scanning_keychain = None;
+            let inspect = |item: ScanItem<'_, Keychain>| {
+                if let ScanItem::Spk {
+                    keychain, index, ..
+                } = item
+                {
+                    if scanning_keychain != Some(*keychain) {
+                        eprint!("\nscanning {}: ", keychain);
+                        scanning_keychain = Some(*keychain);
+                    }
+                    eprint!("{} ", index);
+                    let _ = io::stdout().flush();
+                }
             };
 
             // we scan the iterators **without** a lock on the tracker
-            let wallet_scan = client
-                .scan(
-                    &local_chain,
-                    spk_iterators,
-                    core::iter::empty(),
-                    core::iter::empty(),
-                    stop_gap,
-                    scan_options.parallel_requests,
-                )
-                .context("scanning the blockchain")?;
+            let (wallet_scan, interrupted) = match client.full_scan(
+                request,
+                stop_gap,
+                scan_options.parallel_requests,
+                inspect,
+                None,
+            ) {
+                Ok(wallet_scan) => (wallet_scan, None),
+                // keep what was scanned so that the next scan can resume from it
+                Err(ScanError::Interrupted { error, partial }) => (*partial, Some(error)),
+                Err(err) => return Err(err).context("scanning the blockchain"),
+            };
             eprintln!();
 
             {
@@ -126,110 +128,46 @@ fn main() -> anyhow::Result<()> {
                 let changeset = tracker.apply_update(wallet_scan)?;
                 db.append_changeset(&changeset)?;
             }
+
+            if let Some(error) = interrupted {
+                return Err(error).context("scanning the blockchain (scan again to resume)");
+            }
         }
         EsploraCommands::Sync {
-            mut unused_spks,
-            mut utxos,
-            mut unconfirmed,
+            unused_spks,
+            utxos,
+            unconfirmed,
             all_spks,
             scan_options,
         } => {
-            // Get a short lock on the tracker to get the spks we're interested in
-            let tracker = keychain_tracker.lock().unwrap();
-
-            if !(all_spks || unused_spks || utxos || unconfirmed) {
-                unused_spks = true;
-                unconfirmed = true;
-                utxos = true;
-            } else if all_spks {
-                unused_spks = false;
-            }
-
-            let mut spks: Box<dyn Iterator<Item = bdk_chain::bitcoin::Script>> =
-                Box::new(core::iter::empty());
-            if all_spks {
-                let all_spks = tracker
-                    .txout_index
-                    .all_spks()
-                    .iter()
-                    .map(|(k, v)| (k.clone(), v.clone()))
-                    .collect::<Vec<_>>();
-                spks = Box::new(spks.chain(all_spks.into_iter().map(|(index, script)| {
-                    eprintln!("scanning {:?}", index);
-                    script
-                })));
-            }
-            if unused_spks {
-                let unused_spks = tracker
-                    .txout_index
-                    .unused_spks(..)
-                    .map(|(k, v)| (k.clone(), v.clone()))
-                    .collect::<Vec<_>>();
-                spks = Box::new(spks.chain(unused_spks.into_iter().map(|(index, script)| {
-                    eprintln!(
-                        "Checking if address {} {:?} has been used",
-                        Address::from_script(&script, args.network).unwrap(),
-                        index
-                    );
-
-                    script
-                })));
-            }
-
-            let mut outpoints: Box<dyn Iterator<Item = OutPoint>> = Box::new(core::iter::empty());
-
-            if utxos {
-                let utxos = tracker
-                    .full_utxos()
-                    .map(|(_, utxo)| utxo)
-                    .collect::<Vec<_>>();
-                outpoints = Box::new(
-                    utxos
-                        .into_iter()
-                        .inspect(|utxo| {
-                            eprintln!(
-                                "Checking if outpoint {} (value: {}) has been spent",
-                                utxo.outpoint, utxo.txout.value
-                            );
-                        })
-                        .map(|utxo| utxo.outpoint),
-                );
+            let request =
+                bdk_cli::sync_request(&keychain_tracker, unused_spks, all_spks, utxos, unconfirmed);
+            let inspect = |item: ScanItem<'_, ()>| match item {
+                ScanItem::Spk { spk, txids, .. } | ScanItem::MiscSpk { spk, txids } => {
+                    match Address::from_script(spk, args.network) {
+                        Ok(address) => {
+                            eprintln!("Checked address {} ({} transactions)", address, txids.len())
+                        }
+                        Err(_) => {
+                            eprintln!("Checked script {} ({} transactions)", spk, txids.len())
+                        }
+                    }
+                }
+                ScanItem::Txid(txid) => eprintln!("Checking if {} is confirmed yet", txid),
+                ScanItem::OutPoint(outpoint) => {
+                    eprintln!("Checking if outpoint {} has been spent", outpoint)
+                }
             };
 
-            let mut txids: Box<dyn Iterator<Item = Txid>> = Box::new(core::iter::empty());
-
-            if unconfirmed {
-                let unconfirmed_txids = tracker
-                    .chain()
-                    .range_txids_by_height(TxHeight::Unconfirmed..)
-                    .map(|(_, txid)| *txid)
-                    .collect::<Vec<_>>();
-
-                txids = Box::new(unconfirmed_txids.into_iter().inspect(|txid| {
-                    eprintln!("Checking if {} is confirmed yet", txid);
-                }));
-            }
-
-            let local_chain = tracker.chain().checkpoints().clone();
-
-            // drop lock on tracker
-            drop(tracker);
-
-            // we scan the desired spks **without** a lock on the tracker
+            // we sync **without** a lock on the tracker
             let scan = client
-                .scan_without_keychain(
-                    &local_chain,
-                    spks,
-                    txids,
-                    outpoints,
-                    scan_options.parallel_requests,
-                )
+                .sync(request, scan_options.parallel_requests, inspect, None)
                 .context("scanning the blockchain")?;
 
             {
                 // we take a short lock to apply the results to the tracker and db
                 let tracker = &mut *keychain_tracker.lock().unwrap();
-                let changeset = tracker.apply_update(scan.into())?;
+                let changeset = tracker.apply_update(scan)?;
                 let db = &mut *db.lock().unwrap();
                 db.append_changeset(&changeset)?;
             }
@@ -1,17 +1,18 @@
 //! Module for persisting data on-disk.
 //!
 //! The star of the show is [`KeychainStore`] which maintains an append-only file of
-//! [`KeychainChangeSet`]s which can be used to restore a [`KeychainTracker`].
+//! [`KeychainChangeSet`]s which can be used to restore a [`KeychainTracker`]. The file may also
+//! have a header with [`StoreMetadata`] about the wallet.
 use bdk_chain::{
     bitcoin::Transaction,
     keychain::{KeychainChangeSet, KeychainTracker},
-    sparse_chain, AsTransaction,
+    sparse_chain, AsTransaction, Birthday,
 };
 use core::marker::PhantomData;
 use std::{
     fs::{File, OpenOptions},
     io::{self, Read, Seek, Write},
-    path::Path,
+    path::{Path, PathBuf},
 };
 
 /// BDK File Store magic bytes length.
@@ -20,11 +21,32 @@ pub const MAGIC_BYTES_LEN: usize = 12;
 /// BDK File Store magic bytes.
 pub const MAGIC_BYTES: [u8; MAGIC_BYTES_LEN] = [98, 100, 107, 102, 115, 48, 48, 48, 48, 48, 48, 48];
 
+/// BDK File Store magic bytes of files that have a metadata header.
+///
+/// The magic bytes are followed by [`METADATA_LEN`] bytes of encoded [`StoreMetadata`].
+pub const MAGIC_BYTES_WITH_METADATA: [u8; MAGIC_BYTES_LEN] =
+    [98, 100, 107, 102, 115, 48, 48, 48, 48, 48, 48, 49];
+
+/// The length of the metadata header (which is zero padded).
+pub const METADATA_LEN: usize = 64;
+
+/// Metadata about the wallet that is stored in the header of a [`KeychainStore`] file.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct StoreMetadata {
+    /// When the wallet was created.
+    pub birthday: Option<Birthday>,
+}
+
 /// Persists an append only list of `KeychainChangeSet<K,P>` to a single file.
 /// [`KeychainChangeSet<K,P>`] record the changes made to a [`KeychainTracker<K,P>`].
 #[derive(Debug)]
 pub struct KeychainStore<K, P, T = Transaction> {
     db_file: File,
+    /// The path of `db_file` if the store was opened with [`KeychainStore::new_from_path`].
+    db_path: Option<PathBuf>,
+    metadata: StoreMetadata,
+    /// The position of the first changeset.
+    data_start: u64,
     changeset_type_params: core::marker::PhantomData<(K, P, T)>,
 }
 
@@ -37,7 +59,8 @@ where
 {
     /// Creates a new store from a [`File`].
     ///
-    /// The file must have been opened with read, write permissions.
+    /// The file must have been opened with read, write permissions. The file may start with either
+    /// [`MAGIC_BYTES`] or [`MAGIC_BYTES_WITH_METADATA`].
     ///
     /// [`File`]: std::fs::File
     pub fn new(mut file: File) -> Result<Self, FileError> {
@@ -46,12 +69,24 @@ where
         let mut magic_bytes = [0_u8; MAGIC_BYTES_LEN];
         file.read_exact(&mut magic_bytes)?;
 
-        if magic_bytes != MAGIC_BYTES {
+        let (metadata, data_start) = if magic_bytes == MAGIC_BYTES {
+            (StoreMetadata::default(), MAGIC_BYTES_LEN)
+        } else if magic_bytes == MAGIC_BYTES_WITH_METADATA {
+            let mut metadata_bytes = [0_u8; METADATA_LEN];
+            file.read_exact(&mut metadata_bytes)?;
+            let (bincode::serde::Compat(metadata), _) =
+                bincode::decode_from_slice(&metadata_bytes, bincode::config::standard())
+                    .map_err(FileError::InvalidMetadata)?;
+            (metadata, MAGIC_BYTES_LEN + METADATA_LEN)
+        } else {
             return Err(FileError::InvalidMagicBytes(magic_bytes));
-        }
+        };
 
         Ok(Self {
             db_file: file,
+            db_path: None,
+            metadata,
+            data_start: data_start as u64,
             changeset_type_params: Default::default(),
         })
     }
@@ -64,13 +99,16 @@ where
             .read(true)
             .write(true)
             .create(true)
-            .open(db_path)?;
+            .open(db_path.as_ref())?;
 
         if !already_exists {
-            db_file.write_all(&MAGIC_BYTES)?;
+            db_file.write_all(&MAGIC_BYTES_WITH_METADATA)?;
+            db_file.write_all(&encode_metadata(&StoreMetadata::default())?)?;
         }
 
-        Self::new(db_file)
+        let mut store = Self::new(db_file)?;
+        store.db_path = Some(db_path.as_ref().to_path_buf());
+        Ok(store)
     }
 
     /// Iterates over the stored changeset from first to last changing the seek position at each
@@ -85,8 +123,7 @@ where
     pub fn iter_changesets(
         &mut self,
     ) -> Result<EntryIter<'_, KeychainChangeSet<K, P, T>>, io::Error> {
-        self.db_file
-            .seek(io::SeekFrom::Start(MAGIC_BYTES_LEN as _))?;
+        self.db_file.seek(io::SeekFrom::Start(self.data_start))?;
 
         Ok(EntryIter::new(&mut self.db_file))
     }
@@ -131,6 +168,67 @@ where
         Ok(())
     }
 
+    /// Returns the metadata stored in the header of the file.
+    ///
+    /// This is the default (empty) metadata for files without a metadata header.
+    pub fn metadata(&self) -> &StoreMetadata {
+        &self.metadata
+    }
+
+    /// Overwrite the metadata stored in the header of the file.
+    ///
+    /// Files without a metadata header (starting with [`MAGIC_BYTES`]) are upgraded to include one
+    /// by writing the upgraded file next to the original and renaming it over the original, so a
+    /// crash cannot leave a partially rewritten file behind. This requires the store to have been
+    /// opened with [`new_from_path`] and returns an [`io::ErrorKind::Unsupported`] error otherwise.
+    /// The write position relative to the changesets is preserved.
+    ///
+    /// [`new_from_path`]: Self::new_from_path
+    pub fn set_metadata(&mut self, metadata: StoreMetadata) -> Result<(), io::Error> {
+        let metadata_bytes = encode_metadata(&metadata)?;
+        let mut pos = self.db_file.stream_position()?;
+
+        if self.data_start == MAGIC_BYTES_LEN as u64 {
+            let db_path = self.db_path.as_ref().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "files without a metadata header can only be upgraded when opened from a path",
+                )
+            })?;
+            let mut tmp_path = db_path.clone().into_os_string();
+            tmp_path.push(".tmp");
+
+            let mut data = Vec::new();
+            self.db_file.seek(io::SeekFrom::Start(self.data_start))?;
+            self.db_file.read_to_end(&mut data)?;
+
+            let mut tmp_file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&tmp_path)?;
+            tmp_file.write_all(&MAGIC_BYTES_WITH_METADATA)?;
+            tmp_file.write_all(&metadata_bytes)?;
+            tmp_file.write_all(&data)?;
+            tmp_file.sync_all()?;
+            std::fs::rename(&tmp_path, db_path)?;
+
+            self.db_file = tmp_file;
+            self.data_start += METADATA_LEN as u64;
+            pos += METADATA_LEN as u64;
+        } else {
+            self.db_file
+                .seek(io::SeekFrom::Start(MAGIC_BYTES_LEN as u64))?;
+            self.db_file.write_all(&metadata_bytes)?;
+        }
+        self.db_file.sync_data()?;
+        self.db_file.seek(io::SeekFrom::Start(pos))?;
+
+        self.metadata = metadata;
+        Ok(())
+    }
+
     /// Append a new changeset to the file and truncate file to the end of the appended changeset.
     ///
     /// The truncation is to avoid the possibility of having a valid, but inconsistent changeset
@@ -169,6 +267,22 @@ where
     }
 }
 
+fn encode_metadata(metadata: &StoreMetadata) -> Result<[u8; METADATA_LEN], io::Error> {
+    let mut metadata_bytes = [0_u8; METADATA_LEN];
+    bincode::encode_into_slice(
+        bincode::serde::Compat(metadata),
+        &mut metadata_bytes,
+        bincode::config::standard(),
+    )
+    .map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("cannot encode metadata in {} bytes: {}", METADATA_LEN, e),
+        )
+    })?;
+    Ok(metadata_bytes)
+}
+
 /// Error that occurs due to problems encountered with the file.
 #[derive(Debug)]
 pub enum FileError {
@@ -176,6 +290,8 @@ pub enum FileError {
     Io(io::Error),
     /// Magic bytes do not match expected.
     InvalidMagicBytes([u8; MAGIC_BYTES_LEN]),
+    /// The metadata header cannot be decoded.
+    InvalidMetadata(bincode::error::DecodeError),
 }
 
 impl core::fmt::Display for FileError {
@@ -187,6 +303,7 @@ impl core::fmt::Display for FileError {
                 "file has invalid magic bytes: expected={:?} got={:?}",
                 MAGIC_BYTES, b
             ),
+            Self::InvalidMetadata(e) => write!(f, "file has invalid metadata: {}", e),
         }
     }
 }
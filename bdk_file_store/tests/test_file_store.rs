@@ -1,9 +1,13 @@
 use bdk_chain::{
-    bitcoin::Transaction,
-    keychain::{KeychainChangeSet, KeychainTracker},
-    TxHeight,
+    bitcoin::{hashes::Hash, Transaction, Txid},
+    chain_graph::{Eviction, EvictionReason},
+    keychain::{KeychainChangeSet, KeychainScan, KeychainTracker, LabelRef, ScanProgress},
+    Birthday, BlockId, TxHeight,
+};
+use bdk_file_store::{
+    FileError, IterError, KeychainStore, StoreMetadata, MAGIC_BYTES, MAGIC_BYTES_LEN,
+    MAGIC_BYTES_WITH_METADATA,
 };
-use bdk_file_store::{FileError, IterError, KeychainStore, MAGIC_BYTES, MAGIC_BYTES_LEN};
 use serde;
 use std::{
     format,
@@ -121,6 +125,11 @@ fn append_changeset_truncates_invalid_bytes() {
             .reveal_to_target(&TestKeychain::External, 21)
             .1,
         chain_graph: Default::default(),
+        frozen: Default::default(),
+        labels: Default::default(),
+        watched_spks: Default::default(),
+        watched_outpoints: Default::default(),
+        scan_progress: Default::default(),
     };
 
     let path = TempPath::new();
@@ -156,3 +165,205 @@ fn append_changeset_truncates_invalid_bytes() {
 
     assert_eq!(got_bytes, expected_bytes);
 }
+
+#[test]
+fn labels_and_freezes_are_persisted() {
+    let path = TempPath::new();
+    let tx = Transaction {
+        version: 0x01,
+        lock_time: bdk_chain::bitcoin::PackedLockTime(0),
+        input: vec![],
+        output: vec![],
+    };
+    let outpoint = bdk_chain::bitcoin::OutPoint::new(tx.txid(), 0);
+
+    let mut tracker = KeychainTracker::<TestKeychain, TxHeight, Transaction>::default();
+    let mut store = KeychainStore::<TestKeychain, TxHeight, Transaction>::new_from_path(&path)
+        .expect("should open");
+    let mut changeset = tracker.set_label(LabelRef::Tx(tx.txid()), Some("rent".into()));
+    changeset.append(tracker.set_label(
+        LabelRef::Spk((TestKeychain::External, 3)),
+        Some("exchange".into()),
+    ));
+    changeset.append(tracker.freeze_utxo(outpoint));
+    store.append_changeset(&changeset).expect("should append");
+    store
+        .append_changeset(&tracker.set_label(LabelRef::Tx(tx.txid()), None))
+        .expect("should append");
+    drop(store);
+
+    let mut loaded = KeychainTracker::<TestKeychain, TxHeight, Transaction>::default();
+    KeychainStore::<TestKeychain, TxHeight, Transaction>::new_from_path(&path)
+        .expect("should open")
+        .load_into_keychain_tracker(&mut loaded)
+        .expect("should load");
+    assert_eq!(loaded.labels(), tracker.labels());
+    assert_eq!(loaded.labels().tx_label(tx.txid()), None);
+    assert_eq!(
+        loaded.txout_label(&(TestKeychain::External, 3), outpoint),
+        Some("exchange")
+    );
+    assert!(loaded.frozen_utxos().contains(&outpoint));
+}
+
+#[test]
+fn metadata_is_stored_in_header() {
+    let path = TempPath::new();
+    let mut tracker = KeychainTracker::<TestKeychain, TxHeight, Transaction>::default();
+    let changeset = tracker.watch_spk(Default::default());
+
+    let mut store = KeychainStore::<TestKeychain, TxHeight, Transaction>::new_from_path(&path)
+        .expect("should open");
+    assert_eq!(store.metadata(), &StoreMetadata::default());
+    store.append_changeset(&changeset).expect("should append");
+    let metadata = StoreMetadata {
+        birthday: Some(Birthday::Height(800_000)),
+    };
+    store.set_metadata(metadata.clone()).expect("should set");
+    // the write position is kept after the last changeset
+    store.append_changeset(&changeset).expect("should append");
+    drop(store);
+
+    let mut store = KeychainStore::<TestKeychain, TxHeight, Transaction>::new_from_path(&path)
+        .expect("should open");
+    assert_eq!(store.metadata(), &metadata);
+    assert_eq!(
+        store
+            .iter_changesets()
+            .expect("should seek")
+            .collect::<Result<Vec<_>, _>>()
+            .expect("should read")
+            .len(),
+        2
+    );
+}
+
+#[test]
+fn set_metadata_upgrades_file_without_header() {
+    let path = TempPath::new();
+    path.open().write_all(&MAGIC_BYTES).expect("should write");
+    let mut tracker = KeychainTracker::<TestKeychain, TxHeight, Transaction>::default();
+    let changeset = tracker.watch_spk(Default::default());
+
+    // stores opened from a file cannot be upgraded atomically
+    let mut store = KeychainStore::<TestKeychain, TxHeight, Transaction>::new(path.open())
+        .expect("should open");
+    assert_eq!(store.metadata(), &StoreMetadata::default());
+    store.append_changeset(&changeset).expect("should append");
+    let metadata = StoreMetadata {
+        birthday: Some(Birthday::Time(1_700_000_000)),
+    };
+    assert_eq!(
+        store
+            .set_metadata(metadata.clone())
+            .expect_err("should not upgrade")
+            .kind(),
+        std::io::ErrorKind::Unsupported
+    );
+    assert_eq!(store.metadata(), &StoreMetadata::default());
+    drop(store);
+
+    let mut store = KeychainStore::<TestKeychain, TxHeight, Transaction>::new_from_path(&path)
+        .expect("should open");
+    let (_, result) = store.aggregate_changeset();
+    result.expect("should read");
+    store.set_metadata(metadata.clone()).expect("should set");
+    // the write position is kept after the last changeset
+    store.append_changeset(&changeset).expect("should append");
+    drop(store);
+
+    let mut magic_bytes = [0_u8; MAGIC_BYTES_LEN];
+    path.open()
+        .read_exact(&mut magic_bytes)
+        .expect("should read");
+    assert_eq!(magic_bytes, MAGIC_BYTES_WITH_METADATA);
+
+    let mut store = KeychainStore::<TestKeychain, TxHeight, Transaction>::new(path.open())
+        .expect("should open");
+    assert_eq!(store.metadata(), &metadata);
+    let mut loaded = KeychainTracker::<TestKeychain, TxHeight, Transaction>::default();
+    store
+        .load_into_keychain_tracker(&mut loaded)
+        .expect("should load");
+    assert_eq!(loaded.watched_spks(), tracker.watched_spks());
+    assert_eq!(
+        store
+            .iter_changesets()
+            .expect("should seek")
+            .collect::<Result<Vec<_>, _>>()
+            .expect("should read")
+            .len(),
+        2
+    );
+}
+
+#[test]
+fn scan_progress_is_persisted() {
+    let path = TempPath::new();
+    let mut tracker = KeychainTracker::<TestKeychain, TxHeight, Transaction>::default();
+    let mut progress = ScanProgress::new(BlockId {
+        height: 100,
+        hash: Hash::all_zeros(),
+    });
+    progress.record_finished(TestKeychain::External);
+    progress.record_scanned(&TestKeychain::Internal, 42);
+    let changeset = tracker
+        .apply_update(KeychainScan::<_, _, Transaction> {
+            progress: Some(progress.clone()),
+            ..Default::default()
+        })
+        .expect("should apply");
+    assert_eq!(changeset.scan_progress.as_ref(), Some(&progress));
+
+    let mut store = KeychainStore::<TestKeychain, TxHeight, Transaction>::new_from_path(&path)
+        .expect("should open");
+    store.append_changeset(&changeset).expect("should append");
+    drop(store);
+
+    let mut loaded = KeychainTracker::<TestKeychain, TxHeight, Transaction>::default();
+    KeychainStore::<TestKeychain, TxHeight, Transaction>::new_from_path(&path)
+        .expect("should open")
+        .load_into_keychain_tracker(&mut loaded)
+        .expect("should load");
+    assert_eq!(loaded.scan_progress(), &progress);
+}
+
+#[test]
+fn evictions_are_not_persisted() {
+    let path = TempPath::new();
+    let eviction = (
+        Txid::hash(b"evicted"),
+        Eviction {
+            by: Txid::hash(b"replacement"),
+            reason: EvictionReason::ConflictConfirmed,
+        },
+    );
+
+    // a changeset which only reports an eviction records no changes
+    let mut report_only = KeychainChangeSet::<TestKeychain, TxHeight, Transaction>::default();
+    report_only
+        .chain_graph
+        .evicted
+        .insert(eviction.0, eviction.1);
+    assert!(report_only.is_empty());
+
+    let mut tracker = KeychainTracker::<TestKeychain, TxHeight, Transaction>::default();
+    let mut changeset = tracker.watch_spk(Default::default());
+    changeset.chain_graph.evicted.insert(eviction.0, eviction.1);
+
+    let mut store = KeychainStore::<TestKeychain, TxHeight, Transaction>::new_from_path(&path)
+        .expect("should open");
+    store.append_changeset(&report_only).expect("should append");
+    store.append_changeset(&changeset).expect("should append");
+    drop(store);
+
+    let changesets = KeychainStore::<TestKeychain, TxHeight, Transaction>::new_from_path(&path)
+        .expect("should open")
+        .iter_changesets()
+        .expect("should seek")
+        .collect::<Result<Vec<_>, _>>()
+        .expect("should read");
+    assert_eq!(changesets.len(), 1);
+    assert!(changesets[0].chain_graph.evicted.is_empty());
+    assert_eq!(changesets[0].watched_spks, changeset.watched_spks);
+}